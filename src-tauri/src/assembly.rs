@@ -0,0 +1,1420 @@
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Page attributes a page can inherit from its ancestors in the page tree
+const INHERITABLE_ATTRIBUTES: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Page trees, outlines and name trees deeper than this are treated as malformed (or cyclic)
+const MAX_TREE_DEPTH: u32 = 64;
+
+/// A PDF to take pages from: a file on disk or bytes from the frontend
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PdfSource {
+    Path { path: PathBuf },
+    Content { content: Vec<u8> },
+}
+
+impl PdfSource {
+    pub fn load(&self) -> Result<Document, String> {
+        let doc = match self {
+            PdfSource::Path { path } => Document::load(path),
+            PdfSource::Content { content } => Document::load_mem(content),
+        }
+        .map_err(|e| format!("Failed to load PDF: {}", e))?;
+
+        // Pages of an encrypted file can't be moved into another without decrypting it
+        if doc.is_encrypted() {
+            return Err("Encrypted PDFs can't be edited".to_string());
+        }
+        Ok(doc)
+    }
+}
+
+/// A page of the output: the source it comes from and its page number there, from 1
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageSelection {
+    pub source: usize,
+    pub page: u32,
+}
+
+/// Result of a page assembly command
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssembledPdf {
+    /// The new PDF, or None when it was written to an output path
+    pub content: Option<Vec<u8>>,
+    pub page_count: usize,
+}
+
+/// Merge documents into one. `pages` picks and orders pages across the sources; without
+/// it every page of every source is used, in order. Outlines and form fields of all
+/// sources are kept, and links between them keep working.
+pub fn merge_pdfs_blocking(
+    sources: &[PdfSource],
+    pages: Option<&[PageSelection]>,
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    if sources.is_empty() {
+        return Err("No PDFs to merge".to_string());
+    }
+    let docs = sources
+        .iter()
+        .map(PdfSource::load)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let selection = match pages {
+        Some(pages) => pages.to_vec(),
+        None => docs
+            .iter()
+            .enumerate()
+            .flat_map(|(source, doc)| {
+                (1..=doc.get_pages().len() as u32).map(move |page| PageSelection { source, page })
+            })
+            .collect(),
+    };
+
+    let doc = assemble(docs, &selection)?;
+    save_assembled(doc, output_path)
+}
+
+/// Keep only the pages in `ranges`, e.g. `"1-3, 7, 10-"`, in the order given
+pub fn extract_pages_blocking(
+    source: &PdfSource,
+    ranges: &str,
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    let doc = source.load()?;
+    let pages = parse_page_ranges(ranges, doc.get_pages().len() as u32)?;
+    rearrange(doc, &pages, output_path)
+}
+
+/// Put pages in a new order. `order` lists every page number once.
+pub fn reorder_pages_blocking(
+    source: &PdfSource,
+    order: &[u32],
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    let doc = source.load()?;
+    let page_count = doc.get_pages().len();
+    let mut sorted = order.to_vec();
+    sorted.sort_unstable();
+    if !sorted.iter().copied().eq(1..=page_count as u32) {
+        return Err(format!(
+            "New order must list each of the {} pages exactly once",
+            page_count
+        ));
+    }
+    rearrange(doc, order, output_path)
+}
+
+/// Remove the given pages
+pub fn delete_pages_blocking(
+    source: &PdfSource,
+    pages: &[u32],
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    let doc = source.load()?;
+    let page_count = doc.get_pages().len() as u32;
+    if let Some(page) = pages.iter().find(|&&page| page == 0 || page > page_count) {
+        return Err(format!("Page {} doesn't exist", page));
+    }
+    let delete: HashSet<u32> = pages.iter().copied().collect();
+    let kept: Vec<u32> = (1..=page_count)
+        .filter(|page| !delete.contains(page))
+        .collect();
+    rearrange(doc, &kept, output_path)
+}
+
+/// Set `/Rotate` on the given pages, or on every page. `rotation` is in degrees clockwise
+/// and must be a multiple of 90.
+pub fn rotate_pages_blocking(
+    source: &PdfSource,
+    pages: Option<&[u32]>,
+    rotation: i64,
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    if rotation % 90 != 0 {
+        return Err("Rotation must be a multiple of 90 degrees".to_string());
+    }
+    let mut doc = source.load()?;
+    let page_ids = doc.get_pages();
+    let targets: Vec<ObjectId> = match pages {
+        Some(pages) => pages
+            .iter()
+            .map(|page| {
+                page_ids
+                    .get(page)
+                    .copied()
+                    .ok_or_else(|| format!("Page {} doesn't exist", page))
+            })
+            .collect::<Result<_, _>>()?,
+        None => page_ids.into_values().collect(),
+    };
+    for page_id in targets {
+        doc.get_dictionary_mut(page_id)
+            .map_err(|e| format!("Failed to read page: {}", e))?
+            .set("Rotate", rotation.rem_euclid(360));
+    }
+    save_assembled(doc, output_path)
+}
+
+/// Rebuild a single document from some of its pages
+fn rearrange(
+    doc: Document,
+    pages: &[u32],
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    let selection: Vec<PageSelection> = pages
+        .iter()
+        .map(|&page| PageSelection { source: 0, page })
+        .collect();
+    let doc = assemble(vec![doc], &selection)?;
+    save_assembled(doc, output_path)
+}
+
+/// Parse page ranges such as `"1-3, 7, 10-"` into page numbers. `a-` runs to the last page,
+/// `-b` from the first, and `5-3` counts down.
+pub fn parse_page_ranges(ranges: &str, page_count: u32) -> Result<Vec<u32>, String> {
+    let page = |text: &str| -> Result<u32, String> {
+        match text.trim().parse::<u32>() {
+            Ok(page) if (1..=page_count).contains(&page) => Ok(page),
+            Ok(page) => Err(format!("Page {} doesn't exist", page)),
+            Err(_) => Err(format!("\"{}\" is not a page number", text.trim())),
+        }
+    };
+
+    let mut pages = Vec::new();
+    for range in ranges
+        .split(',')
+        .map(str::trim)
+        .filter(|range| !range.is_empty())
+    {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let first = if first.trim().is_empty() {
+                    1
+                } else {
+                    page(first)?
+                };
+                let last = if last.trim().is_empty() {
+                    page_count
+                } else {
+                    page(last)?
+                };
+                if first <= last {
+                    pages.extend(first..=last);
+                } else {
+                    pages.extend((last..=first).rev());
+                }
+            }
+            None => pages.push(page(range)?),
+        }
+    }
+    if pages.is_empty() {
+        return Err("No pages selected".to_string());
+    }
+    Ok(pages)
+}
+
+/// Build a document from `selection`, taking the catalog, metadata and settings of the
+/// first source. Pages of the later sources are moved in under fresh object numbers.
+///
+/// Pages that aren't selected are dropped along with everything only they used. Outline
+/// entries and links that pointed at them are removed, as are their form fields; any
+/// other reference to them becomes null.
+pub fn assemble(mut docs: Vec<Document>, selection: &[PageSelection]) -> Result<Document, String> {
+    if selection.is_empty() {
+        return Err("The result would have no pages".to_string());
+    }
+    let merging = docs.len() > 1;
+
+    // Give each source object numbers clear of the ones before it
+    let mut source_pages: Vec<Vec<ObjectId>> = Vec::new();
+    let mut next_id = 0;
+    for doc in docs.iter_mut() {
+        shift_object_ids(doc, next_id);
+        next_id = doc.max_id;
+        flatten_inherited_attributes(doc);
+        if merging {
+            // Names can clash between sources; explicit destinations can't
+            resolve_named_destinations(doc);
+        }
+        source_pages.push(doc.get_pages().into_values().collect());
+    }
+    for selected in selection {
+        let exists = source_pages
+            .get(selected.source)
+            .is_some_and(|pages| (1..=pages.len() as u32).contains(&selected.page));
+        if !exists {
+            return Err(format!(
+                "Page {} of document {} doesn't exist",
+                selected.page,
+                selected.source + 1
+            ));
+        }
+    }
+
+    let mut docs = docs.into_iter();
+    let mut doc = docs.next().expect("at least one source");
+    let mut other_catalogs = Vec::new();
+    for other in docs {
+        if let Ok(catalog) = other.trailer.get(b"Root").and_then(Object::as_reference) {
+            other_catalogs.push(catalog);
+        }
+        doc.max_id = doc.max_id.max(other.max_id);
+        doc.objects.extend(other.objects);
+    }
+
+    // Pages used more than once get a copy of their own
+    let mut kids = Vec::with_capacity(selection.len());
+    let mut used = HashSet::new();
+    for selected in selection {
+        let page_id = source_pages[selected.source][selected.page as usize - 1];
+        if used.insert(page_id) {
+            if selected.source > 0 {
+                // Structure tree entries of later sources aren't carried over
+                if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                    page.remove(b"StructParents");
+                }
+            }
+            kids.push(page_id);
+        } else {
+            kids.push(duplicate_page(&mut doc, page_id)?);
+        }
+    }
+    let removed_pages: HashSet<ObjectId> = source_pages
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|page| !used.contains(page))
+        .collect();
+    let unchanged = !merging && kids == source_pages[0];
+
+    // One flat page tree under the first source's root node
+    let pages_root = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"Pages"))
+        .and_then(Object::as_reference)
+        .map_err(|_| "PDF has no page tree".to_string())?;
+    for &kid in &kids {
+        if let Ok(page) = doc.get_dictionary_mut(kid) {
+            page.set("Parent", pages_root);
+        }
+    }
+    let count = kids.len() as i64;
+    doc.objects.insert(
+        pages_root,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids.iter().map(|&kid| Object::Reference(kid)).collect::<Vec<_>>(),
+            "Count" => count,
+        }),
+    );
+
+    let mut field_names = top_level_field_names(&doc);
+    for catalog in other_catalogs {
+        append_outlines(&mut doc, catalog);
+        append_form_fields(&mut doc, catalog, &mut field_names);
+    }
+
+    let mut opens_removed_page = false;
+    if !removed_pages.is_empty() {
+        let names = named_destinations(&doc);
+        opens_removed_page =
+            open_action_page(&doc, &names).is_some_and(|page| removed_pages.contains(&page));
+        let removed_annots = removed_annotations(&doc, &removed_pages, &kids);
+        remove_dead_links(&mut doc, &kids, &removed_pages, &names);
+        prune_outline(&mut doc, &removed_pages, &names);
+        prune_named_destinations(&mut doc, &removed_pages, &names);
+        prune_form_fields(&mut doc, &removed_annots);
+        replace_references_with_null(&mut doc, &removed_pages);
+    }
+    if let Ok(catalog) = doc.catalog_mut() {
+        // Labels are assigned by page index, which no longer lines up
+        if !unchanged {
+            catalog.remove(b"PageLabels");
+        }
+        if opens_removed_page {
+            catalog.remove(b"OpenAction");
+        }
+    }
+    Ok(doc)
+}
+
+/// Drop unreferenced objects, number the rest from 1 and save
+pub fn save_assembled(
+    mut doc: Document,
+    output_path: Option<&Path>,
+) -> Result<AssembledPdf, String> {
+    doc.prune_objects();
+    doc.renumber_objects();
+    let page_count = doc.get_pages().len();
+
+    let content = match output_path {
+        Some(path) => {
+            let file =
+                File::create(path).map_err(|e| format!("Failed to create output file: {}", e))?;
+            let mut writer = BufWriter::new(file);
+            doc.save_to(&mut writer)
+                .map_err(|e| format!("Failed to save PDF: {}", e))?;
+            writer
+                .flush()
+                .map_err(|e| format!("Failed to write output file: {}", e))?;
+            None
+        }
+        None => {
+            let mut content = Vec::new();
+            doc.save_to(&mut content)
+                .map_err(|e| format!("Failed to save PDF: {}", e))?;
+            Some(content)
+        }
+    };
+
+    println!("Assembled PDF with {} pages", page_count);
+    Ok(AssembledPdf {
+        content,
+        page_count,
+    })
+}
+
+/// Add `offset` to every object number, and to every reference in the objects and trailer
+fn shift_object_ids(doc: &mut Document, offset: u32) {
+    if offset == 0 {
+        return;
+    }
+    fn shift(object: &mut Object, offset: u32) {
+        match object {
+            Object::Reference(id) => id.0 += offset,
+            Object::Array(items) => items.iter_mut().for_each(|item| shift(item, offset)),
+            Object::Dictionary(dict) => dict.iter_mut().for_each(|(_, value)| shift(value, offset)),
+            Object::Stream(stream) => stream
+                .dict
+                .iter_mut()
+                .for_each(|(_, value)| shift(value, offset)),
+            _ => {}
+        }
+    }
+
+    let objects = std::mem::take(&mut doc.objects);
+    for ((number, generation), mut object) in objects {
+        shift(&mut object, offset);
+        doc.objects.insert((number + offset, generation), object);
+    }
+    for (_, value) in doc.trailer.iter_mut() {
+        shift(value, offset);
+    }
+    doc.max_id += offset;
+}
+
+/// Copy attributes pages inherit from the page tree onto the pages themselves, since the
+/// tree is rebuilt
+fn flatten_inherited_attributes(doc: &mut Document) {
+    for page_id in doc.get_pages().into_values() {
+        let mut inherited = Vec::new();
+        let mut node_id = page_id;
+        for _ in 0..MAX_TREE_DEPTH {
+            let Ok(node) = doc.get_dictionary(node_id) else {
+                break;
+            };
+            for key in INHERITABLE_ATTRIBUTES {
+                let already = inherited.iter().any(|(found, _)| *found == key);
+                if let (false, Ok(value)) = (already, node.get(key)) {
+                    inherited.push((key, value.clone()));
+                }
+            }
+            match node.get(b"Parent").and_then(Object::as_reference) {
+                Ok(parent) => node_id = parent,
+                Err(_) => break,
+            }
+        }
+        if let Ok(page) = doc.get_dictionary_mut(page_id) {
+            for (key, value) in inherited {
+                page.set(key, value);
+            }
+        }
+    }
+}
+
+/// Copy a page for a second appearance. Content and resources are shared; annotations are
+/// copied too, except form widgets, which belong to exactly one field.
+fn duplicate_page(doc: &mut Document, page_id: ObjectId) -> Result<ObjectId, String> {
+    let mut page = doc
+        .get_dictionary(page_id)
+        .map_err(|e| format!("Failed to read page: {}", e))?
+        .clone();
+    page.remove(b"StructParents");
+    page.remove(b"B");
+    let copy_id = doc.new_object_id();
+
+    let annots = page
+        .remove(b"Annots")
+        .and_then(|annots| {
+            doc.dereference(&annots)
+                .ok()
+                .map(|(_, annots)| annots.clone())
+        })
+        .and_then(|annots| annots.as_array().ok().cloned())
+        .unwrap_or_default();
+    let mut copied = Vec::new();
+    for annot in annots {
+        let Ok((_, Object::Dictionary(annot))) = doc.dereference(&annot) else {
+            continue;
+        };
+        if matches!(
+            annot.get(b"Subtype").and_then(Object::as_name),
+            Ok(b"Widget")
+        ) {
+            continue;
+        }
+        let mut annot = annot.clone();
+        annot.set("P", copy_id);
+        annot.remove(b"StructParent");
+        copied.push(Object::Reference(doc.add_object(annot)));
+    }
+    if !copied.is_empty() {
+        page.set("Annots", copied);
+    }
+
+    doc.objects.insert(copy_id, Object::Dictionary(page));
+    Ok(copy_id)
+}
+
+/// Every named destination, from the catalog's `/Dests` dictionary and the `/Names`
+/// `/Dests` name tree, as explicit destinations
+pub fn named_destinations(doc: &Document) -> HashMap<Vec<u8>, Object> {
+    let mut names = HashMap::new();
+    let Ok(catalog) = doc.catalog() else {
+        return names;
+    };
+
+    let explicit = |value: &Object| -> Option<Object> {
+        let (_, value) = doc.dereference(value).ok()?;
+        match value {
+            Object::Array(_) => Some(value.clone()),
+            Object::Dictionary(dict) => {
+                let (_, dest) = doc.dereference(dict.get(b"D").ok()?).ok()?;
+                dest.as_array().ok().map(|_| dest.clone())
+            }
+            _ => None,
+        }
+    };
+
+    if let Ok((_, Object::Dictionary(dests))) = catalog
+        .get(b"Dests")
+        .and_then(|dests| doc.dereference(dests))
+    {
+        for (name, value) in dests.iter() {
+            if let Some(dest) = explicit(value) {
+                names.insert(name.clone(), dest);
+            }
+        }
+    }
+
+    let tree = catalog
+        .get(b"Names")
+        .and_then(|names| doc.dereference(names))
+        .and_then(|(_, names)| names.as_dict())
+        .and_then(|names| names.get(b"Dests"));
+    if let Ok(tree) = tree {
+        let mut entries = Vec::new();
+        collect_name_tree(doc, tree, 0, &mut entries);
+        for (name, value) in entries {
+            if let Some(dest) = explicit(&value) {
+                names.entry(name).or_insert(dest);
+            }
+        }
+    }
+    names
+}
+
+fn collect_name_tree(
+    doc: &Document,
+    node: &Object,
+    depth: u32,
+    entries: &mut Vec<(Vec<u8>, Object)>,
+) {
+    if depth > MAX_TREE_DEPTH {
+        return;
+    }
+    let Ok(node) = doc.dereference(node).and_then(|(_, node)| node.as_dict()) else {
+        return;
+    };
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        for pair in names.chunks(2) {
+            if let [Object::String(name, _), value] = pair {
+                entries.push((name.clone(), value.clone()));
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids {
+            collect_name_tree(doc, kid, depth + 1, entries);
+        }
+    }
+}
+
+/// Replace destinations given by name in outline items, links and GoTo actions with the
+/// explicit destination the name stands for
+fn resolve_named_destinations(doc: &mut Document) {
+    let names = named_destinations(doc);
+    if names.is_empty() {
+        return;
+    }
+    let lookup = |dest: &Object| match dest {
+        Object::Name(name) | Object::String(name, _) => names.get(name).cloned(),
+        _ => None,
+    };
+
+    for object in doc.objects.values_mut() {
+        let Object::Dictionary(dict) = object else {
+            continue;
+        };
+        if let Some(dest) = dict.get(b"Dest").ok().and_then(lookup) {
+            dict.set("Dest", dest);
+        }
+        let is_goto = matches!(dict.get(b"S").and_then(Object::as_name), Ok(b"GoTo"));
+        if let (true, Some(dest)) = (is_goto, dict.get(b"D").ok().and_then(lookup)) {
+            dict.set("D", dest);
+        }
+        // GoTo actions written directly into a link or outline item
+        if let Ok(Object::Dictionary(action)) = dict.get_mut(b"A") {
+            let is_goto = matches!(action.get(b"S").and_then(Object::as_name), Ok(b"GoTo"));
+            if let (true, Some(dest)) = (is_goto, action.get(b"D").ok().and_then(lookup)) {
+                action.set("D", dest);
+            }
+        }
+    }
+}
+
+/// The explicit destination an outline item or link goes to, from its `/Dest` or GoTo action
+pub fn destination<'a>(
+    doc: &'a Document,
+    dict: &'a Dictionary,
+    names: &'a HashMap<Vec<u8>, Object>,
+) -> Option<&'a [Object]> {
+    let dest = match dict.get(b"Dest") {
+        Ok(dest) => dest,
+        Err(_) => {
+            let (_, action) = doc.dereference(dict.get(b"A").ok()?).ok()?;
+            let action = action.as_dict().ok()?;
+            if !matches!(action.get(b"S").and_then(Object::as_name), Ok(b"GoTo")) {
+                return None;
+            }
+            action.get(b"D").ok()?
+        }
+    };
+    let dest = match doc.dereference(dest).ok()?.1 {
+        Object::Name(name) | Object::String(name, _) => names.get(name)?,
+        dest => dest,
+    };
+    dest.as_array().ok().map(Vec::as_slice)
+}
+
+/// The page an outline item or link goes to
+pub fn target_page(
+    doc: &Document,
+    dict: &Dictionary,
+    names: &HashMap<Vec<u8>, Object>,
+) -> Option<ObjectId> {
+    match destination(doc, dict, names)?.first()? {
+        Object::Reference(page) => Some(*page),
+        _ => None,
+    }
+}
+
+/// The page the document opens at, from a destination or GoTo action in `/OpenAction`
+fn open_action_page(doc: &Document, names: &HashMap<Vec<u8>, Object>) -> Option<ObjectId> {
+    let open_action = doc.catalog().ok()?.get(b"OpenAction").ok()?;
+    // Looked up like the `/Dest` or `/A` of a link
+    let key = match doc.dereference(open_action).ok()?.1 {
+        Object::Dictionary(_) => "A",
+        _ => "Dest",
+    };
+    target_page(doc, &dictionary! { key => open_action.clone() }, names)
+}
+
+/// Annotations on removed pages that no kept page also lists
+fn removed_annotations(
+    doc: &Document,
+    removed_pages: &HashSet<ObjectId>,
+    kept_pages: &[ObjectId],
+) -> HashSet<ObjectId> {
+    let annots_of = |page_id: &ObjectId| -> Vec<ObjectId> {
+        doc.get_dictionary(*page_id)
+            .and_then(|page| page.get(b"Annots"))
+            .and_then(|annots| doc.dereference(annots))
+            .and_then(|(_, annots)| annots.as_array())
+            .map(|annots| {
+                annots
+                    .iter()
+                    .filter_map(|annot| annot.as_reference().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let kept: HashSet<ObjectId> = kept_pages.iter().flat_map(annots_of).collect();
+    removed_pages
+        .iter()
+        .flat_map(annots_of)
+        .filter(|annot| !kept.contains(annot))
+        .collect()
+}
+
+/// Remove link annotations on kept pages that go to a removed page
+fn remove_dead_links(
+    doc: &mut Document,
+    kept_pages: &[ObjectId],
+    removed_pages: &HashSet<ObjectId>,
+    names: &HashMap<Vec<u8>, Object>,
+) {
+    for &page_id in kept_pages {
+        let Ok(annots) = doc
+            .get_dictionary(page_id)
+            .and_then(|page| page.get(b"Annots"))
+            .cloned()
+        else {
+            continue;
+        };
+        let Ok((annots_id, Object::Array(items))) = doc.dereference(&annots) else {
+            continue;
+        };
+        let kept: Vec<Object> = items
+            .iter()
+            .filter(|annot| {
+                let Ok((_, Object::Dictionary(annot))) = doc.dereference(annot) else {
+                    return true;
+                };
+                let is_link =
+                    matches!(annot.get(b"Subtype").and_then(Object::as_name), Ok(b"Link"));
+                !is_link
+                    || !target_page(doc, annot, names)
+                        .is_some_and(|page| removed_pages.contains(&page))
+            })
+            .cloned()
+            .collect();
+        if kept.len() == items.len() {
+            continue;
+        }
+        match annots_id {
+            Some(id) => {
+                doc.objects.insert(id, Object::Array(kept));
+            }
+            None => {
+                if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                    if kept.is_empty() {
+                        page.remove(b"Annots");
+                    } else {
+                        page.set("Annots", kept);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn outline_root(doc: &Document) -> Option<ObjectId> {
+    doc.catalog()
+        .ok()?
+        .get(b"Outlines")
+        .and_then(Object::as_reference)
+        .ok()
+}
+
+/// The items directly under an outline node, following `/First` and `/Next`
+pub fn outline_children(doc: &Document, parent: ObjectId) -> Vec<ObjectId> {
+    let mut children = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = doc
+        .get_dictionary(parent)
+        .and_then(|parent| parent.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
+    while let Some(id) = next {
+        if !seen.insert(id) {
+            break;
+        }
+        children.push(id);
+        next = doc
+            .get_dictionary(id)
+            .and_then(|item| item.get(b"Next"))
+            .and_then(Object::as_reference)
+            .ok();
+    }
+    children
+}
+
+/// Make `children` the items under `parent`, in order
+pub fn relink_outline_children(doc: &mut Document, parent: ObjectId, children: &[ObjectId]) {
+    for (index, &id) in children.iter().enumerate() {
+        let Ok(item) = doc.get_dictionary_mut(id) else {
+            continue;
+        };
+        item.set("Parent", parent);
+        match index.checked_sub(1).map(|prev| children[prev]) {
+            Some(prev) => item.set("Prev", prev),
+            None => {
+                item.remove(b"Prev");
+            }
+        }
+        match children.get(index + 1) {
+            Some(&next) => item.set("Next", next),
+            None => {
+                item.remove(b"Next");
+            }
+        }
+    }
+    if let Ok(parent) = doc.get_dictionary_mut(parent) {
+        match (children.first(), children.last()) {
+            (Some(&first), Some(&last)) => {
+                parent.set("First", first);
+                parent.set("Last", last);
+            }
+            _ => {
+                parent.remove(b"First");
+                parent.remove(b"Last");
+                parent.remove(b"Count");
+            }
+        }
+    }
+}
+
+/// Set `/Count` on every node below `node`: the number of items visible under it, negative
+/// for closed items. Returns the number visible when `node` is open.
+pub fn recount_outline(doc: &mut Document, node: ObjectId, is_root: bool, depth: u32) -> i64 {
+    if depth > MAX_TREE_DEPTH {
+        return 0;
+    }
+    let mut visible = 0;
+    for child in outline_children(doc, node) {
+        let open = doc
+            .get_dictionary(child)
+            .and_then(|item| item.get(b"Count"))
+            .and_then(Object::as_i64)
+            .is_ok_and(|count| count > 0);
+        let below = recount_outline(doc, child, false, depth + 1);
+        visible += 1 + if open { below } else { 0 };
+    }
+    if let Ok(node) = doc.get_dictionary_mut(node) {
+        let had_count = node.has(b"Count");
+        let open = is_root
+            || node
+                .get(b"Count")
+                .and_then(Object::as_i64)
+                .is_ok_and(|count| count > 0);
+        if visible == 0 {
+            node.remove(b"Count");
+        } else if open || had_count {
+            node.set("Count", if open { visible } else { -visible });
+        }
+    }
+    visible
+}
+
+/// Add the top-level items of another source's outline after this document's
+fn append_outlines(doc: &mut Document, other_catalog: ObjectId) {
+    let Ok(other_root) = doc
+        .get_dictionary(other_catalog)
+        .and_then(|catalog| catalog.get(b"Outlines"))
+        .and_then(Object::as_reference)
+    else {
+        return;
+    };
+    let added = outline_children(doc, other_root);
+    if added.is_empty() {
+        return;
+    }
+
+    let root = match outline_root(doc) {
+        Some(root) => root,
+        None => {
+            let root = doc.add_object(dictionary! { "Type" => "Outlines" });
+            if let Ok(catalog) = doc.catalog_mut() {
+                catalog.set("Outlines", root);
+            }
+            root
+        }
+    };
+    let mut children = outline_children(doc, root);
+    children.extend(added);
+    relink_outline_children(doc, root, &children);
+    recount_outline(doc, root, true, 0);
+}
+
+/// Remove the destination of outline items that went to a removed page, and drop such
+/// items altogether when nothing is nested under them
+fn prune_outline(
+    doc: &mut Document,
+    removed_pages: &HashSet<ObjectId>,
+    names: &HashMap<Vec<u8>, Object>,
+) {
+    fn prune(
+        doc: &mut Document,
+        node: ObjectId,
+        removed_pages: &HashSet<ObjectId>,
+        names: &HashMap<Vec<u8>, Object>,
+        depth: u32,
+    ) {
+        if depth > MAX_TREE_DEPTH {
+            return;
+        }
+        let mut kept = Vec::new();
+        let children = outline_children(doc, node);
+        for &child in &children {
+            prune(doc, child, removed_pages, names, depth + 1);
+            let Ok(item) = doc.get_dictionary(child) else {
+                continue;
+            };
+            let dead =
+                target_page(doc, item, names).is_some_and(|page| removed_pages.contains(&page));
+            let has_children = item.has(b"First");
+            if dead {
+                if !has_children {
+                    continue;
+                }
+                if let Ok(item) = doc.get_dictionary_mut(child) {
+                    item.remove(b"Dest");
+                    item.remove(b"A");
+                }
+            }
+            kept.push(child);
+        }
+        if kept.len() != children.len() {
+            relink_outline_children(doc, node, &kept);
+        }
+    }
+
+    let Some(root) = outline_root(doc) else {
+        return;
+    };
+    prune(doc, root, removed_pages, names, 0);
+    recount_outline(doc, root, true, 0);
+}
+
+/// Remove named destinations that go to a removed page
+fn prune_named_destinations(
+    doc: &mut Document,
+    removed_pages: &HashSet<ObjectId>,
+    names: &HashMap<Vec<u8>, Object>,
+) {
+    let dead: HashSet<&Vec<u8>> = names
+        .iter()
+        .filter(|(_, dest)| {
+            matches!(dest.as_array().map(|dest| dest.first()), Ok(Some(Object::Reference(page))) if removed_pages.contains(page))
+        })
+        .map(|(name, _)| name)
+        .collect();
+    if dead.is_empty() {
+        return;
+    }
+
+    // Leaves of the name tree, and the catalog's /Dests dictionary, wherever they live
+    let mut leaves = Vec::new();
+    let mut pending: Vec<(Object, u32)> = Vec::new();
+    if let Ok(catalog) = doc.catalog() {
+        if let Ok(tree) = catalog
+            .get(b"Names")
+            .and_then(|names| doc.dereference(names))
+            .and_then(|(_, names)| names.as_dict())
+            .and_then(|names| names.get(b"Dests"))
+        {
+            pending.push((tree.clone(), 0));
+        }
+    }
+    while let Some((node, depth)) = pending.pop() {
+        let Ok(id) = node.as_reference() else {
+            continue;
+        };
+        let Ok(dict) = doc.get_dictionary(id) else {
+            continue;
+        };
+        if dict.has(b"Names") {
+            leaves.push(id);
+        }
+        if let (true, Ok(kids)) = (
+            depth < MAX_TREE_DEPTH,
+            dict.get(b"Kids").and_then(Object::as_array),
+        ) {
+            pending.extend(kids.iter().map(|kid| (kid.clone(), depth + 1)));
+        }
+    }
+    for leaf in leaves {
+        if let Ok(Object::Array(entries)) = doc
+            .get_dictionary_mut(leaf)
+            .and_then(|leaf| leaf.get_mut(b"Names"))
+        {
+            let kept: Vec<Object> = entries
+                .chunks(2)
+                .filter(|pair| !matches!(pair, [Object::String(name, _), _] if dead.contains(name)))
+                .flatten()
+                .cloned()
+                .collect();
+            *entries = kept;
+        }
+    }
+
+    let dests = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"Dests"))
+        .and_then(Object::as_reference);
+    let dests = match dests {
+        Ok(id) => doc.get_dictionary_mut(id).ok(),
+        Err(_) => doc
+            .catalog_mut()
+            .ok()
+            .and_then(|catalog| catalog.get_mut(b"Dests").ok())
+            .and_then(|dests| dests.as_dict_mut().ok()),
+    };
+    if let Some(dests) = dests {
+        for name in dead {
+            dests.remove(name);
+        }
+    }
+}
+
+fn acro_form_mut(doc: &mut Document) -> Option<&mut Dictionary> {
+    let form = doc.catalog().ok()?.get(b"AcroForm").ok()?;
+    match form {
+        Object::Reference(id) => {
+            let id = *id;
+            doc.get_dictionary_mut(id).ok()
+        }
+        _ => doc
+            .catalog_mut()
+            .ok()?
+            .get_mut(b"AcroForm")
+            .and_then(Object::as_dict_mut)
+            .ok(),
+    }
+}
+
+/// The fields array of a form dictionary, resolving it if it's indirect
+fn form_fields(doc: &Document, form: &Dictionary) -> Vec<Object> {
+    form.get(b"Fields")
+        .and_then(|fields| doc.dereference(fields))
+        .and_then(|(_, fields)| fields.as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn top_level_field_names(doc: &Document) -> HashSet<Vec<u8>> {
+    let form = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"AcroForm"))
+        .and_then(|form| doc.dereference(form))
+        .and_then(|(_, form)| form.as_dict());
+    let Ok(form) = form else {
+        return HashSet::new();
+    };
+    form_fields(doc, form)
+        .iter()
+        .filter_map(|field| doc.dereference(field).ok())
+        .filter_map(|(_, field)| field.as_dict().ok()?.get(b"T").ok()?.as_str().ok())
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// Add another source's form fields to this document's form. Fields with the same name
+/// would share one value, so clashing names get a number appended.
+fn append_form_fields(doc: &mut Document, other_catalog: ObjectId, names: &mut HashSet<Vec<u8>>) {
+    let other_form = doc
+        .get_dictionary(other_catalog)
+        .and_then(|catalog| catalog.get(b"AcroForm"))
+        .and_then(|form| doc.dereference(form))
+        .and_then(|(_, form)| form.as_dict())
+        .cloned();
+    let Ok(other_form) = other_form else {
+        return;
+    };
+    let fields = form_fields(doc, &other_form);
+    if fields.is_empty() {
+        return;
+    }
+
+    for field in &fields {
+        let Ok(id) = field.as_reference() else {
+            continue;
+        };
+        let Ok(field) = doc.get_dictionary_mut(id) else {
+            continue;
+        };
+        let Ok(name) = field.get(b"T").and_then(Object::as_str).map(<[u8]>::to_vec) else {
+            continue;
+        };
+        let mut unique = name.clone();
+        let mut suffix = 2;
+        while names.contains(&unique) {
+            unique = [name.as_slice(), format!(" ({})", suffix).as_bytes()].concat();
+            suffix += 1;
+        }
+        if unique != name {
+            field.set("T", Object::string_literal(unique.clone()));
+        }
+        names.insert(unique);
+    }
+
+    if acro_form_mut(doc).is_none() {
+        let form = doc.add_object(dictionary! { "Fields" => Vec::<Object>::new() });
+        if let Ok(catalog) = doc.catalog_mut() {
+            catalog.set("AcroForm", form);
+        }
+    }
+    let mut all_fields = {
+        let form = acro_form_mut(doc).expect("form was just added").clone();
+        form_fields(doc, &form)
+    };
+    all_fields.extend(fields);
+
+    let other_fonts = other_form
+        .get(b"DR")
+        .and_then(|resources| doc.dereference(resources))
+        .and_then(|(_, resources)| resources.as_dict())
+        .and_then(|resources| resources.get(b"Font"))
+        .and_then(|fonts| doc.dereference(fonts))
+        .and_then(|(_, fonts)| fonts.as_dict())
+        .cloned()
+        .ok();
+    let Some(form) = acro_form_mut(doc) else {
+        return;
+    };
+    form.set("Fields", all_fields);
+    if other_form
+        .get(b"NeedAppearances")
+        .and_then(Object::as_bool)
+        .unwrap_or(false)
+    {
+        form.set("NeedAppearances", true);
+    }
+    for key in [b"DA".as_slice(), b"Q"] {
+        if let (false, Ok(value)) = (form.has(key), other_form.get(key)) {
+            form.set(key, value.clone());
+        }
+    }
+    // Fonts the other form's fields name in their default appearance; ones this form
+    // already has under the same name are kept
+    if let Some(other_fonts) = other_fonts {
+        if !form.has(b"DR") {
+            form.set("DR", dictionary! {});
+        }
+        if let Ok(resources) = form.get_mut(b"DR").and_then(Object::as_dict_mut) {
+            if !resources.has(b"Font") {
+                resources.set("Font", dictionary! {});
+            }
+            if let Ok(fonts) = resources.get_mut(b"Font").and_then(Object::as_dict_mut) {
+                for (name, font) in other_fonts.iter() {
+                    if !fonts.has(name) {
+                        fonts.set(name.clone(), font.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Remove form fields whose widgets were all on removed pages
+fn prune_form_fields(doc: &mut Document, removed_annots: &HashSet<ObjectId>) {
+    fn prune(
+        doc: &mut Document,
+        fields: Vec<Object>,
+        removed_annots: &HashSet<ObjectId>,
+        depth: u32,
+    ) -> Vec<Object> {
+        let mut kept = Vec::new();
+        for field in fields {
+            let Ok(id) = field.as_reference() else {
+                kept.push(field);
+                continue;
+            };
+            if removed_annots.contains(&id) {
+                continue;
+            }
+            let kids = doc
+                .get_dictionary(id)
+                .and_then(|field| field.get(b"Kids"))
+                .and_then(Object::as_array)
+                .cloned()
+                .unwrap_or_default();
+            if depth < MAX_TREE_DEPTH && !kids.is_empty() {
+                let count = kids.len();
+                let kids = prune(doc, kids, removed_annots, depth + 1);
+                if kids.is_empty() {
+                    continue;
+                }
+                if kids.len() != count {
+                    if let Ok(field) = doc.get_dictionary_mut(id) {
+                        field.set("Kids", kids);
+                    }
+                }
+            }
+            kept.push(field);
+        }
+        kept
+    }
+
+    if removed_annots.is_empty() {
+        return;
+    }
+    let Some(fields) = acro_form_mut(doc).map(|form| form.clone()) else {
+        return;
+    };
+    let fields = form_fields(doc, &fields);
+    let fields = prune(doc, fields, removed_annots, 0);
+    if let Some(form) = acro_form_mut(doc) {
+        form.set("Fields", fields);
+    }
+}
+
+/// Replace every remaining reference to the given objects with null
+fn replace_references_with_null(doc: &mut Document, ids: &HashSet<ObjectId>) {
+    fn replace(object: &mut Object, ids: &HashSet<ObjectId>) {
+        match object {
+            Object::Reference(id) if ids.contains(id) => *object = Object::Null,
+            Object::Array(items) => items.iter_mut().for_each(|item| replace(item, ids)),
+            Object::Dictionary(dict) => dict.iter_mut().for_each(|(_, value)| replace(value, ids)),
+            Object::Stream(stream) => stream
+                .dict
+                .iter_mut()
+                .for_each(|(_, value)| replace(value, ids)),
+            _ => {}
+        }
+    }
+
+    for (id, object) in doc.objects.iter_mut() {
+        if !ids.contains(id) {
+            replace(object, ids);
+        }
+    }
+    for (_, value) in doc.trailer.iter_mut() {
+        replace(value, ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::decode_text_string;
+
+    /// A document whose pages are named `{prefix}1`, `{prefix}2`… in a `/Name` entry, with
+    /// a bookmark per `(title, page index)` and a link from the first page to each of
+    /// `links`
+    fn document(
+        prefix: &str,
+        pages: usize,
+        bookmarks: &[(&str, usize)],
+        links: &[usize],
+    ) -> Document {
+        let (mut doc, page_ids) = testing::document(pages, |doc, index| {
+            dictionary! {
+                "Contents" => testing::content(doc, b"0 0 m"),
+                "Name" => Object::string_literal(format!("{}{}", prefix, index + 1)),
+            }
+        });
+        let annots: Vec<Object> = links
+            .iter()
+            .map(|&target| {
+                doc.add_object(dictionary! {
+                    "Type" => "Annot",
+                    "Subtype" => "Link",
+                    "Rect" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+                    "Dest" => vec![page_ids[target].into(), "Fit".into()],
+                })
+                .into()
+            })
+            .collect();
+        if !annots.is_empty() {
+            doc.get_dictionary_mut(page_ids[0])
+                .unwrap()
+                .set("Annots", annots);
+        }
+        testing::add_bookmarks(&mut doc, &page_ids, bookmarks);
+        doc
+    }
+
+    /// Save and re-read an assembled document
+    fn reload(doc: Document) -> Document {
+        let content = save_assembled(doc, None).unwrap().content.unwrap();
+        Document::load_mem(&content).unwrap()
+    }
+
+    fn page_name(doc: &Document, page_id: ObjectId) -> String {
+        let name = doc.get_dictionary(page_id).unwrap().get(b"Name").unwrap();
+        decode_text_string(name).unwrap()
+    }
+
+    fn page_names(doc: &Document) -> Vec<String> {
+        doc.get_pages()
+            .into_values()
+            .map(|id| page_name(doc, id))
+            .collect()
+    }
+
+    /// Each bookmark's title and the name of the page it goes to
+    fn bookmarks(doc: &Document) -> Vec<(String, String)> {
+        let names = named_destinations(doc);
+        let Some(root) = outline_root(doc) else {
+            return Vec::new();
+        };
+        outline_children(doc, root)
+            .into_iter()
+            .map(|id| {
+                let item = doc.get_dictionary(id).unwrap();
+                let title = decode_text_string(item.get(b"Title").unwrap()).unwrap();
+                (
+                    title,
+                    page_name(doc, target_page(doc, item, &names).unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    /// The names of the pages the links on the first page go to
+    fn links(doc: &Document) -> Vec<String> {
+        let names = named_destinations(doc);
+        let first_page = doc.get_pages()[&1];
+        let Ok(annots) = doc.get_dictionary(first_page).unwrap().get(b"Annots") else {
+            return Vec::new();
+        };
+        annots
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|annot| {
+                let annot = doc.get_dictionary(annot.as_reference().unwrap()).unwrap();
+                page_name(doc, target_page(doc, annot, &names).unwrap())
+            })
+            .collect()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_page_ranges() {
+        assert_eq!(
+            parse_page_ranges("1-3, 7, 10-", 12).unwrap(),
+            [1, 2, 3, 7, 10, 11, 12]
+        );
+        assert_eq!(parse_page_ranges("-2,5-3", 6).unwrap(), [1, 2, 5, 4, 3]);
+        assert_eq!(parse_page_ranges(" 2 , ,2 ", 3).unwrap(), [2, 2]);
+        assert_eq!(parse_page_ranges("-", 3).unwrap(), [1, 2, 3]);
+        assert!(parse_page_ranges("0", 3).is_err());
+        assert!(parse_page_ranges("2-4", 3).is_err());
+        assert!(parse_page_ranges("one", 3).is_err());
+        assert!(parse_page_ranges(" , ", 3).is_err());
+    }
+
+    #[test]
+    fn merging_keeps_outlines_and_links() {
+        let first = document("a", 2, &[("A", 1)], &[1]);
+        let second = document("b", 2, &[("B", 0), ("B2", 1)], &[1]);
+        let selection = [
+            PageSelection { source: 1, page: 2 },
+            PageSelection { source: 0, page: 1 },
+            PageSelection { source: 0, page: 2 },
+            PageSelection { source: 1, page: 1 },
+        ];
+        let merged = reload(assemble(vec![first, second], &selection).unwrap());
+
+        assert_eq!(page_names(&merged), strings(&["b2", "a1", "a2", "b1"]));
+        assert_eq!(
+            bookmarks(&merged),
+            [("A", "a2"), ("B", "b1"), ("B2", "b2")]
+                .map(|(title, page)| (title.to_string(), page.to_string()))
+        );
+        // The first page is now b2, which has no links; a1's link still goes to a2
+        assert!(links(&merged).is_empty());
+        let a1 = merged.get_pages()[&2];
+        let annot = merged
+            .get_dictionary(a1)
+            .unwrap()
+            .get(b"Annots")
+            .unwrap()
+            .as_array()
+            .unwrap()[0]
+            .as_reference()
+            .unwrap();
+        let annot = merged.get_dictionary(annot).unwrap();
+        let target = target_page(&merged, annot, &named_destinations(&merged)).unwrap();
+        assert_eq!(page_name(&merged, target), "a2");
+    }
+
+    #[test]
+    fn dropping_pages_removes_bookmarks_and_links_to_them() {
+        let doc = document("p", 3, &[("One", 0), ("Three", 2)], &[1, 2]);
+        let extracted = reload(
+            assemble(
+                vec![doc],
+                &[
+                    PageSelection { source: 0, page: 1 },
+                    PageSelection { source: 0, page: 3 },
+                ],
+            )
+            .unwrap(),
+        );
+        assert_eq!(page_names(&extracted), strings(&["p1", "p3"]));
+        assert_eq!(
+            bookmarks(&extracted),
+            [
+                ("One".to_string(), "p1".to_string()),
+                ("Three".to_string(), "p3".to_string())
+            ]
+        );
+        assert_eq!(links(&extracted), strings(&["p3"]));
+    }
+
+    #[test]
+    fn repeated_pages_get_copies() {
+        let doc = document("p", 2, &[], &[1]);
+        let selection = [1, 1, 2].map(|page| PageSelection { source: 0, page });
+        let doc = reload(assemble(vec![doc], &selection).unwrap());
+        assert_eq!(page_names(&doc), strings(&["p1", "p1", "p2"]));
+        let pages: HashSet<ObjectId> = doc.get_pages().into_values().collect();
+        assert_eq!(pages.len(), 3);
+    }
+
+    #[test]
+    fn drops_an_open_action_to_a_removed_page() {
+        let open_at = |open_action: fn(ObjectId) -> Object, page: u32| {
+            let mut doc = document("p", 3, &[], &[]);
+            let target = doc.get_pages()[&3];
+            doc.catalog_mut()
+                .unwrap()
+                .set("OpenAction", open_action(target));
+            let selection = [PageSelection { source: 0, page }];
+            let doc = reload(assemble(vec![doc], &selection).unwrap());
+            let open_action = doc.catalog().unwrap().get(b"OpenAction").ok().cloned();
+            open_action.map(|_| open_action_page(&doc, &named_destinations(&doc)).unwrap())
+        };
+        let explicit = |page: ObjectId| Object::Array(vec![page.into(), "Fit".into()]);
+        let goto = |page: ObjectId| {
+            Object::Dictionary(dictionary! {
+                "S" => "GoTo",
+                "D" => vec![page.into(), "Fit".into()],
+            })
+        };
+
+        assert_eq!(open_at(explicit, 1), None);
+        assert_eq!(open_at(goto, 1), None);
+        assert!(open_at(explicit, 3).is_some());
+        assert!(open_at(goto, 3).is_some());
+    }
+
+    #[test]
+    fn reordering_needs_every_page_once() {
+        let source = PdfSource::Content {
+            content: testing::save(&mut document("p", 3, &[], &[])),
+        };
+        let reordered = reorder_pages_blocking(&source, &[3, 1, 2], None).unwrap();
+        let doc = Document::load_mem(&reordered.content.unwrap()).unwrap();
+        assert_eq!(page_names(&doc), strings(&["p3", "p1", "p2"]));
+        assert!(reorder_pages_blocking(&source, &[1, 2], None).is_err());
+        assert!(reorder_pages_blocking(&source, &[1, 1, 2], None).is_err());
+    }
+}
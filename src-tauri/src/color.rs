@@ -0,0 +1,537 @@
+use crate::optimize::deflate_best;
+use crate::resources::{decode_content, page_content, stream_content};
+use lopdf::content::Operation;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use moxcms::{ColorProfile, DataColorSpace, Layout, Transform8BitExecutor, TransformOptions};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// A colour-managed CMYK → sRGB conversion built from an ICC profile
+pub type CmykTransform = Arc<Transform8BitExecutor>;
+
+/// Build a CMYK → sRGB transform from raw ICC profile bytes.
+/// Returns None when the profile can't be parsed or doesn't describe a CMYK space.
+pub fn cmyk_to_srgb_transform(icc: &[u8]) -> Option<CmykTransform> {
+    let profile = ColorProfile::new_from_slice(icc).ok()?;
+    if profile.color_space != DataColorSpace::Cmyk {
+        return None;
+    }
+
+    // moxcms takes four-channel input through the Rgba layout
+    profile
+        .create_transform_8bit(
+            Layout::Rgba,
+            &ColorProfile::new_srgb(),
+            Layout::Rgb,
+            TransformOptions::default(),
+        )
+        .ok()
+}
+
+/// Convert interleaved 8-bit CMYK samples to 8-bit sRGB through the given transform
+pub fn convert_cmyk(transform: &CmykTransform, cmyk: &[u8]) -> Option<Vec<u8>> {
+    if !cmyk.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut rgb = vec![0u8; cmyk.len() / 4 * 3];
+    transform.transform(cmyk, &mut rgb).ok()?;
+    Some(rgb)
+}
+
+/// The CMYK profile of the document's output intent (`/OutputIntents` in the catalog), if any.
+/// Print-ready PDFs (PDF/X) declare the press condition here, and it's the profile
+/// DeviceCMYK content is meant to be rendered with.
+pub fn output_intent_cmyk_profile(doc: &Document) -> Option<Vec<u8>> {
+    let intents = doc.catalog().ok()?.get(b"OutputIntents").ok()?;
+    let intents = doc.dereference(intents).ok()?.1.as_array().ok()?;
+
+    intents.iter().find_map(|intent| {
+        let intent = doc.dereference(intent).ok()?.1.as_dict().ok()?;
+        let profile = intent.get(b"DestOutputProfile").ok()?;
+        let Object::Stream(stream) = doc.dereference(profile).ok()?.1 else {
+            return None;
+        };
+        if stream.dict.get(b"N").and_then(Object::as_i64).ok() != Some(4) {
+            return None;
+        }
+        icc_profile_data(stream)
+    })
+}
+
+/// The decoded bytes of an ICC profile stream
+pub fn icc_profile_data(stream: &Stream) -> Option<Vec<u8>> {
+    if stream.dict.has(b"Filter") {
+        stream.decompressed_content().ok()
+    } else {
+        Some(stream.content.clone())
+    }
+}
+
+/// Gray level for an RGB colour, using the NTSC weights PDF itself uses for conversions
+fn rgb_to_gray(r: f32, g: f32, b: f32) -> f32 {
+    0.3 * r + 0.59 * g + 0.11 * b
+}
+
+/// Gray level for a CMYK colour: the inverse of the ink coverage, as in PDF's own conversion
+fn cmyk_to_gray(c: f32, m: f32, y: f32, k: f32) -> f32 {
+    1.0 - (0.3 * c + 0.59 * m + 0.11 * y + k).min(1.0)
+}
+
+/// Convert interleaved 8-bit RGB (3 channels) or CMYK (4 channels) samples to 8-bit gray
+pub fn pixels_to_gray(pixels: &[u8], channels: u32) -> Option<Vec<u8>> {
+    let unit = |v: u8| v as f32 / 255.0;
+    let to_byte = |v: f32| (v * 255.0).round().clamp(0.0, 255.0) as u8;
+
+    match channels {
+        3 => Some(
+            pixels
+                .chunks_exact(3)
+                .map(|p| to_byte(rgb_to_gray(unit(p[0]), unit(p[1]), unit(p[2]))))
+                .collect(),
+        ),
+        4 => Some(
+            pixels
+                .chunks_exact(4)
+                .map(|p| to_byte(cmyk_to_gray(unit(p[0]), unit(p[1]), unit(p[2]), unit(p[3]))))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Colour model of a colorspace as far as gray conversion is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorModel {
+    Gray,
+    Rgb,
+    Cmyk,
+    /// Pattern, Separation, Lab, etc. — left as they are
+    Other,
+}
+
+impl ColorModel {
+    fn from_name(name: &[u8]) -> ColorModel {
+        match name {
+            b"DeviceGray" | b"CalGray" => ColorModel::Gray,
+            b"DeviceRGB" | b"CalRGB" => ColorModel::Rgb,
+            b"DeviceCMYK" => ColorModel::Cmyk,
+            _ => ColorModel::Other,
+        }
+    }
+
+    /// Resolve a colorspace object (a name or an array such as [/ICCBased stream])
+    fn from_object(doc: &Document, color_space: &Object) -> ColorModel {
+        let Ok((_, color_space)) = doc.dereference(color_space) else {
+            return ColorModel::Other;
+        };
+        match color_space {
+            Object::Name(name) => ColorModel::from_name(name),
+            Object::Array(arr) => match arr.first().and_then(|f| f.as_name().ok()) {
+                Some(b"ICCBased") => {
+                    let components = arr
+                        .get(1)
+                        .and_then(|profile| doc.dereference(profile).ok())
+                        .and_then(|(_, profile)| profile.as_stream().ok())
+                        .and_then(|profile| profile.dict.get(b"N").and_then(Object::as_i64).ok());
+                    match components {
+                        Some(1) => ColorModel::Gray,
+                        Some(3) => ColorModel::Rgb,
+                        Some(4) => ColorModel::Cmyk,
+                        _ => ColorModel::Other,
+                    }
+                }
+                Some(family) => ColorModel::from_name(family),
+                None => ColorModel::Other,
+            },
+            _ => ColorModel::Other,
+        }
+    }
+
+    /// The gray level for a colour given as operands in this model, if they are all numbers
+    fn gray_level(self, operands: &[Object]) -> Option<f32> {
+        let values: Vec<f32> = operands
+            .iter()
+            .map(|o| o.as_float().ok())
+            .collect::<Option<_>>()?;
+        let gray = match (self, values.as_slice()) {
+            (ColorModel::Rgb, &[r, g, b]) => rgb_to_gray(r, g, b),
+            (ColorModel::Cmyk, &[c, m, y, k]) => cmyk_to_gray(c, m, y, k),
+            _ => return None,
+        };
+        // Three decimals are finer than any device can render, and keep the operands short
+        Some((gray * 1000.0).round() / 1000.0)
+    }
+}
+
+/// Colour models of the colorspaces named in a resource dictionary
+type NamedColorModels = HashMap<Vec<u8>, ColorModel>;
+
+/// Fill and stroke colour models in effect, saved and restored by q/Q
+#[derive(Clone, Copy)]
+struct ColorState {
+    fill: ColorModel,
+    stroke: ColorModel,
+}
+
+/// Where a job's content comes from
+#[derive(Clone, Copy)]
+enum ContentAt {
+    /// A single stream: a page's only content stream, a form XObject or a tiling pattern
+    Stream(ObjectId),
+    /// All of a page's content streams, which are parsed as one since an operator's operands
+    /// and a colorspace set in one stream can carry over to the next
+    Page(ObjectId),
+}
+
+/// Rewrite the colour operators in every page content stream, form XObject and tiling
+/// pattern to gray equivalents. Content that doesn't fully parse is left as it is. Returns
+/// the number of operators changed.
+pub fn convert_content_to_gray(doc: &mut Document) -> u32 {
+    // Content and the colorspaces named in its resources
+    let mut jobs: Vec<(ContentAt, NamedColorModels)> = Vec::new();
+    let mut seen: HashSet<ObjectId> = HashSet::new();
+
+    for page_id in doc.page_iter() {
+        let mut resources: Vec<&Dictionary> = Vec::new();
+        if let Ok((direct, inherited)) = doc.get_page_resources(page_id) {
+            resources.extend(direct);
+            resources.extend(
+                inherited
+                    .into_iter()
+                    .filter_map(|id| doc.get_dictionary(id).ok()),
+            );
+        }
+
+        let at = match doc.get_page_contents(page_id).as_slice() {
+            [] => continue,
+            // A stream shared by several pages is converted once, in place
+            &[id] if !seen.insert(id) => continue,
+            &[id] => ContentAt::Stream(id),
+            _ => ContentAt::Page(page_id),
+        };
+        jobs.push((at, named_color_models(doc, &resources)));
+    }
+
+    for (&id, obj) in doc.objects.iter() {
+        let Object::Stream(stream) = obj else {
+            continue;
+        };
+        let is_form = matches!(
+            stream.dict.get(b"Subtype").and_then(Object::as_name),
+            Ok(b"Form")
+        );
+        let is_tiling = stream
+            .dict
+            .get(b"PatternType")
+            .and_then(Object::as_i64)
+            .ok()
+            == Some(1);
+        if !(is_form || is_tiling) || !seen.insert(id) {
+            continue;
+        }
+
+        let resources: Vec<&Dictionary> = stream
+            .dict
+            .get(b"Resources")
+            .and_then(|res| doc.dereference(res))
+            .and_then(|(_, res)| res.as_dict())
+            .into_iter()
+            .collect();
+        jobs.push((ContentAt::Stream(id), named_color_models(doc, &resources)));
+    }
+
+    let mut converted = 0;
+    for (at, models) in jobs {
+        let data = match at {
+            ContentAt::Stream(id) => doc
+                .get_object(id)
+                .and_then(Object::as_stream)
+                .ok()
+                .and_then(stream_content),
+            ContentAt::Page(page_id) => page_content(doc, page_id),
+        };
+        let Some(mut content) = data.as_deref().and_then(decode_content) else {
+            continue;
+        };
+
+        let mut state = ColorState {
+            fill: ColorModel::Gray,
+            stroke: ColorModel::Gray,
+        };
+        let changed = gray_operations(&mut content.operations, &models, &mut state);
+        if changed == 0 {
+            // Leave untouched streams byte-for-byte as they were
+            continue;
+        }
+        let Ok(encoded) = content.encode() else {
+            continue;
+        };
+
+        match at {
+            ContentAt::Stream(id) => {
+                if let Ok(Object::Stream(stream)) = doc.get_object_mut(id) {
+                    set_content(stream, encoded);
+                }
+            }
+            // The page's streams become one; the old ones are left for pruning
+            ContentAt::Page(page_id) => {
+                let mut stream = Stream::new(Dictionary::new(), Vec::new());
+                set_content(&mut stream, encoded);
+                let id = doc.add_object(stream);
+                let Ok(page) = doc.get_dictionary_mut(page_id) else {
+                    continue;
+                };
+                page.set("Contents", id);
+            }
+        }
+        converted += changed;
+    }
+
+    converted
+}
+
+/// Store re-encoded content in a stream, deflated
+fn set_content(stream: &mut Stream, encoded: Vec<u8>) {
+    match deflate_best(&encoded) {
+        Some(compressed) => {
+            stream.set_content(compressed);
+            stream
+                .dict
+                .set("Filter", Object::Name(b"FlateDecode".to_vec()));
+        }
+        None => {
+            stream.set_content(encoded);
+            stream.dict.remove(b"Filter");
+        }
+    }
+    stream.dict.remove(b"DecodeParms");
+}
+
+/// Colour models of the named colorspaces in the `ColorSpace` entries of the given resources
+fn named_color_models(doc: &Document, resources: &[&Dictionary]) -> NamedColorModels {
+    let mut models = HashMap::new();
+    // Earlier dictionaries take precedence, like resource lookup itself
+    for res in resources.iter().rev() {
+        let Ok((_, Object::Dictionary(color_spaces))) =
+            res.get(b"ColorSpace").and_then(|cs| doc.dereference(cs))
+        else {
+            continue;
+        };
+        for (name, color_space) in color_spaces.iter() {
+            models.insert(name.clone(), ColorModel::from_object(doc, color_space));
+        }
+    }
+    models
+}
+
+/// Rewrite RGB and CMYK colour operators to their gray counterparts in place, tracking the
+/// colorspaces selected with cs/CS so sc/scn operands can be converted too
+fn gray_operations(
+    operations: &mut [Operation],
+    models: &NamedColorModels,
+    state: &mut ColorState,
+) -> u32 {
+    let mut stack: Vec<ColorState> = Vec::new();
+    let mut converted = 0;
+
+    for operation in operations.iter_mut() {
+        let is_stroke = operation
+            .operator
+            .starts_with(|c: char| c.is_ascii_uppercase());
+        match operation.operator.as_str() {
+            "q" => stack.push(*state),
+            "Q" => {
+                if let Some(saved) = stack.pop() {
+                    *state = saved;
+                }
+            }
+            "g" => state.fill = ColorModel::Gray,
+            "G" => state.stroke = ColorModel::Gray,
+            "rg" | "RG" | "k" | "K" => {
+                let model = if operation.operator.eq_ignore_ascii_case("rg") {
+                    ColorModel::Rgb
+                } else {
+                    ColorModel::Cmyk
+                };
+                let Some(gray) = model.gray_level(&operation.operands) else {
+                    continue;
+                };
+                operation.operator = if is_stroke { "G" } else { "g" }.to_string();
+                operation.operands = vec![Object::Real(gray)];
+                if is_stroke {
+                    state.stroke = ColorModel::Gray;
+                } else {
+                    state.fill = ColorModel::Gray;
+                }
+                converted += 1;
+            }
+            "cs" | "CS" => {
+                let Some(name) = operation.operands.first().and_then(|o| o.as_name().ok()) else {
+                    continue;
+                };
+                let model = models
+                    .get(name)
+                    .copied()
+                    .unwrap_or_else(|| ColorModel::from_name(name));
+                if matches!(model, ColorModel::Rgb | ColorModel::Cmyk) {
+                    operation.operands = vec![Object::Name(b"DeviceGray".to_vec())];
+                    converted += 1;
+                }
+                if is_stroke {
+                    state.stroke = model;
+                } else {
+                    state.fill = model;
+                }
+            }
+            "sc" | "scn" | "SC" | "SCN" => {
+                let model = if is_stroke { state.stroke } else { state.fill };
+                if let Some(gray) = model.gray_level(&operation.operands) {
+                    operation.operands = vec![Object::Real(gray)];
+                    converted += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::dictionary;
+
+    /// A one-page document whose page has the given content streams
+    fn document(streams: &[&[u8]]) -> (Document, ObjectId) {
+        let (doc, page_ids) = testing::document(1, |doc, _| {
+            let contents: Vec<Object> = streams
+                .iter()
+                .map(|data| testing::content(doc, data))
+                .collect();
+            dictionary! { "Contents" => contents }
+        });
+        (doc, page_ids[0])
+    }
+
+    fn operations(doc: &Document, page_id: ObjectId) -> Vec<(String, Vec<Object>)> {
+        let content = page_content(doc, page_id).unwrap();
+        decode_content(&content)
+            .unwrap()
+            .operations
+            .into_iter()
+            .map(|operation| (operation.operator, operation.operands))
+            .collect()
+    }
+
+    #[test]
+    fn converts_device_colours() {
+        let (mut doc, page_id) = document(&[b"1 0 0 rg 0 0 0 1 K /DeviceRGB cs 0 0 1 sc"]);
+        assert_eq!(convert_content_to_gray(&mut doc), 4);
+
+        let operations = operations(&doc, page_id);
+        let operators: Vec<&str> = operations.iter().map(|(op, _)| op.as_str()).collect();
+        assert_eq!(operators, ["g", "G", "cs", "sc"]);
+        let level = |index: usize| operations[index].1[0].as_float().unwrap();
+        assert_eq!(level(0), 0.3);
+        assert_eq!(level(1), 0.0);
+        assert_eq!(operations[2].1, vec![Object::Name(b"DeviceGray".to_vec())]);
+        assert_eq!(level(3), 0.11);
+    }
+
+    #[test]
+    fn leaves_content_that_does_not_fully_parse() {
+        let data: &[u8] = b"1 0 0 rg 0 0 10 10 re f ] 0 1 0 rg 0 0 5 5 re f";
+        let (mut doc, page_id) = document(&[data]);
+        assert_eq!(convert_content_to_gray(&mut doc), 0);
+
+        let content_id = doc.get_page_contents(page_id)[0];
+        let stream = doc.get_object(content_id).unwrap().as_stream().unwrap();
+        assert_eq!(stream.content, data);
+    }
+
+    #[test]
+    fn parses_a_page_s_streams_together() {
+        let (mut doc, page_id) = document(&[b"1 0 0", b"rg 0 0 10 10 re f"]);
+        assert_eq!(convert_content_to_gray(&mut doc), 1);
+        assert_eq!(doc.get_page_contents(page_id).len(), 1);
+
+        let operators: Vec<String> = operations(&doc, page_id)
+            .into_iter()
+            .map(|(operator, _)| operator)
+            .collect();
+        assert_eq!(operators, ["g", "re", "f"]);
+    }
+
+    #[test]
+    fn converts_pixels_to_gray() {
+        assert_eq!(
+            pixels_to_gray(&[255, 0, 0, 0, 0, 255], 3),
+            Some(vec![77, 28])
+        );
+        assert_eq!(
+            pixels_to_gray(&[0, 0, 0, 255, 0, 0, 0, 0], 4),
+            Some(vec![0, 255])
+        );
+        assert_eq!(pixels_to_gray(&[0, 0], 2), None);
+    }
+
+    fn assert_near(actual: &[u8], expected: &[u8]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(a.abs_diff(*e) <= 3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn converts_cmyk_through_an_icc_profile() {
+        let transform = cmyk_to_srgb_transform(&testing::black_ink_profile()).unwrap();
+        let rgb = convert_cmyk(&transform, &[0, 0, 0, 0, 255, 255, 0, 0, 0, 0, 0, 255]).unwrap();
+        // The profile only prints black ink, so blue stays paper white
+        assert_near(&rgb, &[255, 255, 255, 255, 255, 255, 0, 0, 0]);
+        assert_eq!(convert_cmyk(&transform, &[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn rejects_profiles_that_are_unreadable_or_not_cmyk() {
+        let icc = testing::black_ink_profile();
+        assert!(cmyk_to_srgb_transform(b"not a profile").is_none());
+        assert!(cmyk_to_srgb_transform(&icc[..icc.len() / 2]).is_none());
+        let srgb = ColorProfile::new_srgb().encode().unwrap();
+        assert!(cmyk_to_srgb_transform(&srgb).is_none());
+    }
+
+    /// A document whose output intent holds `icc` in a deflated stream with the given /N
+    fn with_output_intent(icc: &[u8], components: i64) -> Document {
+        let (mut doc, _) = testing::document(1, |_, _| dictionary! {});
+        let mut profile = Stream::new(dictionary! { "N" => components }, icc.to_vec());
+        profile.compress().unwrap();
+        let profile_id = doc.add_object(profile);
+        let intent = dictionary! {
+            "Type" => "OutputIntent",
+            "S" => "GTS_PDFX",
+            "DestOutputProfile" => profile_id,
+        };
+        doc.catalog_mut()
+            .unwrap()
+            .set("OutputIntents", vec![intent.into()]);
+        doc
+    }
+
+    #[test]
+    fn reads_the_output_intent_cmyk_profile() {
+        let icc = testing::black_ink_profile();
+        assert_eq!(
+            output_intent_cmyk_profile(&with_output_intent(&icc, 4)),
+            Some(icc.clone())
+        );
+        assert_eq!(
+            output_intent_cmyk_profile(&with_output_intent(&icc, 3)),
+            None
+        );
+        let (doc, _) = testing::document(1, |_, _| dictionary! {});
+        assert_eq!(output_intent_cmyk_profile(&doc), None);
+    }
+}
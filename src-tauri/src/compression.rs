@@ -0,0 +1,3966 @@
+use crate::color::{
+    cmyk_to_srgb_transform, convert_cmyk, convert_content_to_gray, icc_profile_data,
+    output_intent_cmyk_profile, pixels_to_gray, CmykTransform,
+};
+use crate::linearize::save_linearized;
+use crate::optimize::{
+    dedupe_images_and_fonts, deflate_best, optimize_structure, strip_metadata, DedupReport,
+    StructureReport,
+};
+use crate::resources::remove_unused_resources;
+use crate::verify::{check_document, verify_output, VerificationReport};
+use flate2::read::ZlibDecoder;
+use jpeg_encoder::{ColorType, Encoder as JpegEncoder, SamplingFactor};
+use lopdf::{dictionary, Document, Object, ObjectId, SaveOptions};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Largest PDF accepted for compression (500MB)
+const MAX_FILE_SIZE: usize = 500 * 1024 * 1024;
+
+/// Only downsample when an image's effective resolution exceeds the target by this factor,
+/// so images that are only slightly over aren't resampled for a negligible gain
+const DOWNSAMPLE_THRESHOLD: f64 = 1.5;
+
+/// Settings for a compression run, passed from the frontend
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CompressionOptions {
+    /// JPEG quality 10-100 (default 75)
+    pub quality: Option<u8>,
+    /// Downsample images placed above this resolution (e.g. 72, 150, 300)
+    pub target_dpi: Option<u32>,
+    /// Leave image pixels alone and only apply lossless structural optimisations
+    pub lossless: bool,
+    /// Convert images, text and vector art to gray
+    pub grayscale: bool,
+    /// Remove the document info dictionary and XMP metadata
+    pub strip_metadata: bool,
+    /// Write progressive JPEGs, which show a preview while loading and are often smaller
+    pub jpeg_progressive: bool,
+    /// Chroma subsampling for colour JPEGs (default 4:2:2)
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Write a linearized ("Fast Web View") PDF whose first page shows before the whole
+    /// file has downloaded
+    pub linearize: bool,
+}
+
+/// How much colour detail JPEG keeps relative to brightness
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    /// Full colour resolution
+    #[serde(rename = "4:4:4")]
+    Yuv444,
+    /// Half horizontal colour resolution
+    #[serde(rename = "4:2:2")]
+    #[default]
+    Yuv422,
+    /// Half horizontal and vertical colour resolution, the smallest files
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+}
+
+impl ChromaSubsampling {
+    fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// Compressed PDF bytes together with a report of what happened to each image
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressedPdf {
+    pub content: Vec<u8>,
+    pub report: CompressionReport,
+}
+
+/// Summary of a compression run, returned to the frontend so it can explain
+/// why a file did (or didn't) shrink
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionReport {
+    pub quality: u8,
+    pub target_dpi: Option<u32>,
+    pub lossless: bool,
+    pub grayscale: bool,
+    /// The run was cancelled and the original PDF returned
+    pub cancelled: bool,
+    pub original_size: usize,
+    pub compressed_size: usize,
+    pub total_images: usize,
+    pub candidates: usize,
+    pub recompressed: u32,
+    pub skipped_threshold: u32,
+    pub failed: u32,
+    /// Images that were resampled to the target DPI
+    pub downsampled: u32,
+    /// Bytes saved across recompressed image streams
+    pub image_bytes_saved: usize,
+    /// Skipped images grouped by reason label
+    pub skip_counts: BTreeMap<String, u32>,
+    /// Unsupported filters encountered, by filter name
+    pub filter_types: BTreeMap<String, u32>,
+    /// Unsupported colorspaces encountered, by colorspace name
+    pub colorspace_types: BTreeMap<String, u32>,
+    pub images: Vec<ImageReport>,
+    /// Duplicate images and fonts folded into one copy
+    pub dedup: DedupReport,
+    /// Structural optimisations, present in lossless mode
+    pub structure: Option<StructureReport>,
+    /// Colour operators in content streams rewritten to gray, in grayscale mode
+    pub gray_operators: u32,
+    /// Info dictionary and XMP metadata entries removed
+    pub metadata_removed: u32,
+    /// Page and form resource entries no content stream used
+    pub unused_resources_removed: u32,
+    /// Checks on the re-read output; None when the run was cancelled
+    pub verification: Option<VerificationReport>,
+}
+
+/// Per-image entry in the compression report
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageReport {
+    pub object_id: u32,
+    pub generation: u16,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub color_space: String,
+    pub filter: String,
+    /// Soft masks are recompressed losslessly instead of as JPEG
+    pub is_soft_mask: bool,
+    /// Lowest resolution the image is drawn at, if it is painted by a page content stream
+    pub effective_dpi: Option<f64>,
+    /// Dimensions after downsampling, when the image was resampled
+    pub new_width: Option<u32>,
+    pub new_height: Option<u32>,
+    /// How CMYK samples were handled, for DeviceCMYK and 4-component ICCBased images
+    pub cmyk_handling: Option<CmykHandling>,
+    /// Encoder the recompressed image was written with
+    pub encoder: Option<ImageEncoder>,
+    /// None when the image was skipped before recompression was attempted
+    pub result: Option<ImageCompressionResult>,
+    pub skip_reason: Option<SkipReason>,
+    pub original_bytes: usize,
+    pub compressed_bytes: usize,
+    pub bytes_saved: usize,
+}
+
+/// Stage of a compression run, sent with progress events
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CompressionPhase {
+    Loading,
+    Analyzing,
+    Images,
+    Optimizing,
+    Saving,
+    /// Re-reading the output to check it before it's returned
+    Verifying,
+}
+
+/// Progress of a compression run, emitted to the frontend as it goes
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionProgress {
+    pub phase: CompressionPhase,
+    pub images_done: usize,
+    pub images_total: usize,
+    /// Which compression run this is; target-size compression makes several
+    pub attempt: u32,
+}
+
+/// Stops one compression run. Clones share the flag, so the caller keeps one to cancel with
+/// while the run checks another.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Ask the run to stop. It returns the original PDF untouched.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub fn compress_pdf_blocking(
+    content: &[u8],
+    options: CompressionOptions,
+    cancel: &CancelToken,
+    progress: &(dyn Fn(CompressionProgress) + Sync),
+) -> Result<CompressedPdf, String> {
+    let jpeg_quality = options.quality.unwrap_or(75).clamp(10, 100);
+    let emit = |phase, images_done, images_total| {
+        progress(CompressionProgress {
+            phase,
+            images_done,
+            images_total,
+            attempt: 1,
+        })
+    };
+
+    // Security: Check file size limit (500MB)
+    if content.len() > MAX_FILE_SIZE {
+        return Err("PDF too large".to_string());
+    }
+
+    let mut report = CompressionReport {
+        quality: jpeg_quality,
+        target_dpi: options.target_dpi,
+        lossless: options.lossless,
+        grayscale: options.grayscale,
+        original_size: content.len(),
+        ..Default::default()
+    };
+
+    emit(CompressionPhase::Loading, 0, 0);
+    if cancel.is_cancelled() {
+        return Ok(cancelled(content, report));
+    }
+    let mut doc = Document::load_mem(content).map_err(|e| format!("Failed to load PDF: {}", e))?;
+    let original_check = check_document(&doc);
+
+    if compress_document(&mut doc, &options, &mut report, cancel, &emit).is_break() {
+        return Ok(cancelled(content, report));
+    }
+
+    let images_total = report.candidates;
+    emit(CompressionPhase::Saving, images_total, images_total);
+    let mut output = Vec::new();
+    save_compressed(doc, &options, &mut output)?;
+
+    // Never hand back something worse than what came in
+    emit(CompressionPhase::Verifying, images_total, images_total);
+    let mut verification = verify_output(&original_check, Document::load_mem(&output));
+    if verification.should_roll_back(output.len(), size_limit(&options, content.len())) {
+        log_rollback(&verification);
+        output = content.to_vec();
+    }
+
+    report.compressed_size = output.len();
+    report.verification = Some(verification);
+    log_compression(&report);
+
+    Ok(CompressedPdf {
+        content: output,
+        report,
+    })
+}
+
+/// Compress the PDF at `input_path` into `output_path`, without the file contents going
+/// through the frontend.
+///
+/// Peak memory is not bounded by this: lopdf reads the whole input into memory to parse it,
+/// and verification re-reads the whole output the same way. What it saves over
+/// `compress_pdf_blocking` is holding the input bytes, the document and the output bytes all
+/// at once: the output is saved through a buffered writer rather than into memory.
+/// The output is written to a new file next to `output_path` and renamed into place once
+/// complete, so a failed or cancelled run never leaves a partial file, and `output_path` may
+/// be the input itself. A cancelled run copies the original.
+pub fn compress_pdf_file_blocking(
+    input_path: &Path,
+    output_path: &Path,
+    options: CompressionOptions,
+    cancel: &CancelToken,
+    progress: &(dyn Fn(CompressionProgress) + Sync),
+) -> Result<CompressionReport, String> {
+    let jpeg_quality = options.quality.unwrap_or(75).clamp(10, 100);
+    let emit = |phase, images_done, images_total| {
+        progress(CompressionProgress {
+            phase,
+            images_done,
+            images_total,
+            attempt: 1,
+        })
+    };
+
+    let original_size = std::fs::metadata(input_path)
+        .map_err(|e| format!("Failed to read file metadata: {}", e))?
+        .len() as usize;
+    if original_size > MAX_FILE_SIZE {
+        return Err("PDF too large".to_string());
+    }
+
+    let mut report = CompressionReport {
+        quality: jpeg_quality,
+        target_dpi: options.target_dpi,
+        lossless: options.lossless,
+        grayscale: options.grayscale,
+        original_size,
+        ..Default::default()
+    };
+
+    emit(CompressionPhase::Loading, 0, 0);
+    if cancel.is_cancelled() {
+        return copy_original(input_path, output_path, report);
+    }
+    let mut doc = Document::load(input_path).map_err(|e| format!("Failed to load PDF: {}", e))?;
+    let original_check = check_document(&doc);
+
+    if compress_document(&mut doc, &options, &mut report, cancel, &emit).is_break() {
+        return copy_original(input_path, output_path, report);
+    }
+
+    let images_total = report.candidates;
+    emit(CompressionPhase::Saving, images_total, images_total);
+    let (partial_path, file) = create_partial_output(output_path)?;
+    let metadata = match write_output(file, doc, &options) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial_path);
+            return Err(e);
+        }
+    };
+
+    emit(CompressionPhase::Verifying, images_total, images_total);
+    let mut verification = verify_output(&original_check, Document::load(&partial_path));
+    if verification.should_roll_back(metadata.len() as usize, size_limit(&options, original_size)) {
+        log_rollback(&verification);
+        let _ = std::fs::remove_file(&partial_path);
+        if input_path != output_path {
+            std::fs::copy(input_path, output_path)
+                .map_err(|e| format!("Failed to copy original PDF: {}", e))?;
+        }
+        report.compressed_size = original_size;
+    } else {
+        std::fs::rename(&partial_path, output_path)
+            .map_err(|e| format!("Failed to move output file into place: {}", e))?;
+        report.compressed_size = metadata.len() as usize;
+    }
+    report.verification = Some(verification);
+    log_compression(&report);
+
+    Ok(report)
+}
+
+/// Save the compressed document to `file` and flush it to disk
+fn write_output(
+    file: File,
+    doc: Document,
+    options: &CompressionOptions,
+) -> Result<std::fs::Metadata, String> {
+    let mut writer = BufWriter::new(file);
+    save_compressed(doc, options, &mut writer)?;
+    let file = writer
+        .into_inner()
+        .map_err(|e| format!("Failed to write output file: {}", e))?;
+    file.sync_all()
+        .and_then(|_| file.metadata())
+        .map_err(|e| format!("Failed to write output file: {}", e))
+}
+
+/// Create the file the output is written to before being renamed over `output_path`: a new
+/// sibling named `<output>.partial`, or `<output>.2.partial` and so on if that exists.
+/// Files left by other runs are never opened.
+fn create_partial_output(output_path: &Path) -> Result<(PathBuf, File), String> {
+    let file_name = output_path.file_name().unwrap_or_default();
+    let mut copy = 1;
+    loop {
+        let mut partial_name = file_name.to_os_string();
+        match copy {
+            1 => partial_name.push(".partial"),
+            copy => partial_name.push(format!(".{}.partial", copy)),
+        }
+        let partial_path = output_path.with_file_name(partial_name);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial_path)
+        {
+            Ok(file) => return Ok((partial_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => copy += 1,
+            Err(e) => return Err(format!("Failed to create output file: {}", e)),
+        }
+    }
+}
+
+/// Cancelled path-based run: the output is a copy of the untouched original
+fn copy_original(
+    input_path: &Path,
+    output_path: &Path,
+    report: CompressionReport,
+) -> Result<CompressionReport, String> {
+    println!("PDF compression cancelled, copying the original");
+    if input_path != output_path {
+        std::fs::copy(input_path, output_path)
+            .map_err(|e| format!("Failed to copy original PDF: {}", e))?;
+    }
+    Ok(cancelled_report(report))
+}
+
+/// Recompress the images in `doc` and optimise its structure in place, filling in `report`.
+/// Breaks when the run is cancelled, leaving `doc` half done.
+fn compress_document(
+    doc: &mut Document,
+    options: &CompressionOptions,
+    report: &mut CompressionReport,
+    cancel: &CancelToken,
+    emit: &(dyn Fn(CompressionPhase, usize, usize) + Sync),
+) -> ControlFlow<()> {
+    let jpeg_quality = report.quality;
+    emit(CompressionPhase::Analyzing, 0, 0);
+
+    // Fold duplicate images and fonts first, so each copy is only recompressed once
+    report.dedup = dedupe_images_and_fonts(doc);
+
+    // Soft masks are images too, but must stay lossless to keep transparency edges clean
+    let soft_masks: HashSet<ObjectId> = doc
+        .objects
+        .values()
+        .filter_map(|obj| match obj {
+            Object::Stream(stream) => stream
+                .dict
+                .get(b"SMask")
+                .and_then(Object::as_reference)
+                .ok(),
+            _ => None,
+        })
+        .collect();
+
+    // Images premultiplied with a matte colour (an SMask with `/Matte`), by image id, with
+    // their mask's id and the matte colour
+    let mattes: HashMap<ObjectId, (ObjectId, Vec<f32>)> = doc
+        .objects
+        .iter()
+        .filter_map(|(&id, obj)| Some((id, soft_mask_matte(doc, obj.as_stream().ok()?)?)))
+        .collect();
+
+    // Phase 1: Recompress images — the main source of file size in PDFs
+    // Every image stream gets a report entry; candidates remember their entry index
+    // and resolved colorspace
+    let mut colors = ColorResolver::new(doc);
+    let mut candidates: Vec<(ObjectId, usize, ImageColorSpace)> = Vec::new();
+    for (&id, obj) in doc.objects.iter() {
+        let Object::Stream(stream) = obj else {
+            continue;
+        };
+        let Some(check) = is_recompressible_image(stream, &mut colors, options.grayscale) else {
+            // Not an image stream, don't count it
+            continue;
+        };
+
+        let mut entry = describe_image(id, stream);
+        entry.is_soft_mask = soft_masks.contains(&id);
+        match check {
+            Ok(color_space) => {
+                entry.cmyk_handling = color_space.cmyk_handling();
+                candidates.push((id, report.images.len(), color_space));
+            }
+            Err(reason) => {
+                *report
+                    .skip_counts
+                    .entry(reason.label().to_string())
+                    .or_insert(0) += 1;
+                match &reason {
+                    SkipReason::UnsupportedColorSpace(cs) => {
+                        *report.colorspace_types.entry(cs.clone()).or_insert(0) += 1;
+                    }
+                    SkipReason::UnsupportedFilter(f) => {
+                        *report.filter_types.entry(f.clone()).or_insert(0) += 1;
+                    }
+                    _ => {}
+                }
+                entry.skip_reason = Some(reason);
+            }
+        }
+        report.images.push(entry);
+    }
+    report.total_images = report.images.len();
+    report.candidates = candidates.len();
+
+    // Lossless mode never touches pixels, so candidates are reported but not recompressed,
+    // unless they're being converted to gray; that then happens without JPEG or resampling
+    if options.lossless && !options.grayscale {
+        candidates.clear();
+    }
+    let encoding = ImageEncoding {
+        quality: jpeg_quality,
+        progressive: options.jpeg_progressive,
+        chroma_subsampling: options.chroma_subsampling,
+        grayscale: options.grayscale,
+        lossless: options.lossless,
+    };
+    let target_dpi = options.target_dpi.filter(|_| !options.lossless);
+
+    // How large each image is actually drawn decides how far it can be downsampled
+    let placements = collect_image_placements(doc);
+
+    // Take each candidate out of the document and work out its target size up front, so
+    // workers own their stream and the results don't depend on which thread ran first
+    let mut jobs: Vec<ImageJob> = Vec::with_capacity(candidates.len());
+    for (id, index, color_space) in candidates {
+        let entry = &mut report.images[index];
+
+        // The matte colour is in the image's colorspace, so it's converted along with it
+        let matte = mattes.get(&id);
+        let new_matte = match matte {
+            Some((mask_id, matte)) if color_space.is_converted(encoding.grayscale) => {
+                match color_space.convert_matte(matte, encoding.grayscale) {
+                    Some(converted) => Some((*mask_id, converted)),
+                    None => {
+                        entry.result = Some(ImageCompressionResult::Failed);
+                        report.failed += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let Some(Object::Stream(stream)) = doc.objects.remove(&id) else {
+            continue;
+        };
+
+        let resample_to = match (entry.width, entry.height, placements.get(&id)) {
+            (Some(w), Some(h), Some(&placed)) if w > 0 && h > 0 => {
+                let (placed_width, placed_height) = placed;
+                if placed_width > 0.0 && placed_height > 0.0 {
+                    entry.effective_dpi = Some(
+                        (w as f64 / (placed_width / 72.0)).min(h as f64 / (placed_height / 72.0)),
+                    );
+                }
+                // Resampling 1-bit scans would need thresholding, so they keep their size.
+                // A matte needs the image and its mask to stay the same size
+                target_dpi
+                    .filter(|_| !matches!(color_space, ImageColorSpace::Bilevel))
+                    .filter(|_| matte.is_none())
+                    .and_then(|dpi| resample_dimensions(w as u32, h as u32, placed, dpi))
+            }
+            _ => None,
+        };
+
+        jobs.push(ImageJob {
+            id,
+            index,
+            stream,
+            color_space,
+            is_soft_mask: entry.is_soft_mask,
+            resample_to,
+            new_matte,
+            result: None,
+        });
+    }
+
+    // Decoding and encoding dominate the run time, so images are recompressed in parallel
+    let images_total = jobs.len();
+    let images_done = AtomicUsize::new(0);
+    emit(CompressionPhase::Images, 0, images_total);
+    jobs.par_iter_mut().for_each(|job| {
+        if cancel.is_cancelled() {
+            return;
+        }
+        job.result = Some(job.recompress(encoding));
+        let done = images_done.fetch_add(1, Ordering::SeqCst) + 1;
+        emit(CompressionPhase::Images, done, images_total);
+    });
+
+    // Write the results back in document order
+    let mut new_mattes = Vec::new();
+    for job in jobs {
+        let Some(result) = job.result else {
+            // Skipped because the run was cancelled
+            return ControlFlow::Break(());
+        };
+        let stream = job.stream;
+        let entry = &mut report.images[job.index];
+        match result {
+            ImageCompressionResult::Recompressed => {
+                if job.resample_to.is_some() {
+                    entry.new_width = stream
+                        .dict
+                        .get(b"Width")
+                        .and_then(Object::as_i64)
+                        .ok()
+                        .map(|v| v as u32);
+                    entry.new_height = stream
+                        .dict
+                        .get(b"Height")
+                        .and_then(Object::as_i64)
+                        .ok()
+                        .map(|v| v as u32);
+                    report.downsampled += 1;
+                }
+                entry.encoder = ImageEncoder::of_stream(&stream);
+                entry.compressed_bytes = stream.content.len();
+                entry.bytes_saved = entry.original_bytes.saturating_sub(entry.compressed_bytes);
+                report.image_bytes_saved += entry.bytes_saved;
+                report.recompressed += 1;
+                new_mattes.extend(job.new_matte);
+            }
+            ImageCompressionResult::SkippedThreshold => report.skipped_threshold += 1,
+            ImageCompressionResult::Failed => report.failed += 1,
+        }
+        entry.result = Some(result);
+        doc.objects.insert(job.id, Object::Stream(stream));
+    }
+    // Masks can be jobs of their own, so their mattes are only set once all are back
+    for (mask_id, matte) in new_mattes {
+        if let Ok(Object::Stream(mask)) = doc.get_object_mut(mask_id) {
+            let matte: Vec<Object> = matte.into_iter().map(Object::Real).collect();
+            mask.dict.set("Matte", matte);
+        }
+    }
+
+    emit(CompressionPhase::Images, images_total, images_total);
+    if cancel.is_cancelled() {
+        return ControlFlow::Break(());
+    }
+
+    // Phase 2: Standard PDF optimization (prune only, skip compress to avoid inflating already-good streams)
+    // Lossless mode adds structural passes that only re-deflate streams when it helps
+    emit(CompressionPhase::Optimizing, images_total, images_total);
+    if options.grayscale {
+        report.gray_operators = convert_content_to_gray(doc);
+    }
+    if options.strip_metadata {
+        report.metadata_removed = strip_metadata(doc);
+    }
+    report.unused_resources_removed = remove_unused_resources(doc);
+    if options.lossless {
+        report.structure = Some(optimize_structure(doc));
+    }
+    doc.prune_objects();
+    doc.delete_zero_length_streams();
+    // NOTE: doc.compress() removed because it was making files BIGGER by re-compressing already-optimal streams
+
+    ControlFlow::Continue(())
+}
+
+/// Save a compressed document to `output`
+fn save_compressed(
+    mut doc: Document,
+    options: &CompressionOptions,
+    output: &mut impl Write,
+) -> Result<(), String> {
+    if options.linearize {
+        // Linearized files keep every object at top level, so object streams aren't used
+        return save_linearized(doc, output);
+    }
+    if options.lossless {
+        // Pack non-stream objects into object streams, indexed by a cross-reference stream
+        let save_options = SaveOptions::builder()
+            .use_object_streams(true)
+            .use_xref_streams(true)
+            .compression_level(9)
+            .build();
+        doc.save_with_options(output, save_options)
+    } else {
+        doc.save_to(output)
+    }
+    .map_err(|e| format!("Failed to save compressed PDF: {}", e))
+}
+
+/// Largest output accepted before falling back to the original. A linearized file was asked
+/// for, which the original may not be, so it's kept even when its hint tables and second
+/// cross-reference table make it larger.
+fn size_limit(options: &CompressionOptions, original_size: usize) -> usize {
+    if options.linearize {
+        usize::MAX
+    } else {
+        original_size
+    }
+}
+
+fn log_rollback(verification: &VerificationReport) {
+    println!(
+        "Compressed PDF rolled back to the original ({:?})",
+        verification.rollback_reason
+    );
+    for problem in &verification.problems {
+        println!("  {}", problem);
+    }
+}
+
+fn log_compression(report: &CompressionReport) {
+    let ratio = if report.original_size > 0 {
+        ((report.original_size as f64 - report.compressed_size as f64)
+            / report.original_size as f64)
+            * 100.0
+    } else {
+        0.0
+    };
+
+    println!(
+        "PDF compression: {} -> {} bytes ({:.1}% reduction, {} of {} images recompressed)",
+        report.original_size,
+        report.compressed_size,
+        ratio,
+        report.recompressed,
+        report.total_images
+    );
+}
+
+/// The result of a cancelled run: the original bytes, with a report that only records the
+/// settings, since none of the work done so far is kept
+fn cancelled(content: &[u8], report: CompressionReport) -> CompressedPdf {
+    println!("PDF compression cancelled, returning the original");
+    CompressedPdf {
+        content: content.to_vec(),
+        report: cancelled_report(report),
+    }
+}
+
+fn cancelled_report(report: CompressionReport) -> CompressionReport {
+    CompressionReport {
+        quality: report.quality,
+        target_dpi: report.target_dpi,
+        lossless: report.lossless,
+        grayscale: report.grayscale,
+        original_size: report.original_size,
+        compressed_size: report.original_size,
+        cancelled: true,
+        ..Default::default()
+    }
+}
+
+/// Compress until the output fits in `max_bytes`, keeping as much quality as possible.
+///
+/// Lossless optimisation is tried first. After that, each resolution step is tried from
+/// full resolution down: if the lowest JPEG quality fits at that resolution, the highest
+/// quality that still fits is found by binary search.
+pub fn compress_pdf_to_size_blocking(
+    content: &[u8],
+    max_bytes: usize,
+    cancel: &CancelToken,
+    progress: &(dyn Fn(CompressionProgress) + Sync),
+) -> Result<SizedPdf, String> {
+    if max_bytes == 0 {
+        return Err("Target size must be greater than zero".to_string());
+    }
+
+    let mut search = SizeSearch {
+        content,
+        max_bytes,
+        cancel,
+        progress,
+        attempts: 0,
+        smallest: None,
+    };
+
+    // If structure alone gets under the budget, no pixels need to change
+    let lossless = CompressionOptions {
+        lossless: true,
+        ..Default::default()
+    };
+    match search.attempt(&lossless)? {
+        Attempt::Fits(result) | Attempt::Cancelled(result) => {
+            return Ok(search.finish(result, lossless))
+        }
+        Attempt::TooLarge => {}
+    }
+
+    for target_dpi in TARGET_SIZE_DPI_STEPS {
+        let floor = CompressionOptions {
+            quality: Some(TARGET_SIZE_MIN_QUALITY),
+            target_dpi,
+            ..Default::default()
+        };
+        let mut best = match search.attempt(&floor)? {
+            Attempt::Fits(result) => result,
+            // Even the lowest quality is too big at this resolution: downsample further
+            Attempt::TooLarge => continue,
+            Attempt::Cancelled(result) => return Ok(search.finish(result, floor)),
+        };
+        let mut best_options = floor;
+
+        let (mut low, mut high) = (TARGET_SIZE_MIN_QUALITY, TARGET_SIZE_MAX_QUALITY);
+        while high - low >= TARGET_SIZE_QUALITY_TOLERANCE {
+            let mid = (low + high).div_ceil(2);
+            let options = CompressionOptions {
+                quality: Some(mid),
+                target_dpi,
+                ..Default::default()
+            };
+            match search.attempt(&options)? {
+                Attempt::Fits(result) => {
+                    best = result;
+                    best_options = options;
+                    low = mid;
+                }
+                Attempt::TooLarge => high = mid - 1,
+                Attempt::Cancelled(result) => return Ok(search.finish(result, options)),
+            }
+        }
+
+        return Ok(search.finish(best, best_options));
+    }
+
+    let (smallest_size, smallest_options) = search
+        .smallest
+        .ok_or_else(|| "No compression attempts were made".to_string())?;
+    Err(format!(
+        "Cannot compress below {} bytes: the smallest result was {} bytes (quality {}, {} DPI)",
+        max_bytes,
+        smallest_size,
+        smallest_options.quality.unwrap_or(75),
+        smallest_options
+            .target_dpi
+            .map_or_else(|| "original".to_string(), |dpi| dpi.to_string())
+    ))
+}
+
+/// Resolutions tried by `compress_pdf_to_size_blocking`, from untouched down to screen resolution
+const TARGET_SIZE_DPI_STEPS: [Option<u32>; 5] = [None, Some(300), Some(150), Some(96), Some(72)];
+const TARGET_SIZE_MIN_QUALITY: u8 = 10;
+const TARGET_SIZE_MAX_QUALITY: u8 = 90;
+/// Stop searching once the quality range is narrower than this; closer steps barely change size
+const TARGET_SIZE_QUALITY_TOLERANCE: u8 = 5;
+
+/// Result of a target-size compression, with the settings that produced it
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SizedPdf {
+    pub content: Vec<u8>,
+    pub report: CompressionReport,
+    pub settings: CompressionOptions,
+    pub max_bytes: usize,
+    /// Compression runs it took to find the settings
+    pub attempts: u32,
+}
+
+/// Outcome of one compression run during a target-size search
+enum Attempt {
+    Fits(CompressedPdf),
+    TooLarge,
+    /// Cancelled by the user; holds the original PDF
+    Cancelled(CompressedPdf),
+}
+
+/// Bookkeeping for `compress_pdf_to_size_blocking`
+struct SizeSearch<'a> {
+    content: &'a [u8],
+    max_bytes: usize,
+    cancel: &'a CancelToken,
+    progress: &'a (dyn Fn(CompressionProgress) + Sync),
+    attempts: u32,
+    /// Smallest output seen so far, for the error message when nothing fits
+    smallest: Option<(usize, CompressionOptions)>,
+}
+
+impl SizeSearch<'_> {
+    /// Compress with `options` and check the result against the budget
+    fn attempt(&mut self, options: &CompressionOptions) -> Result<Attempt, String> {
+        self.attempts += 1;
+        let attempt = self.attempts;
+        let progress = self.progress;
+        let result =
+            compress_pdf_blocking(self.content, options.clone(), self.cancel, &|update| {
+                progress(CompressionProgress { attempt, ..update })
+            })?;
+        if result.report.cancelled {
+            return Ok(Attempt::Cancelled(result));
+        }
+        let size = result.content.len();
+
+        if self
+            .smallest
+            .as_ref()
+            .is_none_or(|(smallest, _)| size < *smallest)
+        {
+            self.smallest = Some((size, options.clone()));
+        }
+
+        Ok(if size <= self.max_bytes {
+            Attempt::Fits(result)
+        } else {
+            Attempt::TooLarge
+        })
+    }
+
+    fn finish(self, result: CompressedPdf, settings: CompressionOptions) -> SizedPdf {
+        if result.report.cancelled {
+            println!(
+                "Target-size compression cancelled after {} attempts",
+                self.attempts
+            );
+        } else {
+            println!(
+                "Target-size compression: {} bytes fits {} byte budget after {} attempts",
+                result.content.len(),
+                self.max_bytes,
+                self.attempts
+            );
+        }
+        SizedPdf {
+            content: result.content,
+            report: result.report,
+            settings,
+            max_bytes: self.max_bytes,
+            attempts: self.attempts,
+        }
+    }
+}
+
+/// Build the report entry for an image stream from its dictionary, before any changes
+fn describe_image(id: ObjectId, stream: &lopdf::Stream) -> ImageReport {
+    let color_space = match stream.dict.get(b"ColorSpace") {
+        Ok(cs) => {
+            if let Ok(name) = cs.as_name() {
+                String::from_utf8_lossy(name).to_string()
+            } else if let Ok(arr) = cs.as_array() {
+                arr.first()
+                    .and_then(|first| first.as_name().ok())
+                    .map(|name| String::from_utf8_lossy(name).to_string())
+                    .unwrap_or_else(|| "<complex>".to_string())
+            } else {
+                // Usually an indirect reference to a colorspace array
+                "<complex>".to_string()
+            }
+        }
+        Err(_) => "<missing>".to_string(),
+    };
+
+    let filter = match stream.filters() {
+        Ok(filters) if !filters.is_empty() => filters
+            .iter()
+            .map(|f| String::from_utf8_lossy(f).to_string())
+            .collect::<Vec<_>>()
+            .join("+"),
+        _ => "None".to_string(),
+    };
+
+    ImageReport {
+        object_id: id.0,
+        generation: id.1,
+        width: stream.dict.get(b"Width").and_then(Object::as_i64).ok(),
+        height: stream.dict.get(b"Height").and_then(Object::as_i64).ok(),
+        color_space,
+        filter,
+        is_soft_mask: false,
+        effective_dpi: None,
+        new_width: None,
+        new_height: None,
+        cmyk_handling: None,
+        encoder: None,
+        result: None,
+        skip_reason: None,
+        original_bytes: stream.content.len(),
+        compressed_bytes: stream.content.len(),
+        bytes_saved: 0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageCompressionResult {
+    Recompressed,
+    SkippedThreshold,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageEncoder {
+    /// Lossy DCTDecode, for photos
+    Jpeg,
+    /// Lossless FlateDecode (with PNG predictors when they help), for graphics and masks
+    Flate,
+    /// Lossless CCITTFaxDecode (Group 4), for 1-bit images it codes smaller than Flate
+    Ccitt,
+}
+
+impl ImageEncoder {
+    /// The encoder a recompressed image stream was written with, from its filter
+    fn of_stream(stream: &lopdf::Stream) -> Option<ImageEncoder> {
+        match stream.dict.get(b"Filter").and_then(Object::as_name) {
+            Ok(b"DCTDecode") => Some(ImageEncoder::Jpeg),
+            Ok(b"FlateDecode") => Some(ImageEncoder::Flate),
+            Ok(b"CCITTFaxDecode") => Some(ImageEncoder::Ccitt),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "camelCase")]
+pub enum SkipReason {
+    HasTransparency,
+    TooSmall,
+    UnsupportedBitsPerComponent,
+    UnsupportedColorSpace(String),
+    UnsupportedFilter(String),
+    HasPredictor,
+    /// A Decode array can't be carried over once samples change colorspace
+    HasDecodeArray,
+}
+
+impl SkipReason {
+    /// Short label used to group skipped images in the report
+    fn label(&self) -> &'static str {
+        match self {
+            SkipReason::HasTransparency => "transparency",
+            SkipReason::TooSmall => "<2KB",
+            SkipReason::UnsupportedBitsPerComponent => "bits/component",
+            SkipReason::UnsupportedColorSpace(_) => "colorspace",
+            SkipReason::UnsupportedFilter(_) => "filter",
+            SkipReason::HasPredictor => "predictor",
+            SkipReason::HasDecodeArray => "decode array",
+        }
+    }
+}
+
+/// What happened to the samples of a CMYK image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CmykHandling {
+    /// Converted to sRGB through the image's own ICC profile
+    EmbeddedProfile,
+    /// Converted to sRGB through the document's output intent profile
+    OutputIntentProfile,
+    /// No usable profile: kept as CMYK and only recompressed losslessly
+    KeptCmyk,
+}
+
+/// How an image's samples are interpreted, resolved once per image before recompression
+#[derive(Clone)]
+enum ImageColorSpace {
+    /// Gray or RGB samples used as-is: Device, Cal and 1- or 3-component ICCBased spaces.
+    /// The original `ColorSpace` entry is kept, so calibration and ICC profiles survive.
+    Direct { channels: u32 },
+    /// DeviceCMYK or 4-component ICCBased, converted to sRGB when a profile is available
+    Cmyk {
+        to_srgb: Option<CmykTransform>,
+        handling: CmykHandling,
+    },
+    /// 1-bit gray samples or a stencil mask, kept at 1 bit and recompressed losslessly
+    Bilevel,
+    /// [/Indexed base hival lookup], expanded through the palette. The palette is already in
+    /// the output colorspace: the base space, or sRGB for a CMYK base with a profile.
+    Indexed {
+        palette: Vec<u8>,
+        /// Components per palette entry (1, 3 or 4)
+        channels: u32,
+        color_space: Object,
+        cmyk_handling: Option<CmykHandling>,
+    },
+}
+
+impl ImageColorSpace {
+    /// Components per sample in the stream data
+    fn source_channels(&self) -> usize {
+        match self {
+            ImageColorSpace::Direct { channels } => *channels as usize,
+            ImageColorSpace::Cmyk { .. } => 4,
+            ImageColorSpace::Bilevel | ImageColorSpace::Indexed { .. } => 1,
+        }
+    }
+
+    /// Whether recompression changes the colorspace of the samples
+    fn is_converted(&self, grayscale: bool) -> bool {
+        match self {
+            ImageColorSpace::Direct { channels } => grayscale && *channels > 1,
+            ImageColorSpace::Cmyk { to_srgb, .. } => grayscale || to_srgb.is_some(),
+            ImageColorSpace::Bilevel => false,
+            ImageColorSpace::Indexed { .. } => true,
+        }
+    }
+
+    /// Convert the matte colour of a premultiplied image the same way as its samples.
+    /// Components are 0-1, except for Indexed, where the one component is a palette index.
+    fn convert_matte(&self, matte: &[f32], grayscale: bool) -> Option<Vec<f32>> {
+        if matte.len() != self.source_channels() {
+            return None;
+        }
+        let samples: Vec<u8> = matte
+            .iter()
+            .map(|&v| (v * 255.0).round().clamp(0.0, 255.0) as u8)
+            .collect();
+        let (samples, channels) = match self {
+            ImageColorSpace::Direct { channels } => (samples, *channels),
+            ImageColorSpace::Cmyk {
+                to_srgb: Some(transform),
+                ..
+            } => (convert_cmyk(transform, &samples)?, 3),
+            ImageColorSpace::Cmyk { to_srgb: None, .. } => (samples, 4),
+            ImageColorSpace::Bilevel => return None,
+            ImageColorSpace::Indexed {
+                palette, channels, ..
+            } => {
+                let index = u8::try_from(matte[0] as i64).ok()?;
+                (expand_palette(&[index], palette, *channels)?, *channels)
+            }
+        };
+        let samples = if grayscale && channels > 1 {
+            pixels_to_gray(&samples, channels)?
+        } else {
+            samples
+        };
+        Some(samples.iter().map(|&v| v as f32 / 255.0).collect())
+    }
+
+    fn cmyk_handling(&self) -> Option<CmykHandling> {
+        match self {
+            ImageColorSpace::Cmyk { handling, .. } => Some(*handling),
+            ImageColorSpace::Indexed { cmyk_handling, .. } => *cmyk_handling,
+            _ => None,
+        }
+    }
+}
+
+/// Resolves image colorspaces against the document. CMYK transforms are built once per
+/// ICC profile, since a document usually shares one profile between all its images.
+struct ColorResolver<'a> {
+    doc: &'a Document,
+    output_intent: Option<CmykTransform>,
+    icc_transforms: HashMap<ObjectId, Option<CmykTransform>>,
+}
+
+impl<'a> ColorResolver<'a> {
+    fn new(doc: &'a Document) -> Self {
+        ColorResolver {
+            doc,
+            output_intent: output_intent_cmyk_profile(doc)
+                .and_then(|icc| cmyk_to_srgb_transform(&icc)),
+            icc_transforms: HashMap::new(),
+        }
+    }
+
+    fn resolve(&mut self, color_space: &Object) -> Result<ImageColorSpace, SkipReason> {
+        let unsupported = |name: &[u8]| {
+            SkipReason::UnsupportedColorSpace(String::from_utf8_lossy(name).to_string())
+        };
+
+        // Colorspace arrays are often shared between images as indirect objects
+        let Ok((_, color_space)) = self.doc.dereference(color_space) else {
+            return Err(unsupported(b"<complex>"));
+        };
+
+        match color_space {
+            Object::Name(name) => match name.as_slice() {
+                b"DeviceGray" => Ok(ImageColorSpace::Direct { channels: 1 }),
+                b"DeviceRGB" => Ok(ImageColorSpace::Direct { channels: 3 }),
+                b"DeviceCMYK" => Ok(self.device_cmyk()),
+                other => Err(unsupported(other)),
+            },
+            Object::Array(arr) => {
+                let Some(first) = arr.first() else {
+                    return Err(unsupported(b"<empty-array>"));
+                };
+                let Ok(family) = first.as_name() else {
+                    return Err(unsupported(b"<array-error>"));
+                };
+                match family {
+                    b"CalGray" => Ok(ImageColorSpace::Direct { channels: 1 }),
+                    b"CalRGB" => Ok(ImageColorSpace::Direct { channels: 3 }),
+                    b"ICCBased" => self.icc_based(arr).ok_or_else(|| unsupported(b"ICCBased")),
+                    b"Indexed" => self.indexed(arr).ok_or_else(|| unsupported(b"Indexed")),
+                    other => Err(unsupported(other)),
+                }
+            }
+            _ => Err(unsupported(b"<complex>")),
+        }
+    }
+
+    /// DeviceCMYK has no profile of its own; PDF/X files define one through their output intent
+    fn device_cmyk(&self) -> ImageColorSpace {
+        match &self.output_intent {
+            Some(transform) => ImageColorSpace::Cmyk {
+                to_srgb: Some(transform.clone()),
+                handling: CmykHandling::OutputIntentProfile,
+            },
+            None => ImageColorSpace::Cmyk {
+                to_srgb: None,
+                handling: CmykHandling::KeptCmyk,
+            },
+        }
+    }
+
+    /// [/ICCBased stream]: the component count comes from the profile stream's /N
+    fn icc_based(&mut self, arr: &[Object]) -> Option<ImageColorSpace> {
+        let profile_ref = arr.get(1)?;
+        let Object::Stream(profile) = self.doc.dereference(profile_ref).ok()?.1 else {
+            return None;
+        };
+
+        match profile.dict.get(b"N").and_then(Object::as_i64).ok()? {
+            1 => Some(ImageColorSpace::Direct { channels: 1 }),
+            3 => Some(ImageColorSpace::Direct { channels: 3 }),
+            4 => {
+                let transform = match profile_ref.as_reference() {
+                    Ok(id) => self
+                        .icc_transforms
+                        .entry(id)
+                        .or_insert_with(|| {
+                            icc_profile_data(profile).and_then(|icc| cmyk_to_srgb_transform(&icc))
+                        })
+                        .clone(),
+                    Err(_) => {
+                        icc_profile_data(profile).and_then(|icc| cmyk_to_srgb_transform(&icc))
+                    }
+                };
+                Some(match transform {
+                    Some(transform) => ImageColorSpace::Cmyk {
+                        to_srgb: Some(transform),
+                        handling: CmykHandling::EmbeddedProfile,
+                    },
+                    // A profile we can't parse is treated like DeviceCMYK
+                    None => self.device_cmyk(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// [/Indexed base hival lookup]. The lookup table is either a string or a (usually
+    /// compressed) stream, and the base can be any non-Indexed colorspace we support.
+    /// CMYK palettes are converted once here rather than for every pixel.
+    fn indexed(&mut self, arr: &[Object]) -> Option<ImageColorSpace> {
+        let [_, base_object, hival, lookup] = arr else {
+            return None;
+        };
+        let hival = hival.as_i64().ok().filter(|h| (0..=255).contains(h))? as usize;
+        let lookup = match self.doc.dereference(lookup).ok()?.1 {
+            Object::String(bytes, _) => bytes.clone(),
+            Object::Stream(stream) => match decode_stream_data(stream)? {
+                StreamData::Raw(bytes) => bytes.into_owned(),
+                StreamData::Jpeg(_) => return None,
+            },
+            _ => return None,
+        };
+
+        let base = self.resolve(base_object).ok()?;
+        let base_channels = base.source_channels();
+        // Some writers truncate the table; indices past its end fail during expansion
+        let entries = (hival + 1).min(lookup.len() / base_channels);
+        if entries == 0 {
+            return None;
+        }
+        let palette = &lookup[..entries * base_channels];
+
+        match base {
+            ImageColorSpace::Direct { channels } => Some(ImageColorSpace::Indexed {
+                palette: palette.to_vec(),
+                channels,
+                color_space: base_object.clone(),
+                cmyk_handling: None,
+            }),
+            ImageColorSpace::Cmyk {
+                to_srgb: Some(transform),
+                handling,
+            } => Some(ImageColorSpace::Indexed {
+                palette: convert_cmyk(&transform, palette)?,
+                channels: 3,
+                color_space: Object::Name(b"DeviceRGB".to_vec()),
+                cmyk_handling: Some(handling),
+            }),
+            ImageColorSpace::Cmyk {
+                to_srgb: None,
+                handling,
+            } => Some(ImageColorSpace::Indexed {
+                palette: palette.to_vec(),
+                channels: 4,
+                color_space: base_object.clone(),
+                cmyk_handling: Some(handling),
+            }),
+            // An Indexed base isn't allowed
+            ImageColorSpace::Bilevel | ImageColorSpace::Indexed { .. } => None,
+        }
+    }
+}
+
+/// Reduce big-endian 16-bit samples to 8 bits, rounding to the nearest level
+fn reduce_16_bit_samples(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2)
+        .map(|pair| {
+            let value = u16::from_be_bytes([pair[0], pair[1]]) as u32;
+            ((value * 255 + 32_767) / 65_535) as u8
+        })
+        .collect()
+}
+
+/// Expand palette indices (one per byte) to the palette's colours
+fn expand_palette(indices: &[u8], palette: &[u8], channels: u32) -> Option<Vec<u8>> {
+    let channels = channels as usize;
+    let mut pixels = Vec::with_capacity(indices.len() * channels);
+
+    for &index in indices {
+        let palette_offset = (index as usize) * channels;
+        // Invalid palette index
+        let colour = palette.get(palette_offset..palette_offset + channels)?;
+        pixels.extend_from_slice(colour);
+    }
+
+    Some(pixels)
+}
+
+/// Unpack 1, 2 or 4-bit samples to one byte each, keeping their values (not rescaled).
+/// Rows start on a byte boundary, as in PDF image data.
+fn unpack_samples(data: &[u8], samples_per_row: usize, rows: usize, bits: u32) -> Option<Vec<u8>> {
+    let row_bytes = (samples_per_row * bits as usize).div_ceil(8);
+    if data.len() < row_bytes * rows {
+        return None;
+    }
+
+    let per_byte = 8 / bits as usize;
+    let mask = (1u8 << bits) - 1;
+    let mut samples = Vec::with_capacity(samples_per_row * rows);
+    for row in data.chunks_exact(row_bytes).take(rows) {
+        for i in 0..samples_per_row {
+            let shift = 8 - bits as usize * (i % per_byte + 1);
+            samples.push((row[i / per_byte] >> shift) & mask);
+        }
+    }
+
+    Some(samples)
+}
+
+/// Check if a PDF stream is an image that we can safely recompress.
+/// Returns Some(Ok(colorspace)) if recompressible, Some(Err(reason)) if image but unsupported, None if not an image.
+fn is_recompressible_image(
+    stream: &lopdf::Stream,
+    colors: &mut ColorResolver,
+    grayscale: bool,
+) -> Option<Result<ImageColorSpace, SkipReason>> {
+    // Must be an Image XObject
+    let is_image = match stream.dict.get(b"Subtype") {
+        Ok(obj) => matches!(obj.as_name(), Ok(name) if name == b"Image"),
+        Err(_) => false,
+    };
+
+    if !is_image {
+        return None; // Not an image, don't count it
+    }
+
+    // Images with an SMask are fine: the mask is a separate stream that is kept lossless.
+    // Colour-key masking (/Mask [min max ...]) matches exact sample values, which JPEG
+    // wouldn't preserve, so those are skipped.
+    if let Ok(Object::Array(_)) = stream.dict.get(b"Mask") {
+        return Some(Err(SkipReason::HasTransparency));
+    }
+
+    // Skip tiny images (icons, bullets) — lowered from 10KB to 2KB to catch more images.
+    // Grayscale mode still converts them, or they'd be left in colour
+    if stream.content.len() < 2_000 && !grayscale {
+        return Some(Err(SkipReason::TooSmall));
+    }
+
+    // Stencil masks (/ImageMask true) are 1-bit and have no colorspace of their own
+    let is_stencil = matches!(
+        stream.dict.get(b"ImageMask").and_then(Object::as_bool),
+        Ok(true)
+    );
+
+    // 8 and 16 bits per component, 1/2/4-bit palette indices, and 1-bit gray
+    let bits_per_component = match stream.dict.get(b"BitsPerComponent") {
+        Ok(bpc) => match bpc.as_i64() {
+            Ok(bits @ (1 | 2 | 4 | 8 | 16)) => bits,
+            _ => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
+        },
+        Err(_) if is_stencil => 1,
+        Err(_) => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
+    };
+
+    // Handle common color spaces (Device, Cal, ICCBased and Indexed)
+    let color_space = match stream.dict.get(b"ColorSpace") {
+        _ if is_stencil => ImageColorSpace::Bilevel,
+        Ok(cs) => match colors.resolve(cs) {
+            Ok(color_space) => color_space,
+            Err(reason) => return Some(Err(reason)),
+        },
+        Err(_) => {
+            return Some(Err(SkipReason::UnsupportedColorSpace(
+                "<missing>".to_string(),
+            )))
+        }
+    };
+    let color_space = match (bits_per_component, color_space) {
+        (1, ImageColorSpace::Direct { channels: 1 } | ImageColorSpace::Bilevel) => {
+            ImageColorSpace::Bilevel
+        }
+        (_, ImageColorSpace::Bilevel) => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
+        (8, color_space)
+        | (1 | 2 | 4, color_space @ ImageColorSpace::Indexed { .. })
+        | (16, color_space @ (ImageColorSpace::Direct { .. } | ImageColorSpace::Cmyk { .. })) => {
+            color_space
+        }
+        _ => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
+    };
+    if stream.dict.has(b"Decode") && color_space.is_converted(grayscale) {
+        return Some(Err(SkipReason::HasDecodeArray));
+    }
+
+    // Every filter in the chain must be decodable, with DCTDecode only in last position.
+    // No filter means raw uncompressed data — we can handle that
+    let chain = match filter_chain(stream) {
+        Ok(chain) => chain,
+        Err(reason) => return Some(Err(reason)),
+    };
+    for (index, (filter, params)) in chain.iter().enumerate() {
+        let is_last = index + 1 == chain.len();
+        if !DECODABLE_FILTERS.contains(filter) || (*filter == b"DCTDecode" && !is_last) {
+            return Some(Err(SkipReason::UnsupportedFilter(
+                String::from_utf8_lossy(filter).to_string(),
+            )));
+        }
+
+        // TIFF (2) and PNG (10-15) predictors are undone after decoding; anything else is unknown
+        if let Some(predictor) = params.and_then(PredictorParams::from_params) {
+            if !predictor.is_supported() {
+                return Some(Err(SkipReason::HasPredictor));
+            }
+        }
+    }
+
+    // The JPEG decoder converts CMYK to RGB without colour management, which is exactly
+    // the colour shift print files can't take, so CMYK JPEGs are left as they are
+    let ends_in_jpeg = chain
+        .last()
+        .is_some_and(|(filter, _)| *filter == b"DCTDecode");
+    if ends_in_jpeg && color_space.source_channels() == 4 {
+        return Some(Err(SkipReason::UnsupportedColorSpace(
+            "CMYK JPEG".to_string(),
+        )));
+    }
+
+    Some(Ok(color_space))
+}
+
+/// Predictor settings from an image's `DecodeParms`
+struct PredictorParams {
+    predictor: i64,
+    colors: usize,
+    bits_per_component: usize,
+    columns: usize,
+}
+
+impl PredictorParams {
+    /// Read predictor params from a filter's `DecodeParms` dictionary.
+    /// Returns None when no predictor is applied.
+    fn from_params(params: &lopdf::Dictionary) -> Option<PredictorParams> {
+        let get =
+            |key: &[u8], default: i64| params.get(key).and_then(Object::as_i64).unwrap_or(default);
+
+        let predictor = get(b"Predictor", 1);
+        if predictor <= 1 {
+            return None;
+        }
+
+        Some(PredictorParams {
+            predictor,
+            colors: get(b"Colors", 1).max(1) as usize,
+            bits_per_component: get(b"BitsPerComponent", 8).max(1) as usize,
+            columns: get(b"Columns", 1).max(1) as usize,
+        })
+    }
+
+    fn is_supported(&self) -> bool {
+        (self.predictor == 2 || (10..=15).contains(&self.predictor))
+            && matches!(self.bits_per_component, 1 | 2 | 4 | 8 | 16)
+    }
+
+    /// Bytes in one row of samples (excluding the PNG filter-type byte)
+    fn row_bytes(&self) -> usize {
+        (self.colors * self.bits_per_component * self.columns).div_ceil(8)
+    }
+
+    /// Bytes per complete pixel, rounded up to at least 1 (the PNG "bpp")
+    fn pixel_bytes(&self) -> usize {
+        (self.colors * self.bits_per_component).div_ceil(8).max(1)
+    }
+}
+
+/// Filters the image pipeline can undo. DCTDecode is only accepted as the last filter,
+/// since the JPEG data it leaves is decoded separately.
+const DECODABLE_FILTERS: [&[u8]; 6] = [
+    b"FlateDecode",
+    b"LZWDecode",
+    b"RunLengthDecode",
+    b"ASCII85Decode",
+    b"ASCIIHexDecode",
+    b"DCTDecode",
+];
+
+/// Image data after undoing a stream's filter chain
+/// Borrowed from the stream when there was nothing to undo, so unfiltered samples and
+/// plain JPEGs aren't copied
+enum StreamData<'a> {
+    /// Raw samples
+    Raw(Cow<'a, [u8]>),
+    /// JPEG bytes left by a chain ending in DCTDecode
+    Jpeg(Cow<'a, [u8]>),
+}
+
+/// A filter name paired with its `DecodeParms`, if any
+type FilterStep<'a> = (&'a [u8], Option<&'a lopdf::Dictionary>);
+
+/// List a stream's filters in decoding order, each paired with its own `DecodeParms`.
+/// A stream without a Filter entry yields an empty chain.
+fn filter_chain(stream: &lopdf::Stream) -> Result<Vec<FilterStep<'_>>, SkipReason> {
+    if !stream.dict.has(b"Filter") {
+        return Ok(Vec::new());
+    }
+    let filters = stream
+        .filters()
+        .map_err(|_| SkipReason::UnsupportedFilter("<unknown>".to_string()))?;
+
+    // DecodeParms is a single dictionary for a single filter, or an array parallel to Filter
+    let params = stream.dict.get(b"DecodeParms").ok();
+    Ok(filters
+        .into_iter()
+        .enumerate()
+        .map(|(index, filter)| {
+            let filter_params = match params {
+                Some(Object::Dictionary(dict)) if index == 0 => Some(dict),
+                Some(Object::Array(arr)) => arr.get(index).and_then(|p| p.as_dict().ok()),
+                _ => None,
+            };
+            (filter, filter_params)
+        })
+        .collect())
+}
+
+/// Undo every filter on an image stream without touching the stream.
+/// lopdf only handles PNG predictors at 8+ bits, mis-decodes the PNG Average filter and
+/// ignores TIFF prediction, so predictors are stripped before decoding and reversed here.
+fn decode_stream_data(stream: &lopdf::Stream) -> Option<StreamData<'_>> {
+    let chain = filter_chain(stream).ok()?;
+    let mut data = Cow::Borrowed(stream.content.as_slice());
+
+    for (index, (filter, params)) in chain.iter().enumerate() {
+        data = Cow::Owned(match *filter {
+            b"FlateDecode" => undo_predictor(inflate(&data), *params)?,
+            b"LZWDecode" => {
+                // Keep EarlyChange, which affects the LZW code width, but not the predictor
+                let lzw_params = params
+                    .and_then(|p| p.get(b"EarlyChange").ok())
+                    .map(|early_change| dictionary! { "EarlyChange" => early_change.clone() });
+                undo_predictor(
+                    decode_with_lopdf(filter, lzw_params, data.into_owned())?,
+                    *params,
+                )?
+            }
+            b"ASCII85Decode" => decode_with_lopdf(filter, None, data.into_owned())?,
+            b"ASCIIHexDecode" => decode_ascii_hex(&data)?,
+            b"RunLengthDecode" => decode_run_length(&data)?,
+            b"DCTDecode" if index + 1 == chain.len() => return Some(StreamData::Jpeg(data)),
+            _ => return None,
+        });
+    }
+
+    Some(StreamData::Raw(data))
+}
+
+/// Inflate zlib data straight from the stream, where lopdf would need its own copy.
+/// Like lopdf, keeps whatever decoded before a corrupt or truncated tail.
+fn inflate(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    if !data.is_empty() {
+        let _ = ZlibDecoder::new(data).read_to_end(&mut output);
+    }
+    output
+}
+
+/// Run a single filter through lopdf's decoder
+fn decode_with_lopdf(
+    filter: &[u8],
+    params: Option<lopdf::Dictionary>,
+    data: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let mut dict = lopdf::Dictionary::new();
+    dict.set("Filter", Object::Name(filter.to_vec()));
+    if let Some(params) = params {
+        dict.set("DecodeParms", params);
+    }
+    lopdf::Stream::new(dict, data).decompressed_content().ok()
+}
+
+/// Reverse the predictor described by a filter's `DecodeParms`, if any
+fn undo_predictor(data: Vec<u8>, params: Option<&lopdf::Dictionary>) -> Option<Vec<u8>> {
+    match params.and_then(PredictorParams::from_params) {
+        Some(predictor) if predictor.predictor == 2 => undo_tiff_predictor(data, &predictor),
+        Some(predictor) if predictor.is_supported() => undo_png_predictor(&data, &predictor),
+        Some(_) => None,
+        None => Some(data),
+    }
+}
+
+/// ASCIIHexDecode: pairs of hex digits, whitespace ignored, `>` marks the end
+fn decode_ascii_hex(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() / 2);
+    let mut high: Option<u8> = None;
+
+    for &byte in data {
+        if byte == b'>' {
+            break;
+        }
+        if byte.is_ascii_whitespace() {
+            continue;
+        }
+        let nibble = (byte as char).to_digit(16)? as u8;
+        match high.take() {
+            Some(h) => output.push((h << 4) | nibble),
+            None => high = Some(nibble),
+        }
+    }
+
+    // An odd final digit behaves as if followed by 0
+    if let Some(h) = high {
+        output.push(h << 4);
+    }
+
+    Some(output)
+}
+
+/// RunLengthDecode: a length byte of 0-127 copies the next n+1 bytes, 129-255 repeats the
+/// next byte 257-n times, and 128 marks the end of data
+fn decode_run_length(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let length = data[pos] as usize;
+        pos += 1;
+        match length {
+            0..=127 => {
+                let run = data.get(pos..pos + length + 1)?;
+                output.extend_from_slice(run);
+                pos += length + 1;
+            }
+            128 => break,
+            _ => {
+                let byte = *data.get(pos)?;
+                output.extend(std::iter::repeat_n(byte, 257 - length));
+                pos += 1;
+            }
+        }
+    }
+
+    Some(output)
+}
+
+/// Reverse PNG row filters (predictors 10-15; each row carries its own filter type byte)
+fn undo_png_predictor(data: &[u8], params: &PredictorParams) -> Option<Vec<u8>> {
+    let row_bytes = params.row_bytes();
+    let bpp = params.pixel_bytes();
+    let rows = data.len() / (row_bytes + 1);
+
+    let mut output = Vec::with_capacity(rows * row_bytes);
+    let mut prev_row = vec![0u8; row_bytes];
+
+    for encoded in data.chunks_exact(row_bytes + 1) {
+        let filter_type = encoded[0];
+        let mut row = encoded[1..].to_vec();
+
+        for i in 0..row_bytes {
+            let left = if i >= bpp { row[i - bpp] } else { 0 };
+            let up = prev_row[i];
+            let upper_left = if i >= bpp { prev_row[i - bpp] } else { 0 };
+
+            row[i] = match filter_type {
+                0 => row[i],
+                1 => row[i].wrapping_add(left),
+                2 => row[i].wrapping_add(up),
+                3 => row[i].wrapping_add(((left as u16 + up as u16) / 2) as u8),
+                4 => row[i].wrapping_add(paeth(left, up, upper_left)),
+                _ => return None,
+            };
+        }
+
+        output.extend_from_slice(&row);
+        prev_row = row;
+    }
+
+    Some(output)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverse TIFF predictor 2 (horizontal differencing of each colour component)
+fn undo_tiff_predictor(mut data: Vec<u8>, params: &PredictorParams) -> Option<Vec<u8>> {
+    let row_bytes = params.row_bytes();
+    let colors = params.colors;
+
+    for row in data.chunks_exact_mut(row_bytes) {
+        match params.bits_per_component {
+            8 => {
+                for i in colors..row_bytes {
+                    row[i] = row[i].wrapping_add(row[i - colors]);
+                }
+            }
+            16 => {
+                for i in (colors * 2..row_bytes - 1).step_by(2) {
+                    let prev = u16::from_be_bytes([row[i - colors * 2], row[i - colors * 2 + 1]]);
+                    let cur = u16::from_be_bytes([row[i], row[i + 1]]);
+                    let [hi, lo] = cur.wrapping_add(prev).to_be_bytes();
+                    row[i] = hi;
+                    row[i + 1] = lo;
+                }
+            }
+            bits @ (1 | 2 | 4) => {
+                // Sub-byte samples: unpack, accumulate modulo 2^bits, repack in place
+                let samples_per_row = colors * params.columns;
+                let mask = (1u8 << bits) - 1;
+                let mut samples: Vec<u8> = (0..samples_per_row)
+                    .map(|n| {
+                        let bit = n * bits;
+                        (row[bit / 8] >> (8 - bits - bit % 8)) & mask
+                    })
+                    .collect();
+                for n in colors..samples_per_row {
+                    samples[n] = samples[n].wrapping_add(samples[n - colors]) & mask;
+                }
+                row.fill(0);
+                for (n, sample) in samples.into_iter().enumerate() {
+                    let bit = n * bits;
+                    row[bit / 8] |= sample << (8 - bits - bit % 8);
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    Some(data)
+}
+
+/// Raw pixels of an image stream, as 8-bit Gray, RGB or (unconverted) CMYK samples
+struct DecodedImage {
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// 1 (Gray), 3 (RGB) or 4 (CMYK kept without a profile)
+    channels: u32,
+    /// Set when the source colorspace was converted and `ColorSpace` must be rewritten
+    new_color_space: Option<Object>,
+    is_already_jpeg: bool,
+}
+
+/// Decode an image stream to raw 8-bit pixels without modifying it.
+/// Returns None when the encoding or colorspace can't be decoded.
+fn decode_image_stream(
+    stream: &lopdf::Stream,
+    color_space: &ImageColorSpace,
+) -> Option<DecodedImage> {
+    let width = match stream.dict.get(b"Width") {
+        Ok(w) => match w.as_i64() {
+            Ok(v) => {
+                if v <= 0 {
+                    return None;
+                }
+                v as u32
+            }
+            Err(_) => return None,
+        },
+        Err(_) => return None,
+    };
+    let height = match stream.dict.get(b"Height") {
+        Ok(h) => match h.as_i64() {
+            Ok(v) => {
+                if v <= 0 {
+                    return None;
+                }
+                v as u32
+            }
+            Err(_) => return None,
+        },
+        Err(_) => return None,
+    };
+
+    // Undo the filter chain: raw samples, or JPEG bytes when the chain ends in DCTDecode
+    // Note: We avoid mutating the stream until we're sure we'll succeed
+    let stream_data = decode_stream_data(stream)?;
+    let is_already_jpeg = matches!(stream_data, StreamData::Jpeg(_));
+
+    let samples: Vec<u8> = match stream_data {
+        StreamData::Jpeg(jpeg_data) => {
+            // Only Gray and RGB JPEGs get here: CMYK JPEGs are skipped up front
+            // Note: This causes generation loss, so we'll only replace if significantly smaller
+            let ImageColorSpace::Direct { channels } = color_space else {
+                return None;
+            };
+            let img =
+                image::load_from_memory_with_format(&jpeg_data, image::ImageFormat::Jpeg).ok()?;
+            if *channels == 1 {
+                img.to_luma8().into_raw()
+            } else {
+                img.to_rgb8().into_raw()
+            }
+        }
+        StreamData::Raw(decompressed) => decompressed.into_owned(),
+    };
+
+    // Palette indices can be packed several to a byte; 16-bit samples are reduced to 8 bits
+    let bits_per_component = stream
+        .dict
+        .get(b"BitsPerComponent")
+        .and_then(Object::as_i64)
+        .unwrap_or(8);
+    let samples = match (bits_per_component, color_space) {
+        (8, _) => samples,
+        (16, _) => reduce_16_bit_samples(&samples),
+        (bits @ (1 | 2 | 4), ImageColorSpace::Indexed { .. }) => {
+            unpack_samples(&samples, width as usize, height as usize, bits as u32)?
+        }
+        _ => return None,
+    };
+
+    // Validate expected data size (cast to usize before multiplying to prevent u32 overflow)
+    let expected_size = (width as usize) * (height as usize) * color_space.source_channels();
+    if samples.len() != expected_size {
+        return None;
+    }
+
+    let (pixels, channels, new_color_space) = match color_space {
+        ImageColorSpace::Direct { channels } => (samples, *channels, None),
+        // 1-bit images are never unpacked, see `recompress_bilevel`
+        ImageColorSpace::Bilevel => return None,
+        ImageColorSpace::Cmyk {
+            to_srgb: Some(transform),
+            ..
+        } => (
+            convert_cmyk(transform, &samples)?,
+            3,
+            Some(Object::Name(b"DeviceRGB".to_vec())),
+        ),
+        ImageColorSpace::Cmyk { to_srgb: None, .. } => (samples, 4, None),
+        ImageColorSpace::Indexed {
+            palette,
+            channels,
+            color_space,
+            ..
+        } => (
+            expand_palette(&samples, palette, *channels)?,
+            *channels,
+            Some(color_space.clone()),
+        ),
+    };
+
+    Some(DecodedImage {
+        pixels,
+        width,
+        height,
+        channels,
+        new_color_space,
+        is_already_jpeg,
+    })
+}
+
+/// How candidate images are re-encoded in a run
+#[derive(Debug, Clone, Copy)]
+struct ImageEncoding {
+    /// JPEG quality 10-100
+    quality: u8,
+    progressive: bool,
+    chroma_subsampling: ChromaSubsampling,
+    /// Convert colour images to DeviceGray
+    grayscale: bool,
+    /// Deflate pixels instead of encoding them as JPEG
+    lossless: bool,
+}
+
+/// A candidate image taken out of the document to be recompressed on a worker thread
+struct ImageJob {
+    id: ObjectId,
+    /// Index of the image's entry in `CompressionReport::images`
+    index: usize,
+    stream: lopdf::Stream,
+    color_space: ImageColorSpace,
+    is_soft_mask: bool,
+    resample_to: Option<(u32, u32)>,
+    /// The image's soft mask and its matte colour converted to the new colorspace, to be
+    /// set once the image is recompressed
+    new_matte: Option<(ObjectId, Vec<f32>)>,
+    /// None until the job has run
+    result: Option<ImageCompressionResult>,
+}
+
+impl ImageJob {
+    fn recompress(&mut self, encoding: ImageEncoding) -> ImageCompressionResult {
+        if matches!(self.color_space, ImageColorSpace::Bilevel) {
+            recompress_bilevel(&mut self.stream)
+        } else if self.is_soft_mask {
+            recompress_soft_mask(&mut self.stream, &self.color_space)
+        } else {
+            recompress_image_stream(
+                &mut self.stream,
+                &self.color_space,
+                encoding,
+                self.resample_to,
+            )
+        }
+    }
+}
+
+/// Recompress an image stream as JPEG at the encoding's quality (1-100), or losslessly
+/// with Flate for flat graphics.
+/// When `resample_to` is smaller than the image, pixels are downsampled to those
+/// dimensions first and `Width`/`Height` are updated to match.
+/// CMYK kept without a profile can't go through the JPEG encoder and is deflated instead.
+fn recompress_image_stream(
+    stream: &mut lopdf::Stream,
+    color_space: &ImageColorSpace,
+    encoding: ImageEncoding,
+    resample_to: Option<(u32, u32)>,
+) -> ImageCompressionResult {
+    let original_stream_size = stream.content.len();
+    let Some(decoded) = decode_image_stream(stream, color_space) else {
+        return ImageCompressionResult::Failed;
+    };
+    let DecodedImage {
+        pixels: raw_pixels,
+        width,
+        height,
+        channels: target_channels,
+        new_color_space,
+        is_already_jpeg,
+    } = decoded;
+
+    // Grayscale mode: colour samples are reduced to a single gray channel
+    let converted_to_gray = encoding.grayscale && target_channels > 1;
+    let (raw_pixels, target_channels, new_color_space) = if converted_to_gray {
+        match pixels_to_gray(&raw_pixels, target_channels) {
+            Some(gray) => (gray, 1, Some(Object::Name(b"DeviceGray".to_vec()))),
+            None => return ImageCompressionResult::Failed,
+        }
+    } else {
+        (raw_pixels, target_channels, new_color_space)
+    };
+
+    // Flat graphics (screenshots, charts, line art) ring as JPEG but deflate well. Sources
+    // that are already JPEG stay JPEG: their decoded pixels only grow when deflated
+    let is_graphic = !is_already_jpeg
+        && classify_image(&raw_pixels, width, target_channels) == ImageClass::Graphic;
+
+    // Downsample before encoding when the image is placed at a higher resolution than needed
+    let (raw_pixels, width, height, resampled) = match resample_to {
+        Some((new_width, new_height)) if new_width < width || new_height < height => {
+            match resample_pixels(
+                raw_pixels,
+                width,
+                height,
+                target_channels,
+                new_width,
+                new_height,
+            ) {
+                Some(resized) => (resized, new_width, new_height, true),
+                None => return ImageCompressionResult::Failed,
+            }
+        }
+        _ => (raw_pixels, width, height, false),
+    };
+
+    // Quality-aware threshold: lower quality = more aggressive (allow smaller reductions)
+    // This helps achieve meaningful compression even with generation loss on pre-compressed JPEGs
+    let threshold_multiplier = if is_already_jpeg {
+        if encoding.quality < 50 {
+            0.85 // Aggressive: accept 15%+ reduction for very low quality
+        } else if encoding.quality < 70 {
+            0.88 // Moderate: accept 12%+ reduction for medium quality
+        } else {
+            0.92 // Conservative: accept 8%+ reduction for high quality
+        }
+    } else {
+        1.0 // For non-JPEG sources, any reduction is good (no generation loss)
+    };
+
+    // Grayscale conversion is what was asked for, so it is kept even when it doesn't shrink
+    let size_threshold = if converted_to_gray {
+        usize::MAX
+    } else {
+        (original_stream_size as f64 * threshold_multiplier) as usize
+    };
+
+    // Encode as JPEG at the user-chosen quality level, unless the image is better off lossless.
+    // Images too large for the JPEG encoder fall back to Flate as well
+    let jpeg_data = if target_channels == 4 || encoding.lossless || is_graphic {
+        None
+    } else {
+        encode_jpeg(&raw_pixels, width, height, target_channels, &encoding)
+    };
+
+    let result = if let Some(jpeg_data) = jpeg_data {
+        // Palettes and line art often grow as JPEG; once converted to gray, deflating
+        // them is usually far smaller
+        if converted_to_gray
+            && jpeg_data.len() >= original_stream_size
+            && store_lossless(stream, &raw_pixels, width, 1, 8, jpeg_data.len())
+                == ImageCompressionResult::Recompressed
+        {
+            ImageCompressionResult::Recompressed
+        } else if jpeg_data.len() >= size_threshold {
+            return ImageCompressionResult::SkippedThreshold;
+        } else {
+            // Update stream with JPEG data
+            stream.set_content(jpeg_data);
+            stream
+                .dict
+                .set("Filter", lopdf::Object::Name(b"DCTDecode".to_vec()));
+            stream.dict.remove(b"DecodeParms");
+            stream.allows_compression = false;
+            ImageCompressionResult::Recompressed
+        }
+    } else {
+        store_lossless(
+            stream,
+            &raw_pixels,
+            width,
+            target_channels,
+            8,
+            size_threshold,
+        )
+    };
+
+    if result != ImageCompressionResult::Recompressed {
+        return result;
+    }
+
+    if resampled {
+        stream.dict.set("Width", width as i64);
+        stream.dict.set("Height", height as i64);
+    }
+
+    // If we converted CMYK to RGB, expanded a palette, or went to gray, update the colorspace.
+    // Samples are always written at 8 bits, including palette indices and 16-bit samples
+    if let Some(color_space) = new_color_space {
+        stream.dict.set("ColorSpace", color_space);
+    }
+    stream.dict.set("BitsPerComponent", 8);
+
+    result
+}
+
+/// Encode 8-bit Gray or RGB pixels as a (baseline or progressive) JPEG.
+/// Returns None if encoding fails, e.g. for images over 65535 pixels on a side.
+fn encode_jpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    channels: u32,
+    encoding: &ImageEncoding,
+) -> Option<Vec<u8>> {
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return None;
+    };
+    let color_type = if channels == 1 {
+        ColorType::Luma
+    } else {
+        ColorType::Rgb
+    };
+
+    let mut jpeg_data: Vec<u8> = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut jpeg_data, encoding.quality);
+    encoder.set_progressive(encoding.progressive);
+    encoder.set_sampling_factor(encoding.chroma_subsampling.sampling_factor());
+    encoder.set_optimized_huffman_tables(true);
+    encoder.encode(pixels, width, height, color_type).ok()?;
+
+    Some(jpeg_data)
+}
+
+/// What an image looks like, as far as picking an encoder goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageClass {
+    Photo,
+    Graphic,
+}
+
+/// Cap on the pixels examined by `classify_image`; larger images are sampled by row
+const CLASSIFY_MAX_PIXELS: usize = 1_000_000;
+
+/// Classify an image by its distinct colours and neighbouring pixels. Graphics use few
+/// colours, or have large flat areas broken by sharp edges; photos have neither.
+fn classify_image(pixels: &[u8], width: u32, channels: u32) -> ImageClass {
+    let channels = channels as usize;
+    let row_bytes = width as usize * channels;
+    if row_bytes == 0 || width < 2 {
+        return ImageClass::Photo;
+    }
+    let rows = pixels.len() / row_bytes;
+    let row_step = (rows * width as usize).div_ceil(CLASSIFY_MAX_PIXELS).max(1);
+
+    // Gray photos never have more than 256 levels, so few colours means far fewer for them
+    let few_colors = if channels == 1 { 16 } else { 256 };
+    let mut colors: HashSet<&[u8]> = HashSet::new();
+    let mut pairs = 0usize;
+    let mut flat_pairs = 0usize;
+    let mut sharp_pairs = 0usize;
+
+    for row in pixels.chunks_exact(row_bytes).step_by(row_step) {
+        let mut previous: Option<&[u8]> = None;
+        for pixel in row.chunks_exact(channels) {
+            if colors.len() <= few_colors {
+                colors.insert(pixel);
+            }
+            if let Some(previous) = previous {
+                let difference = previous
+                    .iter()
+                    .zip(pixel)
+                    .map(|(a, b)| a.abs_diff(*b))
+                    .max()
+                    .unwrap_or(0);
+                pairs += 1;
+                if difference == 0 {
+                    flat_pairs += 1;
+                } else if difference >= 64 {
+                    sharp_pairs += 1;
+                }
+            }
+            previous = Some(pixel);
+        }
+    }
+
+    let flat = flat_pairs as f64 / pairs.max(1) as f64;
+    let sharp = sharp_pairs as f64 / pairs.max(1) as f64;
+    if colors.len() <= few_colors || (flat >= 0.5 && sharp >= 0.01) {
+        ImageClass::Graphic
+    } else {
+        ImageClass::Photo
+    }
+}
+
+/// The soft mask of an image premultiplied with a matte colour, and that colour
+fn soft_mask_matte(doc: &Document, image: &lopdf::Stream) -> Option<(ObjectId, Vec<f32>)> {
+    let mask_id = image
+        .dict
+        .get(b"SMask")
+        .and_then(Object::as_reference)
+        .ok()?;
+    let mask = doc.get_object(mask_id).and_then(Object::as_stream).ok()?;
+    let matte = mask.dict.get(b"Matte").and_then(Object::as_array).ok()?;
+    let matte = matte.iter().map(Object::as_float).collect::<Result<_, _>>();
+    Some((mask_id, matte.ok()?))
+}
+
+/// Recompress a soft mask (SMask) losslessly. JPEG artefacts in a mask show up as halos
+/// around transparent edges, so masks are only re-deflated, never converted to DCTDecode.
+fn recompress_soft_mask(
+    stream: &mut lopdf::Stream,
+    color_space: &ImageColorSpace,
+) -> ImageCompressionResult {
+    let original_stream_size = stream.content.len();
+    let Some(decoded) = decode_image_stream(stream, color_space) else {
+        return ImageCompressionResult::Failed;
+    };
+    if decoded.channels != 1 {
+        // Soft masks are always DeviceGray
+        return ImageCompressionResult::Failed;
+    }
+
+    let result = store_lossless(
+        stream,
+        &decoded.pixels,
+        decoded.width,
+        decoded.channels,
+        8,
+        original_stream_size,
+    );
+    if result == ImageCompressionResult::Recompressed {
+        // 16-bit masks come out at 8 bits
+        stream.dict.set("BitsPerComponent", 8);
+    }
+    result
+}
+
+/// Recompress a 1-bit image or stencil mask losslessly, keeping it at 1 bit per pixel.
+/// JPEG would blur scanned text, and unpacking to 8 bits would only make it bigger.
+/// CCITT Group 4, the fax coding made for such images, is tried alongside Flate and the
+/// smaller kept: G4 wins on scans, Flate on dithered or patterned images.
+fn recompress_bilevel(stream: &mut lopdf::Stream) -> ImageCompressionResult {
+    let original_stream_size = stream.content.len();
+    let dimension = |key: &[u8]| {
+        stream
+            .dict
+            .get(key)
+            .and_then(Object::as_i64)
+            .ok()
+            .filter(|v| *v > 0)
+    };
+    let (Some(width), Some(height)) = (dimension(b"Width"), dimension(b"Height")) else {
+        return ImageCompressionResult::Failed;
+    };
+    let Some(StreamData::Raw(data)) = decode_stream_data(stream) else {
+        return ImageCompressionResult::Failed;
+    };
+    // The samples are written back into the same stream
+    let data = data.into_owned();
+
+    // Each row is padded to a whole byte
+    let packed_size = (width as usize).div_ceil(8) * height as usize;
+    if data.len() < packed_size {
+        return ImageCompressionResult::Failed;
+    }
+    let pixels = &data[..packed_size];
+
+    let flate = deflate_image_lossless(pixels, width as u32, 1, 1)
+        .map(|(content, params)| (content, "FlateDecode", params));
+    let g4 = encode_g4(pixels, width as u32, height as u32)
+        .map(|(content, params)| (content, "CCITTFaxDecode", Some(params)));
+    let Some((content, filter, decode_parms)) = [flate, g4]
+        .into_iter()
+        .flatten()
+        .min_by_key(|(content, ..)| content.len())
+    else {
+        return ImageCompressionResult::Failed;
+    };
+
+    if content.len() >= original_stream_size {
+        return ImageCompressionResult::SkippedThreshold;
+    }
+    set_image_content(stream, content, filter, decode_parms);
+    ImageCompressionResult::Recompressed
+}
+
+/// Code packed 1-bit rows with CCITT Group 4 (`/K -1`). Returns None for images too large
+/// for the encoder, or if the coded data doesn't decode back to the same pixels.
+fn encode_g4(pixels: &[u8], width: u32, height: u32) -> Option<(Vec<u8>, lopdf::Dictionary)> {
+    let width = u16::try_from(width).ok()?;
+    let height = u16::try_from(height).ok()?;
+    let row_bytes = (width as usize).div_ceil(8);
+
+    // G4 codes runs of white cheaply, so the more common sample value is coded as white
+    let ones: u64 = pixels
+        .chunks_exact(row_bytes)
+        .map(|row| {
+            (0..width as usize)
+                .filter(|&x| row[x / 8] & (0x80 >> (x % 8)) != 0)
+                .count() as u64
+        })
+        .sum();
+    let black_is_1 = ones * 2 < width as u64 * height as u64;
+    let color = |bit: bool| {
+        if bit == black_is_1 {
+            fax::Color::Black
+        } else {
+            fax::Color::White
+        }
+    };
+
+    let mut encoder = fax::encoder::Encoder::new(fax::VecWriter::new());
+    for row in pixels.chunks_exact(row_bytes) {
+        let pels = (0..width as usize).map(|x| color(row[x / 8] & (0x80 >> (x % 8)) != 0));
+        encoder.encode_line(pels, width).ok()?;
+    }
+    let content = encoder.finish().ok()?.finish();
+
+    // Check the round trip, so a coding slip can never change the image
+    let mut decoded = Vec::with_capacity(pixels.len());
+    fax::decoder::decode_g4(
+        content.iter().copied(),
+        width,
+        Some(height),
+        |transitions| {
+            let mut row = vec![0u8; row_bytes];
+            for (x, pel) in fax::decoder::pels(transitions, width).enumerate() {
+                if (pel == fax::Color::Black) == black_is_1 {
+                    row[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            decoded.extend(row);
+        },
+    )?;
+    // Padding bits past the width aren't coded
+    let padding_mask = match width % 8 {
+        0 => 0xff,
+        bits => 0xffu8 << (8 - bits),
+    };
+    let same = decoded.len() == pixels.len()
+        && decoded
+            .chunks_exact(row_bytes)
+            .zip(pixels.chunks_exact(row_bytes))
+            .all(|(decoded, original)| {
+                decoded[..row_bytes - 1] == original[..row_bytes - 1]
+                    && decoded[row_bytes - 1] == original[row_bytes - 1] & padding_mask
+            });
+    if !same {
+        return None;
+    }
+
+    let params = dictionary! {
+        "K" => -1,
+        "Columns" => width as i64,
+        "Rows" => height as i64,
+        "BlackIs1" => black_is_1,
+    };
+    Some((content, params))
+}
+
+/// Replace an image stream's data with losslessly deflated pixels, if that makes it
+/// smaller than `size_threshold`
+fn store_lossless(
+    stream: &mut lopdf::Stream,
+    pixels: &[u8],
+    width: u32,
+    channels: u32,
+    bits_per_component: u32,
+    size_threshold: usize,
+) -> ImageCompressionResult {
+    let Some((content, decode_parms)) =
+        deflate_image_lossless(pixels, width, channels, bits_per_component)
+    else {
+        return ImageCompressionResult::Failed;
+    };
+
+    if content.len() >= size_threshold {
+        return ImageCompressionResult::SkippedThreshold;
+    }
+    set_image_content(stream, content, "FlateDecode", decode_parms);
+    ImageCompressionResult::Recompressed
+}
+
+/// Store losslessly coded image data, with the filter and `DecodeParms` it needs
+fn set_image_content(
+    stream: &mut lopdf::Stream,
+    content: Vec<u8>,
+    filter: &str,
+    decode_parms: Option<lopdf::Dictionary>,
+) {
+    stream.set_content(content);
+    stream
+        .dict
+        .set("Filter", Object::Name(filter.as_bytes().to_vec()));
+    match decode_parms {
+        Some(params) => stream.dict.set("DecodeParms", params),
+        None => {
+            stream.dict.remove(b"DecodeParms");
+        }
+    }
+    stream.allows_compression = false;
+}
+
+/// Deflate raw pixels (rows packed at the given bit depth) at the best compression level, trying both plain Flate and
+/// Flate over PNG-predicted rows. Returns the smaller encoding and the `DecodeParms` it needs.
+fn deflate_image_lossless(
+    pixels: &[u8],
+    width: u32,
+    channels: u32,
+    bits_per_component: u32,
+) -> Option<(Vec<u8>, Option<lopdf::Dictionary>)> {
+    let row_bytes = (width as usize * channels as usize * bits_per_component as usize).div_ceil(8);
+    if row_bytes == 0 {
+        return None;
+    }
+
+    // PNG predictors work on whole bytes, so packed samples predict from the previous byte
+    let bpp = (channels as usize * bits_per_component as usize)
+        .div_ceil(8)
+        .max(1);
+    let plain = deflate_best(pixels)?;
+    let predicted = deflate_best(&apply_png_predictor(pixels, row_bytes, bpp))?;
+
+    if predicted.len() < plain.len() {
+        let params = dictionary! {
+            "Predictor" => 15,
+            "Colors" => channels as i64,
+            "BitsPerComponent" => bits_per_component as i64,
+            "Columns" => width as i64,
+        };
+        Some((predicted, Some(params)))
+    } else {
+        Some((plain, None))
+    }
+}
+
+/// Apply PNG row filters, choosing per row the filter with the smallest sum of absolute
+/// residuals (the usual libpng heuristic). Output rows are prefixed with their filter type.
+fn apply_png_predictor(data: &[u8], row_bytes: usize, bpp: usize) -> Vec<u8> {
+    let rows = data.len() / row_bytes;
+    let mut output = Vec::with_capacity(rows * (row_bytes + 1));
+    let zero_row = vec![0u8; row_bytes];
+    let mut candidate = vec![0u8; row_bytes];
+    let mut best = vec![0u8; row_bytes];
+
+    for (index, row) in data.chunks_exact(row_bytes).enumerate() {
+        let prev_row = if index == 0 {
+            &zero_row[..]
+        } else {
+            &data[(index - 1) * row_bytes..index * row_bytes]
+        };
+
+        let mut best_type = 0u8;
+        let mut best_score = u64::MAX;
+        for filter_type in 0..5u8 {
+            let mut score = 0u64;
+            for i in 0..row_bytes {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let up = prev_row[i];
+                let upper_left = if i >= bpp { prev_row[i - bpp] } else { 0 };
+                let predicted = match filter_type {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, upper_left),
+                };
+                candidate[i] = row[i].wrapping_sub(predicted);
+                score += (candidate[i] as i8).unsigned_abs() as u64;
+            }
+            if score < best_score {
+                best_score = score;
+                best_type = filter_type;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+
+        output.push(best_type);
+        output.extend_from_slice(&best);
+    }
+
+    output
+}
+
+/// Resize raw 8-bit Gray, RGB or CMYK pixels to the given dimensions
+fn resample_pixels(
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    channels: u32,
+    new_width: u32,
+    new_height: u32,
+) -> Option<Vec<u8>> {
+    use image::imageops::{resize, FilterType};
+    use image::{GrayImage, RgbImage, RgbaImage};
+
+    // Bicubic (Catmull-Rom) keeps text and edges reasonably sharp without Lanczos' cost
+    match channels {
+        1 => GrayImage::from_raw(width, height, pixels)
+            .map(|img| resize(&img, new_width, new_height, FilterType::CatmullRom).into_raw()),
+        3 => RgbImage::from_raw(width, height, pixels)
+            .map(|img| resize(&img, new_width, new_height, FilterType::CatmullRom).into_raw()),
+        // CMYK has four channels too; resizing treats them all alike, so the layout fits
+        4 => RgbaImage::from_raw(width, height, pixels)
+            .map(|img| resize(&img, new_width, new_height, FilterType::CatmullRom).into_raw()),
+        _ => None,
+    }
+}
+
+/// Work out the pixel dimensions an image should be resampled to for the target DPI.
+/// Returns None when the image is already at or below the target (within the threshold).
+fn resample_dimensions(
+    width: u32,
+    height: u32,
+    placed_size: (f64, f64),
+    target_dpi: u32,
+) -> Option<(u32, u32)> {
+    let (placed_width, placed_height) = placed_size;
+    if placed_width <= 0.0 || placed_height <= 0.0 || target_dpi == 0 {
+        return None;
+    }
+
+    // Placed size is in user-space units (1/72 inch)
+    let dpi_x = width as f64 / (placed_width / 72.0);
+    let dpi_y = height as f64 / (placed_height / 72.0);
+    let effective_dpi = dpi_x.min(dpi_y);
+    let target = target_dpi as f64;
+
+    if effective_dpi <= target * DOWNSAMPLE_THRESHOLD {
+        return None;
+    }
+
+    // Scale both axes uniformly so the lower-resolution axis lands on the target
+    let scale = target / effective_dpi;
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    Some((new_width, new_height))
+}
+
+/// Affine transform [a b c d e f] as used by the PDF `cm` operator
+type Matrix = [f64; 6];
+
+const IDENTITY_MATRIX: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Nesting limit for form XObjects when walking content streams
+const MAX_FORM_DEPTH: u32 = 12;
+
+/// Returns `m × n` (apply `m`, then `n`)
+fn multiply_matrix(m: &Matrix, n: &Matrix) -> Matrix {
+    [
+        m[0] * n[0] + m[1] * n[2],
+        m[0] * n[1] + m[1] * n[3],
+        m[2] * n[0] + m[3] * n[2],
+        m[2] * n[1] + m[3] * n[3],
+        m[4] * n[0] + m[5] * n[2] + n[4],
+        m[4] * n[1] + m[5] * n[3] + n[5],
+    ]
+}
+
+fn matrix_from_operands(operands: &[Object]) -> Option<Matrix> {
+    if operands.len() != 6 {
+        return None;
+    }
+    let mut m = IDENTITY_MATRIX;
+    for (slot, operand) in m.iter_mut().zip(operands) {
+        *slot = operand.as_float().ok()? as f64;
+    }
+    Some(m)
+}
+
+/// Find the largest size (in user-space units) each image XObject is painted at,
+/// by walking page content streams, annotation appearances, nested form XObjects and
+/// tiling patterns while tracking the CTM. Images drawn nowhere are absent.
+fn collect_image_placements(doc: &Document) -> HashMap<ObjectId, (f64, f64)> {
+    let mut placements = HashMap::new();
+
+    for page_id in doc.page_iter() {
+        if let Ok(content) = doc.get_page_content(page_id) {
+            // Page-level resources, including ones inherited from the page tree
+            let mut resources: Vec<&lopdf::Dictionary> = Vec::new();
+            if let Ok((direct, inherited)) = doc.get_page_resources(page_id) {
+                resources.extend(direct);
+                resources.extend(
+                    inherited
+                        .into_iter()
+                        .filter_map(|id| doc.get_dictionary(id).ok()),
+                );
+            }
+
+            walk_content_for_images(
+                doc,
+                &content,
+                &resources,
+                IDENTITY_MATRIX,
+                0,
+                &mut placements,
+            );
+        }
+
+        for (appearance, rect_size) in appearance_streams(doc, page_id) {
+            // An appearance is scaled so its transformed bounding box fills the annotation
+            let form_matrix = form_matrix(appearance);
+            let Some((width, height)) = appearance
+                .dict
+                .get(b"BBox")
+                .and_then(Object::as_array)
+                .ok()
+                .and_then(|bbox| transformed_size(bbox, &form_matrix))
+            else {
+                continue;
+            };
+            let fit = [
+                rect_size.0 / width,
+                0.0,
+                0.0,
+                rect_size.1 / height,
+                0.0,
+                0.0,
+            ];
+            walk_form_for_images(doc, appearance, &[], fit, 0, &mut placements);
+        }
+    }
+
+    placements
+}
+
+/// The appearance streams of a page's annotations, in every state, each with the width
+/// and height of its annotation's rectangle
+fn appearance_streams(doc: &Document, page_id: ObjectId) -> Vec<(&lopdf::Stream, (f64, f64))> {
+    let mut streams = Vec::new();
+    let Ok((_, Object::Array(annots))) = doc
+        .get_dictionary(page_id)
+        .and_then(|page| page.get(b"Annots"))
+        .and_then(|annots| doc.dereference(annots))
+    else {
+        return streams;
+    };
+    for annot in annots {
+        let Ok((_, Object::Dictionary(annot))) = doc.dereference(annot) else {
+            continue;
+        };
+        let rect: Vec<f64> = annot
+            .get(b"Rect")
+            .and_then(Object::as_array)
+            .map(|rect| {
+                rect.iter()
+                    .filter_map(|n| n.as_float().ok().map(f64::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let [x1, y1, x2, y2] = rect[..] else {
+            continue;
+        };
+        let rect_size = ((x2 - x1).abs(), (y2 - y1).abs());
+        let Ok((_, Object::Dictionary(appearances))) = annot
+            .get(b"AP")
+            .and_then(|appearances| doc.dereference(appearances))
+        else {
+            continue;
+        };
+        // Normal, rollover and down appearances, each a stream or a dictionary of states
+        for (_, appearance) in appearances.iter() {
+            match doc.dereference(appearance) {
+                Ok((_, Object::Stream(stream))) => streams.push((stream, rect_size)),
+                Ok((_, Object::Dictionary(states))) => {
+                    for (_, state) in states.iter() {
+                        if let Ok((_, Object::Stream(stream))) = doc.dereference(state) {
+                            streams.push((stream, rect_size));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    streams
+}
+
+/// The `/Matrix` of a form XObject or pattern
+fn form_matrix(stream: &lopdf::Stream) -> Matrix {
+    stream
+        .dict
+        .get(b"Matrix")
+        .and_then(Object::as_array)
+        .ok()
+        .and_then(|arr| matrix_from_operands(arr))
+        .unwrap_or(IDENTITY_MATRIX)
+}
+
+/// Width and height of the box around a `[x1 y1 x2 y2]` rectangle once transformed
+fn transformed_size(rect: &[Object], matrix: &Matrix) -> Option<(f64, f64)> {
+    let [x1, y1, x2, y2] = [0, 1, 2, 3].map(|i| {
+        rect.get(i)
+            .and_then(|n| n.as_float().ok())
+            .map_or(0.0, f64::from)
+    });
+    let corners = [(x1, y1), (x1, y2), (x2, y1), (x2, y2)]
+        .map(|(x, y)| (matrix[0] * x + matrix[2] * y, matrix[1] * x + matrix[3] * y));
+    let span = |coordinate: fn(&(f64, f64)) -> f64| {
+        let values = corners.iter().map(coordinate);
+        values.clone().fold(f64::MIN, f64::max) - values.fold(f64::MAX, f64::min)
+    };
+    let (width, height) = (span(|c| c.0), span(|c| c.1));
+    (width > 0.0 && height > 0.0).then_some((width, height))
+}
+
+fn walk_content_for_images(
+    doc: &Document,
+    content: &[u8],
+    resources: &[&lopdf::Dictionary],
+    base_ctm: Matrix,
+    depth: u32,
+    placements: &mut HashMap<ObjectId, (f64, f64)>,
+) {
+    let Ok(content) = lopdf::content::Content::decode(content) else {
+        return;
+    };
+
+    // A tiling pattern's cells are drawn in the space the content stream starts in, whatever
+    // the CTM when it's used, so each pattern the content can paint with is walked from there
+    if depth < MAX_FORM_DEPTH {
+        for pattern in tiling_patterns(doc, resources) {
+            walk_form_for_images(doc, pattern, &[], base_ctm, depth + 1, placements);
+        }
+    }
+
+    let mut ctm = base_ctm;
+    let mut stack: Vec<Matrix> = Vec::new();
+
+    for operation in &content.operations {
+        match operation.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => {
+                if let Some(saved) = stack.pop() {
+                    ctm = saved;
+                }
+            }
+            "cm" => {
+                if let Some(m) = matrix_from_operands(&operation.operands) {
+                    ctm = multiply_matrix(&m, &ctm);
+                }
+            }
+            "Do" => {
+                let Some(name) = operation.operands.first().and_then(|o| o.as_name().ok()) else {
+                    continue;
+                };
+                let Some(xobject_id) = lookup_xobject(doc, resources, name) else {
+                    continue;
+                };
+                let Ok(xobject) = doc.get_object(xobject_id).and_then(Object::as_stream) else {
+                    continue;
+                };
+
+                match xobject.dict.get(b"Subtype").and_then(Object::as_name) {
+                    Ok(b"Image") => {
+                        // An image fills the unit square, so the CTM's column lengths are its size
+                        let placed_width = ctm[0].hypot(ctm[1]);
+                        let placed_height = ctm[2].hypot(ctm[3]);
+                        let entry = placements.entry(xobject_id).or_insert((0.0, 0.0));
+                        entry.0 = entry.0.max(placed_width);
+                        entry.1 = entry.1.max(placed_height);
+                    }
+                    Ok(b"Form") if depth < MAX_FORM_DEPTH => {
+                        walk_form_for_images(doc, xobject, resources, ctm, depth + 1, placements);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Walk the content of a form XObject, appearance stream or tiling pattern drawn with
+/// `ctm`. Its `/Matrix` applies first, and without `/Resources` it uses `resources`.
+fn walk_form_for_images(
+    doc: &Document,
+    form: &lopdf::Stream,
+    resources: &[&lopdf::Dictionary],
+    ctm: Matrix,
+    depth: u32,
+    placements: &mut HashMap<ObjectId, (f64, f64)>,
+) {
+    let form_resources: Vec<&lopdf::Dictionary> = match form.dict.get(b"Resources") {
+        Ok(obj) => match doc.dereference(obj) {
+            Ok((_, Object::Dictionary(dict))) => vec![dict],
+            _ => resources.to_vec(),
+        },
+        Err(_) => resources.to_vec(),
+    };
+
+    let Ok(content) = form.get_plain_content() else {
+        return;
+    };
+    walk_content_for_images(
+        doc,
+        &content,
+        &form_resources,
+        multiply_matrix(&form_matrix(form), &ctm),
+        depth,
+        placements,
+    );
+}
+
+/// The tiling patterns (`/PatternType 1`) in the given resource dictionaries
+fn tiling_patterns<'a>(
+    doc: &'a Document,
+    resources: &[&'a lopdf::Dictionary],
+) -> Vec<&'a lopdf::Stream> {
+    resources
+        .iter()
+        .filter_map(|res| match doc.dereference(res.get(b"Pattern").ok()?) {
+            Ok((_, Object::Dictionary(patterns))) => Some(patterns),
+            _ => None,
+        })
+        .flat_map(|patterns| patterns.iter())
+        .filter_map(|(_, pattern)| match doc.dereference(pattern) {
+            Ok((_, Object::Stream(pattern)))
+                if pattern
+                    .dict
+                    .get(b"PatternType")
+                    .and_then(Object::as_i64)
+                    .ok()
+                    == Some(1) =>
+            {
+                Some(pattern)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Resolve an XObject name to its object id via the given resource dictionaries (in order)
+fn lookup_xobject(
+    doc: &Document,
+    resources: &[&lopdf::Dictionary],
+    name: &[u8],
+) -> Option<ObjectId> {
+    resources.iter().find_map(|res| {
+        let xobjects = match doc.dereference(res.get(b"XObject").ok()?) {
+            Ok((_, Object::Dictionary(dict))) => dict,
+            _ => return None,
+        };
+        xobjects.get(name).and_then(Object::as_reference).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::Stream;
+
+    /// A one-page PDF with some text and nothing to recompress
+    fn pdf() -> Vec<u8> {
+        let (mut doc, _) = testing::document(1, |doc, _| {
+            dictionary! { "Contents" => testing::content(doc, b"BT /F1 12 Tf 10 10 Td (Hello) Tj ET") }
+        });
+        testing::save(&mut doc)
+    }
+
+    fn predictor(predictor: i64, colors: i64, bits: i64, columns: i64) -> lopdf::Dictionary {
+        dictionary! {
+            "Predictor" => predictor,
+            "Colors" => colors,
+            "BitsPerComponent" => bits,
+            "Columns" => columns,
+        }
+    }
+
+    fn raw(data: Option<StreamData>) -> Option<Vec<u8>> {
+        match data? {
+            StreamData::Raw(data) => Some(data.into_owned()),
+            StreamData::Jpeg(_) => None,
+        }
+    }
+
+    #[test]
+    fn undoes_png_row_filters() {
+        // Two 3-byte rows: None, then Sub, Up, Average and Paeth over the same samples
+        let params = PredictorParams::from_params(&predictor(15, 1, 8, 3)).unwrap();
+        let first = [0, 10, 20, 30];
+        for (filter, row) in [
+            (1, [40, 5, 5]),
+            (2, [30, 25, 20]),
+            (3, [35, 15, 13]),
+            (4, [30, 5, 5]),
+        ] {
+            let data: Vec<u8> = first.iter().chain(&[filter]).chain(&row).copied().collect();
+            assert_eq!(
+                undo_png_predictor(&data, &params).unwrap(),
+                [10, 20, 30, 40, 45, 50],
+                "filter {}",
+                filter
+            );
+        }
+        assert_eq!(undo_png_predictor(&[5, 1, 2, 3], &params), None);
+    }
+
+    #[test]
+    fn png_predictors_round_trip() {
+        let (columns, colors) = (17, 3);
+        let pixels: Vec<u8> = (0..columns * colors * 9)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let predicted = apply_png_predictor(&pixels, columns * colors, colors);
+        let params = PredictorParams::from_params(&predictor(15, 3, 8, 17)).unwrap();
+        assert_eq!(undo_png_predictor(&predicted, &params).unwrap(), pixels);
+    }
+
+    #[test]
+    fn undoes_tiff_predictor() {
+        let undo = |params: lopdf::Dictionary, data: &[u8]| {
+            undo_predictor(data.to_vec(), Some(&params)).unwrap()
+        };
+        assert_eq!(
+            undo(predictor(2, 1, 8, 4), &[10, 1, 1, 1]),
+            [10, 11, 12, 13]
+        );
+        // Components are differenced separately
+        assert_eq!(
+            undo(predictor(2, 2, 8, 2), &[10, 100, 1, 255]),
+            [10, 100, 11, 99]
+        );
+        assert_eq!(
+            undo(predictor(2, 1, 16, 2), &[0x01, 0x00, 0x00, 0x05]),
+            [0x01, 0x00, 0x01, 0x05]
+        );
+        assert_eq!(undo(predictor(2, 1, 4, 4), &[0x11, 0x1f]), [0x12, 0x32]);
+        assert_eq!(undo(predictor(2, 1, 1, 8), &[0b1100_0000]), [0b1000_0000]);
+    }
+
+    #[test]
+    fn decodes_predicted_flate_images() {
+        let pixels: Vec<u8> = (0..60).map(|i| (i * 3) as u8).collect();
+        let predicted = apply_png_predictor(&pixels, 12, 3);
+        let stream = Stream::new(
+            dictionary! {
+                "Filter" => "FlateDecode",
+                "DecodeParms" => predictor(12, 3, 8, 4),
+            },
+            deflate_best(&predicted).unwrap(),
+        );
+        assert_eq!(raw(decode_stream_data(&stream)).unwrap(), pixels);
+    }
+
+    #[test]
+    fn decodes_ascii_hex() {
+        assert_eq!(
+            decode_ascii_hex(b"48 65\n6C6c 6f>ignored").unwrap(),
+            b"Hello"
+        );
+        // An odd final digit is followed by an implied 0
+        assert_eq!(decode_ascii_hex(b"7>").unwrap(), [0x70]);
+        assert_eq!(decode_ascii_hex(b"4G"), None);
+    }
+
+    #[test]
+    fn decodes_run_length() {
+        // A literal run of 3 bytes, 4 repeats of 'z', then end of data
+        let data = [2, b'a', b'b', b'c', 253, b'z', 128, b'x'];
+        assert_eq!(decode_run_length(&data).unwrap(), b"abczzzz");
+        assert_eq!(decode_run_length(&[]).unwrap(), b"");
+        // Runs cut short by the end of the data
+        assert_eq!(decode_run_length(&[3, b'a']), None);
+        assert_eq!(decode_run_length(&[200]), None);
+    }
+
+    #[test]
+    fn decodes_filter_chains_in_order() {
+        let pixels: Vec<u8> = (0..48).collect();
+        let hex: String = deflate_best(&pixels)
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let stream = Stream::new(
+            dictionary! {
+                "Filter" => vec!["ASCIIHexDecode".into(), "FlateDecode".into()],
+                "DecodeParms" => vec![Object::Null, Object::Null],
+            },
+            format!("{}>", hex).into_bytes(),
+        );
+        assert_eq!(raw(decode_stream_data(&stream)).unwrap(), pixels);
+
+        // Each filter takes its own DecodeParms from the parallel array
+        let deflated = deflate_best(&apply_png_predictor(&pixels, 16, 1)).unwrap();
+        let run_length: Vec<u8> = deflated
+            .chunks(128)
+            .flat_map(|chunk| [&[chunk.len() as u8 - 1], chunk].concat())
+            .chain([128])
+            .collect();
+        let stream = Stream::new(
+            dictionary! {
+                "Filter" => vec!["RunLengthDecode".into(), "FlateDecode".into()],
+                "DecodeParms" => vec![Object::Null, predictor(15, 1, 8, 16).into()],
+            },
+            run_length,
+        );
+        assert_eq!(raw(decode_stream_data(&stream)).unwrap(), pixels);
+    }
+
+    #[test]
+    fn leaves_jpeg_data_from_a_chain_ending_in_dct() {
+        let stream = Stream::new(
+            dictionary! { "Filter" => vec!["ASCIIHexDecode".into(), "DCTDecode".into()] },
+            b"FFD8FFD9>".to_vec(),
+        );
+        match decode_stream_data(&stream) {
+            Some(StreamData::Jpeg(data)) => assert_eq!(data.as_ref(), [0xff, 0xd8, 0xff, 0xd9]),
+            _ => panic!("expected JPEG data"),
+        }
+        let stream = Stream::new(
+            dictionary! { "Filter" => vec!["DCTDecode".into(), "FlateDecode".into()] },
+            Vec::new(),
+        );
+        assert!(decode_stream_data(&stream).is_none());
+    }
+
+    /// A raw 1-bit image stream with the given packed rows
+    fn bilevel_stream(width: u32, height: u32, pixels: Vec<u8>) -> lopdf::Stream {
+        Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "BitsPerComponent" => 1,
+                "ColorSpace" => "DeviceGray",
+            },
+            pixels,
+        )
+    }
+
+    /// Packed rows of a `width` x `height` image, black (0) where `black` says
+    fn bilevel_pixels(width: u32, height: u32, black: impl Fn(u32, u32) -> bool) -> Vec<u8> {
+        let row_bytes = width.div_ceil(8) as usize;
+        let mut pixels = vec![0; row_bytes * height as usize];
+        for y in 0..height {
+            for x in 0..width {
+                if !black(x, y) {
+                    pixels[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+        pixels
+    }
+
+    /// Whether a pixel falls in one of 60 scattered discs, like blots of ink on a scan
+    fn in_disc(x: u32, y: u32) -> bool {
+        (0..60u32).any(|i| {
+            let dx = x as i64 - (i.wrapping_mul(2_654_435_761) % 380) as i64;
+            let dy = y as i64 - (i.wrapping_mul(40_503) % 280) as i64;
+            let radius = 3 + (i % 7) as i64;
+            dx * dx + dy * dy <= radius * radius
+        })
+    }
+
+    fn decode_g4(stream: &lopdf::Stream, width: u32, height: u32) -> Vec<u8> {
+        let params = stream.dict.get(b"DecodeParms").unwrap().as_dict().unwrap();
+        let black_is_1 = params.get(b"BlackIs1").unwrap().as_bool().unwrap();
+        let row_bytes = width.div_ceil(8) as usize;
+        let mut pixels = Vec::new();
+        fax::decoder::decode_g4(
+            stream.content.iter().copied(),
+            width as u16,
+            Some(height as u16),
+            |transitions| {
+                let mut row = vec![0u8; row_bytes];
+                for (x, pel) in fax::decoder::pels(transitions, width as u16).enumerate() {
+                    if (pel == fax::Color::Black) == black_is_1 {
+                        row[x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+                pixels.extend(row);
+            },
+        )
+        .unwrap();
+        pixels
+    }
+
+    #[test]
+    fn codes_scanned_text_with_g4() {
+        // An odd width, so rows end in padding bits
+        let (width, height) = (403, 300);
+        let pixels = bilevel_pixels(width, height, in_disc);
+        let mut stream = bilevel_stream(width, height, pixels.clone());
+
+        let result = recompress_bilevel(&mut stream);
+        assert_eq!(result, ImageCompressionResult::Recompressed);
+        assert_eq!(ImageEncoder::of_stream(&stream), Some(ImageEncoder::Ccitt));
+        let params = stream.dict.get(b"DecodeParms").unwrap().as_dict().unwrap();
+        assert_eq!(params.get(b"K").unwrap().as_i64().unwrap(), -1);
+        assert_eq!(params.get(b"Columns").unwrap().as_i64().unwrap(), 403);
+        assert_eq!(decode_g4(&stream, width, height), pixels);
+
+        // Whatever is in the padding bits doesn't stop G4 from being used
+        let mut padded = pixels;
+        for row in padded.chunks_exact_mut(51) {
+            row[50] |= 0x1f;
+        }
+        assert!(encode_g4(&padded, width, height).is_some());
+    }
+
+    #[test]
+    fn codes_mostly_black_images_with_black_is_1() {
+        let (width, height) = (400, 300);
+        let pixels = bilevel_pixels(width, height, |x, y| !in_disc(x, y));
+        let mut stream = bilevel_stream(width, height, pixels.clone());
+
+        assert_eq!(
+            recompress_bilevel(&mut stream),
+            ImageCompressionResult::Recompressed
+        );
+        let params = stream.dict.get(b"DecodeParms").unwrap().as_dict().unwrap();
+        assert!(params.get(b"BlackIs1").unwrap().as_bool().unwrap());
+        assert_eq!(decode_g4(&stream, width, height), pixels);
+    }
+
+    #[test]
+    fn keeps_flate_for_dithered_images() {
+        // A checkerboard changes colour at every pixel, the worst case for G4's run coding
+        let (width, height) = (256, 256);
+        let pixels = bilevel_pixels(width, height, |x, y| (x + y) % 2 == 0);
+        let g4_size = encode_g4(&pixels, width, height).unwrap().0.len();
+        let mut stream = bilevel_stream(width, height, pixels);
+
+        assert_eq!(
+            recompress_bilevel(&mut stream),
+            ImageCompressionResult::Recompressed
+        );
+        assert_eq!(ImageEncoder::of_stream(&stream), Some(ImageEncoder::Flate));
+        assert!(stream.content.len() < g4_size);
+    }
+
+    /// A page drawing `content` with `resources`, and the given annotations. Both are made
+    /// by closures given the id of an image for them to use.
+    fn page_document(
+        content: &[u8],
+        resources: impl FnOnce(&mut Document, ObjectId) -> lopdf::Dictionary,
+        annots: impl FnOnce(&mut Document, ObjectId) -> Vec<Object>,
+    ) -> (Document, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let image_id = doc.add_object(bilevel_stream(8, 8, vec![0; 8]));
+        let resources = resources(&mut doc, image_id);
+        let annots = annots(&mut doc, image_id);
+        let page = dictionary! {
+            "MediaBox" => vec![0.into(), 0.into(), 600.into(), 800.into()],
+            "Contents" => testing::content(&mut doc, content),
+            "Resources" => resources,
+            "Annots" => annots,
+        };
+        testing::add_page_tree(&mut doc, 1, |_, _| page.clone());
+        (doc, image_id)
+    }
+
+    fn image_resources(image_id: ObjectId) -> lopdf::Dictionary {
+        dictionary! { "XObject" => dictionary! { "Im" => image_id } }
+    }
+
+    #[test]
+    fn places_images_drawn_by_pages_and_forms() {
+        let (mut doc, image_id) = page_document(
+            b"q 50 0 0 20 0 0 cm /Im Do Q q 2 0 0 2 0 0 cm /Fm Do Q",
+            |_, _| dictionary! {},
+            |_, _| Vec::new(),
+        );
+        let form_id = doc.add_object(Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Form",
+                "BBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+                "Matrix" => vec![1.5.into(), 0.into(), 0.into(), 1.5.into(), 0.into(), 0.into()],
+            },
+            b"10 0 0 10 0 0 cm /Im Do".to_vec(),
+        ));
+        let page_id = doc.page_iter().next().unwrap();
+        let mut resources = image_resources(image_id);
+        resources.set("XObject", dictionary! { "Im" => image_id, "Fm" => form_id });
+        doc.get_dictionary_mut(page_id)
+            .unwrap()
+            .set("Resources", resources);
+
+        // The form's own matrix and the page's scale both apply; the form has no resources
+        // of its own, so it finds the image through the page's
+        let placements = collect_image_placements(&doc);
+        assert_eq!(placements[&image_id], (50.0, 30.0));
+    }
+
+    #[test]
+    fn places_images_drawn_by_annotation_appearances() {
+        let (doc, image_id) = page_document(
+            b"q 10 0 0 10 0 0 cm /Im Do Q",
+            |_, image_id| image_resources(image_id),
+            |doc, image_id| {
+                // A 20 x 10 appearance stretched over a 300 x 100 rectangle
+                let appearance = doc.add_object(Stream::new(
+                    dictionary! {
+                        "Type" => "XObject",
+                        "Subtype" => "Form",
+                        "BBox" => vec![0.into(), 0.into(), 20.into(), 10.into()],
+                        "Resources" => image_resources(image_id),
+                    },
+                    b"20 0 0 10 0 0 cm /Im Do".to_vec(),
+                ));
+                let annot = doc.add_object(dictionary! {
+                    "Type" => "Annot",
+                    "Subtype" => "Stamp",
+                    "Rect" => vec![100.into(), 100.into(), 400.into(), 200.into()],
+                    "AP" => dictionary! { "N" => appearance },
+                });
+                vec![annot.into()]
+            },
+        );
+
+        let placements = collect_image_placements(&doc);
+        assert_eq!(placements[&image_id], (300.0, 100.0));
+    }
+
+    #[test]
+    fn places_images_drawn_by_tiling_patterns() {
+        let (doc, image_id) = page_document(
+            b"/Pattern cs /P1 scn 0 0 600 800 re f",
+            |doc, image_id| {
+                let pattern = doc.add_object(Stream::new(
+                    dictionary! {
+                        "Type" => "Pattern",
+                        "PatternType" => 1,
+                        "PaintType" => 1,
+                        "TilingType" => 1,
+                        "BBox" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+                        "XStep" => 10,
+                        "YStep" => 10,
+                        "Matrix" => vec![4.into(), 0.into(), 0.into(), 4.into(), 0.into(), 0.into()],
+                        "Resources" => image_resources(image_id),
+                    },
+                    b"10 0 0 10 0 0 cm /Im Do".to_vec(),
+                ));
+                dictionary! { "Pattern" => dictionary! { "P1" => pattern } }
+            },
+            |_, _| Vec::new(),
+        );
+
+        let placements = collect_image_placements(&doc);
+        assert_eq!(placements[&image_id], (40.0, 40.0));
+    }
+
+    #[test]
+    fn cancelled_run_returns_the_original_with_its_settings() {
+        let content = pdf();
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let options = CompressionOptions {
+            quality: Some(60),
+            grayscale: true,
+            ..Default::default()
+        };
+        let result = compress_pdf_blocking(&content, options, &cancel, &|_| {}).unwrap();
+        assert!(result.report.cancelled);
+        assert_eq!(result.content, content);
+        assert_eq!(result.report.quality, 60);
+        assert!(result.report.grayscale);
+    }
+
+    #[test]
+    fn cancelling_one_run_leaves_others_running() {
+        let content = pdf();
+        let first = CancelToken::default();
+        let second = CancelToken::default();
+        let cancelled = compress_pdf_blocking(&content, Default::default(), &first, &|update| {
+            if matches!(update.phase, CompressionPhase::Images) {
+                first.cancel();
+            }
+        })
+        .unwrap();
+        assert!(cancelled.report.cancelled);
+
+        let result = compress_pdf_blocking(&content, Default::default(), &second, &|_| {}).unwrap();
+        assert!(!result.report.cancelled);
+        assert!(result.report.verification.is_some());
+    }
+
+    /// An 8-bit image stream, deflated
+    fn image_stream(width: u32, height: u32, color_space: &str, pixels: Vec<u8>) -> lopdf::Stream {
+        let mut stream = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => width as i64,
+                "Height" => height as i64,
+                "BitsPerComponent" => 8,
+                "ColorSpace" => color_space,
+            },
+            pixels,
+        );
+        stream.compress().unwrap();
+        stream
+    }
+
+    /// RGB gradients with a little noise, like a photo
+    fn photo_pixels(width: u32, height: u32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| {
+                (0..width).flat_map(move |x| {
+                    let noise = (x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(40_503)) >> 28;
+                    [
+                        x * 200 / width + noise,
+                        y * 200 / height + noise,
+                        (x + y) * 100 / (width + height) + noise,
+                    ]
+                    .map(|v| v as u8)
+                })
+            })
+            .collect()
+    }
+
+    /// A one-page document drawing `image` as a square `placed` points wide
+    fn image_page(image: lopdf::Stream, placed: f64) -> (Document, ObjectId) {
+        let mut doc = Document::with_version("1.5");
+        let image_id = doc.add_object(image);
+        let content = format!("q {0} 0 0 {0} 0 0 cm /Im Do Q", placed);
+        testing::add_page_tree(&mut doc, 1, |doc, _| {
+            dictionary! {
+                "Contents" => testing::content(doc, content.as_bytes()),
+                "Resources" => image_resources(image_id),
+            }
+        });
+        (doc, image_id)
+    }
+
+    /// Give an image an 8-bit soft mask, premultiplied with `matte` if given
+    fn add_soft_mask(doc: &mut Document, image_id: ObjectId, matte: Option<&[f32]>) -> ObjectId {
+        let image = doc.get_object(image_id).unwrap().as_stream().unwrap();
+        let width = image.dict.get(b"Width").unwrap().as_i64().unwrap() as u32;
+        let height = image.dict.get(b"Height").unwrap().as_i64().unwrap() as u32;
+        let alpha = (0..width * height).map(|i| (i % width * 255 / width) as u8);
+        let mut mask = image_stream(width, height, "DeviceGray", alpha.collect());
+        if let Some(matte) = matte {
+            let matte: Vec<Object> = matte.iter().map(|&v| Object::Real(v)).collect();
+            mask.dict.set("Matte", matte);
+        }
+        let mask_id = doc.add_object(mask);
+        let image = doc
+            .get_object_mut(image_id)
+            .unwrap()
+            .as_stream_mut()
+            .unwrap();
+        image.dict.set("SMask", mask_id);
+        mask_id
+    }
+
+    /// Run the compression passes over `doc` in place
+    fn compress(doc: &mut Document, options: CompressionOptions) -> CompressionReport {
+        let mut report = CompressionReport {
+            quality: options.quality.unwrap_or(75),
+            ..Default::default()
+        };
+        let flow = compress_document(
+            doc,
+            &options,
+            &mut report,
+            &CancelToken::default(),
+            &|_, _, _| {},
+        );
+        assert!(flow.is_continue());
+        report
+    }
+
+    fn dict_value(doc: &Document, id: ObjectId, key: &[u8]) -> Object {
+        let stream = doc.get_object(id).unwrap().as_stream().unwrap();
+        stream.dict.get(key).unwrap().clone()
+    }
+
+    #[test]
+    fn keeps_premultiplied_images_at_their_mask_size() {
+        for matte in [None, Some(&[1.0, 1.0, 1.0][..])] {
+            // 300 pixels drawn an inch wide, downsampled to 72 DPI unless premultiplied
+            let (mut doc, image_id) = image_page(
+                image_stream(300, 300, "DeviceRGB", photo_pixels(300, 300)),
+                72.0,
+            );
+            let mask_id = add_soft_mask(&mut doc, image_id, matte);
+            let options = CompressionOptions {
+                target_dpi: Some(72),
+                ..Default::default()
+            };
+            let report = compress(&mut doc, options);
+
+            let expected = if matte.is_some() { 300 } else { 72 };
+            assert_eq!(
+                dict_value(&doc, image_id, b"Width").as_i64().unwrap(),
+                expected
+            );
+            assert_eq!(dict_value(&doc, mask_id, b"Width").as_i64().unwrap(), 300);
+            assert_eq!(report.downsampled, u32::from(matte.is_none()));
+        }
+    }
+
+    #[test]
+    fn converts_the_matte_colour_to_gray_with_the_image() {
+        let (mut doc, image_id) = image_page(
+            image_stream(64, 64, "DeviceRGB", photo_pixels(64, 64)),
+            64.0,
+        );
+        let mask_id = add_soft_mask(&mut doc, image_id, Some(&[1.0, 0.0, 0.0]));
+        let options = CompressionOptions {
+            grayscale: true,
+            ..Default::default()
+        };
+        compress(&mut doc, options);
+
+        assert_eq!(
+            dict_value(&doc, image_id, b"ColorSpace"),
+            Object::Name(b"DeviceGray".to_vec())
+        );
+        let matte = dict_value(&doc, mask_id, b"Matte");
+        let matte = matte.as_array().unwrap();
+        let red = pixels_to_gray(&[255, 0, 0], 3).unwrap()[0] as f32 / 255.0;
+        assert_eq!(matte.len(), 1);
+        assert!((matte[0].as_float().unwrap() - red).abs() < 0.01);
+    }
+
+    /// A file holding `photo_pdf`, in a directory of its own for `test`
+    fn photo_file(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("leed-compression-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("input.pdf");
+        std::fs::write(&path, photo_pdf()).unwrap();
+        path
+    }
+
+    fn compress_file(input_path: &Path, output_path: &Path) -> CompressionReport {
+        let options = CompressionOptions {
+            target_dpi: Some(72),
+            ..Default::default()
+        };
+        compress_pdf_file_blocking(
+            input_path,
+            output_path,
+            options,
+            &CancelToken::default(),
+            &|_| {},
+        )
+        .unwrap()
+    }
+
+    fn dir_entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn compresses_file_to_file() {
+        let input_path = photo_file("file-to-file");
+        let dir = input_path.parent().unwrap();
+        let output_path = dir.join("output.pdf");
+        // Left by another run, and not to be touched
+        std::fs::write(dir.join("output.pdf.partial"), b"other").unwrap();
+        let original = std::fs::read(&input_path).unwrap();
+
+        let report = compress_file(&input_path, &output_path);
+
+        let output = std::fs::read(&output_path).unwrap();
+        assert!(output.len() < original.len());
+        assert_eq!(report.original_size, original.len());
+        assert_eq!(report.compressed_size, output.len());
+        assert_eq!(report.downsampled, 1);
+        assert_eq!(Document::load_mem(&output).unwrap().get_pages().len(), 1);
+        assert_eq!(std::fs::read(&input_path).unwrap(), original);
+        assert_eq!(
+            std::fs::read(dir.join("output.pdf.partial")).unwrap(),
+            b"other"
+        );
+        assert_eq!(
+            dir_entries(dir),
+            ["input.pdf", "output.pdf", "output.pdf.partial"]
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn compresses_a_file_in_place() {
+        let path = photo_file("in-place");
+        let original = std::fs::read(&path).unwrap();
+
+        let report = compress_file(&path, &path);
+
+        let output = std::fs::read(&path).unwrap();
+        assert!(output.len() < original.len());
+        assert_eq!(report.original_size, original.len());
+        assert_eq!(report.compressed_size, output.len());
+        assert_eq!(Document::load_mem(&output).unwrap().get_pages().len(), 1);
+        let dir = path.parent().unwrap();
+        assert_eq!(dir_entries(dir), ["input.pdf"]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// A saved page with a 300-pixel photo drawn an inch wide
+    fn photo_pdf() -> Vec<u8> {
+        let (mut doc, _) = image_page(
+            image_stream(300, 300, "DeviceRGB", photo_pixels(300, 300)),
+            72.0,
+        );
+        testing::save(&mut doc)
+    }
+
+    fn compressed_size(content: &[u8], options: CompressionOptions) -> usize {
+        compress_pdf_blocking(content, options, &CancelToken::default(), &|_| {})
+            .unwrap()
+            .content
+            .len()
+    }
+
+    #[test]
+    fn size_search_ends_within_a_reachable_target() {
+        let content = photo_pdf();
+        let lossless = compressed_size(
+            &content,
+            CompressionOptions {
+                lossless: true,
+                ..Default::default()
+            },
+        );
+        let smallest = compressed_size(
+            &content,
+            CompressionOptions {
+                quality: Some(TARGET_SIZE_MIN_QUALITY),
+                target_dpi: Some(72),
+                ..Default::default()
+            },
+        );
+        assert!(smallest < lossless);
+        let max_bytes = (smallest + lossless) / 2;
+
+        let result =
+            compress_pdf_to_size_blocking(&content, max_bytes, &CancelToken::default(), &|_| {})
+                .unwrap();
+
+        assert!(result.content.len() <= max_bytes);
+        assert_eq!(result.max_bytes, max_bytes);
+        assert!(!result.settings.lossless);
+        assert!(!result.report.cancelled);
+        assert!(result.attempts > 1);
+        assert_eq!(result.report.compressed_size, result.content.len());
+        assert_eq!(
+            Document::load_mem(&result.content)
+                .unwrap()
+                .get_pages()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn size_search_reports_an_unreachable_target() {
+        let content = photo_pdf();
+        let smallest = compressed_size(
+            &content,
+            CompressionOptions {
+                quality: Some(TARGET_SIZE_MIN_QUALITY),
+                target_dpi: Some(72),
+                ..Default::default()
+            },
+        );
+
+        let error =
+            compress_pdf_to_size_blocking(&content, smallest - 1, &CancelToken::default(), &|_| {})
+                .unwrap_err();
+
+        assert_eq!(
+            error,
+            format!(
+                "Cannot compress below {} bytes: the smallest result was {} bytes (quality 10, 72 DPI)",
+                smallest - 1,
+                smallest
+            )
+        );
+        assert!(
+            compress_pdf_to_size_blocking(&content, 0, &CancelToken::default(), &|_| {}).is_err()
+        );
+    }
+
+    /// A page drawing a CMYK image without black ink, in an ICCBased colorspace holding
+    /// `profile` if given, and with the black-ink output intent if `output_intent`
+    fn cmyk_page(profile: Option<&[u8]>, output_intent: bool) -> (Document, ObjectId) {
+        let pixels = photo_pixels(128, 128)
+            .chunks(3)
+            .flat_map(|cmy| [cmy[0], cmy[1], cmy[2], 0])
+            .collect();
+        let (mut doc, image_id) = image_page(image_stream(128, 128, "DeviceCMYK", pixels), 128.0);
+        if let Some(profile) = profile {
+            let profile_id =
+                doc.add_object(Stream::new(dictionary! { "N" => 4 }, profile.to_vec()));
+            let color_space: Vec<Object> = vec!["ICCBased".into(), profile_id.into()];
+            let image = doc
+                .get_object_mut(image_id)
+                .unwrap()
+                .as_stream_mut()
+                .unwrap();
+            image.dict.set("ColorSpace", color_space);
+        }
+        if output_intent {
+            let profile = Stream::new(dictionary! { "N" => 4 }, testing::black_ink_profile());
+            let intent = dictionary! {
+                "S" => "GTS_PDFX",
+                "DestOutputProfile" => doc.add_object(profile),
+            };
+            doc.catalog_mut()
+                .unwrap()
+                .set("OutputIntents", vec![intent.into()]);
+        }
+        (doc, image_id)
+    }
+
+    #[test]
+    fn converts_cmyk_images_through_their_profile() {
+        let icc = testing::black_ink_profile();
+        let cases: [(Option<&[u8]>, bool, CmykHandling); 4] = [
+            (Some(&icc), false, CmykHandling::EmbeddedProfile),
+            (None, true, CmykHandling::OutputIntentProfile),
+            // Unreadable profiles fall back to the output intent, then to keeping CMYK
+            (
+                Some(b"not a profile"),
+                true,
+                CmykHandling::OutputIntentProfile,
+            ),
+            (Some(b"not a profile"), false, CmykHandling::KeptCmyk),
+        ];
+        for (profile, output_intent, handling) in cases {
+            let (mut doc, image_id) = cmyk_page(profile, output_intent);
+            let original_color_space = dict_value(&doc, image_id, b"ColorSpace");
+            let report = compress(&mut doc, CompressionOptions::default());
+
+            assert_eq!(report.images[0].cmyk_handling, Some(handling));
+            let image = doc.get_object(image_id).unwrap().as_stream().unwrap();
+            if handling == CmykHandling::KeptCmyk {
+                assert_eq!(
+                    image.dict.get(b"ColorSpace").unwrap(),
+                    &original_color_space
+                );
+            } else {
+                assert_eq!(
+                    image.dict.get(b"ColorSpace").unwrap(),
+                    &Object::Name(b"DeviceRGB".to_vec())
+                );
+                // The profile prints only black ink, so the image is paper white
+                let pixels = image.decompressed_content().unwrap();
+                assert!(pixels.iter().all(|&v| v >= 250), "{:?}", &pixels[..3]);
+            }
+        }
+    }
+
+    /// Resolve an image's colorspace against `doc` and decode its samples
+    fn decode(doc: &Document, image: &lopdf::Stream) -> DecodedImage {
+        let color_space = image.dict.get(b"ColorSpace").unwrap();
+        let color_space = ColorResolver::new(doc).resolve(color_space).unwrap();
+        decode_image_stream(image, &color_space).unwrap()
+    }
+
+    /// An uncompressed image of palette indices packed `bits` to a sample
+    fn indexed_image(indices: &[Vec<u8>], bits: i64, color_space: Object) -> lopdf::Stream {
+        let per_byte = 8 / bits as usize;
+        let data = indices
+            .iter()
+            .flat_map(|row| {
+                row.chunks(per_byte).map(|chunk| {
+                    chunk.iter().enumerate().fold(0, |byte, (i, &index)| {
+                        byte | index << (8 - bits as usize * (i + 1))
+                    })
+                })
+            })
+            .collect();
+        Stream::new(
+            dictionary! {
+                "Subtype" => "Image",
+                "Width" => indices[0].len() as i64,
+                "Height" => indices.len() as i64,
+                "BitsPerComponent" => bits,
+                "ColorSpace" => color_space,
+            },
+            data,
+        )
+    }
+
+    fn indexed(base: Object, hival: i64, lookup: Object) -> Object {
+        vec!["Indexed".into(), base, hival.into(), lookup].into()
+    }
+
+    #[test]
+    fn decodes_packed_palette_indices() {
+        let doc = Document::with_version("1.5");
+        for bits in [1, 2, 4] {
+            let entries = 1u8 << bits;
+            let palette: Vec<u8> = (0..entries).flat_map(|i| [i, i * 2, 255 - i]).collect();
+            // Five samples a row, so rows end part-way through a byte
+            let indices: Vec<Vec<u8>> = (0..3)
+                .map(|y| (0..5).map(|x| (x + y) % entries).collect())
+                .collect();
+            let color_space = indexed(
+                "DeviceRGB".into(),
+                entries as i64 - 1,
+                Object::string_literal(palette.clone()),
+            );
+
+            let decoded = decode(&doc, &indexed_image(&indices, bits, color_space));
+
+            let expected: Vec<u8> = indices
+                .concat()
+                .iter()
+                .flat_map(|&i| palette[i as usize * 3..][..3].to_vec())
+                .collect();
+            assert_eq!(decoded.pixels, expected, "{} bits", bits);
+            assert_eq!(decoded.channels, 3);
+            assert_eq!(decoded.new_color_space, Some("DeviceRGB".into()));
+        }
+    }
+
+    #[test]
+    fn decodes_palettes_stored_in_streams() {
+        let mut doc = Document::with_version("1.5");
+        let mut lookup = Stream::new(dictionary! {}, vec![10, 20, 30, 200]);
+        lookup.compress().unwrap();
+        let color_space = indexed("DeviceGray".into(), 3, doc.add_object(lookup).into());
+
+        let decoded = decode(&doc, &indexed_image(&[vec![3, 0, 2, 1]], 2, color_space));
+
+        assert_eq!(decoded.pixels, [200, 10, 30, 20]);
+        assert_eq!(decoded.channels, 1);
+    }
+
+    #[test]
+    fn decodes_cmyk_palettes_through_their_profile() {
+        let mut doc = Document::with_version("1.5");
+        let profile = Stream::new(dictionary! { "N" => 4 }, testing::black_ink_profile());
+        let base: Object = vec!["ICCBased".into(), doc.add_object(profile).into()].into();
+        let palette = Object::string_literal(vec![0, 0, 0, 0, 255, 255, 0, 0, 0, 0, 0, 255]);
+        let indices = [vec![2, 1, 0, 2]];
+
+        let image = indexed_image(&indices, 2, indexed(base, 2, palette.clone()));
+        let decoded = decode(&doc, &image);
+        assert_eq!(decoded.channels, 3);
+        assert_eq!(decoded.new_color_space, Some("DeviceRGB".into()));
+        let expected = [0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0];
+        for (pixel, expected) in decoded.pixels.iter().zip(expected) {
+            assert!(pixel.abs_diff(expected) <= 3, "{:?}", decoded.pixels);
+        }
+
+        // Without a profile the palette stays CMYK
+        let image = indexed_image(&indices, 2, indexed("DeviceCMYK".into(), 2, palette));
+        let decoded = decode(&doc, &image);
+        assert_eq!(decoded.channels, 4);
+        assert_eq!(decoded.new_color_space, Some("DeviceCMYK".into()));
+        assert_eq!(
+            decoded.pixels,
+            [0, 0, 0, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255]
+        );
+    }
+
+    /// A white RGB screen with a smooth gradient banner over its top fifth and black blocks
+    /// like lines of text below: many colours, but mostly flat with sharp edges
+    fn screenshot_pixels(width: u32, height: u32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| {
+                (0..width).flat_map(move |x| {
+                    if y < height / 5 {
+                        [x * 255 / width, y * 255 / height, 128].map(|v| v as u8)
+                    } else if y % 10 < 6 && x % 40 < 30 && x % 7 != 0 {
+                        [0, 0, 0]
+                    } else {
+                        [255, 255, 255]
+                    }
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_graphics_and_photos() {
+        let bars: Vec<u8> = (0..100 * 100)
+            .flat_map(|i| [(i % 100 / 25 * 60) as u8, 0, 200])
+            .collect();
+        assert_eq!(classify_image(&bars, 100, 3), ImageClass::Graphic);
+        let screenshot = screenshot_pixels(200, 200);
+        assert_eq!(classify_image(&screenshot, 200, 3), ImageClass::Graphic);
+        assert_eq!(
+            classify_image(&photo_pixels(200, 200), 200, 3),
+            ImageClass::Photo
+        );
+        let gray = pixels_to_gray(&photo_pixels(200, 200), 3).unwrap();
+        assert_eq!(classify_image(&gray, 200, 1), ImageClass::Photo);
+    }
+
+    #[test]
+    fn deflates_graphics_and_encodes_photos_as_jpeg() {
+        for (pixels, encoder) in [
+            (screenshot_pixels(200, 200), ImageEncoder::Flate),
+            (photo_pixels(200, 200), ImageEncoder::Jpeg),
+        ] {
+            let (mut doc, image_id) =
+                image_page(image_stream(200, 200, "DeviceRGB", pixels), 200.0);
+            let report = compress(&mut doc, CompressionOptions::default());
+
+            assert_eq!(report.images[0].encoder, Some(encoder));
+            let image = doc.get_object(image_id).unwrap().as_stream().unwrap();
+            assert_eq!(ImageEncoder::of_stream(image), Some(encoder));
+        }
+    }
+
+    /// The SOF marker of a JPEG and each component's sampling factors (horizontal << 4 |
+    /// vertical)
+    fn jpeg_frame(jpeg: &[u8]) -> (u8, Vec<u8>) {
+        let mut offset = 2;
+        loop {
+            let marker = jpeg[offset + 1];
+            let length = u16::from_be_bytes([jpeg[offset + 2], jpeg[offset + 3]]) as usize;
+            if matches!(marker, 0xc0 | 0xc2) {
+                let components = jpeg[offset + 9] as usize;
+                let sampling = (0..components).map(|i| jpeg[offset + 11 + i * 3]).collect();
+                return (marker, sampling);
+            }
+            offset += 2 + length;
+        }
+    }
+
+    #[test]
+    fn writes_progressive_and_subsampled_jpegs() {
+        let pixels = photo_pixels(64, 64);
+        for (subsampling, luma) in [
+            (ChromaSubsampling::Yuv444, 0x11),
+            (ChromaSubsampling::Yuv422, 0x21),
+            (ChromaSubsampling::Yuv420, 0x22),
+        ] {
+            for (progressive, marker) in [(false, 0xc0), (true, 0xc2)] {
+                let encoding = ImageEncoding {
+                    quality: 75,
+                    progressive,
+                    chroma_subsampling: subsampling,
+                    grayscale: false,
+                    lossless: false,
+                };
+                let jpeg = encode_jpeg(&pixels, 64, 64, 3, &encoding).unwrap();
+                assert_eq!(jpeg_frame(&jpeg), (marker, vec![luma, 0x11, 0x11]));
+            }
+        }
+
+        let (mut doc, image_id) = image_page(image_stream(64, 64, "DeviceRGB", pixels), 64.0);
+        let options = CompressionOptions {
+            jpeg_progressive: true,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+            ..Default::default()
+        };
+        compress(&mut doc, options);
+        let image = doc.get_object(image_id).unwrap().as_stream().unwrap();
+        assert_eq!(jpeg_frame(&image.content), (0xc2, vec![0x22, 0x11, 0x11]));
+    }
+
+    #[test]
+    fn output_does_not_depend_on_the_thread_count() {
+        let mut doc = Document::with_version("1.5");
+        let images: Vec<ObjectId> = (0..6u32)
+            .map(|i| {
+                let size = 100 + i * 20;
+                let pixels = if i % 2 == 0 {
+                    photo_pixels(size, size)
+                } else {
+                    screenshot_pixels(size, size)
+                };
+                doc.add_object(image_stream(size, size, "DeviceRGB", pixels))
+            })
+            .collect();
+        add_soft_mask(&mut doc, images[0], None);
+        testing::add_page_tree(&mut doc, images.len(), |doc, index| {
+            dictionary! {
+                "Contents" => testing::content(doc, b"q 50 0 0 50 0 0 cm /Im Do Q"),
+                "Resources" => image_resources(images[index]),
+            }
+        });
+        let content = testing::save(&mut doc);
+
+        let compress_with_threads = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let options = CompressionOptions {
+                target_dpi: Some(150),
+                ..Default::default()
+            };
+            pool.install(|| {
+                compress_pdf_blocking(&content, options, &CancelToken::default(), &|_| {})
+            })
+            .unwrap()
+        };
+        let single = compress_with_threads(1);
+        assert!(single.report.recompressed as usize >= images.len());
+        for threads in [2, 4] {
+            assert!(compress_with_threads(threads).content == single.content);
+        }
+    }
+
+    #[test]
+    fn reports_each_image_and_the_totals() {
+        let (mut doc, photo_id) = image_page(
+            image_stream(128, 128, "DeviceRGB", photo_pixels(128, 128)),
+            128.0,
+        );
+        let tiny_id = doc.add_object(image_stream(4, 4, "DeviceGray", vec![0; 16]));
+        let mut key_masked = image_stream(128, 128, "DeviceRGB", photo_pixels(128, 128));
+        let key: Vec<Object> = [0, 10, 0, 10, 0, 10].map(Object::from).to_vec();
+        key_masked.dict.set("Mask", key);
+        let key_masked_id = doc.add_object(key_masked);
+        let mut lab = image_stream(128, 128, "DeviceRGB", photo_pixels(128, 128));
+        let white_point: Vec<Object> = vec![0.95.into(), 1.into(), 1.09.into()];
+        let lab_space: Vec<Object> = vec![
+            "Lab".into(),
+            dictionary! { "WhitePoint" => white_point }.into(),
+        ];
+        lab.dict.set("ColorSpace", lab_space);
+        let lab_id = doc.add_object(lab);
+
+        let report = compress(&mut doc, CompressionOptions::default());
+
+        assert_eq!(report.total_images, 4);
+        assert_eq!(report.candidates, 1);
+        assert_eq!(report.recompressed, 1);
+        assert_eq!((report.skipped_threshold, report.failed), (0, 0));
+        let skip_counts: Vec<(&str, u32)> = report
+            .skip_counts
+            .iter()
+            .map(|(label, &count)| (label.as_str(), count))
+            .collect();
+        assert_eq!(
+            skip_counts,
+            [("<2KB", 1), ("colorspace", 1), ("transparency", 1)]
+        );
+        assert_eq!(report.colorspace_types.get("Lab"), Some(&1));
+
+        let entry = |id: ObjectId| {
+            report
+                .images
+                .iter()
+                .find(|image| (image.object_id, image.generation) == id)
+                .unwrap()
+        };
+        let photo = entry(photo_id);
+        assert_eq!(photo.result, Some(ImageCompressionResult::Recompressed));
+        assert!(photo.skip_reason.is_none());
+        assert_eq!((photo.width, photo.height), (Some(128), Some(128)));
+        assert_eq!(photo.color_space, "DeviceRGB");
+        assert_eq!(photo.effective_dpi, Some(72.0));
+        assert_eq!(photo.encoder, Some(ImageEncoder::Jpeg));
+        assert!(photo.compressed_bytes < photo.original_bytes);
+        assert_eq!(
+            photo.bytes_saved,
+            photo.original_bytes - photo.compressed_bytes
+        );
+        assert_eq!(report.image_bytes_saved, photo.bytes_saved);
+
+        for (id, reason) in [
+            (tiny_id, "<2KB"),
+            (key_masked_id, "transparency"),
+            (lab_id, "colorspace"),
+        ] {
+            let skipped = entry(id);
+            assert_eq!(
+                skipped.skip_reason.as_ref().map(SkipReason::label),
+                Some(reason)
+            );
+            assert!(skipped.result.is_none() && skipped.encoder.is_none());
+        }
+        assert_eq!(entry(lab_id).color_space, "Lab");
+    }
+
+    #[test]
+    fn recompresses_masked_images_and_keeps_their_masks_lossless() {
+        let (mut doc, image_id) = image_page(
+            image_stream(128, 128, "DeviceRGB", photo_pixels(128, 128)),
+            128.0,
+        );
+        let mask_id = add_soft_mask(&mut doc, image_id, None);
+        // Stored uncompressed, so it's large enough to be recompressed
+        let mask = doc
+            .get_object_mut(mask_id)
+            .unwrap()
+            .as_stream_mut()
+            .unwrap();
+        mask.decompress().unwrap();
+        let alpha = mask.content.clone();
+        let options = CompressionOptions {
+            quality: Some(10),
+            ..Default::default()
+        };
+
+        let report = compress(&mut doc, options);
+
+        let entry = |id: ObjectId| {
+            report
+                .images
+                .iter()
+                .find(|image| image.object_id == id.0)
+                .unwrap()
+        };
+        assert!(!entry(image_id).is_soft_mask);
+        assert_eq!(entry(image_id).encoder, Some(ImageEncoder::Jpeg));
+        assert!(entry(mask_id).is_soft_mask);
+        assert_eq!(
+            entry(mask_id).result,
+            Some(ImageCompressionResult::Recompressed)
+        );
+        assert_eq!(entry(mask_id).encoder, Some(ImageEncoder::Flate));
+        assert_eq!(
+            dict_value(&doc, image_id, b"SMask"),
+            Object::Reference(mask_id)
+        );
+        let mask = doc.get_object(mask_id).unwrap().as_stream().unwrap();
+        assert_eq!(mask.decompressed_content().unwrap(), alpha);
+    }
+}
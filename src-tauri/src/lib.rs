@@ -1,18 +1,58 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 use tauri::{Emitter, RunEvent};
 
+mod compression;
+use compression::{
+    compress_pdf_blocking, compress_pdf_file_blocking, compress_pdf_to_size_blocking, CancelToken,
+    CompressedPdf, CompressionOptions, CompressionProgress, CompressionReport, SizedPdf,
+};
+
+mod presets;
+use presets::{delete_preset, list_presets, save_preset, CompressionPreset};
+
 mod license;
 use license::{
     activate_license_key, check_license_smart, get_stored_license, remove_stored_license,
     store_activated_license, store_license, validate_license_key,
 };
 
+mod linearize;
+use linearize::linearize_pdf;
+
+mod sanitize;
+use sanitize::{sanitize_pdf_blocking, SanitizedPdf};
+
+mod assembly;
+use assembly::{
+    delete_pages_blocking, extract_pages_blocking, merge_pdfs_blocking, reorder_pages_blocking,
+    rotate_pages_blocking, AssembledPdf, PageSelection, PdfSource,
+};
+
+mod metadata;
+use metadata::{get_metadata_blocking, set_metadata_blocking, PdfMetadata};
+
+mod outline;
+use outline::{get_outline_blocking, set_outline_blocking, OutlineItem};
+
+mod split;
+use split::{split_pdf_blocking, SplitFile, SplitMode};
+
+mod color;
+mod optimize;
+mod resources;
+mod verify;
+
+#[cfg(test)]
+mod testing;
+
 // Global state to store pending file paths
 static PENDING_FILES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
 static FILE_PROCESSED: Mutex<bool> = Mutex::new(false);
+// Cancel tokens of the compressions in progress, by the run ID the frontend gave them
+static COMPRESSION_RUNS: Mutex<BTreeMap<String, CancelToken>> = Mutex::new(BTreeMap::new());
 
 // Configuration constants
 const MAX_FILE_LOADING_ATTEMPTS: u32 = 30;
@@ -257,13 +297,8 @@ fn check_app_state() -> Result<String, String> {
     Ok(state)
 }
 
-#[tauri::command]
-fn read_file_content(file_path: String) -> Result<Vec<u8>, String> {
-    println!("Reading file content from: {}", file_path);
-
-    // Security: Validate and canonicalize the file path
-    let path = std::path::Path::new(&file_path);
-
+// Security: Canonicalize an absolute path and check it lies under the user's directories
+fn canonicalize_allowed_path(path: &std::path::Path) -> Result<std::path::PathBuf, String> {
     // Check if path is absolute and valid
     if !path.is_absolute() {
         return Err("File path must be absolute".to_string());
@@ -299,6 +334,28 @@ fn read_file_content(file_path: String) -> Result<Vec<u8>, String> {
         return Err("File path is outside of allowed directories".to_string());
     }
 
+    Ok(canonical_path)
+}
+
+// Security: Output files may not exist yet, so check the directory they go in instead
+fn canonicalize_output_path(path: &std::path::Path) -> Result<std::path::PathBuf, String> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err("Output path must name a file".to_string());
+    };
+    let output_path = canonicalize_allowed_path(dir)?.join(name);
+    if output_path.is_dir() {
+        return Err("Output path points to a directory".to_string());
+    }
+    Ok(output_path)
+}
+
+#[tauri::command]
+fn read_file_content(file_path: String) -> Result<Vec<u8>, String> {
+    println!("Reading file content from: {}", file_path);
+
+    // Security: Validate and canonicalize the file path
+    let canonical_path = canonicalize_allowed_path(std::path::Path::new(&file_path))?;
+
     // Security: Check if it's a regular file (not a directory, symlink, etc.)
     let metadata = match std::fs::metadata(&canonical_path) {
         Ok(meta) => meta,
@@ -336,798 +393,368 @@ fn read_file_content(file_path: String) -> Result<Vec<u8>, String> {
     }
 }
 
+// Title, author, dates and the rest, from the Info dictionary or the XMP metadata
 #[tauri::command]
-async fn compress_pdf(content: Vec<u8>, quality: Option<u8>) -> Result<Vec<u8>, String> {
-    tauri::async_runtime::spawn_blocking(move || compress_pdf_blocking(content, quality))
+async fn get_pdf_metadata(source: PdfSource) -> Result<PdfMetadata, String> {
+    let source = check_pdf_source(source)?;
+    tauri::async_runtime::spawn_blocking(move || get_metadata_blocking(&source))
         .await
-        .map_err(|e| format!("Compression task failed: {}", e))?
+        .map_err(|e| format!("Metadata task failed: {}", e))?
 }
 
-fn compress_pdf_blocking(content: Vec<u8>, quality: Option<u8>) -> Result<Vec<u8>, String> {
-    use lopdf::{Document, Object, ObjectId};
-
-    let jpeg_quality = quality.unwrap_or(75).clamp(10, 100);
-
-    // Security: Check file size limit (500MB)
-    const MAX_FILE_SIZE: usize = 500 * 1024 * 1024;
-    // content is a Vec<u8>
-    if content.len() > MAX_FILE_SIZE {
-        return Err("PDF too large".to_string());
-    }
+// Write metadata to both the Info dictionary and the XMP metadata, so readers of either agree
+#[tauri::command]
+async fn set_pdf_metadata(content: Vec<u8>, metadata: PdfMetadata) -> Result<Vec<u8>, String> {
+    tauri::async_runtime::spawn_blocking(move || set_metadata_blocking(&content, &metadata))
+        .await
+        .map_err(|e| format!("Metadata task failed: {}", e))?
+}
 
-    let mut doc = Document::load_mem(&content).map_err(|e| format!("Failed to load PDF: {}", e))?;
-
-    // Phase 1: Recompress images — the main source of file size in PDFs
-    let total_images = doc
-        .objects
-        .iter()
-        .filter(|(_, obj)| {
-            if let Object::Stream(stream) = obj {
-                if let Ok(subtype) = stream.dict.get(b"Subtype") {
-                    if let Ok(name) = subtype.as_name() {
-                        return name == b"Image";
-                    }
-                }
-            }
-            false
-        })
-        .count();
-
-    let mut skip_counts: std::collections::HashMap<String, u32> = std::collections::HashMap::new();
-    let mut filter_types: std::collections::HashMap<String, u32> = std::collections::HashMap::new();
-    let mut colorspace_types: std::collections::HashMap<String, u32> =
-        std::collections::HashMap::new();
-
-    let image_ids: Vec<ObjectId> = doc
-        .objects
-        .iter()
-        .filter_map(|(&id, obj)| {
-            if let Object::Stream(stream) = obj {
-                match is_recompressible_image(stream) {
-                    Some(Ok(())) => return Some(id),
-                    Some(Err(reason)) => match reason {
-                        SkipReason::HasTransparency => {
-                            *skip_counts.entry("transparency".to_string()).or_insert(0) += 1;
-                        }
-                        SkipReason::TooSmall => {
-                            *skip_counts.entry("<2KB".to_string()).or_insert(0) += 1;
-                        }
-                        SkipReason::UnsupportedBitsPerComponent => {
-                            *skip_counts.entry("bits/component".to_string()).or_insert(0) += 1;
-                        }
-                        SkipReason::UnsupportedColorSpace(cs) => {
-                            let cs_name = String::from_utf8_lossy(&cs).to_string();
-                            *skip_counts.entry("colorspace".to_string()).or_insert(0) += 1;
-                            *colorspace_types.entry(cs_name).or_insert(0) += 1;
-                        }
-                        SkipReason::UnsupportedFilter(f) => {
-                            let filter_name = String::from_utf8_lossy(&f).to_string();
-                            *skip_counts.entry("filter".to_string()).or_insert(0) += 1;
-                            *filter_types.entry(filter_name).or_insert(0) += 1;
-                        }
-                        SkipReason::HasPredictor => {
-                            *skip_counts.entry("predictor".to_string()).or_insert(0) += 1;
-                        }
-                    },
-                    None => {
-                        // Not an image stream, don't count it
-                    }
-                }
-            }
-            None
-        })
-        .collect();
+/// Payload of the compression-progress event, tagged with the run it belongs to
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunProgress<'a> {
+    run_id: &'a str,
+    #[serde(flatten)]
+    progress: CompressionProgress,
+}
 
-    println!(
-        "Image analysis: {} total images, {} candidates for recompression (quality: {}%)",
-        total_images,
-        image_ids.len(),
-        jpeg_quality
-    );
-    if image_ids.len() < total_images {
-        println!("  Skipped breakdown:");
-        for (reason, count) in &skip_counts {
-            println!("    - {}: {} images", reason, count);
-        }
-        if !filter_types.is_empty() {
-            println!("  Filter types encountered:");
-            for (filter, count) in &filter_types {
-                println!("    - {}: {} images", filter, count);
-            }
-        }
-        if !colorspace_types.is_empty() {
-            println!("  ColorSpace types encountered:");
-            for (cs, count) in &colorspace_types {
-                println!("    - {}: {} images", cs, count);
-            }
-        }
+// Send compression progress to the frontend as "compression-progress" events
+fn emit_compression_progress(
+    app_handle: &tauri::AppHandle,
+    run_id: &str,
+    progress: CompressionProgress,
+) {
+    if let Err(e) = app_handle.emit("compression-progress", RunProgress { run_id, progress }) {
+        println!("Failed to emit compression-progress event: {:?}", e);
     }
+}
 
-    let mut images_processed = 0u32;
-    let mut images_skipped_threshold = 0u32;
-    for id in image_ids {
-        if let Some(Object::Stream(ref mut stream)) = doc.objects.get_mut(&id) {
-            match recompress_image_stream(stream, jpeg_quality) {
-                ImageCompressionResult::Recompressed => images_processed += 1,
-                ImageCompressionResult::SkippedThreshold => images_skipped_threshold += 1,
-                ImageCompressionResult::Failed => {}
-            }
-        }
+/// Register a new compression run so `cancel_compression` can find it
+fn start_compression_run(run_id: &str) -> Result<CancelToken, String> {
+    let cancel = CancelToken::default();
+    let mut runs = COMPRESSION_RUNS
+        .lock()
+        .map_err(|e| format!("Failed to register compression run: {}", e))?;
+    if runs.contains_key(run_id) {
+        return Err(format!("Compression run {} is already in progress", run_id));
     }
+    runs.insert(run_id.to_string(), cancel.clone());
+    Ok(cancel)
+}
 
-    if images_skipped_threshold > 0 {
-        println!(
-            "  {} images skipped (new size didn't meet threshold)",
-            images_skipped_threshold
-        );
+fn finish_compression_run(run_id: &str) {
+    if let Ok(mut runs) = COMPRESSION_RUNS.lock() {
+        runs.remove(run_id);
     }
-
-    // Phase 2: Standard PDF optimization (prune only, skip compress to avoid inflating already-good streams)
-    doc.prune_objects();
-    doc.delete_zero_length_streams();
-    // NOTE: doc.compress() removed because it was making files BIGGER by re-compressing already-optimal streams
-
-    let mut output = Vec::new();
-    doc.save_to(&mut output)
-        .map_err(|e| format!("Failed to save compressed PDF: {}", e))?;
-
-    let original_size = content.len();
-    let compressed_size = output.len();
-    let ratio = if original_size > 0 {
-        ((original_size as f64 - compressed_size as f64) / original_size as f64) * 100.0
-    } else {
-        0.0
-    };
-
-    println!(
-        "PDF compression: {} -> {} bytes ({:.1}% reduction, {} images recompressed)",
-        original_size, compressed_size, ratio, images_processed
-    );
-
-    Ok(output)
 }
 
-enum ImageCompressionResult {
-    Recompressed,
-    SkippedThreshold,
-    Failed,
+#[tauri::command]
+async fn compress_pdf(
+    app_handle: tauri::AppHandle,
+    content: Vec<u8>,
+    options: Option<CompressionOptions>,
+    run_id: String,
+) -> Result<CompressedPdf, String> {
+    let options = options.unwrap_or_default();
+    let cancel = start_compression_run(&run_id)?;
+    let task_run_id = run_id.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        compress_pdf_blocking(&content, options, &cancel, &|progress| {
+            emit_compression_progress(&app_handle, &task_run_id, progress)
+        })
+    })
+    .await;
+    finish_compression_run(&run_id);
+    result.map_err(|e| format!("Compression task failed: {}", e))?
 }
 
-enum SkipReason {
-    HasTransparency,
-    TooSmall,
-    UnsupportedBitsPerComponent,
-    UnsupportedColorSpace(Vec<u8>),
-    UnsupportedFilter(Vec<u8>),
-    HasPredictor,
+#[tauri::command]
+async fn compress_pdf_to_size(
+    app_handle: tauri::AppHandle,
+    content: Vec<u8>,
+    max_bytes: usize,
+    run_id: String,
+) -> Result<SizedPdf, String> {
+    let cancel = start_compression_run(&run_id)?;
+    let task_run_id = run_id.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        compress_pdf_to_size_blocking(&content, max_bytes, &cancel, &|progress| {
+            emit_compression_progress(&app_handle, &task_run_id, progress)
+        })
+    })
+    .await;
+    finish_compression_run(&run_id);
+    result.map_err(|e| format!("Compression task failed: {}", e))?
 }
 
-/// Convert CMYK pixels to RGB.
-///
-/// Note: This implements a simple (1-C)(1-K) CMYK→RGB transform which is an approximation
-/// and ignores ICC profiles and under-color removal. It is a rough, non-color-managed
-/// conversion suitable for compressed/export-preview only.
-///
-/// TODO: Use color-managed libraries or ICC profile handling for print-quality results
-/// when better color accuracy is required.
-///
-/// CMYK uses subtractive color model, RGB uses additive
-fn cmyk_to_rgb(cmyk_data: &[u8]) -> Vec<u8> {
-    let mut rgb_data = Vec::with_capacity((cmyk_data.len() / 4) * 3);
-
-    for chunk in cmyk_data.chunks_exact(4) {
-        let c = chunk[0] as f32 / 255.0;
-        let m = chunk[1] as f32 / 255.0;
-        let y = chunk[2] as f32 / 255.0;
-        let k = chunk[3] as f32 / 255.0;
-
-        // Standard CMYK to RGB conversion
-        let r = ((1.0 - c) * (1.0 - k) * 255.0) as u8;
-        let g = ((1.0 - m) * (1.0 - k) * 255.0) as u8;
-        let b = ((1.0 - y) * (1.0 - k) * 255.0) as u8;
-
-        rgb_data.push(r);
-        rgb_data.push(g);
-        rgb_data.push(b);
+// Compress a PDF on disk into another file, so large documents never cross the IPC bridge
+#[tauri::command]
+async fn compress_pdf_file(
+    app_handle: tauri::AppHandle,
+    input_path: String,
+    output_path: String,
+    options: Option<CompressionOptions>,
+    run_id: String,
+) -> Result<CompressionReport, String> {
+    println!("Compressing {} to {}", input_path, output_path);
+
+    // Security: Both files must live in the user's directories, like read_file_content
+    let input_path = canonicalize_allowed_path(std::path::Path::new(&input_path))?;
+    if !input_path.is_file() {
+        return Err("Path does not point to a regular file".to_string());
     }
-
-    rgb_data
+    let output_path = canonicalize_output_path(std::path::Path::new(&output_path))?;
+
+    let options = options.unwrap_or_default();
+    let cancel = start_compression_run(&run_id)?;
+    let task_run_id = run_id.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        compress_pdf_file_blocking(&input_path, &output_path, options, &cancel, &|progress| {
+            emit_compression_progress(&app_handle, &task_run_id, progress)
+        })
+    })
+    .await;
+    finish_compression_run(&run_id);
+    result.map_err(|e| format!("Compression task failed: {}", e))?
 }
 
-/// Expand indexed/palette colorspace to RGB
-/// Indexed format: pixel values are indices into a color lookup table
-fn expand_indexed_to_rgb(
-    indexed_data: &[u8],
-    base_colorspace: &[u8],
-    palette_data: &[u8],
-) -> Result<Vec<u8>, ()> {
-    let colors_per_pixel = if base_colorspace == b"DeviceRGB" {
-        3
-    } else if base_colorspace == b"DeviceGray" {
-        1
-    } else {
-        return Err(()); // Unsupported base colorspace
-    };
-
-    let mut rgb_data = Vec::with_capacity(indexed_data.len() * 3);
-
-    for &index in indexed_data {
-        let palette_offset = (index as usize) * colors_per_pixel;
-
-        if palette_offset + colors_per_pixel > palette_data.len() {
-            return Err(()); // Invalid palette index
-        }
-
-        if colors_per_pixel == 3 {
-            // RGB palette
-            rgb_data.push(palette_data[palette_offset]);
-            rgb_data.push(palette_data[palette_offset + 1]);
-            rgb_data.push(palette_data[palette_offset + 2]);
-        } else {
-            // Grayscale palette - convert to RGB
-            let gray = palette_data[palette_offset];
-            rgb_data.push(gray);
-            rgb_data.push(gray);
-            rgb_data.push(gray);
-        }
-    }
-
-    Ok(rgb_data)
+#[tauri::command]
+fn list_compression_presets(
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<CompressionPreset>, String> {
+    list_presets(&app_handle)
 }
 
-/// Check if a PDF stream is an image that we can safely recompress.
-/// Returns Some(Ok(())) if recompressible, Some(Err(reason)) if image but unsupported, None if not an image.
-fn is_recompressible_image(stream: &lopdf::Stream) -> Option<Result<(), SkipReason>> {
-    // Must be an Image XObject
-    let is_image = match stream.dict.get(b"Subtype") {
-        Ok(obj) => match obj.as_name() {
-            Ok(name) if name == b"Image" => true,
-            _ => false,
-        },
-        Err(_) => false,
-    };
-
-    if !is_image {
-        return None; // Not an image, don't count it
-    }
-
-    // Skip images with alpha/transparency masks (JPEG doesn't support alpha)
-    if stream.dict.get(b"SMask").is_ok() {
-        return Some(Err(SkipReason::HasTransparency));
-    }
-
-    // Skip tiny images (icons, bullets) — lowered from 10KB to 2KB to catch more images
-    if stream.content.len() < 2_000 {
-        return Some(Err(SkipReason::TooSmall));
-    }
-
-    // Only handle 8 bits per component
-    match stream.dict.get(b"BitsPerComponent") {
-        Ok(bpc) => match bpc.as_i64() {
-            Ok(8) => {}
-            _ => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
-        },
-        Err(_) => return Some(Err(SkipReason::UnsupportedBitsPerComponent)),
-    }
+#[tauri::command]
+fn save_compression_preset(
+    app_handle: tauri::AppHandle,
+    preset: CompressionPreset,
+) -> Result<Vec<CompressionPreset>, String> {
+    save_preset(&app_handle, preset)?;
+    list_presets(&app_handle)
+}
 
-    // Handle common color spaces (DeviceRGB, DeviceGray, DeviceCMYK, Indexed)
-    match stream.dict.get(b"ColorSpace") {
-        Ok(cs) => {
-            // Check if it's a simple name
-            if let Ok(name) = cs.as_name() {
-                if name == b"DeviceRGB" || name == b"DeviceGray" || name == b"DeviceCMYK" {
-                    // These are all supported
-                } else {
-                    return Some(Err(SkipReason::UnsupportedColorSpace(name.to_vec())));
-                }
-            } else if let Ok(arr) = cs.as_array() {
-                // Array-based colorspaces: Indexed, ICCBased, CalRGB, CalGray, etc.
-                if let Some(first) = arr.first() {
-                    if let Ok(name) = first.as_name() {
-                        if name == b"Indexed" {
-                            // Indexed: [/Indexed /BaseColorSpace hival lookup]
-                            // Supported - we'll expand the palette
-                        } else if name == b"ICCBased" {
-                            // ICCBased: [/ICCBased <stream>]
-                            // Supported - treat like RGB/Gray/CMYK based on component count
-                        } else if name == b"CalRGB" || name == b"CalGray" {
-                            // Calibrated RGB/Gray - treat like DeviceRGB/DeviceGray
-                            // Supported
-                        } else {
-                            return Some(Err(SkipReason::UnsupportedColorSpace(name.to_vec())));
-                        }
-                    } else {
-                        return Some(Err(SkipReason::UnsupportedColorSpace(
-                            b"<array-error>".to_vec(),
-                        )));
-                    }
-                } else {
-                    return Some(Err(SkipReason::UnsupportedColorSpace(
-                        b"<empty-array>".to_vec(),
-                    )));
-                }
-            } else {
-                return Some(Err(SkipReason::UnsupportedColorSpace(
-                    b"<complex>".to_vec(),
-                )));
-            }
-        }
-        Err(_) => {
-            return Some(Err(SkipReason::UnsupportedColorSpace(
-                b"<missing>".to_vec(),
-            )))
-        }
-    }
+#[tauri::command]
+fn delete_compression_preset(
+    app_handle: tauri::AppHandle,
+    name: String,
+) -> Result<Vec<CompressionPreset>, String> {
+    delete_preset(&app_handle, &name)?;
+    list_presets(&app_handle)
+}
 
-    // Handle filters we know how to decode (single or array)
-    match stream.dict.get(b"Filter") {
-        Ok(filter) => {
-            // Try single filter name first
-            if let Ok(name) = filter.as_name() {
-                if name == b"FlateDecode" || name == b"DCTDecode" {
-                    // Supported filters
-                } else {
-                    return Some(Err(SkipReason::UnsupportedFilter(name.to_vec())));
-                }
-            } else if let Ok(arr) = filter.as_array() {
-                // Filter array - check if it's a single-element array (common in some PDFs)
-                if arr.len() == 1 {
-                    if let Some(first) = arr.first() {
-                        if let Ok(name) = first.as_name() {
-                            if name == b"FlateDecode" || name == b"DCTDecode" {
-                                // Single filter in array - supported
-                            } else {
-                                return Some(Err(SkipReason::UnsupportedFilter(name.to_vec())));
-                            }
-                        } else {
-                            return Some(Err(SkipReason::UnsupportedFilter(
-                                b"<array-error>".to_vec(),
-                            )));
-                        }
-                    }
-                } else {
-                    // Multiple filters - too complex for now
-                    return Some(Err(SkipReason::UnsupportedFilter(
-                        b"<multi-filter>".to_vec(),
-                    )));
-                }
-            } else {
-                return Some(Err(SkipReason::UnsupportedFilter(b"<unknown>".to_vec())));
-            }
-        }
-        // No filter means raw uncompressed data — we can handle that
-        Err(_) => {}
-    }
+#[tauri::command]
+async fn sanitize_pdf(content: Vec<u8>) -> Result<SanitizedPdf, String> {
+    tauri::async_runtime::spawn_blocking(move || sanitize_pdf_blocking(&content))
+        .await
+        .map_err(|e| format!("Sanitize task failed: {}", e))?
+}
 
-    // Skip FlateDecode images with predictors (complex PNG-style encoding)
-    if let Ok(params) = stream.dict.get(b"DecodeParms") {
-        if let Ok(dict) = params.as_dict() {
-            if let Ok(predictor) = dict.get(b"Predictor") {
-                if let Ok(val) = predictor.as_i64() {
-                    if val > 1 {
-                        return Some(Err(SkipReason::HasPredictor));
-                    }
-                }
+// Security: Sources given by path must be regular files in the user's directories
+fn check_pdf_source(source: PdfSource) -> Result<PdfSource, String> {
+    match source {
+        PdfSource::Path { path } => {
+            let path = canonicalize_allowed_path(&path)?;
+            if !path.is_file() {
+                return Err("Path does not point to a regular file".to_string());
             }
+            Ok(PdfSource::Path { path })
         }
+        source => Ok(source),
     }
-
-    Some(Ok(()))
 }
 
-/// Helper for ICCBased CMYK images that need CMYK→RGB conversion before JPEG encoding
-fn recompress_icc_cmyk(
-    stream: &mut lopdf::Stream,
-    rgb_data: &[u8],
-    width: u32,
-    height: u32,
-    quality: u8,
-    original_stream_size: usize,
-    _is_already_jpeg: bool,
-) -> ImageCompressionResult {
-    use image::codecs::jpeg::JpegEncoder;
-    use image::ExtendedColorType;
-
-    let mut jpeg_data: Vec<u8> = Vec::new();
-    {
-        let mut encoder = JpegEncoder::new_with_quality(&mut jpeg_data, quality);
-        if encoder
-            .encode(rgb_data, width, height, ExtendedColorType::Rgb8)
-            .is_err()
-        {
-            return ImageCompressionResult::Failed;
-        }
-    }
-
-    if jpeg_data.len() >= original_stream_size {
-        return ImageCompressionResult::SkippedThreshold;
-    }
-
-    let cs_display = "ICCBased(CMYK→RGB)";
-    println!(
-        "  Image {}x{} {}: {} -> {} bytes (saved {})",
-        width,
-        height,
-        cs_display,
-        original_stream_size,
-        jpeg_data.len(),
-        original_stream_size - jpeg_data.len()
-    );
-
-    stream.set_content(jpeg_data);
-    stream
-        .dict
-        .set("Filter", lopdf::Object::Name(b"DCTDecode".to_vec()));
-    stream.dict.remove(b"DecodeParms");
-    stream
-        .dict
-        .set("ColorSpace", lopdf::Object::Name(b"DeviceRGB".to_vec()));
-    stream.allows_compression = false;
-
-    ImageCompressionResult::Recompressed
+// Run a page assembly command off the main thread, with its source and output checked
+async fn run_assembly<F>(
+    sources: Vec<PdfSource>,
+    output_path: Option<String>,
+    assemble: F,
+) -> Result<AssembledPdf, String>
+where
+    F: FnOnce(&[PdfSource], Option<&std::path::Path>) -> Result<AssembledPdf, String>
+        + Send
+        + 'static,
+{
+    let sources = sources
+        .into_iter()
+        .map(check_pdf_source)
+        .collect::<Result<Vec<_>, _>>()?;
+    let output_path = output_path
+        .map(|path| canonicalize_output_path(std::path::Path::new(&path)))
+        .transpose()?;
+    tauri::async_runtime::spawn_blocking(move || assemble(&sources, output_path.as_deref()))
+        .await
+        .map_err(|e| format!("Page assembly task failed: {}", e))?
 }
 
-/// Recompress an image stream as JPEG at the given quality (1-100).
-fn recompress_image_stream(stream: &mut lopdf::Stream, quality: u8) -> ImageCompressionResult {
-    use image::codecs::jpeg::JpegEncoder;
-    use image::ExtendedColorType;
-
-    let width = match stream.dict.get(b"Width") {
-        Ok(w) => match w.as_i64() {
-            Ok(v) => {
-                if v <= 0 {
-                    return ImageCompressionResult::Failed;
-                }
-                v as u32
-            }
-            Err(_) => return ImageCompressionResult::Failed,
-        },
-        Err(_) => return ImageCompressionResult::Failed,
-    };
-    let height = match stream.dict.get(b"Height") {
-        Ok(h) => match h.as_i64() {
-            Ok(v) => {
-                if v <= 0 {
-                    return ImageCompressionResult::Failed;
-                }
-                v as u32
-            }
-            Err(_) => return ImageCompressionResult::Failed,
-        },
-        Err(_) => return ImageCompressionResult::Failed,
-    };
-
-    // Get colorspace - handle both simple names and arrays (for Indexed)
-    // For Indexed, extract: [/Indexed /BaseColorSpace hival lookup]
-    let (cs_bytes, is_indexed, is_cmyk, indexed_info): (
-        Vec<u8>,
-        bool,
-        bool,
-        Option<(Vec<u8>, i64, Vec<u8>)>,
-    ) = match stream.dict.get(b"ColorSpace") {
-        Ok(cs) => {
-            if let Ok(name) = cs.as_name() {
-                let is_cmyk = name == b"DeviceCMYK";
-                (name.to_vec(), false, is_cmyk, None)
-            } else if let Ok(arr) = cs.as_array() {
-                // Handle array-based colorspaces
-                if let Some(first) = arr.first() {
-                    if let Ok(name) = first.as_name() {
-                        if name == b"Indexed" {
-                            // Indexed colorspace: [/Indexed /BaseColorSpace hival lookup]
-                            if arr.len() >= 4 {
-                                if let (Some(base), Some(hival_obj), Some(lookup)) =
-                                    (arr.get(1), arr.get(2), arr.get(3))
-                                {
-                                    if let (Ok(base_cs), Ok(hival)) =
-                                        (base.as_name(), hival_obj.as_i64())
-                                    {
-                                        // Extract lookup table data (might be inline or reference)
-                                        let palette_data = match lookup {
-                                            lopdf::Object::String(ref bytes, _) => bytes.clone(),
-                                            _ => {
-                                                // Lookup table might be a stream reference or other type - skip for now
-                                                return ImageCompressionResult::Failed;
-                                            }
-                                        };
-
-                                        (
-                                            b"Indexed".to_vec(),
-                                            true,
-                                            false,
-                                            Some((base_cs.to_vec(), hival, palette_data)),
-                                        )
-                                    } else {
-                                        return ImageCompressionResult::Failed;
-                                    }
-                                } else {
-                                    return ImageCompressionResult::Failed;
-                                }
-                            } else {
-                                return ImageCompressionResult::Failed;
-                            }
-                        } else if name == b"ICCBased" {
-                            // ICCBased: [/ICCBased <stream_ref>]
-                            // Infer channel count from the stream's data size after decompression
-                            // We mark it as ICCBased and resolve the actual channel count later
-                            (b"ICCBased".to_vec(), false, false, None)
-                        } else if name == b"CalRGB" {
-                            // CalRGB: treat as DeviceRGB
-                            (b"DeviceRGB".to_vec(), false, false, None)
-                        } else if name == b"CalGray" {
-                            // CalGray: treat as DeviceGray
-                            (b"DeviceGray".to_vec(), false, false, None)
-                        } else {
-                            return ImageCompressionResult::Failed;
-                        }
-                    } else {
-                        return ImageCompressionResult::Failed;
-                    }
-                } else {
-                    return ImageCompressionResult::Failed;
-                }
-            } else {
-                return ImageCompressionResult::Failed;
-            }
-        }
-        Err(_) => return ImageCompressionResult::Failed,
-    };
-
-    let is_icc_based = cs_bytes == b"ICCBased";
-
-    // Determine channels and color type for encoding
-    // Note: CMYK and Indexed will be converted to RGB
-    // For ICCBased, we'll resolve channels after decompression
-    let (_source_channels, color_type, target_channels) = if is_indexed {
-        // Indexed: 1 byte per pixel (index), but will expand to RGB (3 bytes)
-        (1u32, ExtendedColorType::Rgb8, 3u32)
-    } else if cs_bytes == b"DeviceRGB" {
-        (3u32, ExtendedColorType::Rgb8, 3u32)
-    } else if cs_bytes == b"DeviceGray" {
-        (1u32, ExtendedColorType::L8, 1u32)
-    } else if cs_bytes == b"DeviceCMYK" {
-        // CMYK: 4 channels in source, but we'll convert to 3 (RGB) for JPEG
-        (4u32, ExtendedColorType::Rgb8, 3u32)
-    } else if is_icc_based {
-        // ICCBased: placeholder — we'll infer the actual channel count from data size
-        // Default to RGB; will be corrected after decompression
-        (0u32, ExtendedColorType::Rgb8, 3u32)
-    } else {
-        return ImageCompressionResult::Failed;
-    };
-
-    // Extract filter name - handle both single name and single-element arrays
-    let filter_bytes: Option<Vec<u8>> = stream.dict.get(b"Filter").ok().and_then(|f| {
-        if let Ok(name) = f.as_name() {
-            Some(name.to_vec())
-        } else if let Ok(arr) = f.as_array() {
-            // Single-element filter array
-            if arr.len() == 1 {
-                arr.first()
-                    .and_then(|obj| obj.as_name().ok())
-                    .map(|n| n.to_vec())
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    });
-
-    let original_stream_size = stream.content.len();
-    let filter_name = filter_bytes.as_deref();
-    let is_already_jpeg = filter_name == Some(b"DCTDecode");
-
-    // Get raw pixel data depending on current encoding
-    // Note: We avoid mutating the stream until we're sure we'll succeed
-    let raw_pixels: Vec<u8> = if filter_name == Some(b"FlateDecode") {
-        // Decompress zlib to get raw pixels
-        // Clone the stream to avoid side-effects if we fail later
-        let mut temp_stream = stream.clone();
-        if temp_stream.decompress().is_err() {
-            return ImageCompressionResult::Failed;
-        }
-        let decompressed = temp_stream.content;
-
-        if is_indexed {
-            // Indexed image: expand palette to RGB
-            if let Some((ref base_cs, _hival, ref palette)) = indexed_info {
-                match expand_indexed_to_rgb(&decompressed, base_cs, palette) {
-                    Ok(rgb) => rgb,
-                    Err(_) => return ImageCompressionResult::Failed,
-                }
-            } else {
-                return ImageCompressionResult::Failed;
-            }
-        } else if is_cmyk {
-            // CMYK FlateDecode: convert to RGB
-            cmyk_to_rgb(&decompressed)
-        } else {
-            // RGB or Grayscale - use as-is
-            decompressed
-        }
-    } else if is_already_jpeg {
-        // Already JPEG (including CMYK JPEG) — decode to RGB
-        // The image crate automatically converts CMYK JPEG to RGB
-        // Note: This causes generation loss, so we'll only replace if significantly smaller
-        match image::load_from_memory_with_format(&stream.content, image::ImageFormat::Jpeg) {
-            Ok(img) => {
-                // Convert to RGB8 or Luma8 depending on source
-                if is_cmyk || cs_bytes == b"DeviceRGB" {
-                    img.to_rgb8().into_raw()
-                } else if cs_bytes == b"ICCBased" {
-                    // Inspect decoded image color type
-                    // ICCBased grayscale JPEGs are intentionally coerced to RGB if multi-channel,
-                    // otherwise kept as Luma8
-                    if img.color().channel_count() == 1 {
-                        img.to_luma8().into_raw()
-                    } else {
-                        img.to_rgb8().into_raw()
-                    }
-                } else {
-                    img.to_luma8().into_raw()
-                }
-            }
-            Err(_) => return ImageCompressionResult::Failed,
-        }
-    } else if filter_name.is_none() {
-        // Uncompressed raw data
-        let raw_data = stream.content.clone();
-
-        if is_indexed {
-            // Indexed image: expand palette to RGB
-            if let Some((ref base_cs, _hival, ref palette)) = indexed_info {
-                match expand_indexed_to_rgb(&raw_data, base_cs, palette) {
-                    Ok(rgb) => rgb,
-                    Err(_) => return ImageCompressionResult::Failed,
-                }
-            } else {
-                return ImageCompressionResult::Failed;
-            }
-        } else if is_cmyk {
-            // Uncompressed CMYK: convert to RGB
-            cmyk_to_rgb(&raw_data)
-        } else {
-            // RGB or Grayscale - use as-is
-            raw_data
-        }
-    } else {
-        return ImageCompressionResult::Failed;
-    };
+// Page assembly: each command takes PDFs as paths or bytes, and returns the new PDF's
+// bytes or writes it to output_path
+#[tauri::command]
+async fn merge_pdfs(
+    sources: Vec<PdfSource>,
+    pages: Option<Vec<PageSelection>>,
+    output_path: Option<String>,
+) -> Result<AssembledPdf, String> {
+    println!("Merging {} PDFs", sources.len());
+    run_assembly(sources, output_path, move |sources, output| {
+        merge_pdfs_blocking(sources, pages.as_deref(), output)
+    })
+    .await
+}
 
-    // For ICCBased, infer the actual channels from decompressed data size
-    let (color_type, target_channels) = if is_icc_based && !is_already_jpeg {
-        let pixel_count = (width as usize) * (height as usize);
-        if pixel_count == 0 {
-            return ImageCompressionResult::Failed;
-        }
+#[tauri::command]
+async fn extract_pdf_pages(
+    source: PdfSource,
+    ranges: String,
+    output_path: Option<String>,
+) -> Result<AssembledPdf, String> {
+    println!("Extracting pages {}", ranges);
+    run_assembly(vec![source], output_path, move |sources, output| {
+        extract_pages_blocking(&sources[0], &ranges, output)
+    })
+    .await
+}
 
-        if raw_pixels.len() % pixel_count != 0 {
-            return ImageCompressionResult::Failed;
-        }
+#[tauri::command]
+async fn reorder_pdf_pages(
+    source: PdfSource,
+    order: Vec<u32>,
+    output_path: Option<String>,
+) -> Result<AssembledPdf, String> {
+    run_assembly(vec![source], output_path, move |sources, output| {
+        reorder_pages_blocking(&sources[0], &order, output)
+    })
+    .await
+}
 
-        let inferred_channels = raw_pixels.len() / pixel_count;
-        match inferred_channels {
-            1 => (ExtendedColorType::L8, 1u32),
-            3 => (ExtendedColorType::Rgb8, 3u32),
-            4 => {
-                // ICCBased CMYK: convert to RGB
-                let rgb_data = cmyk_to_rgb(&raw_pixels);
-                // We need to replace raw_pixels but can't reassign; handled below
-                return recompress_icc_cmyk(
-                    stream,
-                    &rgb_data,
-                    width,
-                    height,
-                    quality,
-                    original_stream_size,
-                    is_already_jpeg,
-                );
-            }
-            _ => return ImageCompressionResult::Failed,
-        }
-    } else {
-        (color_type, target_channels)
-    };
+#[tauri::command]
+async fn delete_pdf_pages(
+    source: PdfSource,
+    pages: Vec<u32>,
+    output_path: Option<String>,
+) -> Result<AssembledPdf, String> {
+    println!("Deleting pages {:?}", pages);
+    run_assembly(vec![source], output_path, move |sources, output| {
+        delete_pages_blocking(&sources[0], &pages, output)
+    })
+    .await
+}
 
-    // Validate expected data size (cast to usize before multiplying to prevent u32 overflow)
-    // Use target_channels (post-conversion) for validation
-    let expected_size = (width as usize) * (height as usize) * (target_channels as usize);
-    if raw_pixels.len() != expected_size {
-        return ImageCompressionResult::Failed;
-    }
+#[tauri::command]
+async fn rotate_pdf_pages(
+    source: PdfSource,
+    pages: Option<Vec<u32>>,
+    rotation: i64,
+    output_path: Option<String>,
+) -> Result<AssembledPdf, String> {
+    println!("Rotating pages {:?} to {} degrees", pages, rotation);
+    run_assembly(vec![source], output_path, move |sources, output| {
+        rotate_pages_blocking(&sources[0], pages.as_deref(), rotation, output)
+    })
+    .await
+}
 
-    // Encode as JPEG at the user-chosen quality level
-    let mut jpeg_data: Vec<u8> = Vec::new();
-    {
-        let mut encoder = JpegEncoder::new_with_quality(&mut jpeg_data, quality);
-        if encoder
-            .encode(&raw_pixels, width, height, color_type)
-            .is_err()
-        {
-            return ImageCompressionResult::Failed;
-        }
+// Split a PDF into files in output_dir, one per top-level bookmark, every N pages or up
+// to a maximum size, and list the files written
+#[tauri::command]
+async fn split_pdf(
+    source: PdfSource,
+    mode: SplitMode,
+    output_dir: String,
+    base_name: Option<String>,
+) -> Result<Vec<SplitFile>, String> {
+    println!("Splitting PDF into {} by {:?}", output_dir, mode);
+    let source = check_pdf_source(source)?;
+    let output_dir = canonicalize_allowed_path(std::path::Path::new(&output_dir))?;
+    if !output_dir.is_dir() {
+        return Err("Output path does not point to a directory".to_string());
     }
+    tauri::async_runtime::spawn_blocking(move || {
+        split_pdf_blocking(&source, mode, &output_dir, base_name.as_deref())
+    })
+    .await
+    .map_err(|e| format!("Split task failed: {}", e))?
+}
 
-    // Quality-aware threshold: lower quality = more aggressive (allow smaller reductions)
-    // This helps achieve meaningful compression even with generation loss on pre-compressed JPEGs
-    let threshold_multiplier = if is_already_jpeg {
-        if quality < 50 {
-            0.85 // Aggressive: accept 15%+ reduction for very low quality
-        } else if quality < 70 {
-            0.88 // Moderate: accept 12%+ reduction for medium quality
-        } else {
-            0.92 // Conservative: accept 8%+ reduction for high quality
-        }
-    } else {
-        1.0 // For non-JPEG sources, any reduction is good (no generation loss)
-    };
-
-    let size_threshold = (original_stream_size as f64 * threshold_multiplier) as usize;
-
-    if jpeg_data.len() >= size_threshold {
-        return ImageCompressionResult::SkippedThreshold;
-    }
+#[tauri::command]
+async fn get_pdf_outline(source: PdfSource) -> Result<Vec<OutlineItem>, String> {
+    let source = check_pdf_source(source)?;
+    tauri::async_runtime::spawn_blocking(move || get_outline_blocking(&source))
+        .await
+        .map_err(|e| format!("Outline task failed: {}", e))?
+}
 
-    let cs_display = String::from_utf8_lossy(&cs_bytes);
-    println!(
-        "  Image {}x{} {}: {} -> {} bytes (saved {})",
-        width,
-        height,
-        cs_display,
-        original_stream_size,
-        jpeg_data.len(),
-        original_stream_size - jpeg_data.len()
-    );
+#[tauri::command]
+async fn set_pdf_outline(content: Vec<u8>, outline: Vec<OutlineItem>) -> Result<Vec<u8>, String> {
+    tauri::async_runtime::spawn_blocking(move || set_outline_blocking(&content, &outline))
+        .await
+        .map_err(|e| format!("Outline task failed: {}", e))?
+}
 
-    // Update stream with JPEG data
-    stream.set_content(jpeg_data);
-    stream
-        .dict
-        .set("Filter", lopdf::Object::Name(b"DCTDecode".to_vec()));
-    stream.dict.remove(b"DecodeParms");
-
-    // If we converted CMYK, Indexed, or ICCBased to RGB/Gray, update the colorspace
-    if is_cmyk || is_indexed || is_icc_based {
-        let new_cs = if target_channels == 1 {
-            b"DeviceGray".to_vec()
-        } else {
-            b"DeviceRGB".to_vec()
-        };
-        stream.dict.set("ColorSpace", lopdf::Object::Name(new_cs));
-        if is_icc_based {
-            // Remove stale ICCBased dictionary entries if present
-            // The simple name setting above already handles the key update
-            // But we should ensure we're not leaving any other artifacts if we had complex dicts
-        }
+#[tauri::command]
+fn cancel_compression(run_id: String) {
+    println!("Compression cancel requested for run {}", run_id);
+    match COMPRESSION_RUNS.lock() {
+        Ok(runs) => match runs.get(&run_id) {
+            Some(cancel) => cancel.cancel(),
+            None => println!("No compression run {} in progress", run_id),
+        },
+        Err(e) => println!("Failed to cancel compression: {}", e),
     }
-
-    stream.allows_compression = false;
-
-    ImageCompressionResult::Recompressed
 }
 
 #[tauri::command]
-fn export_file(
+async fn export_file(
     _app_handle: tauri::AppHandle,
     content: Vec<u8>,
     default_filename: String,
     filter_name: String,
     extension: String,
+    linearize: Option<bool>,
+    sanitize: Option<bool>,
+    outline: Option<Vec<OutlineItem>>,
 ) -> Result<Option<String>, String> {
-    use rfd::FileDialog;
+    use rfd::AsyncFileDialog;
 
-    let path = FileDialog::new()
+    // Ask first, so nothing is processed for an export the user cancels
+    let Some(file) = AsyncFileDialog::new()
         .add_filter(&filter_name, &[&extension])
         .set_file_name(&default_filename)
-        .save_file();
+        .save_file()
+        .await
+    else {
+        return Ok(None); // User cancelled
+    };
+    let path = file.path().to_path_buf();
 
-    if let Some(p) = path {
-        std::fs::write(&p, content).map_err(|e| e.to_string())?;
-        Ok(Some(p.to_string_lossy().to_string()))
-    } else {
-        Ok(None) // User cancelled
-    }
+    tauri::async_runtime::spawn_blocking(move || -> Result<Option<String>, String> {
+        // An edited outline is what the user asked to save, so failing to write it fails the export
+        let content = match outline {
+            Some(outline) => set_outline_blocking(&content, &outline)?,
+            None => content,
+        };
+        // Unlike linearization this is a privacy promise, so failing to sanitize fails the export
+        let content = if sanitize.unwrap_or(false) {
+            sanitize_pdf_blocking(&content)?.content
+        } else {
+            content
+        };
+        // A PDF that can't be linearized is still exported as it is
+        let content = if linearize.unwrap_or(false) {
+            linearize_pdf(&content).unwrap_or_else(|e| {
+                println!("Exporting without linearization: {}", e);
+                content
+            })
+        } else {
+            content
+        };
+
+        std::fs::write(&path, content).map_err(|e| e.to_string())?;
+        Ok(Some(path.to_string_lossy().to_string()))
+    })
+    .await
+    .map_err(|e| format!("Export task failed: {}", e))?
 }
 
 #[cfg(debug_assertions)]
@@ -1256,7 +883,24 @@ pub fn run() {
             get_system_fonts,
             frontend_ready,
             read_file_content,
+            get_pdf_metadata,
+            set_pdf_metadata,
             compress_pdf,
+            compress_pdf_to_size,
+            compress_pdf_file,
+            list_compression_presets,
+            save_compression_preset,
+            delete_compression_preset,
+            cancel_compression,
+            sanitize_pdf,
+            merge_pdfs,
+            extract_pdf_pages,
+            reorder_pdf_pages,
+            delete_pdf_pages,
+            rotate_pdf_pages,
+            split_pdf,
+            get_pdf_outline,
+            set_pdf_outline,
             export_file,
             #[cfg(debug_assertions)]
             test_file_event,
@@ -0,0 +1,981 @@
+use lopdf::xref::XrefType;
+use lopdf::{Document, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Width the values in the linearization dictionary and first-page trailer are padded to,
+/// so the section sizes are known before the offsets they hold are
+const OFFSET_WIDTH: usize = 10;
+
+/// Linearize a PDF held in memory
+pub fn linearize_pdf(content: &[u8]) -> Result<Vec<u8>, String> {
+    let doc = Document::load_mem(content).map_err(|e| format!("Failed to load PDF: {}", e))?;
+    let mut output = Vec::with_capacity(content.len());
+    save_linearized(doc, &mut output)?;
+    Ok(output)
+}
+
+/// Write `doc` as a linearized ("Fast Web View") PDF, laid out as in ISO 32000 Annex F.
+///
+/// The linearization dictionary, first-page cross-reference table, catalog, hint stream and
+/// everything the first page uses come first, so a viewer reading over a slow link can show
+/// page 1 before the rest arrives. The remaining pages follow in order, each with the objects
+/// only it uses, then objects shared between pages, then everything else.
+///
+/// lopdf serialises the objects; they're renumbered and reordered here, with the
+/// cross-reference tables and hint tables written around them. Object streams aren't used.
+pub fn save_linearized(mut doc: Document, target: &mut dyn Write) -> Result<(), String> {
+    if doc.is_encrypted() {
+        return Err("Encrypted PDFs can't be linearized".to_string());
+    }
+    let catalog_id = doc
+        .trailer
+        .get(b"Root")
+        .and_then(Object::as_reference)
+        .map_err(|_| "PDF has no catalog".to_string())?;
+    let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+    if pages.is_empty() {
+        return Err("PDF has no pages".to_string());
+    }
+
+    // Leftovers from the source's own linearization or object streams are rebuilt, not kept
+    doc.objects
+        .retain(|_, object| !matches!(object.type_name(), Ok(b"ObjStm" | b"XRef" | b"Linearized")));
+
+    let sections = Sections::new(&doc, catalog_id, &pages);
+
+    // Number the main section (later pages, shared and other objects) from 1, then the
+    // first-page section after it, in the order everything is written
+    let main_count = sections.main_order().count() as u32;
+    let linearization_number = main_count + 1;
+    let catalog_number = main_count + 2;
+    let hint_number = main_count + 3;
+    let first_page_number = main_count + 4;
+    let size = first_page_number + sections.first_page.len() as u32;
+
+    let mut numbers: HashMap<ObjectId, u32> = HashMap::new();
+    for (index, &id) in sections.main_order().enumerate() {
+        numbers.insert(id, index as u32 + 1);
+    }
+    numbers.insert(catalog_id, catalog_number);
+    for (index, &id) in sections.first_page.iter().enumerate() {
+        numbers.insert(id, first_page_number + index as u32);
+    }
+
+    // Serialise every object once through lopdf, then cut the output up by object
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .and_then(Object::as_reference)
+        .ok()
+        .and_then(|id| numbers.get(&id).copied());
+    let file_id = file_identifier(&doc);
+    let mut scratch = Document::with_version(doc.version.clone());
+    scratch.binary_mark = doc.binary_mark.clone();
+    scratch.reference_table.cross_reference_type = XrefType::CrossReferenceTable;
+    for (id, mut object) in std::mem::take(&mut doc.objects) {
+        if let Some(&number) = numbers.get(&id) {
+            renumber_references(&mut object, &numbers);
+            scratch.objects.insert((number, 0), object);
+        }
+    }
+    scratch.max_id = size - 1;
+    scratch
+        .trailer
+        .set("Root", Object::Reference((catalog_number, 0)));
+    drop(doc);
+
+    let mut serialised = Vec::new();
+    scratch
+        .save_to(&mut serialised)
+        .map_err(|e| format!("Failed to save PDF: {}", e))?;
+    drop(scratch);
+    let (header_length, chunks) = split_objects(&serialised)?;
+    let chunk = |number: u32| chunks.get(&number).copied().unwrap_or_default();
+
+    let first_page_numbers: Vec<u32> = (first_page_number..size).collect();
+    let main_numbers: Vec<u32> = (1..=main_count).collect();
+
+    // Fixed-size parts: header, linearization dictionary and first-page xref with trailer
+    let header = &serialised[..header_length];
+    let linearization_length =
+        linearization_dict(linearization_number, &LinearizationParams::default()).len();
+    let first_xref_offset = header.len() + linearization_length;
+    let first_trailer =
+        |prev: usize| first_page_trailer(size, catalog_number, info, file_id.as_deref(), prev);
+    let first_xref_length = xref_header(linearization_number, size - linearization_number).len()
+        + 20 * (size - linearization_number) as usize
+        + first_trailer(0).len();
+
+    // Offsets as if the hint stream weren't there, which is what hint tables record
+    let catalog_offset = first_xref_offset + first_xref_length;
+    let hint_offset = catalog_offset + chunk(catalog_number).len();
+    let mut offsets: HashMap<u32, usize> = HashMap::new();
+    let mut position = hint_offset;
+    for &number in first_page_numbers.iter().chain(&main_numbers) {
+        offsets.insert(number, position);
+        position += chunk(number).len();
+    }
+
+    let (hint_data, shared_table_offset) =
+        HintTables::new(&sections, &numbers, &offsets, &chunk).encode();
+    let hint_stream = hint_stream_object(hint_number, &hint_data, shared_table_offset);
+    let hint_length = hint_stream.len();
+
+    // Real offsets: everything after the hint stream moves down by its length
+    for offset in offsets.values_mut() {
+        *offset += hint_length;
+    }
+    let first_page_end = first_page_numbers
+        .last()
+        .map(|&number| offsets[&number] + chunk(number).len())
+        .unwrap_or(hint_offset + hint_length);
+    let main_xref_offset = main_numbers
+        .last()
+        .map(|&number| offsets[&number] + chunk(number).len())
+        .unwrap_or(first_page_end);
+
+    let mut main_xref = xref_header(0, main_count + 1);
+    // The white-space before the first entry, which /T points at
+    let main_xref_entries = main_xref_offset + main_xref.len() - 1;
+    main_xref.extend_from_slice(b"0000000000 65535 f \n");
+    for number in &main_numbers {
+        main_xref.extend_from_slice(xref_entry(offsets[number]).as_bytes());
+    }
+    main_xref.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} >>\nstartxref\n{}\n%%EOF\n",
+            main_count + 1,
+            first_xref_offset
+        )
+        .as_bytes(),
+    );
+    let file_length = main_xref_offset + main_xref.len();
+
+    let linearization = linearization_dict(
+        linearization_number,
+        &LinearizationParams {
+            file_length,
+            hint_offset,
+            hint_length,
+            first_page_object: first_page_number,
+            first_page_end,
+            pages: pages.len(),
+            main_xref_entries,
+        },
+    );
+
+    let mut first_xref = xref_header(linearization_number, size - linearization_number);
+    first_xref.extend_from_slice(xref_entry(header.len()).as_bytes());
+    first_xref.extend_from_slice(xref_entry(catalog_offset).as_bytes());
+    first_xref.extend_from_slice(xref_entry(hint_offset).as_bytes());
+    for number in &first_page_numbers {
+        first_xref.extend_from_slice(xref_entry(offsets[number]).as_bytes());
+    }
+    first_xref.extend_from_slice(&first_trailer(main_xref_offset));
+
+    let write = |target: &mut dyn Write, bytes: &[u8]| {
+        target
+            .write_all(bytes)
+            .map_err(|e| format!("Failed to write PDF: {}", e))
+    };
+    write(target, header)?;
+    write(target, &linearization)?;
+    write(target, &first_xref)?;
+    write(target, chunk(catalog_number))?;
+    write(target, &hint_stream)?;
+    for &number in first_page_numbers.iter().chain(&main_numbers) {
+        write(target, chunk(number))?;
+    }
+    write(target, &main_xref)?;
+
+    Ok(())
+}
+
+/// Which part of a linearized file each object goes in
+struct Sections {
+    /// The first page and everything it uses, page object first
+    first_page: Vec<ObjectId>,
+    /// For each later page: the page and the objects only it uses
+    later_pages: Vec<Vec<ObjectId>>,
+    /// Objects used by more than one later page and not by the first
+    shared: Vec<ObjectId>,
+    /// Everything else: page tree nodes, outlines, the info dictionary and so on
+    other: Vec<ObjectId>,
+    /// For each later page, every object it uses in the first page or shared sections
+    later_page_shared: Vec<Vec<ObjectId>>,
+    /// Each page's first content stream
+    contents: Vec<Option<ObjectId>>,
+}
+
+impl Sections {
+    fn new(doc: &Document, catalog_id: ObjectId, pages: &[ObjectId]) -> Sections {
+        // Walking one page's objects must stop at other pages, the page tree and the catalog,
+        // which annotations and destinations point back to
+        let mut stops = page_tree_nodes(doc, catalog_id);
+        stops.extend(pages.iter().copied());
+        stops.insert(catalog_id);
+
+        let first_page = collect_page_objects(doc, pages[0], &stops);
+        let in_first_page: HashSet<ObjectId> = first_page.iter().copied().collect();
+
+        let later_objects: Vec<Vec<ObjectId>> = pages[1..]
+            .iter()
+            .map(|&page| collect_page_objects(doc, page, &stops))
+            .collect();
+        let mut users: HashMap<ObjectId, usize> = HashMap::new();
+        for objects in &later_objects {
+            for id in objects.iter().filter(|id| !in_first_page.contains(id)) {
+                *users.entry(*id).or_insert(0) += 1;
+            }
+        }
+
+        let mut shared = Vec::new();
+        let mut in_shared = HashSet::new();
+        let mut later_pages = Vec::new();
+        for objects in &later_objects {
+            let mut own = Vec::new();
+            for &id in objects.iter().filter(|id| !in_first_page.contains(id)) {
+                if users[&id] > 1 {
+                    if in_shared.insert(id) {
+                        shared.push(id);
+                    }
+                } else {
+                    own.push(id);
+                }
+            }
+            later_pages.push(own);
+        }
+        let later_page_shared = later_objects
+            .iter()
+            .map(|objects| {
+                objects
+                    .iter()
+                    .copied()
+                    .filter(|id| in_first_page.contains(id) || in_shared.contains(id))
+                    .collect()
+            })
+            .collect();
+
+        let mut placed: HashSet<ObjectId> = in_first_page;
+        placed.insert(catalog_id);
+        placed.extend(later_pages.iter().flatten().copied());
+        placed.extend(shared.iter().copied());
+        let other = doc
+            .objects
+            .keys()
+            .copied()
+            .filter(|id| !placed.contains(id))
+            .collect();
+
+        let contents = pages
+            .iter()
+            .map(|&page| doc.get_page_contents(page).first().copied())
+            .collect();
+
+        Sections {
+            first_page,
+            later_pages,
+            shared,
+            other,
+            later_page_shared,
+            contents,
+        }
+    }
+
+    /// Objects of the main section, in file order
+    fn main_order(&self) -> impl Iterator<Item = &ObjectId> {
+        self.later_pages
+            .iter()
+            .flatten()
+            .chain(&self.shared)
+            .chain(&self.other)
+    }
+}
+
+/// Every node of the page tree, including pages
+fn page_tree_nodes(doc: &Document, catalog_id: ObjectId) -> HashSet<ObjectId> {
+    let mut nodes = HashSet::new();
+    let mut pending: Vec<ObjectId> = doc
+        .get_dictionary(catalog_id)
+        .and_then(|catalog| catalog.get(b"Pages"))
+        .and_then(Object::as_reference)
+        .into_iter()
+        .collect();
+    while let Some(id) = pending.pop() {
+        if !nodes.insert(id) {
+            continue;
+        }
+        if let Ok(kids) = doc
+            .get_dictionary(id)
+            .and_then(|node| node.get(b"Kids"))
+            .and_then(Object::as_array)
+        {
+            pending.extend(kids.iter().filter_map(|kid| kid.as_reference().ok()));
+        }
+    }
+    nodes
+}
+
+/// A page and every object reachable from it, page first, not following `/Parent` or
+/// entering anything in `stops`
+fn collect_page_objects(
+    doc: &Document,
+    page_id: ObjectId,
+    stops: &HashSet<ObjectId>,
+) -> Vec<ObjectId> {
+    let mut objects = vec![page_id];
+    let mut seen = HashSet::from([page_id]);
+    let mut pending = Vec::new();
+    if let Ok(page) = doc.get_dictionary(page_id) {
+        for (key, value) in page.iter() {
+            if key.as_slice() != b"Parent" {
+                collect_references(value, &mut pending);
+            }
+        }
+    }
+    // Pop in the order references appear, so e.g. /Contents keeps its place
+    pending.reverse();
+
+    while let Some(id) = pending.pop() {
+        if stops.contains(&id) || !seen.insert(id) {
+            continue;
+        }
+        let Ok(object) = doc.get_object(id) else {
+            continue;
+        };
+        objects.push(id);
+        let mut references = Vec::new();
+        collect_references(object, &mut references);
+        pending.extend(references.into_iter().rev());
+    }
+    objects
+}
+
+fn collect_references(object: &Object, references: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => references.push(*id),
+        Object::Array(items) => {
+            for item in items {
+                collect_references(item, references);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter() {
+                collect_references(value, references);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter() {
+                collect_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Point references at the new object numbers. References to objects that don't exist
+/// would otherwise land on whatever now has their number, so they become null.
+fn renumber_references(object: &mut Object, numbers: &HashMap<ObjectId, u32>) {
+    match object {
+        Object::Reference(id) => {
+            *object = match numbers.get(id) {
+                Some(&number) => Object::Reference((number, 0)),
+                None => Object::Null,
+            };
+        }
+        Object::Array(items) => {
+            for item in items {
+                renumber_references(item, numbers);
+            }
+        }
+        Object::Dictionary(dict) => {
+            for (_, value) in dict.iter_mut() {
+                renumber_references(value, numbers);
+            }
+        }
+        Object::Stream(stream) => {
+            for (_, value) in stream.dict.iter_mut() {
+                renumber_references(value, numbers);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Split a PDF lopdf just wrote into its header length and each object's bytes, using the
+/// cross-reference table at the end. Objects are written back to back, so each runs to the
+/// start of the next one, and the last to the cross-reference table.
+fn split_objects(pdf: &[u8]) -> Result<(usize, HashMap<u32, &[u8]>), String> {
+    let malformed = || "Failed to read back the saved PDF".to_string();
+    let startxref = pdf
+        .windows(9)
+        .rposition(|window| window == b"startxref")
+        .ok_or_else(malformed)?;
+    let xref_offset: usize = std::str::from_utf8(&pdf[startxref + 9..])
+        .ok()
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|offset| offset.parse().ok())
+        .filter(|&offset| offset < startxref)
+        .ok_or_else(malformed)?;
+
+    let table = std::str::from_utf8(&pdf[xref_offset..startxref]).map_err(|_| malformed())?;
+    let mut tokens = table.split_whitespace();
+    if tokens.next() != Some("xref") {
+        return Err(malformed());
+    }
+    let mut starts: Vec<(usize, u32)> = Vec::new();
+    while let Some(token) = tokens.next() {
+        if token == "trailer" {
+            break;
+        }
+        let first: u32 = token.parse().map_err(|_| malformed())?;
+        let count: u32 = tokens
+            .next()
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(malformed)?;
+        for number in first..first + count {
+            let (Some(offset), Some(_generation), Some(kind)) =
+                (tokens.next(), tokens.next(), tokens.next())
+            else {
+                return Err(malformed());
+            };
+            if kind == "n" {
+                starts.push((offset.parse().map_err(|_| malformed())?, number));
+            }
+        }
+    }
+
+    starts.sort_unstable();
+    let header_length = starts.first().map_or(xref_offset, |&(offset, _)| offset);
+    let mut chunks = HashMap::new();
+    for (index, &(offset, number)) in starts.iter().enumerate() {
+        let end = starts.get(index + 1).map_or(xref_offset, |&(next, _)| next);
+        chunks.insert(number, pdf.get(offset..end).ok_or_else(malformed)?);
+    }
+    Ok((header_length, chunks))
+}
+
+/// The first file identifier from the trailer, as hex, so the first-page trailer can
+/// carry `/ID` like the original
+fn file_identifier(doc: &Document) -> Option<String> {
+    let ids = doc.trailer.get(b"ID").and_then(Object::as_array).ok()?;
+    let hex: Vec<String> = ids
+        .iter()
+        .filter_map(|id| id.as_str().ok())
+        .map(|id| id.iter().map(|byte| format!("{:02X}", byte)).collect())
+        .collect();
+    (hex.len() == 2).then(|| format!("[<{}> <{}>]", hex[0], hex[1]))
+}
+
+#[derive(Default)]
+struct LinearizationParams {
+    file_length: usize,
+    hint_offset: usize,
+    hint_length: usize,
+    first_page_object: u32,
+    first_page_end: usize,
+    pages: usize,
+    main_xref_entries: usize,
+}
+
+/// The linearization parameter dictionary. Values that depend on the layout are padded to
+/// `OFFSET_WIDTH`, so the length is the same before and after they're known.
+fn linearization_dict(number: u32, params: &LinearizationParams) -> Vec<u8> {
+    format!(
+        "{} 0 obj\n<< /Linearized 1 /L {:>w$} /H [ {:>w$} {:>w$} ] /O {:>w$} /E {:>w$} /N {:>w$} /T {:>w$} >>\nendobj\n",
+        number,
+        params.file_length,
+        params.hint_offset,
+        params.hint_length,
+        params.first_page_object,
+        params.first_page_end,
+        params.pages,
+        params.main_xref_entries,
+        w = OFFSET_WIDTH
+    )
+    .into_bytes()
+}
+
+fn first_page_trailer(
+    size: u32,
+    catalog_number: u32,
+    info: Option<u32>,
+    file_id: Option<&str>,
+    prev: usize,
+) -> Vec<u8> {
+    let info = info
+        .map(|number| format!(" /Info {} 0 R", number))
+        .unwrap_or_default();
+    let file_id = file_id.map(|id| format!(" /ID {}", id)).unwrap_or_default();
+    format!(
+        "trailer\n<< /Size {} /Root {} 0 R{}{} /Prev {:>w$} >>\nstartxref\n0\n%%EOF\n",
+        size,
+        catalog_number,
+        info,
+        file_id,
+        prev,
+        w = OFFSET_WIDTH
+    )
+    .into_bytes()
+}
+
+fn xref_header(first: u32, count: u32) -> Vec<u8> {
+    format!("xref\n{} {}\n", first, count).into_bytes()
+}
+
+/// A 20-byte cross-reference entry for an object at `offset`
+fn xref_entry(offset: usize) -> String {
+    format!("{:010} 00000 n \n", offset)
+}
+
+fn hint_stream_object(number: u32, data: &[u8], shared_table_offset: usize) -> Vec<u8> {
+    let mut object = format!(
+        "{} 0 obj\n<< /Length {} /S {} >>\nstream\n",
+        number,
+        data.len(),
+        shared_table_offset
+    )
+    .into_bytes();
+    object.extend_from_slice(data);
+    object.extend_from_slice(b"\nendstream\nendobj\n");
+    object
+}
+
+/// Per-page entry of the page offset hint table
+struct PageHint {
+    objects: usize,
+    length: usize,
+    /// Shared object hint table entries the page uses
+    shared: Vec<usize>,
+    content_offset: usize,
+    content_length: usize,
+}
+
+/// The page offset and shared object hint tables (ISO 32000 F.4), with offsets as if the
+/// hint stream weren't in the file
+struct HintTables {
+    pages: Vec<PageHint>,
+    first_page_offset: usize,
+    /// Lengths of the shared object groups: the first page's objects, then shared objects
+    shared_lengths: Vec<usize>,
+    first_page_objects: usize,
+    first_shared_number: u32,
+    first_shared_offset: usize,
+}
+
+impl HintTables {
+    fn new<'a>(
+        sections: &Sections,
+        numbers: &HashMap<ObjectId, u32>,
+        offsets: &HashMap<u32, usize>,
+        chunk: &dyn Fn(u32) -> &'a [u8],
+    ) -> HintTables {
+        let length_of =
+            |ids: &[ObjectId]| -> usize { ids.iter().map(|id| chunk(numbers[id]).len()).sum() };
+        let mut shared_ids: HashMap<ObjectId, usize> = HashMap::new();
+        for (index, &id) in sections
+            .first_page
+            .iter()
+            .chain(&sections.shared)
+            .enumerate()
+        {
+            shared_ids.insert(id, index);
+        }
+
+        let page_hint = |objects: &[ObjectId], shared: &[ObjectId], contents: Option<ObjectId>| {
+            let page_number = numbers[&objects[0]];
+            // Offset of the page's first content stream from the start of the page,
+            // when the stream lies within the page's own objects
+            let (content_offset, content_length) = contents
+                .filter(|id| objects.contains(id))
+                .map(|id| numbers[&id])
+                .map(|number| {
+                    (
+                        offsets[&number] - offsets[&page_number],
+                        chunk(number).len(),
+                    )
+                })
+                .unwrap_or((0, 0));
+            PageHint {
+                objects: objects.len(),
+                length: length_of(objects),
+                shared: shared.iter().map(|id| shared_ids[id]).collect(),
+                content_offset,
+                content_length,
+            }
+        };
+
+        // The first page's objects are all in its own section, so it references no shared objects
+        let mut pages = vec![page_hint(&sections.first_page, &[], sections.contents[0])];
+        for (index, objects) in sections.later_pages.iter().enumerate() {
+            pages.push(page_hint(
+                objects,
+                &sections.later_page_shared[index],
+                sections.contents[index + 1],
+            ));
+        }
+
+        let first_page_number = numbers[&sections.first_page[0]];
+        let (first_shared_number, first_shared_offset) = sections
+            .shared
+            .first()
+            .map(|id| (numbers[id], offsets[&numbers[id]]))
+            .unwrap_or((0, 0));
+        HintTables {
+            pages,
+            first_page_offset: offsets[&first_page_number],
+            shared_lengths: sections
+                .first_page
+                .iter()
+                .chain(&sections.shared)
+                .map(|id| chunk(numbers[id]).len())
+                .collect(),
+            first_page_objects: sections.first_page.len(),
+            first_shared_number,
+            first_shared_offset,
+        }
+    }
+
+    /// Encode both tables, returning the data and the shared object table's offset in it
+    fn encode(&self) -> (Vec<u8>, usize) {
+        let range = |values: &mut dyn Iterator<Item = usize>| {
+            values.fold((usize::MAX, 0), |(least, most), value| {
+                (least.min(value), most.max(value))
+            })
+        };
+        let (least_objects, most_objects) = range(&mut self.pages.iter().map(|p| p.objects));
+        let (least_length, most_length) = range(&mut self.pages.iter().map(|p| p.length));
+        let (least_content_offset, most_content_offset) =
+            range(&mut self.pages.iter().map(|p| p.content_offset));
+        let (least_content_length, most_content_length) =
+            range(&mut self.pages.iter().map(|p| p.content_length));
+        let most_shared = self.pages.iter().map(|p| p.shared.len()).max().unwrap_or(0);
+        let most_shared_id = self
+            .pages
+            .iter()
+            .flat_map(|p| p.shared.iter().copied())
+            .max()
+            .unwrap_or(0);
+
+        let objects_bits = bits_needed(most_objects - least_objects);
+        let length_bits = bits_needed(most_length - least_length);
+        let content_offset_bits = bits_needed(most_content_offset - least_content_offset);
+        let content_length_bits = bits_needed(most_content_length - least_content_length);
+        let shared_count_bits = bits_needed(most_shared);
+        let shared_id_bits = bits_needed(most_shared_id);
+
+        // Page offset hint table: header, then each item for every page in turn
+        let mut bits = BitWriter::default();
+        bits.write(least_objects, 32);
+        bits.write(self.first_page_offset, 32);
+        bits.write(objects_bits as usize, 16);
+        bits.write(least_length, 32);
+        bits.write(length_bits as usize, 16);
+        bits.write(least_content_offset, 32);
+        bits.write(content_offset_bits as usize, 16);
+        bits.write(least_content_length, 32);
+        bits.write(content_length_bits as usize, 16);
+        bits.write(shared_count_bits as usize, 16);
+        bits.write(shared_id_bits as usize, 16);
+        // Fractional positions of shared objects aren't recorded: 0 bits over 1
+        bits.write(0, 16);
+        bits.write(1, 16);
+
+        for page in &self.pages {
+            bits.write(page.objects - least_objects, objects_bits);
+        }
+        bits.flush();
+        for page in &self.pages {
+            bits.write(page.length - least_length, length_bits);
+        }
+        bits.flush();
+        for page in &self.pages {
+            bits.write(page.shared.len(), shared_count_bits);
+        }
+        bits.flush();
+        for page in &self.pages {
+            for &id in &page.shared {
+                bits.write(id, shared_id_bits);
+            }
+        }
+        bits.flush();
+        for page in &self.pages {
+            bits.write(
+                page.content_offset - least_content_offset,
+                content_offset_bits,
+            );
+        }
+        bits.flush();
+        for page in &self.pages {
+            bits.write(
+                page.content_length - least_content_length,
+                content_length_bits,
+            );
+        }
+        bits.flush();
+        let shared_table_offset = bits.bytes.len();
+
+        // Shared object hint table: one object per group
+        let (least_group, most_group) = range(&mut self.shared_lengths.iter().copied());
+        let group_bits = bits_needed(most_group - least_group);
+        bits.write(self.first_shared_number as usize, 32);
+        bits.write(self.first_shared_offset, 32);
+        bits.write(self.first_page_objects, 32);
+        bits.write(self.shared_lengths.len(), 32);
+        bits.write(0, 16);
+        bits.write(least_group, 32);
+        bits.write(group_bits as usize, 16);
+        for &length in &self.shared_lengths {
+            bits.write(length - least_group, group_bits);
+        }
+        bits.flush();
+        // No MD5 signatures
+        for _ in &self.shared_lengths {
+            bits.write(0, 1);
+        }
+        bits.flush();
+
+        (bits.bytes, shared_table_offset)
+    }
+}
+
+fn bits_needed(value: usize) -> u32 {
+    usize::BITS - value.leading_zeros()
+}
+
+/// Writes values most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    filled: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: usize, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.current = (self.current << 1) | ((value >> bit) & 1) as u8;
+            self.filled += 1;
+            if self.filled == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Pad with zeros to the next byte boundary
+    fn flush(&mut self) {
+        if self.filled > 0 {
+            self.write(0, 8 - self.filled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::dictionary;
+
+    /// Three pages sharing a font, each drawing its own text
+    fn document() -> Document {
+        let mut doc = Document::with_version("1.5");
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+        testing::add_page_tree(&mut doc, 3, |doc, index| {
+            let content = format!("BT /F1 12 Tf 10 10 Td (Page {}) Tj ET", index + 1);
+            dictionary! {
+                "Contents" => testing::content(doc, content.as_bytes()),
+                "Resources" => dictionary! { "Font" => dictionary! { "F1" => font_id } },
+            }
+        });
+        let info_id = doc.add_object(dictionary! { "Title" => Object::string_literal("Test") });
+        doc.trailer.set("Info", info_id);
+        doc
+    }
+
+    fn linearized() -> Vec<u8> {
+        linearize_pdf(&testing::save(&mut document())).unwrap()
+    }
+
+    /// The integers following `key` in the linearization dictionary
+    fn linearization_values(pdf: &[u8], key: &str) -> Vec<usize> {
+        let text = String::from_utf8_lossy(&pdf[..pdf.len().min(1024)]);
+        let dict = &text[text.find("/Linearized").unwrap()..];
+        let dict = &dict[..dict.find(">>").unwrap()];
+        let rest = &dict[dict.find(&format!("{} ", key)).unwrap() + key.len()..];
+        rest.split_whitespace()
+            .skip_while(|token| *token == "[")
+            .map_while(|token| token.parse().ok())
+            .collect()
+    }
+
+    fn object_offset(pdf: &[u8], number: usize) -> usize {
+        let header = format!("{} 0 obj", number);
+        pdf.windows(header.len())
+            .position(|window| window == header.as_bytes())
+            .unwrap()
+    }
+
+    /// Reads values most significant bit first, like `BitWriter` writes them
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, bits: u32) -> usize {
+            (0..bits).fold(0, |value, _| {
+                let bit = (self.bytes[self.position / 8] >> (7 - self.position % 8)) & 1;
+                self.position += 1;
+                (value << 1) | bit as usize
+            })
+        }
+
+        fn align(&mut self) {
+            self.position = self.position.div_ceil(8) * 8;
+        }
+    }
+
+    #[test]
+    fn output_reparses_with_the_same_pages() {
+        let pdf = linearized();
+        let doc = Document::load_mem(&pdf).unwrap();
+        let pages = doc.get_pages();
+        assert_eq!(pages.len(), 3);
+        for (number, page_id) in pages {
+            let content = doc.get_page_content(page_id).unwrap();
+            let expected = format!("(Page {})", number);
+            assert!(String::from_utf8_lossy(&content).contains(&expected));
+        }
+        let info = doc.trailer.get(b"Info").unwrap().as_reference().unwrap();
+        assert!(doc.get_dictionary(info).unwrap().has(b"Title"));
+    }
+
+    #[test]
+    fn linearization_dictionary_describes_the_file() {
+        let pdf = linearized();
+        assert!(pdf.starts_with(b"%PDF-1.5"));
+        assert_eq!(linearization_values(&pdf, "/L"), [pdf.len()]);
+        assert_eq!(linearization_values(&pdf, "/N"), [3]);
+
+        // The first page object comes right after the hint stream, and /E is past it
+        let first_page = linearization_values(&pdf, "/O")[0];
+        let doc = Document::load_mem(&pdf).unwrap();
+        assert_eq!(doc.get_pages()[&1], (first_page as u32, 0));
+        let [hint_offset, hint_length] = linearization_values(&pdf, "/H")[..] else {
+            panic!("/H needs an offset and a length");
+        };
+        assert_eq!(object_offset(&pdf, first_page), hint_offset + hint_length);
+        assert!(linearization_values(&pdf, "/E")[0] > hint_offset + hint_length);
+
+        // /T is the white-space before the main cross-reference table's first entry
+        let main_xref_entries = linearization_values(&pdf, "/T")[0];
+        assert!(pdf[main_xref_entries].is_ascii_whitespace());
+        assert!(pdf[main_xref_entries + 1..].starts_with(b"0000000000 65535 f"));
+    }
+
+    #[test]
+    fn cross_reference_entries_point_at_their_objects() {
+        let pdf = linearized();
+        let text = String::from_utf8_lossy(&pdf);
+        let tables: Vec<usize> = text
+            .match_indices("\nxref\n")
+            .map(|(at, _)| at + 1)
+            .collect();
+        assert_eq!(tables.len(), 2);
+        for table in tables {
+            let mut lines = text[table..].lines().skip(1);
+            let mut section = lines.next().unwrap().split_whitespace();
+            let first: usize = section.next().unwrap().parse().unwrap();
+            let count: usize = section.next().unwrap().parse().unwrap();
+            for (number, entry) in (first..first + count).zip(lines) {
+                let (offset, kind) = (&entry[..10], &entry[17..18]);
+                if kind == "n" {
+                    let offset: usize = offset.parse().unwrap();
+                    let header = format!("{} 0 obj", number);
+                    assert!(
+                        pdf[offset..].starts_with(header.as_bytes()),
+                        "object {}",
+                        number
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hint_tables_describe_the_pages() {
+        let pdf = linearized();
+        let [hint_offset, hint_length] = linearization_values(&pdf, "/H")[..] else {
+            panic!("/H needs an offset and a length");
+        };
+        let hint = &pdf[hint_offset..hint_offset + hint_length];
+        let text = String::from_utf8_lossy(hint);
+        let shared_table_offset: usize = text[text.find("/S ").unwrap() + 3..]
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let data = &hint[text.find("stream\n").unwrap() + 7..];
+
+        // Page offset hint table header
+        let mut bits = BitReader {
+            bytes: data,
+            position: 0,
+        };
+        let least_objects = bits.read(32);
+        assert_eq!(bits.read(32), hint_offset);
+        let objects_bits = bits.read(16) as u32;
+        bits.read(32);
+        let length_bits = bits.read(16) as u32;
+        bits.read(32 + 16 + 32 + 16);
+        let shared_count_bits = bits.read(16) as u32;
+        bits.read(16 + 16 + 16);
+
+        // Page 1 holds its content and the font; later pages just their own content and
+        // a reference to the font among the shared objects
+        let objects: Vec<usize> = (0..3)
+            .map(|_| least_objects + bits.read(objects_bits))
+            .collect();
+        assert_eq!(objects, [3, 2, 2]);
+        bits.align();
+        for _ in 0..3 {
+            bits.read(length_bits);
+        }
+        bits.align();
+        let shared: Vec<usize> = (0..3).map(|_| bits.read(shared_count_bits)).collect();
+        assert_eq!(shared, [0, 1, 1]);
+
+        // Shared object hint table: no objects shared only by later pages, and one group
+        // per first-page object
+        let mut bits = BitReader {
+            bytes: data,
+            position: shared_table_offset * 8,
+        };
+        assert_eq!(bits.read(32), 0);
+        assert_eq!(bits.read(32), 0);
+        assert_eq!(bits.read(32), 3);
+        assert_eq!(bits.read(32), 3);
+    }
+
+    #[test]
+    fn bit_writer_packs_most_significant_bit_first() {
+        let mut bits = BitWriter::default();
+        bits.write(0b101, 3);
+        bits.write(1, 1);
+        bits.flush();
+        bits.write(0x1234, 16);
+        assert_eq!(bits.bytes, [0b1011_0000, 0x12, 0x34]);
+        assert_eq!(bits_needed(0), 0);
+        assert_eq!(bits_needed(1), 1);
+        assert_eq!(bits_needed(255), 8);
+        assert_eq!(bits_needed(256), 9);
+    }
+}
@@ -0,0 +1,1036 @@
+use crate::assembly::PdfSource;
+use lopdf::{decode_text_string, dictionary, text_string, Document, Object, ObjectId, Stream};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Info dictionary key and matching XMP property of each text field
+const TEXT_FIELDS: [(&[u8], &str); 6] = [
+    (b"Title", "dc:title"),
+    (b"Author", "dc:creator"),
+    (b"Subject", "dc:description"),
+    (b"Keywords", "pdf:Keywords"),
+    (b"Creator", "xmp:CreatorTool"),
+    (b"Producer", "pdf:Producer"),
+];
+
+/// Info dictionary key and matching XMP property of each date
+const DATE_FIELDS: [(&[u8], &str); 2] = [
+    (b"CreationDate", "xmp:CreateDate"),
+    (b"ModDate", "xmp:ModifyDate"),
+];
+
+/// When the XMP was last written; set alongside the other properties
+const XMP_METADATA_DATE: &str = "xmp:MetadataDate";
+
+const XMP_NAMESPACES: &str = concat!(
+    r#"xmlns:dc="http://purl.org/dc/elements/1.1/" "#,
+    r#"xmlns:xmp="http://ns.adobe.com/xap/1.0/" "#,
+    r#"xmlns:pdf="http://ns.adobe.com/pdf/1.3/""#,
+);
+
+const EMPTY_XMP_PACKET: &str = concat!(
+    "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+    "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+    "</rdf:RDF>\n",
+    "</x:xmpmeta>\n",
+    "<?xpacket end=\"w\"?>",
+);
+
+/// Document information, as shown in a viewer's document properties
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PdfMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    /// The application the document was created in
+    pub creator: Option<String>,
+    /// The application that converted it to PDF
+    pub producer: Option<String>,
+    /// ISO 8601, e.g. "2024-05-01T13:45:00+02:00"; without an offset when the PDF gives none
+    pub creation_date: Option<String>,
+    pub mod_date: Option<String>,
+}
+
+impl PdfMetadata {
+    fn text_fields(&self) -> [&Option<String>; 6] {
+        [
+            &self.title,
+            &self.author,
+            &self.subject,
+            &self.keywords,
+            &self.creator,
+            &self.producer,
+        ]
+    }
+
+    fn text_fields_mut(&mut self) -> [&mut Option<String>; 6] {
+        [
+            &mut self.title,
+            &mut self.author,
+            &mut self.subject,
+            &mut self.keywords,
+            &mut self.creator,
+            &mut self.producer,
+        ]
+    }
+}
+
+/// Read a PDF's metadata. Values come from the Info dictionary, falling back to the XMP
+/// metadata for fields it doesn't have.
+pub fn get_metadata_blocking(source: &PdfSource) -> Result<PdfMetadata, String> {
+    let doc = source.load()?;
+    Ok(read_metadata(&doc))
+}
+
+/// Replace a PDF's metadata in both the Info dictionary and the XMP metadata. Fields left
+/// as None are removed, except `mod_date`, which then becomes the current time.
+pub fn set_metadata_blocking(content: &[u8], metadata: &PdfMetadata) -> Result<Vec<u8>, String> {
+    let mut doc = PdfSource::Content {
+        content: content.to_vec(),
+    }
+    .load()?;
+    write_metadata(&mut doc, metadata)?;
+    doc.prune_objects();
+
+    let mut output = Vec::new();
+    doc.save_to(&mut output)
+        .map_err(|e| format!("Failed to save PDF: {}", e))?;
+    println!("Updated document metadata");
+    Ok(output)
+}
+
+/// The document's metadata, with dates as ISO 8601
+pub fn read_metadata(doc: &Document) -> PdfMetadata {
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|info| doc.dereference(info))
+        .and_then(|(_, info)| info.as_dict())
+        .ok();
+    let info_value = |key: &[u8]| {
+        let (_, value) = doc.dereference(info?.get(key).ok()?).ok()?;
+        decode_text(value).filter(|text| !text.trim().is_empty())
+    };
+    let xmp = xmp_packet(doc).map(|(_, xmp)| xmp);
+    let xmp_value = |property: &str| xmp_property(xmp.as_deref()?, property);
+
+    let mut metadata = PdfMetadata::default();
+    for ((key, property), field) in TEXT_FIELDS.iter().zip(metadata.text_fields_mut()) {
+        *field = info_value(key).or_else(|| xmp_value(property));
+    }
+    let [creation_date, mod_date] = DATE_FIELDS.map(|(key, property)| {
+        let from_info = info_value(key).and_then(|date| PdfDate::parse_pdf(&date));
+        from_info
+            .or_else(|| xmp_value(property).and_then(|date| PdfDate::parse_iso(&date)))
+            .map(|date| date.to_iso())
+    });
+    metadata.creation_date = creation_date;
+    metadata.mod_date = mod_date;
+    metadata
+}
+
+/// Write `metadata` to the Info dictionary and the catalog's XMP packet, creating them if
+/// needed. Fails, changing nothing, if there's an XMP packet that can't be read.
+pub fn write_metadata(doc: &mut Document, metadata: &PdfMetadata) -> Result<(), String> {
+    // A packet this can't edit is refused rather than replaced, which would lose the
+    // properties of other schemas it holds
+    let existing = xmp_packet(doc);
+    let mut xmp = match &existing {
+        Some((_, xmp)) if xmp.contains("</rdf:RDF>") => xmp.clone(),
+        None if !has_xmp_stream(doc) => EMPTY_XMP_PACKET.to_string(),
+        _ => {
+            return Err(
+                "Failed to update metadata: the document's XMP metadata can't be read".to_string(),
+            )
+        }
+    };
+
+    let parse_date = |date: &Option<String>, name: &str| -> Result<Option<PdfDate>, String> {
+        date.as_deref()
+            .map(|date| {
+                PdfDate::parse_iso(date)
+                    .or_else(|| PdfDate::parse_pdf(date))
+                    .ok_or_else(|| format!("Invalid {}: {}", name, date))
+            })
+            .transpose()
+    };
+    let now = PdfDate::now();
+    let dates = [
+        parse_date(&metadata.creation_date, "creation date")?,
+        Some(parse_date(&metadata.mod_date, "modification date")?.unwrap_or(now)),
+    ];
+    let texts = metadata
+        .text_fields()
+        .map(|text| text.as_deref().filter(|text| !text.trim().is_empty()));
+
+    // Info dictionary, keeping keys this doesn't cover (custom keys, /Trapped)
+    let info_ref = doc.trailer.get(b"Info").ok().cloned();
+    let mut info = info_ref
+        .as_ref()
+        .and_then(|info| doc.dereference(info).ok())
+        .and_then(|(_, info)| info.as_dict().ok())
+        .cloned()
+        .unwrap_or_default();
+    for ((key, _), text) in TEXT_FIELDS.iter().zip(texts) {
+        match text {
+            Some(text) => info.set(*key, text_string(text)),
+            None => {
+                info.remove(key);
+            }
+        }
+    }
+    for ((key, _), date) in DATE_FIELDS.iter().zip(dates) {
+        match date {
+            Some(date) => info.set(*key, Object::string_literal(date.to_pdf())),
+            None => {
+                info.remove(key);
+            }
+        }
+    }
+    match info_ref {
+        Some(Object::Reference(id)) => {
+            doc.objects.insert(id, Object::Dictionary(info));
+        }
+        _ => {
+            let id = doc.add_object(info);
+            doc.trailer.set("Info", id);
+        }
+    }
+
+    // XMP, keeping properties of other schemas (PDF/A identification, document IDs)
+    for (_, property) in TEXT_FIELDS.iter().chain(DATE_FIELDS.iter()) {
+        remove_xmp_property(&mut xmp, property);
+    }
+    remove_xmp_property(&mut xmp, XMP_METADATA_DATE);
+
+    let mut properties = String::new();
+    for ((_, property), text) in TEXT_FIELDS.iter().zip(texts) {
+        if let Some(text) = text {
+            properties.push_str(&xmp_element(property, text));
+        }
+    }
+    for ((_, property), date) in DATE_FIELDS.iter().zip(dates) {
+        if let Some(date) = date {
+            properties.push_str(&xmp_element(property, &date.to_iso()));
+        }
+    }
+    properties.push_str(&xmp_element(XMP_METADATA_DATE, &now.to_iso()));
+    remove_empty_descriptions(&mut xmp);
+    add_to_description(&mut xmp, &properties);
+
+    // Left uncompressed so tools that don't parse PDF can still find the packet
+    match existing.and_then(|(id, _)| id) {
+        Some(id) => {
+            if let Ok(Object::Stream(stream)) = doc.get_object_mut(id) {
+                stream.set_plain_content(xmp.into_bytes());
+            }
+        }
+        None => {
+            let stream = Stream::new(
+                dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+                xmp.into_bytes(),
+            );
+            let id = doc.add_object(stream);
+            doc.catalog_mut()
+                .map_err(|e| format!("Failed to read catalog: {}", e))?
+                .set("Metadata", id);
+        }
+    }
+    Ok(())
+}
+
+/// Decode a PDF text string: PDFDocEncoding, or UTF-16BE or UTF-8 with a byte order mark.
+/// Language escapes (`ESC lang ESC`) inside Unicode strings are dropped.
+fn decode_text(object: &Object) -> Option<String> {
+    let bytes = object.as_str().ok()?;
+    let text = match bytes {
+        // lopdf keeps the UTF-8 byte order mark in the text
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        // Not allowed, but written by some tools
+        [0xFF, 0xFE, rest @ ..] => String::from_utf16_lossy(
+            &rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        _ => decode_text_string(object).ok()?,
+    };
+    if !text.contains('\u{1b}') {
+        return Some(text);
+    }
+    Some(
+        text.split('\u{1b}')
+            .enumerate()
+            .filter(|(index, _)| index % 2 == 0)
+            .map(|(_, part)| part)
+            .collect(),
+    )
+}
+
+/// A date as PDF and XMP store it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PdfDate {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    /// Minutes east of UTC; None when the date doesn't say
+    offset: Option<i16>,
+}
+
+impl PdfDate {
+    /// Parse a PDF date, `D:YYYYMMDDHHmmSSOHH'mm'`. Everything after the year is optional,
+    /// and the `D:` prefix and apostrophes are often missing in practice.
+    fn parse_pdf(text: &str) -> Option<PdfDate> {
+        let text = text.trim();
+        let text = text.strip_prefix("D:").unwrap_or(text).as_bytes();
+        let mut pos = 0;
+        let mut digits = |len: usize| -> Option<u32> {
+            let field = text.get(pos..pos + len)?;
+            if !field.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            pos += len;
+            std::str::from_utf8(field).ok()?.parse().ok()
+        };
+
+        let year = digits(4)?;
+        let month = digits(2).unwrap_or(1);
+        let day = digits(2).unwrap_or(1);
+        let hour = digits(2).unwrap_or(0);
+        let minute = digits(2).unwrap_or(0);
+        let second = digits(2).unwrap_or(0);
+        let offset = match &text[pos..] {
+            [] => None,
+            // Some writers follow Z with 00'00'
+            [b'Z', rest @ ..] if rest.iter().all(|&b| b == b'0' || b == b'\'') => Some(0),
+            [sign @ (b'+' | b'-'), rest @ ..] => {
+                let digits: Vec<i16> = rest
+                    .iter()
+                    .filter(|&&b| b != b'\'')
+                    .map(|&b| b.is_ascii_digit().then(|| i16::from(b - b'0')))
+                    .collect::<Option<_>>()?;
+                let (hours, minutes) = match digits[..] {
+                    [h1, h2] => (h1 * 10 + h2, 0),
+                    [h1, h2, m1, m2] => (h1 * 10 + h2, m1 * 10 + m2),
+                    _ => return None,
+                };
+                if hours >= 24 || minutes >= 60 {
+                    return None;
+                }
+                let offset = hours * 60 + minutes;
+                Some(if *sign == b'-' { -offset } else { offset })
+            }
+            _ => return None,
+        };
+        PdfDate::new(year, month, day, hour, minute, second, offset)
+    }
+
+    /// Parse an XMP (ISO 8601) date: `YYYY`, `YYYY-MM` or `YYYY-MM-DD`, optionally followed
+    /// by `Thh:mm`, `:ss`, fractional seconds and `Z` or `±hh:mm`
+    fn parse_iso(text: &str) -> Option<PdfDate> {
+        let text = text.trim();
+        let number = |field: &str| -> Option<u32> {
+            if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            field.parse().ok()
+        };
+
+        let (date, time) = match text.split_once('T') {
+            Some((date, time)) => (date, Some(time)),
+            None => (text, None),
+        };
+        let mut date_parts = date.split('-');
+        let year = date_parts
+            .next()
+            .filter(|year| year.len() == 4)
+            .and_then(number)?;
+        let month = date_parts.next().map(number).unwrap_or(Some(1))?;
+        let day = date_parts.next().map(number).unwrap_or(Some(1))?;
+        if date_parts.next().is_some() {
+            return None;
+        }
+
+        let Some(time) = time else {
+            return PdfDate::new(year, month, day, 0, 0, 0, None);
+        };
+        let (time, offset) = if let Some(time) = time.strip_suffix('Z') {
+            (time, Some(0))
+        } else if let Some(sign_at) = time.rfind(['+', '-']) {
+            let (hours, minutes) = time[sign_at + 1..].split_once(':')?;
+            let (hours, minutes) = (number(hours)?, number(minutes)?);
+            if hours >= 24 || minutes >= 60 {
+                return None;
+            }
+            let offset = (hours * 60 + minutes) as i16;
+            let offset = if time[sign_at..].starts_with('-') {
+                -offset
+            } else {
+                offset
+            };
+            (&time[..sign_at], Some(offset))
+        } else {
+            (time, None)
+        };
+        let mut time_parts = time.split(':');
+        let hour = number(time_parts.next()?)?;
+        let minute = number(time_parts.next()?)?;
+        let second = match time_parts.next() {
+            // Fractional seconds are dropped; PDF dates don't have them
+            Some(second) => number(second.split('.').next()?)?,
+            None => 0,
+        };
+        if time_parts.next().is_some() {
+            return None;
+        }
+        PdfDate::new(year, month, day, hour, minute, second, offset)
+    }
+
+    fn new(
+        year: u32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+        second: u32,
+        offset: Option<i16>,
+    ) -> Option<PdfDate> {
+        let valid = (1..=12).contains(&month)
+            && (1..=days_in_month(year, month)).contains(&day)
+            && hour < 24
+            && minute < 60
+            && second < 60;
+        valid.then_some(PdfDate {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: hour as u8,
+            minute: minute as u8,
+            second: second as u8,
+            offset,
+        })
+    }
+
+    /// The current time, in UTC
+    fn now() -> PdfDate {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as i64)
+            .unwrap_or(0);
+        let (days, time) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+
+        // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        PdfDate {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time % 3600 / 60) as u8,
+            second: (time % 60) as u8,
+            offset: Some(0),
+        }
+    }
+
+    fn to_pdf(self) -> String {
+        let date = format!(
+            "D:{:04}{:02}{:02}{:02}{:02}{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        );
+        match self.offset {
+            None => date,
+            Some(0) => date + "Z",
+            Some(offset) => format!(
+                "{}{}{:02}'{:02}'",
+                date,
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            ),
+        }
+    }
+
+    fn to_iso(self) -> String {
+        let date = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        );
+        match self.offset {
+            None => date,
+            Some(0) => date + "Z",
+            Some(offset) => format!(
+                "{}{}{:02}:{:02}",
+                date,
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60,
+                offset.abs() % 60
+            ),
+        }
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The catalog's XMP packet and the stream it's in, when it's UTF-8 text
+fn xmp_packet(doc: &Document) -> Option<(Option<ObjectId>, String)> {
+    let metadata = doc.catalog().ok()?.get(b"Metadata").ok()?;
+    let (id, stream) = doc.dereference(metadata).ok()?;
+    let content = stream.as_stream().ok()?.get_plain_content().ok()?;
+    String::from_utf8(content).ok().map(|xmp| (id, xmp))
+}
+
+/// A property's value in an XMP packet, written either as an element or as an attribute
+/// of `rdf:Description`. Language alternatives give their default; lists are joined
+/// with "; ".
+fn xmp_property(xmp: &str, name: &str) -> Option<String> {
+    let value = if let Some((_, content)) = find_xmp_element(xmp, name) {
+        let content = &xmp[content];
+        let items = xmp_list_items(content);
+        if items.is_empty() {
+            xml_unescape(content.trim())
+        } else if content.contains("<rdf:Alt") {
+            let default = items
+                .iter()
+                .find(|(lang, _)| lang.as_deref() == Some("x-default"));
+            default.unwrap_or(&items[0]).1.clone()
+        } else {
+            let items: Vec<String> = items.into_iter().map(|(_, text)| text).collect();
+            items.join("; ")
+        }
+    } else {
+        let (_, value) = find_xmp_attribute(xmp, name)?;
+        xml_unescape(&xmp[value])
+    };
+    Some(value).filter(|value| !value.trim().is_empty())
+}
+
+/// `rdf:li` entries in an element's content, with their `xml:lang`
+fn xmp_list_items(content: &str) -> Vec<(Option<String>, String)> {
+    let mut items = Vec::new();
+    let mut rest = content;
+    while let Some((whole, text)) = find_xmp_element(rest, "rdf:li") {
+        let tag = &rest[whole.start..text.start];
+        let lang = find_xmp_attribute(tag, "xml:lang").map(|(_, lang)| tag[lang].to_string());
+        items.push((lang, xml_unescape(rest[text].trim())));
+        rest = &rest[whole.end..];
+    }
+    items
+}
+
+/// Byte ranges of the first `<name ...>...</name>` element and of its content
+fn find_xmp_element(xmp: &str, name: &str) -> Option<(Range<usize>, Range<usize>)> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut from = 0;
+    while let Some(found) = xmp[from..].find(&open) {
+        let start = from + found;
+        let after = start + open.len();
+        from = after;
+        let ends_name = xmp[after..]
+            .chars()
+            .next()
+            .is_some_and(|c| c == '>' || c == '/' || c.is_whitespace());
+        if !ends_name {
+            continue;
+        }
+        let tag_end = after + xmp[after..].find('>')? + 1;
+        if xmp[..tag_end].ends_with("/>") {
+            return Some((start..tag_end, tag_end..tag_end));
+        }
+        let content_end = tag_end + xmp[tag_end..].find(&close)?;
+        return Some((start..content_end + close.len(), tag_end..content_end));
+    }
+    None
+}
+
+/// Byte ranges of the first ` name="value"` attribute, including the space before it,
+/// and of its value
+fn find_xmp_attribute(xmp: &str, name: &str) -> Option<(Range<usize>, Range<usize>)> {
+    let pattern = format!("{}=", name);
+    let mut from = 0;
+    while let Some(found) = xmp[from..].find(&pattern) {
+        let start = from + found;
+        from = start + pattern.len();
+        let after_space = xmp[..start].ends_with(char::is_whitespace);
+        let quote = xmp[from..].chars().next();
+        let (true, Some(quote @ ('"' | '\''))) = (after_space, quote) else {
+            continue;
+        };
+        let value_start = from + 1;
+        let value_end = value_start + xmp[value_start..].find(quote)?;
+        return Some((start - 1..value_end + 1, value_start..value_end));
+    }
+    None
+}
+
+/// Whether the catalog has an XMP stream, readable or not
+fn has_xmp_stream(doc: &Document) -> bool {
+    doc.catalog()
+        .and_then(|catalog| catalog.get(b"Metadata"))
+        .and_then(|metadata| doc.dereference(metadata))
+        .is_ok_and(|(_, metadata)| metadata.as_stream().is_ok())
+}
+
+/// Add property elements to the packet's first `rdf:Description`, declaring the
+/// namespaces they use, or to a new one if there is none
+fn add_to_description(xmp: &mut String, properties: &str) {
+    let Some((whole, content)) = find_xmp_element(xmp, "rdf:Description") else {
+        let description = format!(
+            "<rdf:Description rdf:about=\"\" {}>\n{}</rdf:Description>\n",
+            XMP_NAMESPACES, properties
+        );
+        let end = xmp.rfind("</rdf:RDF>").expect("packet has an RDF element");
+        xmp.insert_str(end, &description);
+        return;
+    };
+    let tag = &xmp[whole.start..content.start];
+    let self_closing = tag.ends_with("/>");
+    let mut open_tag = tag
+        .trim_end_matches('>')
+        .trim_end_matches('/')
+        .trim_end()
+        .to_string();
+    for namespace in XMP_NAMESPACES.split_whitespace() {
+        let (prefix, _) = namespace.split_once('=').expect("namespace declaration");
+        if find_xmp_attribute(&open_tag, prefix).is_none() {
+            open_tag.push(' ');
+            open_tag.push_str(namespace);
+        }
+    }
+    open_tag.push('>');
+    if self_closing {
+        let element = format!("{}\n{}</rdf:Description>", open_tag, properties);
+        xmp.replace_range(whole, &element);
+    } else {
+        xmp.insert_str(content.end, properties);
+        xmp.replace_range(whole.start..content.start, &open_tag);
+    }
+}
+
+/// Remove `rdf:Description` elements left with neither property elements nor property
+/// attributes
+fn remove_empty_descriptions(xmp: &mut String) {
+    let mut from = 0;
+    while let Some((whole, content)) = find_xmp_element(&xmp[from..], "rdf:Description") {
+        let (whole, content) = (
+            from + whole.start..from + whole.end,
+            from + content.start..from + content.end,
+        );
+        let tag = &xmp[whole.start..content.start];
+        if xmp[content].trim().is_empty() && !has_property_attributes(tag) {
+            let end = whole.end + xmp[whole.end..].len() - xmp[whole.end..].trim_start().len();
+            xmp.replace_range(whole.start..end, "");
+            from = whole.start;
+        } else {
+            from = whole.end;
+        }
+    }
+}
+
+/// Whether an `rdf:Description` start tag has attributes besides `rdf:about` and
+/// namespace declarations
+fn has_property_attributes(tag: &str) -> bool {
+    let mut rest = tag.trim_start_matches("<rdf:Description");
+    while let Some((name, after)) = rest.split_once('=') {
+        let name = name.trim();
+        if name != "rdf:about" && name != "xmlns" && !name.starts_with("xmlns:") {
+            return true;
+        }
+        let after = after.trim_start();
+        let Some(quote) = after.chars().next() else {
+            break;
+        };
+        let value = &after[quote.len_utf8()..];
+        rest = match value.find(quote) {
+            Some(end) => &value[end + quote.len_utf8()..],
+            None => break,
+        };
+    }
+    false
+}
+
+/// Remove every occurrence of a property, as an element or as an attribute
+fn remove_xmp_property(xmp: &mut String, name: &str) {
+    while let Some((whole, _)) = find_xmp_element(xmp, name) {
+        xmp.replace_range(whole, "");
+    }
+    while let Some((whole, _)) = find_xmp_attribute(xmp, name) {
+        xmp.replace_range(whole, "");
+    }
+}
+
+/// A property element, with the structure XMP prescribes for it
+fn xmp_element(name: &str, value: &str) -> String {
+    let value = xml_escape(value);
+    match name {
+        "dc:title" | "dc:description" => format!(
+            "<{0}><rdf:Alt><rdf:li xml:lang=\"x-default\">{1}</rdf:li></rdf:Alt></{0}>\n",
+            name, value
+        ),
+        "dc:creator" => format!(
+            "<{0}><rdf:Seq><rdf:li>{1}</rdf:li></rdf:Seq></{0}>\n",
+            name, value
+        ),
+        _ => format!("<{0}>{1}</{0}>\n", name, value),
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_unescape(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        output.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semicolon) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..semicolon];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                output.push(c);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn date(
+        (year, month, day): (u32, u32, u32),
+        (hour, minute, second): (u32, u32, u32),
+        offset: Option<i16>,
+    ) -> Option<PdfDate> {
+        PdfDate::new(year, month, day, hour, minute, second, offset)
+    }
+
+    /// A one-page PDF with an Info dictionary holding `info`
+    fn pdf(info: lopdf::Dictionary) -> Vec<u8> {
+        let (mut doc, _) = testing::document(1, |_, _| lopdf::Dictionary::new());
+        let info_id = doc.add_object(info);
+        doc.trailer.set("Info", info_id);
+        testing::save(&mut doc)
+    }
+
+    fn metadata_of(content: &[u8]) -> PdfMetadata {
+        get_metadata_blocking(&PdfSource::Content {
+            content: content.to_vec(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_pdf_dates() {
+        let parse = PdfDate::parse_pdf;
+        assert_eq!(
+            parse("D:20240501134500+02'00'"),
+            date((2024, 5, 1), (13, 45, 0), Some(120))
+        );
+        assert_eq!(
+            parse("D:20240501134500-05'30"),
+            date((2024, 5, 1), (13, 45, 0), Some(-330))
+        );
+        assert_eq!(
+            parse("D:20240501134500Z00'00'"),
+            date((2024, 5, 1), (13, 45, 0), Some(0))
+        );
+        assert_eq!(
+            parse("20240501134500+0200"),
+            date((2024, 5, 1), (13, 45, 0), Some(120))
+        );
+        assert_eq!(parse("D:2024"), date((2024, 1, 1), (0, 0, 0), None));
+        assert_eq!(parse("D:20240229"), date((2024, 2, 29), (0, 0, 0), None));
+
+        assert_eq!(parse("D:20230229"), None);
+        assert_eq!(parse("D:20241301"), None);
+        assert_eq!(parse("D:20240501134500+25'00'"), None);
+        assert_eq!(parse("D:20240501134500 junk"), None);
+        assert_eq!(parse("May 1, 2024"), None);
+    }
+
+    #[test]
+    fn parses_iso_dates() {
+        let parse = PdfDate::parse_iso;
+        assert_eq!(
+            parse("2024-05-01T13:45:00.250+02:00"),
+            date((2024, 5, 1), (13, 45, 0), Some(120))
+        );
+        assert_eq!(
+            parse("2024-05-01T13:45-05:30"),
+            date((2024, 5, 1), (13, 45, 0), Some(-330))
+        );
+        assert_eq!(
+            parse("2024-05-01T13:45:30Z"),
+            date((2024, 5, 1), (13, 45, 30), Some(0))
+        );
+        assert_eq!(
+            parse("2024-05-01T13:45:30"),
+            date((2024, 5, 1), (13, 45, 30), None)
+        );
+        assert_eq!(parse("2024-05"), date((2024, 5, 1), (0, 0, 0), None));
+
+        assert_eq!(parse("24-05-01"), None);
+        assert_eq!(parse("2024-05-32"), None);
+        assert_eq!(parse("2024-05-01T13"), None);
+        assert_eq!(parse("2024-05-01T13:45+0200"), None);
+    }
+
+    #[test]
+    fn formats_dates_for_pdf_and_xmp() {
+        let east = date((2024, 5, 1), (13, 45, 0), Some(120)).unwrap();
+        assert_eq!(east.to_pdf(), "D:20240501134500+02'00'");
+        assert_eq!(east.to_iso(), "2024-05-01T13:45:00+02:00");
+        let west = date((2024, 5, 1), (13, 45, 0), Some(-330)).unwrap();
+        assert_eq!(west.to_pdf(), "D:20240501134500-05'30'");
+        assert_eq!(west.to_iso(), "2024-05-01T13:45:00-05:30");
+        let utc = date((2024, 5, 1), (13, 45, 0), Some(0)).unwrap();
+        assert_eq!(utc.to_pdf(), "D:20240501134500Z");
+        assert_eq!(utc.to_iso(), "2024-05-01T13:45:00Z");
+        let local = date((2024, 5, 1), (13, 45, 0), None).unwrap();
+        assert_eq!(PdfDate::parse_pdf(&local.to_pdf()), Some(local));
+        assert_eq!(PdfDate::parse_iso(&local.to_iso()), Some(local));
+    }
+
+    #[test]
+    fn decodes_text_strings() {
+        let decode = |bytes: &[u8]| decode_text(&Object::string_literal(bytes.to_vec()));
+        assert_eq!(decode(b"Caf\xe9").as_deref(), Some("Café"));
+        assert_eq!(
+            decode(b"\xfe\xff\x00H\x00i\x00 \x4e\x16").as_deref(),
+            Some("Hi 世")
+        );
+        assert_eq!(decode(b"\xff\xfeH\x00i\x00").as_deref(), Some("Hi"));
+        assert_eq!(
+            decode("\u{feff}Zürich".as_bytes()).as_deref(),
+            Some("Zürich")
+        );
+        // ESC en ESC before the text marks it as English
+        assert_eq!(
+            decode(b"\xfe\xff\x00\x1ben\x00\x1b\x00H\x00i").as_deref(),
+            Some("Hi")
+        );
+        assert_eq!(decode_text(&Object::Integer(1)), None);
+    }
+
+    #[test]
+    fn finds_xmp_elements_and_attributes() {
+        let xmp = concat!(
+            r#"<rdf:Description rdf:about="" pdf:Producer="Tool &amp; Co" xmp:CreatorTool='App'>"#,
+            "<dc:titles>wrong</dc:titles>",
+            r#"<dc:title><rdf:Alt><rdf:li xml:lang="de">Titel</rdf:li>"#,
+            r#"<rdf:li xml:lang="x-default">Title</rdf:li></rdf:Alt></dc:title>"#,
+            "<dc:creator><rdf:Seq><rdf:li>Ann</rdf:li><rdf:li>Bo</rdf:li></rdf:Seq></dc:creator>",
+            "<pdf:Keywords/>",
+            "<xmp:CreateDate>2024-05-01</xmp:CreateDate>",
+            "</rdf:Description>",
+        );
+
+        let (whole, content) = find_xmp_element(xmp, "xmp:CreateDate").unwrap();
+        assert_eq!(&xmp[whole], "<xmp:CreateDate>2024-05-01</xmp:CreateDate>");
+        assert_eq!(&xmp[content], "2024-05-01");
+        let (whole, content) = find_xmp_element(xmp, "pdf:Keywords").unwrap();
+        assert_eq!(&xmp[whole], "<pdf:Keywords/>");
+        assert!(content.is_empty());
+        assert!(find_xmp_element(xmp, "dc:subject").is_none());
+
+        assert_eq!(xmp_property(xmp, "dc:title").as_deref(), Some("Title"));
+        assert_eq!(xmp_property(xmp, "dc:creator").as_deref(), Some("Ann; Bo"));
+        assert_eq!(
+            xmp_property(xmp, "pdf:Producer").as_deref(),
+            Some("Tool & Co")
+        );
+        assert_eq!(xmp_property(xmp, "xmp:CreatorTool").as_deref(), Some("App"));
+        assert_eq!(xmp_property(xmp, "pdf:Keywords"), None);
+
+        let mut edited = xmp.to_string();
+        remove_xmp_property(&mut edited, "dc:title");
+        remove_xmp_property(&mut edited, "pdf:Producer");
+        assert!(!edited.contains("Title") && !edited.contains("Producer"));
+        assert!(edited.contains("<dc:titles>wrong</dc:titles>"));
+    }
+
+    #[test]
+    fn written_metadata_reads_back() {
+        let content = pdf(dictionary! {
+            "Title" => Object::string_literal("Old"),
+            "Custom" => Object::string_literal("kept"),
+        });
+        let metadata = PdfMetadata {
+            title: Some("Zürich – 東京 <draft>".to_string()),
+            author: Some("Ann".to_string()),
+            subject: Some("Cities".to_string()),
+            keywords: Some("travel, maps".to_string()),
+            creator: Some("Writer".to_string()),
+            producer: None,
+            creation_date: Some("2024-05-01T13:45:00+02:00".to_string()),
+            mod_date: None,
+        };
+        let updated = set_metadata_blocking(&content, &metadata).unwrap();
+
+        let read = metadata_of(&updated);
+        assert_eq!(read.title, metadata.title);
+        assert_eq!(read.author, metadata.author);
+        assert_eq!(read.subject, metadata.subject);
+        assert_eq!(read.keywords, metadata.keywords);
+        assert_eq!(read.creator, metadata.creator);
+        assert_eq!(read.producer, None);
+        assert_eq!(read.creation_date, metadata.creation_date);
+        // Left unset, the modification date becomes the time of writing
+        assert!(read.mod_date.is_some_and(|date| date.ends_with('Z')));
+
+        // The Info dictionary keeps keys it doesn't cover; XMP holds the same values
+        let doc = Document::load_mem(&updated).unwrap();
+        let info = doc.trailer.get(b"Info").unwrap().as_reference().unwrap();
+        let info = doc.get_dictionary(info).unwrap();
+        assert!(info.has(b"Custom") && !info.has(b"Producer"));
+        assert_eq!(
+            info.get(b"CreationDate").unwrap().as_str().unwrap(),
+            b"D:20240501134500+02'00'"
+        );
+        let (_, xmp) = xmp_packet(&doc).unwrap();
+        assert_eq!(xmp_property(&xmp, "dc:title"), metadata.title);
+        assert_eq!(xmp_property(&xmp, "dc:creator"), metadata.author);
+        assert_eq!(xmp_property(&xmp, "xmp:CreateDate"), metadata.creation_date);
+    }
+
+    #[test]
+    fn falls_back_to_xmp_and_replaces_it_in_place() {
+        let content = pdf(dictionary! {});
+        let metadata = PdfMetadata {
+            title: Some("First".to_string()),
+            ..PdfMetadata::default()
+        };
+        let updated = set_metadata_blocking(&content, &metadata).unwrap();
+
+        // A title only in the XMP still reads back
+        let mut doc = Document::load_mem(&updated).unwrap();
+        let info = doc.trailer.get(b"Info").unwrap().as_reference().unwrap();
+        doc.get_dictionary_mut(info).unwrap().remove(b"Title");
+        assert_eq!(read_metadata(&doc).title.as_deref(), Some("First"));
+
+        // Writing again replaces the properties rather than adding a second set
+        let metadata = PdfMetadata {
+            title: Some("Second".to_string()),
+            ..PdfMetadata::default()
+        };
+        write_metadata(&mut doc, &metadata).unwrap();
+        let (_, xmp) = xmp_packet(&doc).unwrap();
+        assert_eq!(xmp.matches("<dc:title>").count(), 1);
+        assert_eq!(xmp.matches("<xmp:MetadataDate>").count(), 1);
+        assert_eq!(xmp_property(&xmp, "dc:title").as_deref(), Some("Second"));
+    }
+
+    /// A document whose catalog has an XMP stream holding `xmp`
+    fn with_xmp(xmp: &[u8]) -> Document {
+        let (mut doc, _) = testing::document(1, |_, _| lopdf::Dictionary::new());
+        let xmp_id = doc.add_object(Stream::new(
+            dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+            xmp.to_vec(),
+        ));
+        doc.catalog_mut().unwrap().set("Metadata", xmp_id);
+        doc
+    }
+
+    #[test]
+    fn writes_into_the_existing_description() {
+        let packet = EMPTY_XMP_PACKET.replace(
+            "</rdf:RDF>",
+            concat!(
+                "<rdf:Description rdf:about=\"\" ",
+                "xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\" ",
+                "pdfaid:part=\"2\" pdfaid:conformance=\"B\"/>\n",
+                "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+                "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">Old</rdf:li></rdf:Alt></dc:title>\n",
+                "</rdf:Description>\n",
+                "</rdf:RDF>"
+            ),
+        );
+        let mut doc = with_xmp(packet.as_bytes());
+        for title in ["First", "Second", "Third"] {
+            let metadata = PdfMetadata {
+                title: Some(title.to_string()),
+                ..PdfMetadata::default()
+            };
+            write_metadata(&mut doc, &metadata).unwrap();
+        }
+
+        let (_, xmp) = xmp_packet(&doc).unwrap();
+        assert_eq!(xmp.matches("<rdf:Description").count(), 1);
+        assert_eq!(xmp.matches("xmlns:dc=").count(), 1);
+        assert!(xmp.contains("pdfaid:part=\"2\" pdfaid:conformance=\"B\""));
+        assert_eq!(xmp_property(&xmp, "dc:title").as_deref(), Some("Third"));
+        assert_eq!(xmp_property(&xmp, "pdfaid:part").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn refuses_to_replace_unreadable_xmp() {
+        let metadata = PdfMetadata {
+            title: Some("Title".to_string()),
+            ..PdfMetadata::default()
+        };
+        for packet in [&b"<x:xmpmeta>\xff\xfe</x:xmpmeta>"[..], b"<x:xmpmeta/>"] {
+            let mut doc = with_xmp(packet);
+            assert!(write_metadata(&mut doc, &metadata).is_err());
+            assert!(doc.trailer.get(b"Info").is_err());
+        }
+    }
+}
@@ -0,0 +1,850 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+
+/// Folding duplicates can expose more duplicates; stop after this many rounds
+const MAX_DEDUPE_ROUNDS: u32 = 4;
+
+/// Image dictionary entries that don't affect rendering: bookkeeping, the obsolete
+/// PDF 1.0 resource name, and XMP metadata
+const IMAGE_IGNORED_KEYS: &[&[u8]] = &[b"Length", b"Name", b"Metadata"];
+
+/// Name trees deeper than this are treated as malformed (or cyclic) and left alone
+const MAX_NAME_TREE_DEPTH: u32 = 32;
+
+/// What the lossless structural passes changed
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StructureReport {
+    /// Streams whose data got smaller when deflated at the highest level
+    pub streams_redeflated: u32,
+    pub redeflate_bytes_saved: usize,
+    /// Byte-identical streams folded into a single object
+    pub duplicate_streams: u32,
+    pub duplicate_bytes_saved: usize,
+    pub thumbnails_removed: u32,
+    pub piece_info_removed: u32,
+    pub named_dests_removed: u32,
+}
+
+/// Images and fonts folded into a single copy
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupReport {
+    pub images: u32,
+    /// Font programs, CMaps, descriptors and font dictionaries
+    pub fonts: u32,
+    /// Stream data no longer stored twice
+    pub bytes_saved: usize,
+}
+
+/// Run every lossless structural pass over the document. None of them change how pages
+/// render: only data viewers never draw is removed, and stream data is re-encoded losslessly.
+pub fn optimize_structure(doc: &mut Document) -> StructureReport {
+    let mut report = StructureReport::default();
+
+    remove_cruft(doc, &mut report);
+    remove_unused_named_dests(doc, &mut report);
+    dedupe_streams(doc, &mut report);
+
+    // Drop everything the passes above orphaned before spending time deflating it
+    doc.prune_objects();
+    redeflate_streams(doc, &mut report);
+
+    report
+}
+
+/// Zlib-compress data at the highest compression level
+pub fn deflate_best(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+/// Remove the document information dictionary (`/Info`) and every XMP `/Metadata` stream
+/// reference, on the catalog, pages, images and fonts alike. The orphaned objects are left
+/// for `prune_objects`. Returns how many entries were removed.
+pub fn strip_metadata(doc: &mut Document) -> u32 {
+    let mut removed = u32::from(doc.trailer.remove(b"Info").is_some());
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if dict.remove(b"Metadata").is_some() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Remove page thumbnails and application private data (`/PieceInfo`).
+/// Viewers regenerate thumbnails themselves, and PieceInfo is only read by the authoring app.
+fn remove_cruft(doc: &mut Document, report: &mut StructureReport) {
+    for page_id in doc.get_pages().into_values() {
+        if let Ok(page) = doc.get_dictionary_mut(page_id) {
+            if page.remove(b"Thumb").is_some() {
+                report.thumbnails_removed += 1;
+            }
+        }
+    }
+
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        // PieceInfo is allowed on the catalog, pages and form XObjects
+        let owns_piece_info =
+            matches!(
+                dict.get(b"Type").and_then(Object::as_name),
+                Ok(b"Catalog" | b"Page")
+            ) || matches!(dict.get(b"Subtype").and_then(Object::as_name), Ok(b"Form"));
+        if owns_piece_info && dict.remove(b"PieceInfo").is_some() {
+            report.piece_info_removed += 1;
+        }
+    }
+}
+
+/// Remove named destinations nothing in the document links to, from both the
+/// catalog `/Dests` dictionary and the `/Names` `/Dests` name tree.
+/// Documents with JavaScript are left alone, since scripts can jump to a destination by name.
+fn remove_unused_named_dests(doc: &mut Document, report: &mut StructureReport) {
+    let mut used: HashSet<Vec<u8>> = HashSet::new();
+    let mut has_javascript = false;
+    for object in doc.objects.values() {
+        collect_dest_names(object, &mut used, &mut has_javascript);
+    }
+    if has_javascript {
+        return;
+    }
+
+    // Catalog /Dests: a dictionary from names to destinations (PDF 1.1)
+    let dests_id = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Dests").ok())
+        .and_then(|dests| dests.as_reference().ok());
+    let dests = match dests_id {
+        Some(id) => doc.get_dictionary_mut(id).ok(),
+        None => doc
+            .catalog_mut()
+            .ok()
+            .and_then(|catalog| catalog.get_mut(b"Dests").ok())
+            .and_then(|dests| dests.as_dict_mut().ok()),
+    };
+    if let Some(dests) = dests {
+        let before = dests.len();
+        let unused: Vec<Vec<u8>> = dests
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| !used.contains(name))
+            .collect();
+        for name in unused {
+            dests.remove(&name);
+        }
+        report.named_dests_removed += (before - dests.len()) as u32;
+    }
+
+    // /Names /Dests: a name tree from strings to destinations (PDF 1.2)
+    let names = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Names").ok())
+        .and_then(|names| doc.dereference(names).ok())
+        .map(|(id, names)| (id, names.as_dict().ok().cloned()));
+    let Some((names_id, Some(mut names))) = names else {
+        return;
+    };
+    let Some((root_id, mut root)) = names
+        .get(b"Dests")
+        .ok()
+        .and_then(|root| doc.dereference(root).ok())
+        .and_then(|(id, root)| Some((id, root.as_dict().ok()?.clone())))
+    else {
+        return;
+    };
+
+    // Kid nodes are only written back once the whole tree has been walked successfully
+    let mut updated_kids = Vec::new();
+    let Some((removed, kept)) = prune_dest_tree(doc, &mut root, &used, &mut updated_kids, 0) else {
+        return;
+    };
+    report.named_dests_removed += removed;
+    for (kid_id, kid_node) in updated_kids {
+        doc.objects.insert(kid_id, Object::Dictionary(kid_node));
+    }
+    // The root carries no Limits
+    root.remove(b"Limits");
+
+    if kept.is_none() {
+        names.remove(b"Dests");
+    } else if let Some(root_id) = root_id {
+        doc.objects.insert(root_id, Object::Dictionary(root));
+    } else {
+        names.set("Dests", Object::Dictionary(root));
+    }
+    match names_id {
+        Some(id) => {
+            doc.objects.insert(id, Object::Dictionary(names));
+        }
+        None => {
+            if let Ok(catalog) = doc.catalog_mut() {
+                catalog.set("Names", Object::Dictionary(names));
+            }
+        }
+    }
+}
+
+/// Record every destination name an outline item, link or GoTo action refers to
+fn collect_dest_names(object: &Object, used: &mut HashSet<Vec<u8>>, has_javascript: &mut bool) {
+    let dict = match object {
+        Object::Dictionary(dict) => dict,
+        Object::Stream(stream) => &stream.dict,
+        Object::Array(arr) => {
+            for item in arr {
+                collect_dest_names(item, used, has_javascript);
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    if dict.has(b"JS") || matches!(dict.get(b"S").and_then(Object::as_name), Ok(b"JavaScript")) {
+        *has_javascript = true;
+    }
+    for (key, value) in dict.iter() {
+        if key == b"Dest" || key == b"D" {
+            match value {
+                Object::String(name, _) => {
+                    used.insert(name.clone());
+                }
+                Object::Name(name) => {
+                    used.insert(name.clone());
+                }
+                _ => {}
+            }
+        }
+        collect_dest_names(value, used, has_javascript);
+    }
+}
+
+/// First and last key of a name tree node
+type NameLimits = (Vec<u8>, Vec<u8>);
+
+/// Drop unused entries from a destination name tree node and its kids, keeping `/Limits`
+/// accurate. Changed kid nodes are collected in `updated_kids` rather than written back.
+/// Returns the number of entries removed and the first/last key still in the node,
+/// or None if the tree is malformed and should be left untouched.
+fn prune_dest_tree(
+    doc: &Document,
+    node: &mut Dictionary,
+    used: &HashSet<Vec<u8>>,
+    updated_kids: &mut Vec<(ObjectId, Dictionary)>,
+    depth: u32,
+) -> Option<(u32, Option<NameLimits>)> {
+    if depth > MAX_NAME_TREE_DEPTH {
+        return None;
+    }
+
+    let mut removed = 0;
+    let mut first: Option<Vec<u8>> = None;
+    let mut last: Option<Vec<u8>> = None;
+
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        let mut kept_kids = Vec::new();
+        for kid in kids.clone() {
+            let kid_id = kid.as_reference().ok()?;
+            let mut kid_node = doc.get_dictionary(kid_id).ok()?.clone();
+            let (kid_removed, kid_limits) =
+                prune_dest_tree(doc, &mut kid_node, used, updated_kids, depth + 1)?;
+            removed += kid_removed;
+            if let Some((kid_first, kid_last)) = kid_limits {
+                first.get_or_insert(kid_first);
+                last = Some(kid_last);
+                updated_kids.push((kid_id, kid_node));
+                kept_kids.push(kid);
+            }
+        }
+        node.set("Kids", Object::Array(kept_kids));
+    }
+
+    if let Ok(entries) = node.get(b"Names").and_then(Object::as_array) {
+        let mut kept_entries = Vec::new();
+        for pair in entries.chunks(2) {
+            let [key, value] = pair else {
+                continue;
+            };
+            // Only string keys can be matched; keep anything unexpected
+            if let Ok(name) = key.as_str() {
+                if !used.contains(name) {
+                    removed += 1;
+                    continue;
+                }
+                first.get_or_insert_with(|| name.to_vec());
+                last = Some(name.to_vec());
+            }
+            kept_entries.push(key.clone());
+            kept_entries.push(value.clone());
+        }
+        node.set("Names", Object::Array(kept_entries));
+    }
+
+    let limits = first.zip(last);
+    if let Some((first, last)) = &limits {
+        node.set(
+            "Limits",
+            Object::Array(vec![
+                Object::string_literal(first.clone()),
+                Object::string_literal(last.clone()),
+            ]),
+        );
+    }
+    Some((removed, limits))
+}
+
+/// Point every reference to a byte-identical stream at one copy and drop the others.
+/// Streams match when both their data and dictionaries are equal.
+fn dedupe_streams(doc: &mut Document, report: &mut StructureReport) {
+    // Group by content hash first; only streams in the same group are compared in full
+    let mut groups: HashMap<(u64, usize), Vec<ObjectId>> = HashMap::new();
+    for (&id, object) in doc.objects.iter() {
+        let Object::Stream(stream) = object else {
+            continue;
+        };
+        if matches!(
+            stream.dict.get(b"Type").and_then(Object::as_name),
+            Ok(b"XRef" | b"ObjStm")
+        ) {
+            continue;
+        }
+        groups
+            .entry(content_key(&stream.content))
+            .or_default()
+            .push(id);
+    }
+
+    let replacements = find_duplicates(doc, groups.into_values(), &[b"Length"]);
+    for &id in replacements.keys() {
+        report.duplicate_bytes_saved += stream_len(doc, id);
+    }
+    report.duplicate_streams += replacements.len() as u32;
+    fold_duplicates(doc, &replacements);
+}
+
+/// Fold identical images and fonts into a single object each. Merged PDFs typically carry
+/// one copy of a logo or font program per source file.
+///
+/// Images match on their data plus every dictionary entry that affects rendering; fonts match
+/// on their program streams, then their descriptor and font dictionaries. Repointing
+/// references can make the objects that hold them identical in turn (an image's SMask, a
+/// descriptor's FontFile), so this runs in rounds until nothing more folds.
+pub fn dedupe_images_and_fonts(doc: &mut Document) -> DedupReport {
+    let mut report = DedupReport::default();
+
+    for _ in 0..MAX_DEDUPE_ROUNDS {
+        let font_streams = collect_font_streams(doc);
+
+        let mut image_groups: HashMap<(u64, usize), Vec<ObjectId>> = HashMap::new();
+        let mut font_stream_groups: HashMap<(u64, usize), Vec<ObjectId>> = HashMap::new();
+        let mut font_dict_groups: HashMap<(Vec<u8>, Vec<u8>), Vec<ObjectId>> = HashMap::new();
+        for (&id, object) in doc.objects.iter() {
+            match object {
+                Object::Stream(stream) if font_streams.contains(&id) => {
+                    font_stream_groups
+                        .entry(content_key(&stream.content))
+                        .or_default()
+                        .push(id);
+                }
+                Object::Stream(stream)
+                    if matches!(
+                        stream.dict.get(b"Subtype").and_then(Object::as_name),
+                        Ok(b"Image")
+                    ) =>
+                {
+                    image_groups
+                        .entry(content_key(&stream.content))
+                        .or_default()
+                        .push(id);
+                }
+                Object::Dictionary(dict) => {
+                    let Ok(kind @ (b"Font" | b"FontDescriptor")) =
+                        dict.get(b"Type").and_then(Object::as_name)
+                    else {
+                        continue;
+                    };
+                    let name = dict
+                        .get(b"BaseFont")
+                        .or_else(|_| dict.get(b"FontName"))
+                        .and_then(Object::as_name)
+                        .unwrap_or_default();
+                    font_dict_groups
+                        .entry((kind.to_vec(), name.to_vec()))
+                        .or_default()
+                        .push(id);
+                }
+                _ => {}
+            }
+        }
+
+        let images = find_duplicates(doc, image_groups.into_values(), IMAGE_IGNORED_KEYS);
+        let fonts = find_duplicates(
+            doc,
+            font_stream_groups
+                .into_values()
+                .chain(font_dict_groups.into_values()),
+            &[b"Length"],
+        );
+        if images.is_empty() && fonts.is_empty() {
+            break;
+        }
+
+        report.images += images.len() as u32;
+        report.fonts += fonts.len() as u32;
+        let mut replacements = images;
+        replacements.extend(fonts);
+        for &id in replacements.keys() {
+            report.bytes_saved += stream_len(doc, id);
+        }
+        fold_duplicates(doc, &replacements);
+    }
+
+    report
+}
+
+/// Streams that belong to a font: embedded programs, CID sets and maps, and ToUnicode CMaps
+fn collect_font_streams(doc: &Document) -> HashSet<ObjectId> {
+    let mut font_streams = HashSet::new();
+    for object in doc.objects.values() {
+        let Object::Dictionary(dict) = object else {
+            continue;
+        };
+        let keys: &[&[u8]] = match dict.get(b"Type").and_then(Object::as_name) {
+            Ok(b"FontDescriptor") => &[b"FontFile", b"FontFile2", b"FontFile3", b"CIDSet"],
+            Ok(b"Font") => &[b"ToUnicode", b"Encoding", b"CIDToGIDMap"],
+            _ => continue,
+        };
+        for key in keys {
+            if let Ok(id) = dict.get(key).and_then(Object::as_reference) {
+                if matches!(doc.objects.get(&id), Some(Object::Stream(_))) {
+                    font_streams.insert(id);
+                }
+            }
+        }
+    }
+    font_streams
+}
+
+/// Group key for stream data: a hash of the bytes plus their length
+fn content_key(content: &[u8]) -> (u64, usize) {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    (hasher.finish(), content.len())
+}
+
+/// Data length of a stream object, or 0 for anything else
+fn stream_len(doc: &Document, id: ObjectId) -> usize {
+    match doc.objects.get(&id) {
+        Some(Object::Stream(stream)) => stream.content.len(),
+        _ => 0,
+    }
+}
+
+/// Compare the objects within each group and map every duplicate to the first object it
+/// is identical to. The lowest object id becomes the canonical copy, so the result doesn't
+/// depend on hash order.
+fn find_duplicates(
+    doc: &Document,
+    groups: impl IntoIterator<Item = Vec<ObjectId>>,
+    ignored_keys: &[&[u8]],
+) -> HashMap<ObjectId, ObjectId> {
+    let mut replacements = HashMap::new();
+    for mut ids in groups.into_iter().filter(|ids| ids.len() > 1) {
+        ids.sort();
+        let mut canonical: Vec<ObjectId> = Vec::new();
+        for id in ids {
+            let duplicate_of = canonical
+                .iter()
+                .copied()
+                .find(|&other| objects_identical(doc, id, other, ignored_keys));
+            match duplicate_of {
+                Some(other) => {
+                    replacements.insert(id, other);
+                }
+                None => canonical.push(id),
+            }
+        }
+    }
+    replacements
+}
+
+/// Whether two objects have equal dictionaries (apart from `ignored_keys`) and, for streams,
+/// equal data
+fn objects_identical(doc: &Document, a: ObjectId, b: ObjectId, ignored_keys: &[&[u8]]) -> bool {
+    let (a_dict, b_dict) = match (doc.objects.get(&a), doc.objects.get(&b)) {
+        (Some(Object::Stream(a)), Some(Object::Stream(b))) => {
+            if a.content != b.content {
+                return false;
+            }
+            (&a.dict, &b.dict)
+        }
+        (Some(Object::Dictionary(a)), Some(Object::Dictionary(b))) => (a, b),
+        _ => return false,
+    };
+
+    let mut a_dict = a_dict.clone();
+    let mut b_dict = b_dict.clone();
+    for key in ignored_keys {
+        a_dict.remove(key);
+        b_dict.remove(key);
+    }
+    a_dict == b_dict
+}
+
+/// Repoint references from each duplicate to its canonical copy, then drop the duplicates
+fn fold_duplicates(doc: &mut Document, replacements: &HashMap<ObjectId, ObjectId>) {
+    if replacements.is_empty() {
+        return;
+    }
+    replace_references(doc, replacements);
+    for id in replacements.keys() {
+        doc.objects.remove(id);
+    }
+}
+
+/// Rewrite every reference reachable from the trailer according to `replacements`
+pub fn replace_references(doc: &mut Document, replacements: &HashMap<ObjectId, ObjectId>) {
+    doc.traverse_objects(|object| {
+        if let Object::Reference(id) = object {
+            if let Some(&new_id) = replacements.get(id) {
+                *id = new_id;
+            }
+        }
+    });
+}
+
+/// Re-deflate FlateDecode streams at the highest level, and deflate unfiltered ones,
+/// keeping the result only when it is smaller. Predictor parameters still apply unchanged
+/// because the inflated bytes are identical.
+fn redeflate_streams(doc: &mut Document, report: &mut StructureReport) {
+    for object in doc.objects.values_mut() {
+        let Object::Stream(stream) = object else {
+            continue;
+        };
+        // XMP is left readable for tools that scan files for it
+        if matches!(
+            stream.dict.get(b"Type").and_then(Object::as_name),
+            Ok(b"Metadata" | b"XRef" | b"ObjStm")
+        ) {
+            continue;
+        }
+
+        let filters = if stream.dict.has(b"Filter") {
+            match stream.filters() {
+                Ok(filters) => filters,
+                Err(_) => continue,
+            }
+        } else {
+            Vec::new()
+        };
+        let is_flate = match filters.as_slice() {
+            [] => false,
+            [filter] if *filter == b"FlateDecode" => true,
+            _ => continue,
+        };
+        // DecodeParms without a filter has nothing to apply to; don't guess
+        if !is_flate && stream.dict.has(b"DecodeParms") {
+            continue;
+        }
+
+        let raw = if is_flate {
+            let mut inflated = Vec::new();
+            if ZlibDecoder::new(stream.content.as_slice())
+                .read_to_end(&mut inflated)
+                .is_err()
+            {
+                // Truncated or corrupt data; re-encoding could lose whatever a viewer recovers
+                continue;
+            }
+            inflated
+        } else {
+            stream.content.clone()
+        };
+
+        let Some(deflated) = deflate_best(&raw) else {
+            continue;
+        };
+        if deflated.len() < stream.content.len() {
+            report.streams_redeflated += 1;
+            report.redeflate_bytes_saved += stream.content.len() - deflated.len();
+            if !is_flate {
+                stream
+                    .dict
+                    .set("Filter", Object::Name(b"FlateDecode".to_vec()));
+            }
+            stream.set_content(deflated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::{dictionary, Stream};
+
+    /// A document with a `/Names` `/Dests` tree holding one kid per group of names, and a
+    /// link to each of the `linked` names
+    fn document(groups: &[&[&str]], linked: &[&str]) -> (Document, Vec<ObjectId>) {
+        let mut doc = Document::with_version("1.5");
+        let kids: Vec<ObjectId> = groups
+            .iter()
+            .map(|names| {
+                let entries = names
+                    .iter()
+                    .flat_map(|name| {
+                        [
+                            Object::string_literal(*name),
+                            vec![Object::Null, "Fit".into()].into(),
+                        ]
+                    })
+                    .collect::<Vec<Object>>();
+                doc.add_object(dictionary! { "Names" => entries })
+            })
+            .collect();
+        let kid_refs: Vec<Object> = kids.iter().map(|&id| id.into()).collect();
+        let root_id = doc.add_object(dictionary! { "Kids" => kid_refs });
+        let names_id = doc.add_object(dictionary! { "Dests" => root_id });
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Names" => names_id });
+        doc.trailer.set("Root", catalog_id);
+        for name in linked {
+            doc.add_object(dictionary! {
+                "Type" => "Annot",
+                "Subtype" => "Link",
+                "Dest" => Object::string_literal(*name),
+            });
+        }
+        (doc, kids)
+    }
+
+    fn strings(object: &Object) -> Vec<String> {
+        object
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|item| item.as_str().ok())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect()
+    }
+
+    fn dest_tree(doc: &Document) -> Option<&Dictionary> {
+        let names = doc.catalog().unwrap().get(b"Names").unwrap();
+        let names = doc.dereference(names).unwrap().1.as_dict().unwrap();
+        let root = names.get(b"Dests").ok()?;
+        Some(doc.dereference(root).unwrap().1.as_dict().unwrap())
+    }
+
+    #[test]
+    fn prunes_unlinked_names_and_updates_limits() {
+        let (mut doc, kids) = document(&[&["a", "b", "c"], &["d", "e"]], &["b", "c", "e"]);
+        let mut report = StructureReport::default();
+        remove_unused_named_dests(&mut doc, &mut report);
+        assert_eq!(report.named_dests_removed, 2);
+
+        let first = doc.get_dictionary(kids[0]).unwrap();
+        assert_eq!(strings(first.get(b"Names").unwrap()), ["b", "c"]);
+        assert_eq!(strings(first.get(b"Limits").unwrap()), ["b", "c"]);
+        let second = doc.get_dictionary(kids[1]).unwrap();
+        assert_eq!(strings(second.get(b"Names").unwrap()), ["e"]);
+        assert_eq!(strings(second.get(b"Limits").unwrap()), ["e", "e"]);
+        assert!(!dest_tree(&doc).unwrap().has(b"Limits"));
+    }
+
+    #[test]
+    fn drops_kids_and_trees_left_empty() {
+        let (mut doc, kids) = document(&[&["a"], &["b"]], &["b"]);
+        let mut report = StructureReport::default();
+        remove_unused_named_dests(&mut doc, &mut report);
+        assert_eq!(report.named_dests_removed, 1);
+        let root = dest_tree(&doc).unwrap();
+        assert_eq!(
+            root.get(b"Kids").unwrap().as_array().unwrap(),
+            &vec![Object::Reference(kids[1])]
+        );
+
+        let (mut doc, _) = document(&[&["a"], &["b"]], &[]);
+        remove_unused_named_dests(&mut doc, &mut StructureReport::default());
+        assert!(dest_tree(&doc).is_none());
+    }
+
+    #[test]
+    fn prunes_the_catalog_dests_dictionary() {
+        let (mut doc, _) = document(&[&["a"]], &["a"]);
+        doc.add_object(dictionary! { "S" => "GoTo", "D" => Object::Name(b"kept".to_vec()) });
+        doc.catalog_mut().unwrap().set(
+            "Dests",
+            dictionary! {
+                "kept" => vec![Object::Null, "Fit".into()],
+                "unused" => vec![Object::Null, "Fit".into()],
+            },
+        );
+        let mut report = StructureReport::default();
+        remove_unused_named_dests(&mut doc, &mut report);
+        assert_eq!(report.named_dests_removed, 1);
+        let dests = doc
+            .catalog()
+            .unwrap()
+            .get(b"Dests")
+            .unwrap()
+            .as_dict()
+            .unwrap();
+        assert!(dests.has(b"kept") && !dests.has(b"unused"));
+    }
+
+    #[test]
+    fn leaves_documents_with_javascript_alone() {
+        let (mut doc, kids) = document(&[&["a", "b"]], &["a"]);
+        doc.add_object(dictionary! { "S" => "JavaScript", "JS" => Object::string_literal("") });
+        let mut report = StructureReport::default();
+        remove_unused_named_dests(&mut doc, &mut report);
+        assert_eq!(report.named_dests_removed, 0);
+        let kid = doc.get_dictionary(kids[0]).unwrap();
+        assert_eq!(strings(kid.get(b"Names").unwrap()), ["a", "b"]);
+    }
+
+    #[test]
+    fn leaves_malformed_trees_alone() {
+        let (mut doc, kids) = document(&[&["a", "b"], &["c"]], &["a"]);
+        // A kid that isn't a name tree node stops the whole pass
+        doc.objects.insert(kids[1], Object::Integer(0));
+        let mut report = StructureReport::default();
+        remove_unused_named_dests(&mut doc, &mut report);
+        assert_eq!(report.named_dests_removed, 0);
+        let kid = doc.get_dictionary(kids[0]).unwrap();
+        assert_eq!(strings(kid.get(b"Names").unwrap()), ["a", "b"]);
+        assert!(!kid.has(b"Limits"));
+    }
+
+    /// A one-row gray image of `data`, with `extra` dictionary entries
+    fn image(data: &[u8], extra: Dictionary) -> Stream {
+        let mut dict = dictionary! {
+            "Type" => "XObject",
+            "Subtype" => "Image",
+            "Width" => data.len() as i64,
+            "Height" => 1,
+            "BitsPerComponent" => 8,
+            "ColorSpace" => "DeviceGray",
+        };
+        dict.extend(&extra);
+        Stream::new(dict, data.to_vec())
+    }
+
+    /// Give the document a page per resource, naming it /R in its `kind` resources
+    fn add_pages_using(doc: &mut Document, kind: &str, resources: &[ObjectId]) {
+        testing::add_page_tree(doc, resources.len(), |_, index| {
+            dictionary! {
+                "Resources" => dictionary! { kind => dictionary! { "R" => resources[index] } },
+            }
+        });
+    }
+
+    /// The resource each page of an `add_pages_using` document uses
+    fn page_resources(doc: &Document, kind: &[u8]) -> Vec<ObjectId> {
+        doc.get_pages()
+            .values()
+            .map(|&page_id| {
+                let page = doc.get_dictionary(page_id).unwrap();
+                let resources = page.get(b"Resources").unwrap().as_dict().unwrap();
+                let named = resources.get(kind).unwrap().as_dict().unwrap();
+                named.get(b"R").unwrap().as_reference().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn folds_identical_images() {
+        let mut doc = Document::with_version("1.5");
+        let first = doc.add_object(image(b"pixels", dictionary! {}));
+        // Differs only in entries that don't affect rendering
+        let copy = doc.add_object(image(b"pixels", dictionary! { "Name" => "Im2" }));
+        let inverted = doc.add_object(image(
+            b"pixels",
+            dictionary! { "Decode" => vec![1.into(), 0.into()] },
+        ));
+        let other = doc.add_object(image(b"others", dictionary! {}));
+        let images = [first, copy, inverted, other];
+        add_pages_using(&mut doc, "XObject", &images);
+
+        let report = dedupe_images_and_fonts(&mut doc);
+
+        assert_eq!((report.images, report.fonts), (1, 0));
+        assert_eq!(report.bytes_saved, b"pixels".len());
+        assert_eq!(
+            page_resources(&doc, b"XObject"),
+            [first, first, inverted, other]
+        );
+        assert!(!doc.objects.contains_key(&copy));
+    }
+
+    #[test]
+    fn folds_images_whose_soft_masks_fold() {
+        let mut doc = Document::with_version("1.5");
+        let masks: Vec<ObjectId> = (0..2)
+            .map(|_| doc.add_object(image(b"mask data", dictionary! {})))
+            .collect();
+        let images: Vec<ObjectId> = masks
+            .iter()
+            .map(|&mask| doc.add_object(image(b"pixels", dictionary! { "SMask" => mask })))
+            .collect();
+        add_pages_using(&mut doc, "XObject", &images);
+
+        let report = dedupe_images_and_fonts(&mut doc);
+
+        // The masks fold in the first round, which makes the images identical in the next
+        assert_eq!(report.images, 2);
+        assert_eq!(report.bytes_saved, b"mask data".len() + b"pixels".len());
+        assert_eq!(page_resources(&doc, b"XObject"), [images[0], images[0]]);
+        assert!(!doc.objects.contains_key(&masks[1]));
+    }
+
+    #[test]
+    fn folds_fonts_whose_programs_fold() {
+        let mut doc = Document::with_version("1.5");
+        let mut font = |program: &[u8]| {
+            let program = doc.add_object(Stream::new(dictionary! {}, program.to_vec()));
+            let descriptor = doc.add_object(dictionary! {
+                "Type" => "FontDescriptor",
+                "FontName" => "Sans",
+                "FontFile2" => program,
+            });
+            doc.add_object(dictionary! {
+                "Type" => "Font",
+                "Subtype" => "TrueType",
+                "BaseFont" => "Sans",
+                "FontDescriptor" => descriptor,
+            })
+        };
+        let fonts = [font(b"glyphs"), font(b"glyphs"), font(b"other glyphs")];
+        add_pages_using(&mut doc, "Font", &fonts);
+        let objects = doc.objects.len();
+
+        let report = dedupe_images_and_fonts(&mut doc);
+
+        // The program, then the descriptor pointing to it, then the font dictionary
+        assert_eq!((report.images, report.fonts), (0, 3));
+        assert_eq!(report.bytes_saved, b"glyphs".len());
+        assert_eq!(
+            page_resources(&doc, b"Font"),
+            [fonts[0], fonts[0], fonts[2]]
+        );
+        assert_eq!(doc.objects.len(), objects - 3);
+    }
+}
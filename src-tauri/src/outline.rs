@@ -0,0 +1,751 @@
+use crate::assembly::{
+    destination, named_destinations, outline_children, outline_root, recount_outline,
+    relink_outline_children, PdfSource,
+};
+use lopdf::{
+    decode_text_string, dictionary, text_string, Dictionary, Document, Object, ObjectId, Stream,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Outlines nested deeper than this are cut off when read, and refused when written
+const MAX_OUTLINE_DEPTH: u32 = 64;
+
+/// A bookmark's action is dropped when read if copying it takes more objects than this
+const MAX_ACTION_OBJECTS: usize = 64;
+
+/// The view of a destination that leaves position and zoom as they are
+const CURRENT_VIEW: Zoom = Zoom::Xyz {
+    left: None,
+    top: None,
+    zoom: None,
+};
+
+/// Outline item `/F` flags
+const ITALIC: i64 = 1;
+const BOLD: i64 = 2;
+
+/// A bookmark and the bookmarks nested under it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutlineItem {
+    pub title: String,
+    /// Page the bookmark goes to, from 1; None when it goes nowhere in this document
+    pub page: Option<u32>,
+    /// How the page is shown; None keeps the reader's current zoom
+    pub zoom: Option<Zoom>,
+    /// Whether the bookmark's children are shown expanded
+    pub open: bool,
+    pub children: Vec<OutlineItem>,
+    /// The named destination the bookmark goes through, kept while it still leads to `page`
+    /// and `zoom`
+    pub destination_name: Option<String>,
+    /// What the bookmark does when it doesn't go to a page of this document (open a web
+    /// address or another file, run a script), carried as it was read
+    pub action: Option<PdfValue>,
+    /// Title colour as RGB components from 0 to 1
+    pub color: Option<[f32; 3]>,
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+}
+
+/// A PDF object carried through the frontend as it is. References to pages become page
+/// numbers and other indirect objects are copied in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "camelCase")]
+pub enum PdfValue {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Real(f32),
+    Name(String),
+    String(Vec<u8>),
+    Array(Vec<PdfValue>),
+    Dictionary(Vec<(String, PdfValue)>),
+    /// Data as stored, with the dictionary describing its filters
+    Stream {
+        dict: Vec<(String, PdfValue)>,
+        data: Vec<u8>,
+    },
+    /// A page of this document, from 1
+    Page(u32),
+}
+
+/// The view part of a destination. Coordinates left as None keep their current value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Zoom {
+    /// Position the given point at the top left, at a zoom factor (1.0 is 100%)
+    Xyz {
+        left: Option<f32>,
+        top: Option<f32>,
+        zoom: Option<f32>,
+    },
+    /// Fit the whole page in the window
+    Fit,
+    /// Fit the page width, with `top` at the top of the window
+    FitH {
+        top: Option<f32>,
+    },
+    /// Fit the page height, with `left` at the left of the window
+    FitV {
+        left: Option<f32>,
+    },
+    /// Fit a rectangle in the window
+    FitR {
+        left: f32,
+        bottom: f32,
+        right: f32,
+        top: f32,
+    },
+    /// Like Fit, FitH and FitV, but for the page content's bounding box
+    FitB,
+    FitBH {
+        top: Option<f32>,
+    },
+    FitBV {
+        left: Option<f32>,
+    },
+}
+
+impl Zoom {
+    fn from_destination(view: &[Object]) -> Option<Zoom> {
+        let number = |index: usize| view.get(index).and_then(|n| n.as_float().ok());
+        let zoom = match view.first()?.as_name().ok()? {
+            b"XYZ" => Zoom::Xyz {
+                left: number(1),
+                top: number(2),
+                // 0 means the same as null: keep the current zoom
+                zoom: number(3).filter(|&zoom| zoom != 0.0),
+            },
+            b"Fit" => Zoom::Fit,
+            b"FitH" => Zoom::FitH { top: number(1) },
+            b"FitV" => Zoom::FitV { left: number(1) },
+            b"FitR" => Zoom::FitR {
+                left: number(1)?,
+                bottom: number(2)?,
+                right: number(3)?,
+                top: number(4)?,
+            },
+            b"FitB" => Zoom::FitB,
+            b"FitBH" => Zoom::FitBH { top: number(1) },
+            b"FitBV" => Zoom::FitBV { left: number(1) },
+            _ => return None,
+        };
+        Some(zoom)
+    }
+
+    fn to_destination(&self) -> Vec<Object> {
+        let number = |n: &Option<f32>| n.map_or(Object::Null, Object::Real);
+        match self {
+            Zoom::Xyz { left, top, zoom } => {
+                vec!["XYZ".into(), number(left), number(top), number(zoom)]
+            }
+            Zoom::Fit => vec!["Fit".into()],
+            Zoom::FitH { top } => vec!["FitH".into(), number(top)],
+            Zoom::FitV { left } => vec!["FitV".into(), number(left)],
+            Zoom::FitR {
+                left,
+                bottom,
+                right,
+                top,
+            } => vec![
+                "FitR".into(),
+                Object::Real(*left),
+                Object::Real(*bottom),
+                Object::Real(*right),
+                Object::Real(*top),
+            ],
+            Zoom::FitB => vec!["FitB".into()],
+            Zoom::FitBH { top } => vec!["FitBH".into(), number(top)],
+            Zoom::FitBV { left } => vec!["FitBV".into(), number(left)],
+        }
+    }
+}
+
+/// Read the outline of a PDF on disk or in memory
+pub fn get_outline_blocking(source: &PdfSource) -> Result<Vec<OutlineItem>, String> {
+    let doc = source.load()?;
+    Ok(read_outline(&doc))
+}
+
+/// Replace the outline of a PDF; an empty tree removes it
+pub fn set_outline_blocking(content: &[u8], outline: &[OutlineItem]) -> Result<Vec<u8>, String> {
+    let mut doc = PdfSource::Content {
+        content: content.to_vec(),
+    }
+    .load()?;
+    write_outline(&mut doc, outline)?;
+    doc.prune_objects();
+
+    let mut output = Vec::new();
+    doc.save_to(&mut output)
+        .map_err(|e| format!("Failed to save PDF: {}", e))?;
+    println!("Wrote outline with {} top-level bookmarks", outline.len());
+    Ok(output)
+}
+
+/// The document's bookmarks, with named destinations resolved to pages
+pub fn read_outline(doc: &Document) -> Vec<OutlineItem> {
+    let Some(root) = outline_root(doc) else {
+        return Vec::new();
+    };
+    let names = named_destinations(doc);
+    let page_numbers: HashMap<ObjectId, u32> = doc
+        .get_pages()
+        .into_iter()
+        .map(|(number, id)| (id, number))
+        .collect();
+    read_items(doc, root, &names, &page_numbers, 0)
+}
+
+fn read_items(
+    doc: &Document,
+    parent: ObjectId,
+    names: &HashMap<Vec<u8>, Object>,
+    page_numbers: &HashMap<ObjectId, u32>,
+    depth: u32,
+) -> Vec<OutlineItem> {
+    if depth > MAX_OUTLINE_DEPTH {
+        return Vec::new();
+    }
+    outline_children(doc, parent)
+        .into_iter()
+        .filter_map(|id| {
+            let item = doc.get_dictionary(id).ok()?;
+            let title = item
+                .get(b"Title")
+                .and_then(|title| doc.dereference(title))
+                .ok()
+                .and_then(|(_, title)| decode_text_string(title).ok())
+                .unwrap_or_default();
+            let dest = destination(doc, item, names);
+            let page = dest.and_then(|dest| match dest.first()? {
+                Object::Reference(page) => page_numbers.get(page).copied(),
+                // Some writers put a 0-based page index where the page reference belongs
+                Object::Integer(index) => u32::try_from(*index)
+                    .ok()
+                    .map(|index| index + 1)
+                    .filter(|page| *page as usize <= page_numbers.len()),
+                _ => None,
+            });
+            let action = match page {
+                Some(_) => None,
+                None => other_action(doc, item, page_numbers),
+            };
+            let color =
+                item.get(b"C")
+                    .and_then(Object::as_array)
+                    .ok()
+                    .and_then(|color| match color.as_slice() {
+                        [r, g, b] => {
+                            Some([r.as_float().ok()?, g.as_float().ok()?, b.as_float().ok()?])
+                        }
+                        _ => None,
+                    });
+            let flags = item.get(b"F").and_then(Object::as_i64).unwrap_or(0);
+            Some(OutlineItem {
+                title,
+                page,
+                zoom: page
+                    .and(dest)
+                    .and_then(|dest| Zoom::from_destination(&dest[1..]))
+                    .filter(|zoom| *zoom != CURRENT_VIEW),
+                open: item
+                    .get(b"Count")
+                    .and_then(Object::as_i64)
+                    .is_ok_and(|count| count > 0),
+                children: read_items(doc, id, names, page_numbers, depth + 1),
+                destination_name: page
+                    .and_then(|_| destination_name(doc, item))
+                    .map(|name| String::from_utf8_lossy(&name).into_owned()),
+                action,
+                color,
+                bold: flags & BOLD != 0,
+                italic: flags & ITALIC != 0,
+            })
+        })
+        .collect()
+}
+
+/// The name of the named destination an outline item goes to, directly or by a GoTo action
+fn destination_name(doc: &Document, item: &Dictionary) -> Option<Vec<u8>> {
+    let dest = match item.get(b"Dest") {
+        Ok(dest) => dest,
+        Err(_) => {
+            let (_, action) = doc.dereference(item.get(b"A").ok()?).ok()?;
+            action.as_dict().ok()?.get(b"D").ok()?
+        }
+    };
+    match doc.dereference(dest).ok()?.1 {
+        Object::Name(name) | Object::String(name, _) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// An outline item's action, unless it's a GoTo within the document
+fn other_action(
+    doc: &Document,
+    item: &Dictionary,
+    page_numbers: &HashMap<ObjectId, u32>,
+) -> Option<PdfValue> {
+    let action = item.get(b"A").ok()?;
+    let (_, dict) = doc.dereference(action).ok()?;
+    if matches!(
+        dict.as_dict()
+            .and_then(|dict| dict.get(b"S"))
+            .and_then(Object::as_name),
+        Ok(b"GoTo")
+    ) {
+        return None;
+    }
+    let mut copied = 0;
+    let value = to_value(doc, action, page_numbers, &mut Vec::new(), &mut copied);
+    if value.is_none() {
+        println!("Outline action too large to carry, it is left out");
+    }
+    value
+}
+
+/// Copy an object into a `PdfValue`, following references except those to pages. Cycles
+/// become null; None when more than `MAX_ACTION_OBJECTS` objects would be copied.
+fn to_value(
+    doc: &Document,
+    object: &Object,
+    page_numbers: &HashMap<ObjectId, u32>,
+    path: &mut Vec<ObjectId>,
+    copied: &mut usize,
+) -> Option<PdfValue> {
+    let dict_value = |dict: &Dictionary, path: &mut Vec<ObjectId>, copied: &mut usize| {
+        dict.iter()
+            .map(|(key, value)| {
+                let value = to_value(doc, value, page_numbers, path, copied)?;
+                Some((String::from_utf8_lossy(key).into_owned(), value))
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+    let value = match object {
+        Object::Null => PdfValue::Null,
+        Object::Boolean(value) => PdfValue::Boolean(*value),
+        Object::Integer(value) => PdfValue::Integer(*value),
+        Object::Real(value) => PdfValue::Real(*value),
+        Object::Name(name) => PdfValue::Name(String::from_utf8_lossy(name).into_owned()),
+        Object::String(text, _) => PdfValue::String(text.clone()),
+        Object::Array(items) => PdfValue::Array(
+            items
+                .iter()
+                .map(|item| to_value(doc, item, page_numbers, path, copied))
+                .collect::<Option<_>>()?,
+        ),
+        Object::Dictionary(dict) => PdfValue::Dictionary(dict_value(dict, path, copied)?),
+        Object::Stream(stream) => PdfValue::Stream {
+            dict: dict_value(&stream.dict, path, copied)?,
+            data: stream.content.clone(),
+        },
+        Object::Reference(id) => {
+            if let Some(&page) = page_numbers.get(id) {
+                return Some(PdfValue::Page(page));
+            }
+            if path.contains(id) {
+                return Some(PdfValue::Null);
+            }
+            *copied += 1;
+            if *copied > MAX_ACTION_OBJECTS {
+                return None;
+            }
+            let Ok(target) = doc.get_object(*id) else {
+                return Some(PdfValue::Null);
+            };
+            path.push(*id);
+            let value = to_value(doc, target, page_numbers, path, copied);
+            path.pop();
+            return value;
+        }
+    };
+    Some(value)
+}
+
+/// Turn a `PdfValue` back into an object of `doc`, adding its streams as objects
+fn from_value(doc: &mut Document, value: &PdfValue, pages: &[ObjectId]) -> Object {
+    let dict = |doc: &mut Document, entries: &[(String, PdfValue)]| {
+        let mut dict = Dictionary::new();
+        for (key, value) in entries {
+            let value = from_value(doc, value, pages);
+            dict.set(key.as_bytes(), value);
+        }
+        dict
+    };
+    match value {
+        PdfValue::Null => Object::Null,
+        PdfValue::Boolean(value) => Object::Boolean(*value),
+        PdfValue::Integer(value) => Object::Integer(*value),
+        PdfValue::Real(value) => Object::Real(*value),
+        PdfValue::Name(name) => Object::Name(name.as_bytes().to_vec()),
+        PdfValue::String(text) => Object::string_literal(text.clone()),
+        PdfValue::Array(items) => Object::Array(
+            items
+                .iter()
+                .map(|item| from_value(doc, item, pages))
+                .collect(),
+        ),
+        PdfValue::Dictionary(entries) => Object::Dictionary(dict(doc, entries)),
+        PdfValue::Stream {
+            dict: entries,
+            data,
+        } => {
+            let stream_dict = dict(doc, entries);
+            // Compression is already applied, as the dictionary's /Filter says
+            let stream = Stream::new(stream_dict, data.clone()).with_compression(false);
+            doc.add_object(stream).into()
+        }
+        PdfValue::Page(page) => page
+            .checked_sub(1)
+            .and_then(|index| pages.get(index as usize))
+            .map_or(Object::Null, |&id| Object::Reference(id)),
+    }
+}
+
+/// Replace the document's outline with `items`. The old outline's objects are left for
+/// pruning.
+pub fn write_outline(doc: &mut Document, items: &[OutlineItem]) -> Result<(), String> {
+    let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+    let names = named_destinations(doc);
+    doc.catalog_mut()
+        .map_err(|e| format!("Failed to read catalog: {}", e))?
+        .remove(b"Outlines");
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let root = doc.add_object(dictionary! { "Type" => "Outlines" });
+    write_items(doc, root, items, &pages, &names, 0)?;
+    recount_outline(doc, root, true, 0);
+    doc.catalog_mut()
+        .map_err(|e| format!("Failed to read catalog: {}", e))?
+        .set("Outlines", root);
+    Ok(())
+}
+
+fn write_items(
+    doc: &mut Document,
+    parent: ObjectId,
+    items: &[OutlineItem],
+    pages: &[ObjectId],
+    names: &HashMap<Vec<u8>, Object>,
+    depth: u32,
+) -> Result<(), String> {
+    if depth > MAX_OUTLINE_DEPTH {
+        return Err("Outline is nested too deeply".to_string());
+    }
+    let mut ids = Vec::with_capacity(items.len());
+    for item in items {
+        let mut dict = dictionary! {
+            "Title" => text_string(&item.title),
+            "Parent" => parent,
+        };
+        if let Some(page) = item.page {
+            let page_id = page
+                .checked_sub(1)
+                .and_then(|index| pages.get(index as usize))
+                .ok_or_else(|| {
+                    format!(
+                        "Bookmark \"{}\" goes to page {}, which doesn't exist",
+                        item.title, page
+                    )
+                })?;
+            let mut dest = vec![Object::Reference(*page_id)];
+            match &item.zoom {
+                Some(zoom) => dest.extend(zoom.to_destination()),
+                None => dest.extend(CURRENT_VIEW.to_destination()),
+            }
+            let named = item
+                .destination_name
+                .as_deref()
+                .filter(|name| leads_to(names.get(name.as_bytes()), &dest));
+            match named {
+                Some(name) => dict.set("Dest", name_object(doc, name)),
+                None => dict.set("Dest", dest),
+            }
+        } else if let Some(action) = &item.action {
+            let action = from_value(doc, action, pages);
+            dict.set("A", action);
+        }
+        if let Some(color) = item.color {
+            dict.set("C", color.map(Object::Real).to_vec());
+        }
+        let flags = if item.italic { ITALIC } else { 0 } | if item.bold { BOLD } else { 0 };
+        if flags != 0 {
+            dict.set("F", flags);
+        }
+        // The sign marks the open state; the number is filled in by recount_outline
+        if !item.children.is_empty() {
+            dict.set("Count", if item.open { 1 } else { -1 });
+        }
+        let id = doc.add_object(dict);
+        write_items(doc, id, &item.children, pages, names, depth + 1)?;
+        ids.push(id);
+    }
+    relink_outline_children(doc, parent, &ids);
+    Ok(())
+}
+
+/// Whether a named destination goes to the same page and view as `dest`. A view without
+/// a zoom is the same as `XYZ` with every coordinate left as it is.
+fn leads_to(named: Option<&Object>, dest: &[Object]) -> bool {
+    let Some(Ok(named)) = named.map(Object::as_array) else {
+        return false;
+    };
+    let view = |dest: &[Object]| Zoom::from_destination(&dest[1..]).unwrap_or(CURRENT_VIEW);
+    !named.is_empty() && named[0] == dest[0] && view(named) == view(dest)
+}
+
+/// A destination name as the document defines it: a name in the catalog's `/Dests`
+/// dictionary, or a string in the `/Dests` name tree
+fn name_object(doc: &Document, name: &str) -> Object {
+    let in_dests_dict = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"Dests"))
+        .and_then(|dests| doc.dereference(dests))
+        .and_then(|(_, dests)| dests.as_dict())
+        .is_ok_and(|dests| dests.has(name.as_bytes()));
+    if in_dests_dict {
+        Object::Name(name.as_bytes().to_vec())
+    } else {
+        Object::string_literal(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A document with `count` empty pages
+    fn document(count: usize) -> Document {
+        testing::document(count, |_, _| Dictionary::new()).0
+    }
+
+    fn item(title: &str, page: Option<u32>) -> OutlineItem {
+        OutlineItem {
+            title: title.to_string(),
+            page,
+            zoom: None,
+            open: false,
+            children: Vec::new(),
+            destination_name: None,
+            action: None,
+            color: None,
+            bold: false,
+            italic: false,
+        }
+    }
+
+    fn uri(address: &str) -> PdfValue {
+        PdfValue::Dictionary(vec![
+            ("S".to_string(), PdfValue::Name("URI".to_string())),
+            (
+                "URI".to_string(),
+                PdfValue::String(address.as_bytes().to_vec()),
+            ),
+        ])
+    }
+
+    #[test]
+    fn round_trips_through_set_and_get() {
+        let outline = vec![
+            OutlineItem {
+                zoom: Some(Zoom::FitH { top: Some(700.0) }),
+                open: true,
+                color: Some([1.0, 0.0, 0.0]),
+                bold: true,
+                children: vec![
+                    OutlineItem {
+                        zoom: Some(Zoom::Xyz {
+                            left: Some(10.0),
+                            top: None,
+                            zoom: Some(1.5),
+                        }),
+                        italic: true,
+                        ..item("Section 1.1", Some(2))
+                    },
+                    item("Sources", None),
+                ],
+                ..item("Chapter 1 – Überblick", Some(1))
+            },
+            OutlineItem {
+                action: Some(uri("https://example.com")),
+                ..item("Website", None)
+            },
+            item("Chapter 2", Some(3)),
+        ];
+        let content = set_outline_blocking(&testing::save(&mut document(3)), &outline).unwrap();
+        let read = get_outline_blocking(&PdfSource::Content { content }).unwrap();
+
+        assert_eq!(read.len(), 3);
+        let chapter = &read[0];
+        assert_eq!(chapter.title, "Chapter 1 – Überblick");
+        assert_eq!(chapter.page, Some(1));
+        assert_eq!(chapter.zoom, Some(Zoom::FitH { top: Some(700.0) }));
+        assert!(chapter.open && chapter.bold && !chapter.italic);
+        assert_eq!(chapter.color, Some([1.0, 0.0, 0.0]));
+        assert_eq!(chapter.children.len(), 2);
+        assert_eq!(chapter.children[0].page, Some(2));
+        assert!(chapter.children[0].italic);
+        assert_eq!(chapter.children[0].zoom, outline[0].children[0].zoom);
+        assert_eq!(chapter.children[1].page, None);
+        assert_eq!(read[1].action, Some(uri("https://example.com")));
+        assert_eq!(read[2].page, Some(3));
+        assert_eq!(read[2].zoom, None);
+    }
+
+    #[test]
+    fn keeps_actions_styles_and_named_destinations() {
+        let mut doc = document(2);
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        let script_id = doc.add_object(Stream::new(dictionary! {}, b"app.alert(1)".to_vec()));
+        let root = doc.add_object(dictionary! { "Type" => "Outlines" });
+        let items = [
+            dictionary! {
+                "Title" => Object::string_literal("Appendix"),
+                "Dest" => Object::string_literal("appendix"),
+                "C" => vec![0.into(), 0.into(), Object::Real(1.0)],
+                "F" => 3,
+            },
+            dictionary! {
+                "Title" => Object::string_literal("Other file"),
+                "A" => dictionary! {
+                    "S" => "GoToR",
+                    "F" => Object::string_literal("other.pdf"),
+                    "D" => vec![0.into(), "Fit".into()],
+                },
+            },
+            dictionary! {
+                "Title" => Object::string_literal("Script"),
+                "A" => dictionary! {
+                    "S" => "JavaScript",
+                    "JS" => script_id,
+                    "Next" => dictionary! { "S" => "GoTo", "D" => vec![pages[0].into(), "Fit".into()] },
+                },
+            },
+        ];
+        let ids: Vec<ObjectId> = items.into_iter().map(|item| doc.add_object(item)).collect();
+        relink_outline_children(&mut doc, root, &ids);
+        let catalog = doc.catalog_mut().unwrap();
+        catalog.set("Outlines", root);
+        catalog.set(
+            "Names",
+            dictionary! {
+                "Dests" => dictionary! {
+                    "Names" => vec![
+                        Object::string_literal("appendix"),
+                        vec![pages[1].into(), "Fit".into()].into(),
+                    ],
+                },
+            },
+        );
+
+        let original = testing::save(&mut doc);
+        let read = get_outline_blocking(&PdfSource::Content {
+            content: original.clone(),
+        })
+        .unwrap();
+        assert_eq!(read[0].page, Some(2));
+        assert_eq!(read[0].destination_name.as_deref(), Some("appendix"));
+        assert!(read[0].bold && read[0].italic);
+        assert_eq!(read[0].color, Some([0.0, 0.0, 1.0]));
+        assert!(read[1].action.is_some() && read[2].action.is_some());
+
+        // Written back unchanged, every bookmark does what it did
+        let content = set_outline_blocking(&original, &read).unwrap();
+        let doc = Document::load_mem(&content).unwrap();
+        let root = outline_root(&doc).unwrap();
+        let written: Vec<&Dictionary> = outline_children(&doc, root)
+            .into_iter()
+            .map(|id| doc.get_dictionary(id).unwrap())
+            .collect();
+        assert_eq!(
+            written[0].get(b"Dest").unwrap(),
+            &Object::string_literal("appendix")
+        );
+        assert_eq!(written[0].get(b"F").unwrap(), &Object::Integer(3));
+
+        let go_to_r = written[1].get(b"A").and_then(Object::as_dict).unwrap();
+        assert_eq!(go_to_r.get(b"S").unwrap(), &Object::Name(b"GoToR".to_vec()));
+        assert_eq!(
+            go_to_r.get(b"F").unwrap(),
+            &Object::string_literal("other.pdf")
+        );
+        assert_eq!(
+            go_to_r.get(b"D").unwrap(),
+            &Object::Array(vec![0.into(), "Fit".into()])
+        );
+
+        let script = written[2].get(b"A").and_then(Object::as_dict).unwrap();
+        let script_stream = doc
+            .get_object(script.get(b"JS").and_then(Object::as_reference).unwrap())
+            .and_then(Object::as_stream)
+            .unwrap();
+        assert_eq!(script_stream.content, b"app.alert(1)");
+        let next = script.get(b"Next").and_then(Object::as_dict).unwrap();
+        let first_page = doc.get_pages()[&1];
+        assert_eq!(
+            next.get(b"D").and_then(Object::as_array).unwrap()[0],
+            Object::Reference(first_page)
+        );
+    }
+
+    #[test]
+    fn drops_named_destinations_that_no_longer_match() {
+        let mut doc = document(2);
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        doc.catalog_mut().unwrap().set(
+            "Dests",
+            dictionary! { "intro" => vec![pages[0].into(), "Fit".into()] },
+        );
+        let content = testing::save(&mut doc);
+
+        let kept = OutlineItem {
+            destination_name: Some("intro".to_string()),
+            zoom: Some(Zoom::Fit),
+            ..item("Intro", Some(1))
+        };
+        let moved = OutlineItem {
+            page: Some(2),
+            ..kept.clone()
+        };
+        let content = set_outline_blocking(&content, &[kept, moved]).unwrap();
+        let doc = Document::load_mem(&content).unwrap();
+        let root = outline_root(&doc).unwrap();
+        let dests: Vec<Object> = outline_children(&doc, root)
+            .into_iter()
+            .map(|id| {
+                doc.get_dictionary(id)
+                    .unwrap()
+                    .get(b"Dest")
+                    .unwrap()
+                    .clone()
+            })
+            .collect();
+        assert_eq!(dests[0], Object::Name(b"intro".to_vec()));
+        assert!(dests[1].as_array().is_ok());
+    }
+
+    #[test]
+    fn rejects_pages_that_do_not_exist() {
+        let content = testing::save(&mut document(1));
+        let error = set_outline_blocking(&content, &[item("Missing", Some(2))]).unwrap_err();
+        assert!(error.contains("page 2"), "{}", error);
+    }
+
+    #[test]
+    fn removes_the_outline_when_empty() {
+        let content = testing::save(&mut document(1));
+        let content = set_outline_blocking(&content, &[item("Only", Some(1))]).unwrap();
+        let content = set_outline_blocking(&content, &[]).unwrap();
+        assert!(get_outline_blocking(&PdfSource::Content { content })
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+use crate::compression::{ChromaSubsampling, CompressionOptions};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager};
+
+/// A named bundle of compression settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressionPreset {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub options: CompressionOptions,
+    /// Built-in presets can't be overwritten or deleted
+    #[serde(default)]
+    pub built_in: bool,
+}
+
+/// The presets every user has, from smallest file to best quality
+pub fn built_in_presets() -> Vec<CompressionPreset> {
+    let preset = |name: &str, description: &str, options| CompressionPreset {
+        name: name.to_string(),
+        description: description.to_string(),
+        options,
+        built_in: true,
+    };
+
+    vec![
+        preset(
+            "Screen",
+            "Smallest file for reading on screen: 72 DPI images, no metadata",
+            CompressionOptions {
+                quality: Some(40),
+                target_dpi: Some(72),
+                strip_metadata: true,
+                jpeg_progressive: true,
+                chroma_subsampling: ChromaSubsampling::Yuv420,
+                ..Default::default()
+            },
+        ),
+        preset(
+            "Ebook",
+            "Good quality for tablets and e-readers: 150 DPI images, no metadata",
+            CompressionOptions {
+                quality: Some(60),
+                target_dpi: Some(150),
+                strip_metadata: true,
+                jpeg_progressive: true,
+                chroma_subsampling: ChromaSubsampling::Yuv420,
+                ..Default::default()
+            },
+        ),
+        preset(
+            "Printer",
+            "High quality for office printing: 300 DPI images",
+            CompressionOptions {
+                quality: Some(85),
+                target_dpi: Some(300),
+                ..Default::default()
+            },
+        ),
+        preset(
+            "Prepress",
+            "No visible change for professional printing: lossless optimisation only",
+            CompressionOptions {
+                lossless: true,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+fn get_presets_file_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    let app_data_dir = app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to get app data directory: {}", e))?;
+
+    std::fs::create_dir_all(&app_data_dir)
+        .map_err(|e| format!("Failed to create app data directory: {}", e))?;
+
+    Ok(app_data_dir.join("compression_presets.json"))
+}
+
+fn read_user_presets(app_handle: &AppHandle) -> Result<Vec<CompressionPreset>, String> {
+    read_presets_file(&get_presets_file_path(app_handle)?)
+}
+
+fn write_user_presets(app_handle: &AppHandle, presets: &[CompressionPreset]) -> Result<(), String> {
+    write_presets_file(&get_presets_file_path(app_handle)?, presets)
+}
+
+fn read_presets_file(presets_file: &Path) -> Result<Vec<CompressionPreset>, String> {
+    if !presets_file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = std::fs::read_to_string(presets_file)
+        .map_err(|e| format!("Failed to read presets file: {}", e))?;
+
+    let presets: Vec<CompressionPreset> = serde_json::from_str(&content)
+        .map_err(|e| format!("Failed to parse presets file: {}", e))?;
+
+    Ok(presets)
+}
+
+/// Write the presets to a temporary file and move it into place, so a crash midway leaves
+/// the previous file intact
+fn write_presets_file(presets_file: &Path, presets: &[CompressionPreset]) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(presets)
+        .map_err(|e| format!("Failed to serialize presets: {}", e))?;
+
+    let temp_file = presets_file.with_extension("json.tmp");
+    let written = File::create(&temp_file).and_then(|mut file| {
+        file.write_all(content.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| std::fs::rename(&temp_file, presets_file)) {
+        let _ = std::fs::remove_file(&temp_file);
+        return Err(format!("Failed to write presets file: {}", e));
+    }
+
+    Ok(())
+}
+
+/// Built-in presets followed by the user's own, in the order they were saved
+pub fn list_presets(app_handle: &AppHandle) -> Result<Vec<CompressionPreset>, String> {
+    let mut presets = built_in_presets();
+    presets.extend(read_user_presets(app_handle)?);
+    Ok(presets)
+}
+
+/// Save a user preset, replacing any user preset with the same name (ignoring case)
+pub fn save_preset(app_handle: &AppHandle, preset: CompressionPreset) -> Result<(), String> {
+    let mut presets = read_user_presets(app_handle)?;
+    add_preset(&mut presets, preset)?;
+    write_user_presets(app_handle, &presets)
+}
+
+/// Delete a user preset by name (ignoring case)
+pub fn delete_preset(app_handle: &AppHandle, name: &str) -> Result<(), String> {
+    let mut presets = read_user_presets(app_handle)?;
+    remove_preset(&mut presets, name)?;
+    write_user_presets(app_handle, &presets)
+}
+
+/// Add a preset to the user's list, in place of one with the same name (ignoring case)
+fn add_preset(
+    presets: &mut Vec<CompressionPreset>,
+    preset: CompressionPreset,
+) -> Result<(), String> {
+    let name = preset.name.trim().to_string();
+    if name.is_empty() {
+        return Err("Preset name cannot be empty".to_string());
+    }
+    if is_built_in(&name) {
+        return Err(format!("\"{}\" is a built-in preset", name));
+    }
+
+    let preset = CompressionPreset {
+        name,
+        built_in: false,
+        ..preset
+    };
+    match presets
+        .iter_mut()
+        .find(|existing| existing.name.eq_ignore_ascii_case(&preset.name))
+    {
+        Some(existing) => *existing = preset,
+        None => presets.push(preset),
+    }
+    Ok(())
+}
+
+/// Remove a preset from the user's list by name (ignoring case)
+fn remove_preset(presets: &mut Vec<CompressionPreset>, name: &str) -> Result<(), String> {
+    let name = name.trim();
+    if is_built_in(name) {
+        return Err(format!("\"{}\" is a built-in preset", name));
+    }
+
+    let count = presets.len();
+    presets.retain(|preset| !preset.name.eq_ignore_ascii_case(name));
+    if presets.len() == count {
+        return Err(format!("No preset named \"{}\"", name));
+    }
+    Ok(())
+}
+
+fn is_built_in(name: &str) -> bool {
+    built_in_presets()
+        .iter()
+        .any(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str, quality: u8) -> CompressionPreset {
+        CompressionPreset {
+            name: name.to_string(),
+            description: String::new(),
+            options: CompressionOptions {
+                quality: Some(quality),
+                ..Default::default()
+            },
+            built_in: true,
+        }
+    }
+
+    fn names(presets: &[CompressionPreset]) -> Vec<&str> {
+        presets.iter().map(|preset| preset.name.as_str()).collect()
+    }
+
+    #[test]
+    fn adds_and_replaces_presets_ignoring_case() {
+        let mut presets = Vec::new();
+        add_preset(&mut presets, preset("  Scans ", 50)).unwrap();
+        add_preset(&mut presets, preset("Photos", 80)).unwrap();
+        add_preset(&mut presets, preset("SCANS", 30)).unwrap();
+
+        assert_eq!(names(&presets), ["SCANS", "Photos"]);
+        assert_eq!(presets[0].options.quality, Some(30));
+        assert!(presets.iter().all(|preset| !preset.built_in));
+    }
+
+    #[test]
+    fn rejects_empty_and_built_in_names() {
+        let mut presets = vec![preset("Mine", 50)];
+        assert!(add_preset(&mut presets, preset("   ", 50)).is_err());
+        assert!(add_preset(&mut presets, preset("ebook", 50)).is_err());
+        assert!(remove_preset(&mut presets, " Screen ").is_err());
+        assert!(remove_preset(&mut presets, "Other").is_err());
+        assert_eq!(names(&presets), ["Mine"]);
+
+        remove_preset(&mut presets, "mine").unwrap();
+        assert!(presets.is_empty());
+    }
+
+    #[test]
+    fn replaces_the_presets_file_whole() {
+        let dir = std::env::temp_dir().join(format!("leed-presets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("compression_presets.json");
+        assert!(read_presets_file(&file).unwrap().is_empty());
+
+        write_presets_file(&file, &[preset("One", 10), preset("Two", 20)]).unwrap();
+        write_presets_file(&file, &[preset("Three", 30)]).unwrap();
+        assert_eq!(names(&read_presets_file(&file).unwrap()), ["Three"]);
+        let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,549 @@
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+
+/// Resource categories content streams refer to by name. Anything else in `/Resources`
+/// (`/ProcSet`) is left alone.
+const CATEGORIES: [&[u8]; 7] = [
+    b"Font",
+    b"XObject",
+    b"ExtGState",
+    b"ColorSpace",
+    b"Pattern",
+    b"Shading",
+    b"Properties",
+];
+const FONT: usize = 0;
+const XOBJECT: usize = 1;
+const EXT_G_STATE: usize = 2;
+const COLOR_SPACE: usize = 3;
+const PATTERN: usize = 4;
+const SHADING: usize = 5;
+const PROPERTIES: usize = 6;
+
+/// Page tree deeper than this is treated as malformed (or cyclic) when looking for
+/// inherited resources
+const MAX_PAGE_TREE_DEPTH: u32 = 64;
+
+/// Where a `/Resources` dictionary lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ResourcesAt {
+    /// An indirect object of its own
+    Object(ObjectId),
+    /// Directly in the dictionary of this page, page tree node or form XObject
+    In(ObjectId),
+}
+
+impl ResourcesAt {
+    /// The object whose references to category dictionaries this dictionary holds
+    fn holder(self) -> ObjectId {
+        match self {
+            ResourcesAt::Object(id) | ResourcesAt::In(id) => id,
+        }
+    }
+}
+
+/// Where a category dictionary (`/Font`, `/XObject`, ...) lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CategoryAt {
+    /// An indirect object of its own
+    Object(ObjectId),
+    /// Directly in a resources dictionary, by index into `CATEGORIES`
+    In(ResourcesAt, usize),
+}
+
+/// Names used from each category dictionary, found by walking the pages' content streams
+/// and the form XObjects they draw
+#[derive(Default)]
+struct Usage {
+    /// None when any name could be in use, e.g. because a content stream didn't parse
+    names: HashMap<CategoryAt, Option<HashSet<Vec<u8>>>>,
+    /// For indirect resources and category dictionaries, the objects the walk found
+    /// references to them in
+    referrers: HashMap<ObjectId, HashSet<ObjectId>>,
+    indirect_resources: HashSet<ObjectId>,
+    seen_resources: HashSet<ResourcesAt>,
+    visited_forms: HashSet<(ObjectId, ResourcesAt)>,
+}
+
+/// Remove `/Resources` entries that no content stream uses, on pages and on the form
+/// XObjects they draw (nested forms included). Pages left over from deleting or merging
+/// often keep fonts and images they no longer show, which keeps `prune_objects` from
+/// dropping them. Returns how many entries were removed; run `prune_objects` afterwards.
+///
+/// A dictionary is only pruned when every reference to it was seen on the walk: one that is
+/// also used somewhere else (an annotation appearance, the AcroForm defaults, a Type 3
+/// font's glyphs) is left alone, as is everything a content stream that doesn't parse uses.
+pub fn remove_unused_resources(doc: &mut Document) -> u32 {
+    let mut usage = Usage::default();
+    for page_id in doc.get_pages().into_values() {
+        let Some(resources) = usage.page_resources(doc, page_id) else {
+            continue;
+        };
+        let content = page_content(doc, page_id);
+        usage.walk_content(doc, content, resources);
+    }
+
+    let references = count_references(doc);
+    let mut removed = 0;
+    for (at, names) in &usage.names {
+        let Some(names) = names else {
+            continue;
+        };
+        if !usage.fully_seen(*at, &references) {
+            continue;
+        }
+        let Some(dict) = category_dict_mut(doc, *at) else {
+            continue;
+        };
+        let unused: Vec<Vec<u8>> = dict
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| !names.contains(*name))
+            .cloned()
+            .collect();
+        for name in unused {
+            dict.remove(&name);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+impl Usage {
+    /// The resources a page uses: its own, or the nearest ancestor's in the page tree
+    fn page_resources(&mut self, doc: &Document, page_id: ObjectId) -> Option<ResourcesAt> {
+        let mut node_id = page_id;
+        for _ in 0..MAX_PAGE_TREE_DEPTH {
+            let node = doc.get_dictionary(node_id).ok()?;
+            if let Ok(resources) = node.get(b"Resources") {
+                return self.resources_at(resources, node_id);
+            }
+            node_id = node.get(b"Parent").and_then(Object::as_reference).ok()?;
+        }
+        None
+    }
+
+    /// Locate the `/Resources` value held by `holder`, recording the reference if it's one
+    fn resources_at(&mut self, resources: &Object, holder: ObjectId) -> Option<ResourcesAt> {
+        match resources {
+            Object::Reference(id) => {
+                self.referrers.entry(*id).or_default().insert(holder);
+                self.indirect_resources.insert(*id);
+                Some(ResourcesAt::Object(*id))
+            }
+            Object::Dictionary(_) => Some(ResourcesAt::In(holder)),
+            _ => None,
+        }
+    }
+
+    /// Record the names a content stream uses from `resources`, following form XObjects.
+    /// `content` is None when the stream couldn't be read.
+    fn walk_content(&mut self, doc: &Document, content: Option<Vec<u8>>, resources: ResourcesAt) {
+        self.record_resources(doc, resources);
+        let Some(content) = content.as_deref().and_then(decode_content) else {
+            self.use_all(doc, resources);
+            return;
+        };
+
+        for operation in &content.operations {
+            let operands = &operation.operands;
+            let (category, name) = match operation.operator.as_str() {
+                "Tf" => (FONT, operands.first()),
+                "Do" => (XOBJECT, operands.first()),
+                "gs" => (EXT_G_STATE, operands.first()),
+                "cs" | "CS" => (COLOR_SPACE, operands.first()),
+                "scn" | "SCN" => (PATTERN, operands.last()),
+                "sh" => (SHADING, operands.first()),
+                "BDC" | "DP" => (PROPERTIES, operands.get(1)),
+                // Inline images can name a colour space from the resources
+                "BI" => match operands.first() {
+                    Some(Object::Stream(image)) => (
+                        COLOR_SPACE,
+                        image
+                            .dict
+                            .get(b"CS")
+                            .or_else(|_| image.dict.get(b"ColorSpace"))
+                            .ok(),
+                    ),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            if let Some(Ok(name)) = name.map(Object::as_name) {
+                self.use_name(doc, resources, category, name);
+            }
+        }
+    }
+
+    /// Note the category dictionaries of a resources dictionary, so any the walk finds no
+    /// use for are emptied
+    fn record_resources(&mut self, doc: &Document, resources: ResourcesAt) {
+        if !self.seen_resources.insert(resources) {
+            return;
+        }
+        let Some(dict) = resources_dict(doc, resources) else {
+            return;
+        };
+        for (index, category) in CATEGORIES.iter().enumerate() {
+            let at = match dict.get(category) {
+                Ok(Object::Reference(id)) => {
+                    self.referrers
+                        .entry(*id)
+                        .or_default()
+                        .insert(resources.holder());
+                    CategoryAt::Object(*id)
+                }
+                Ok(Object::Dictionary(_)) => CategoryAt::In(resources, index),
+                _ => continue,
+            };
+            self.names.entry(at).or_insert_with(|| Some(HashSet::new()));
+        }
+    }
+
+    fn use_name(&mut self, doc: &Document, resources: ResourcesAt, category: usize, name: &[u8]) {
+        let Some(at) = category_at(doc, resources, category) else {
+            return;
+        };
+        if let Some(Some(names)) = self.names.get_mut(&at) {
+            names.insert(name.to_vec());
+        }
+
+        let Some(entry) = category_dict(doc, at).and_then(|dict| dict.get(name).ok()) else {
+            return;
+        };
+        match category {
+            XOBJECT => {
+                if let Object::Reference(form_id) = entry {
+                    self.walk_form(doc, *form_id, resources);
+                }
+            }
+            // Type 3 glyphs without resources of their own draw with the page's
+            FONT => {
+                let font = doc.dereference(entry).and_then(|(_, font)| font.as_dict());
+                if let Ok(font) = font {
+                    let is_type3 =
+                        matches!(font.get(b"Subtype").and_then(Object::as_name), Ok(b"Type3"));
+                    if is_type3 && !font.has(b"Resources") {
+                        self.use_all(doc, resources);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Walk a form XObject drawn with `parent` resources in effect
+    fn walk_form(&mut self, doc: &Document, form_id: ObjectId, parent: ResourcesAt) {
+        let Ok(form) = doc.get_object(form_id).and_then(Object::as_stream) else {
+            return;
+        };
+        if !matches!(
+            form.dict.get(b"Subtype").and_then(Object::as_name),
+            Ok(b"Form")
+        ) {
+            return;
+        }
+        // Forms from before PDF 1.2 may leave out /Resources and use their painter's
+        let resources = match form.dict.get(b"Resources") {
+            Ok(resources) => match self.resources_at(resources, form_id) {
+                Some(resources) => resources,
+                None => return,
+            },
+            Err(_) => parent,
+        };
+        if !self.visited_forms.insert((form_id, resources)) {
+            return;
+        }
+        let content = stream_content(form);
+        self.walk_content(doc, content, resources);
+    }
+
+    /// Keep every entry of every category in `resources`
+    fn use_all(&mut self, doc: &Document, resources: ResourcesAt) {
+        for category in 0..CATEGORIES.len() {
+            if let Some(at) = category_at(doc, resources, category) {
+                self.names.insert(at, None);
+            }
+        }
+    }
+
+    /// Whether the walk saw every reference to the dictionary at `at`, and to the indirect
+    /// resources dictionaries holding it
+    fn fully_seen(&self, at: CategoryAt, references: &HashMap<ObjectId, usize>) -> bool {
+        let seen = |id: ObjectId| {
+            self.referrers.get(&id).map_or(0, HashSet::len)
+                == references.get(&id).copied().unwrap_or(0)
+        };
+        match at {
+            CategoryAt::In(ResourcesAt::In(_), _) => true,
+            CategoryAt::In(ResourcesAt::Object(id), _) => seen(id),
+            CategoryAt::Object(id) => {
+                seen(id)
+                    && self.referrers[&id]
+                        .iter()
+                        .all(|holder| !self.indirect_resources.contains(holder) || seen(*holder))
+            }
+        }
+    }
+}
+
+/// Operator appended by `decode_content` to find out whether the parser reached the end
+const END_OF_CONTENT: &str = "LeedEndOfContent";
+
+/// Parse content stream data, or None unless all of it parses. lopdf's parser stops at the
+/// first token it can't read and returns the operations before it, so a marker operator is
+/// appended and has to come back as the last operation.
+pub fn decode_content(data: &[u8]) -> Option<Content> {
+    let mut input = Vec::with_capacity(data.len() + END_OF_CONTENT.len() + 2);
+    input.extend_from_slice(data);
+    input.push(b'\n');
+    input.extend_from_slice(END_OF_CONTENT.as_bytes());
+    input.push(b'\n');
+
+    let mut content = Content::decode(&input).ok()?;
+    match content.operations.pop() {
+        Some(end) if end.operator == END_OF_CONTENT && end.operands.is_empty() => Some(content),
+        _ => None,
+    }
+}
+
+/// Decoded content of all a page's content streams, or None if any can't be decoded
+pub fn page_content(doc: &Document, page_id: ObjectId) -> Option<Vec<u8>> {
+    let mut content = Vec::new();
+    for content_id in doc.get_page_contents(page_id) {
+        let stream = doc
+            .get_object(content_id)
+            .and_then(Object::as_stream)
+            .ok()?;
+        content.extend(stream_content(stream)?);
+        // Streams are split between tokens, so a separator keeps the last token of one
+        // from running into the first of the next
+        content.push(b'\n');
+    }
+    Some(content)
+}
+
+pub fn stream_content(stream: &lopdf::Stream) -> Option<Vec<u8>> {
+    if stream.dict.has(b"Filter") {
+        stream.decompressed_content().ok()
+    } else {
+        Some(stream.content.clone())
+    }
+}
+
+fn resources_dict(doc: &Document, resources: ResourcesAt) -> Option<&Dictionary> {
+    match resources {
+        ResourcesAt::Object(id) => doc.get_dictionary(id).ok(),
+        ResourcesAt::In(holder) => {
+            let holder = match doc.get_object(holder).ok()? {
+                Object::Dictionary(dict) => dict,
+                Object::Stream(stream) => &stream.dict,
+                _ => return None,
+            };
+            holder.get(b"Resources").and_then(Object::as_dict).ok()
+        }
+    }
+}
+
+fn category_at(doc: &Document, resources: ResourcesAt, category: usize) -> Option<CategoryAt> {
+    match resources_dict(doc, resources)?
+        .get(CATEGORIES[category])
+        .ok()?
+    {
+        Object::Reference(id) => Some(CategoryAt::Object(*id)),
+        Object::Dictionary(_) => Some(CategoryAt::In(resources, category)),
+        _ => None,
+    }
+}
+
+fn category_dict(doc: &Document, at: CategoryAt) -> Option<&Dictionary> {
+    match at {
+        CategoryAt::Object(id) => doc.get_dictionary(id).ok(),
+        CategoryAt::In(resources, category) => resources_dict(doc, resources)?
+            .get(CATEGORIES[category])
+            .and_then(Object::as_dict)
+            .ok(),
+    }
+}
+
+fn category_dict_mut(doc: &mut Document, at: CategoryAt) -> Option<&mut Dictionary> {
+    let (resources, category) = match at {
+        CategoryAt::Object(id) => return doc.get_dictionary_mut(id).ok(),
+        CategoryAt::In(resources, category) => (resources, category),
+    };
+    let resources = match resources {
+        ResourcesAt::Object(id) => doc.get_dictionary_mut(id).ok()?,
+        ResourcesAt::In(holder) => {
+            let holder = match doc.get_object_mut(holder).ok()? {
+                Object::Dictionary(dict) => dict,
+                Object::Stream(stream) => &mut stream.dict,
+                _ => return None,
+            };
+            holder
+                .get_mut(b"Resources")
+                .and_then(Object::as_dict_mut)
+                .ok()?
+        }
+    };
+    resources
+        .get_mut(CATEGORIES[category])
+        .and_then(Object::as_dict_mut)
+        .ok()
+}
+
+/// How many references to each object there are, across every object and the trailer
+fn count_references(doc: &Document) -> HashMap<ObjectId, usize> {
+    fn count(object: &Object, counts: &mut HashMap<ObjectId, usize>) {
+        match object {
+            Object::Reference(id) => *counts.entry(*id).or_insert(0) += 1,
+            Object::Array(items) => items.iter().for_each(|item| count(item, counts)),
+            Object::Dictionary(dict) => dict.iter().for_each(|(_, value)| count(value, counts)),
+            Object::Stream(stream) => stream
+                .dict
+                .iter()
+                .for_each(|(_, value)| count(value, counts)),
+            _ => {}
+        }
+    }
+
+    let mut counts = HashMap::new();
+    for object in doc.objects.values() {
+        count(object, &mut counts);
+    }
+    for (_, value) in doc.trailer.iter() {
+        count(value, &mut counts);
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::{dictionary, Stream};
+
+    /// A one-page document drawing `content` with the resources `resources` adds
+    fn document(
+        content: &[u8],
+        resources: impl FnOnce(&mut Document) -> Dictionary,
+    ) -> (Document, ObjectId) {
+        let mut resources = Some(resources);
+        let (doc, page_ids) = testing::document(1, |doc, _| {
+            let resources = resources.take().unwrap()(doc);
+            dictionary! {
+                "Contents" => testing::content(doc, content),
+                "Resources" => resources,
+            }
+        });
+        (doc, page_ids[0])
+    }
+
+    fn font(base_font: &str) -> Dictionary {
+        dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => base_font }
+    }
+
+    fn fonts() -> Dictionary {
+        dictionary! { "F1" => font("Helvetica"), "F2" => font("Courier") }
+    }
+
+    fn font_names(doc: &Document, page_id: ObjectId) -> Vec<Vec<u8>> {
+        let page = doc.get_dictionary(page_id).unwrap();
+        let resources = page.get(b"Resources").and_then(Object::as_dict).unwrap();
+        let fonts = resources.get(b"Font").and_then(Object::as_dict).unwrap();
+        fonts.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    #[test]
+    fn decodes_only_complete_content() {
+        assert_eq!(
+            decode_content(b"q 1 0 0 1 0 0 cm Q")
+                .unwrap()
+                .operations
+                .len(),
+            3
+        );
+        assert_eq!(
+            decode_content(b"q Q % trailing comment")
+                .unwrap()
+                .operations
+                .len(),
+            2
+        );
+        assert!(decode_content(b"1 0 0 rg 0 0 10 10 re f ] 0 1 0 rg").is_none());
+        assert!(decode_content(b"BT (unterminated Tj ET").is_none());
+        assert!(decode_content(b"").unwrap().operations.is_empty());
+    }
+
+    #[test]
+    fn removes_unused_fonts() {
+        let (mut doc, page_id) = document(b"BT /F1 12 Tf (Hi) Tj ET", |_| {
+            dictionary! { "Font" => fonts() }
+        });
+        assert_eq!(remove_unused_resources(&mut doc), 1);
+        assert_eq!(font_names(&doc, page_id), [b"F1".to_vec()]);
+    }
+
+    #[test]
+    fn keeps_everything_when_content_has_a_stray_token() {
+        let (mut doc, page_id) =
+            document(b"BT /F1 12 Tf (Hi) Tj ET ] BT /F2 12 Tf (Hi) Tj ET", |_| {
+                dictionary! { "Font" => fonts() }
+            });
+        assert_eq!(remove_unused_resources(&mut doc), 0);
+        assert_eq!(font_names(&doc, page_id).len(), 2);
+    }
+
+    #[test]
+    fn follows_form_xobjects() {
+        let (mut doc, page_id) = document(b"BT /F1 12 Tf ET /Fm0 Do", |doc| {
+            // No /Resources, so the form draws with the page's
+            let form_id = doc.add_object(Stream::new(
+                dictionary! {
+                    "Type" => "XObject",
+                    "Subtype" => "Form",
+                    "BBox" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+                },
+                b"BT /F2 12 Tf (Hi) Tj ET".to_vec(),
+            ));
+            let mut fonts = fonts();
+            fonts.set("F3", font("Symbol"));
+            dictionary! {
+                "Font" => fonts,
+                "XObject" => dictionary! { "Fm0" => form_id, "Fm1" => form_id },
+            }
+        });
+        assert_eq!(remove_unused_resources(&mut doc), 2);
+        assert_eq!(font_names(&doc, page_id), [b"F1".to_vec(), b"F2".to_vec()]);
+    }
+
+    #[test]
+    fn keeps_resources_used_elsewhere() {
+        let mut fonts_id = None;
+        let (mut doc, page_id) = document(b"BT /F1 12 Tf (Hi) Tj ET", |doc| {
+            let id = doc.add_object(fonts());
+            fonts_id = Some(id);
+            dictionary! { "Font" => id }
+        });
+        let fonts_id = fonts_id.unwrap();
+
+        // An annotation appearance draws with the same font dictionary
+        let appearance_id = doc.add_object(Stream::new(
+            dictionary! {
+                "Subtype" => "Form",
+                "Resources" => dictionary! { "Font" => fonts_id },
+            },
+            b"BT /F2 12 Tf (Hi) Tj ET".to_vec(),
+        ));
+        let annotation = dictionary! {
+            "Subtype" => "FreeText",
+            "AP" => dictionary! { "N" => appearance_id },
+        };
+        doc.get_dictionary_mut(page_id)
+            .unwrap()
+            .set("Annots", vec![Object::Dictionary(annotation)]);
+
+        assert_eq!(remove_unused_resources(&mut doc), 0);
+        assert_eq!(doc.get_dictionary(fonts_id).unwrap().len(), 2);
+    }
+}
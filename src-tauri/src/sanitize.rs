@@ -0,0 +1,704 @@
+use lopdf::{decode_text_string, Dictionary, Document, Object, ObjectId, StringFormat};
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name trees deeper than this are treated as malformed (or cyclic) and left alone
+const MAX_NAME_TREE_DEPTH: u32 = 32;
+
+/// Actions besides JavaScript that start another program, send or load form data, or open
+/// another file
+const EXTERNAL_ACTIONS: [&[u8]; 5] = [b"Launch", b"SubmitForm", b"ImportData", b"GoToR", b"GoToE"];
+
+/// PDF bytes with identifying and active content removed, and a list of what went
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SanitizedPdf {
+    pub content: Vec<u8>,
+    pub removed: Vec<SanitizedItem>,
+}
+
+/// One thing the sanitizer removed or reset
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SanitizedItem {
+    pub kind: SanitizedKind,
+    /// What it was, e.g. `Author: Jane Doe` or an attachment's file name
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SanitizedKind {
+    /// An entry of the document information dictionary
+    Info,
+    /// An XMP metadata stream
+    Xmp,
+    /// The file identifier in the trailer, replaced with a random one
+    FileId,
+    /// An attached or associated file
+    EmbeddedFile,
+    /// A document-level script or a JavaScript action
+    JavaScript,
+    /// A launch, form submission, data import or go-to-another-file action
+    Action,
+    /// An XFA form, which can carry scripts of its own
+    Xfa,
+}
+
+impl SanitizedItem {
+    fn new(kind: SanitizedKind, description: impl Into<String>) -> SanitizedItem {
+        SanitizedItem {
+            kind,
+            description: description.into(),
+        }
+    }
+}
+
+pub fn sanitize_pdf_blocking(content: &[u8]) -> Result<SanitizedPdf, String> {
+    let mut doc = Document::load_mem(content).map_err(|e| format!("Failed to load PDF: {}", e))?;
+    let removed = sanitize_document(&mut doc)?;
+
+    let mut output = Vec::new();
+    doc.save_to(&mut output)
+        .map_err(|e| format!("Failed to save sanitized PDF: {}", e))?;
+
+    // Only counts: the descriptions hold the very data that was removed
+    println!("PDF sanitized: {} items removed", removed.len());
+    let mut counts: Vec<(SanitizedKind, usize)> = Vec::new();
+    for item in &removed {
+        match counts.iter_mut().find(|(kind, _)| *kind == item.kind) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((item.kind, 1)),
+        }
+    }
+    for (kind, count) in counts {
+        println!("  {:?}: {}", kind, count);
+    }
+
+    Ok(SanitizedPdf {
+        content: output,
+        removed,
+    })
+}
+
+/// Remove what a PDF gives away about its author and origin, and anything it could run or
+/// carry besides its pages: the document information dictionary, XMP metadata, embedded
+/// and associated files, JavaScript, actions that reach outside the document, and XFA
+/// forms. The file identifier is replaced with a random one, since viewers and PDF/A expect
+/// one. Pages render the same afterwards.
+pub fn sanitize_document(doc: &mut Document) -> Result<Vec<SanitizedItem>, String> {
+    // Rewriting an encrypted file would need it decrypted first
+    if doc.is_encrypted() {
+        return Err("Encrypted PDFs can't be sanitized".to_string());
+    }
+
+    let mut removed = Vec::new();
+    remove_info(doc, &mut removed);
+    remove_xmp(doc, &mut removed);
+    remove_embedded_files(doc, &mut removed);
+    remove_actions(doc, &mut removed);
+    remove_xfa(doc, &mut removed);
+    reset_file_id(doc, &mut removed);
+
+    // Drop the streams and dictionaries left unreferenced, so their data isn't saved
+    doc.prune_objects();
+    Ok(removed)
+}
+
+fn remove_info(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    let Some(info) = doc.trailer.remove(b"Info") else {
+        return;
+    };
+    let Ok((_, Object::Dictionary(info))) = doc.dereference(&info) else {
+        removed.push(SanitizedItem::new(
+            SanitizedKind::Info,
+            "Document information",
+        ));
+        return;
+    };
+    for (key, value) in info.iter() {
+        let key = String::from_utf8_lossy(key);
+        let description = match decode_text_string(value) {
+            Ok(text) => format!("{}: {}", key, text),
+            Err(_) => key.into_owned(),
+        };
+        removed.push(SanitizedItem::new(SanitizedKind::Info, description));
+    }
+}
+
+fn remove_xmp(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    let catalog_id = doc.trailer.get(b"Root").and_then(Object::as_reference).ok();
+    for (&id, object) in doc.objects.iter_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if dict.remove(b"Metadata").is_none() {
+            continue;
+        }
+        let description = if Some(id) == catalog_id {
+            "Document XMP metadata".to_string()
+        } else {
+            let kind = dict
+                .get(b"Subtype")
+                .or_else(|_| dict.get(b"Type"))
+                .and_then(Object::as_name)
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .unwrap_or_else(|_| "object".to_string());
+            format!("XMP metadata on {} {} {}", kind, id.0, id.1)
+        };
+        removed.push(SanitizedItem::new(SanitizedKind::Xmp, description));
+    }
+}
+
+/// Remove the `/EmbeddedFiles` name tree, file attachment annotations, associated files
+/// (`/AF`) and portfolio `/Collection` settings
+fn remove_embedded_files(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    if let Some(tree) = take_catalog_name_tree(doc, b"EmbeddedFiles") {
+        let mut entries = Vec::new();
+        collect_name_tree(doc, &tree, 0, &mut entries);
+        for (name, file_spec) in entries {
+            let description = file_spec_name(doc, &file_spec)
+                .unwrap_or_else(|| String::from_utf8_lossy(&name).into_owned());
+            removed.push(SanitizedItem::new(SanitizedKind::EmbeddedFile, description));
+        }
+    }
+    let collection = doc
+        .catalog_mut()
+        .ok()
+        .and_then(|catalog| catalog.remove(b"Collection"));
+    if collection.is_some() {
+        removed.push(SanitizedItem::new(
+            SanitizedKind::EmbeddedFile,
+            "Portfolio settings",
+        ));
+    }
+
+    for (page_number, page_id) in doc.get_pages() {
+        let Ok(annots) = doc
+            .get_dictionary(page_id)
+            .and_then(|page| page.get(b"Annots"))
+            .cloned()
+        else {
+            continue;
+        };
+        let Ok((annots_id, Object::Array(items))) = doc.dereference(&annots) else {
+            continue;
+        };
+        let mut kept = Vec::with_capacity(items.len());
+        for item in items {
+            let is_attachment = doc
+                .dereference(item)
+                .and_then(|(_, annot)| annot.as_dict())
+                .and_then(|annot| annot.get(b"Subtype"))
+                .and_then(Object::as_name)
+                .is_ok_and(|subtype| subtype == b"FileAttachment");
+            if !is_attachment {
+                kept.push(item.clone());
+                continue;
+            }
+            let name = doc
+                .dereference(item)
+                .and_then(|(_, annot)| annot.as_dict())
+                .ok()
+                .and_then(|annot| annot.get(b"FS").ok())
+                .and_then(|file_spec| file_spec_name(doc, file_spec));
+            removed.push(SanitizedItem::new(
+                SanitizedKind::EmbeddedFile,
+                format!(
+                    "{} (attached to page {})",
+                    name.as_deref().unwrap_or("File"),
+                    page_number
+                ),
+            ));
+        }
+        if kept.len() == items.len() {
+            continue;
+        }
+        // The annotation array may be shared between pages, so it's replaced where it lives
+        match annots_id {
+            Some(id) => {
+                doc.objects.insert(id, Object::Array(kept));
+            }
+            None => {
+                if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                    page.set("Annots", kept);
+                }
+            }
+        }
+    }
+
+    let mut associated = Vec::new();
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if let Some(files) = dict.remove(b"AF") {
+            associated.push(files);
+        }
+    }
+    for files in associated {
+        let files = match doc.dereference(&files) {
+            Ok((_, Object::Array(files))) => files.clone(),
+            _ => continue,
+        };
+        for file_spec in &files {
+            removed.push(SanitizedItem::new(
+                SanitizedKind::EmbeddedFile,
+                file_spec_name(doc, file_spec).unwrap_or_else(|| "Associated file".to_string()),
+            ));
+        }
+    }
+}
+
+/// Remove the document-level `/JavaScript` name tree and every JavaScript or external
+/// action: open actions, link and widget actions, additional actions (`/AA`) and `/Next`
+/// chains
+fn remove_actions(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    if let Some(tree) = take_catalog_name_tree(doc, b"JavaScript") {
+        let mut entries = Vec::new();
+        collect_name_tree(doc, &tree, 0, &mut entries);
+        for (name, _) in entries {
+            removed.push(SanitizedItem::new(
+                SanitizedKind::JavaScript,
+                format!("Document script \"{}\"", String::from_utf8_lossy(&name)),
+            ));
+        }
+    }
+
+    let indirect: HashMap<ObjectId, (SanitizedKind, String)> = doc
+        .objects
+        .iter()
+        .filter_map(|(&id, object)| Some((id, removed_action(object.as_dict().ok()?)?)))
+        .collect();
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        if removed_action(dict).is_none() {
+            remove_nested_actions(dict, &indirect, removed);
+        }
+    }
+}
+
+/// What kind of action `dict` is and its type, if it's one to remove
+fn removed_action(dict: &Dictionary) -> Option<(SanitizedKind, String)> {
+    if dict.has(b"JS") {
+        return Some((SanitizedKind::JavaScript, "JavaScript".to_string()));
+    }
+    match dict.get(b"S").and_then(Object::as_name).ok()? {
+        b"JavaScript" => Some((SanitizedKind::JavaScript, "JavaScript".to_string())),
+        action if EXTERNAL_ACTIONS.contains(&action) => Some((
+            SanitizedKind::Action,
+            String::from_utf8_lossy(action).into_owned(),
+        )),
+        _ => None,
+    }
+}
+
+/// Remove entries of `dict`, and of dictionaries and arrays directly inside it, that are
+/// actions to remove. `indirect` holds the ones that are objects of their own.
+fn remove_nested_actions(
+    dict: &mut Dictionary,
+    indirect: &HashMap<ObjectId, (SanitizedKind, String)>,
+    removed: &mut Vec<SanitizedItem>,
+) {
+    let action = |value: &Object| match value {
+        Object::Reference(id) => indirect.get(id).cloned(),
+        Object::Dictionary(dict) => removed_action(dict),
+        _ => None,
+    };
+    let item = |(kind, action): (SanitizedKind, String), key: &[u8]| {
+        SanitizedItem::new(
+            kind,
+            format!("{} action in /{}", action, String::from_utf8_lossy(key)),
+        )
+    };
+
+    let keys: Vec<Vec<u8>> = dict.iter().map(|(key, _)| key.clone()).collect();
+    for key in keys {
+        let Ok(value) = dict.get_mut(&key) else {
+            continue;
+        };
+        if let Some(found) = action(value) {
+            dict.remove(&key);
+            removed.push(item(found, &key));
+            continue;
+        }
+        match value {
+            Object::Dictionary(inner) => {
+                remove_nested_actions(inner, indirect, removed);
+                // An /AA dictionary whose every trigger ran a removed action is now empty
+                if key == b"AA" && inner.is_empty() {
+                    dict.remove(&key);
+                }
+            }
+            Object::Array(items) => {
+                items.retain(|value| match action(value) {
+                    Some(found) => {
+                        removed.push(item(found, &key));
+                        false
+                    }
+                    None => true,
+                });
+                for value in items.iter_mut() {
+                    if let Object::Dictionary(inner) = value {
+                        remove_nested_actions(inner, indirect, removed);
+                    }
+                }
+                // A /Next chain that only ran removed actions
+                if key == b"Next" && items.is_empty() {
+                    dict.remove(&key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Remove the XFA form from the interactive form. Hybrid forms keep their AcroForm fields;
+/// a form that only exists as XFA is left without one, like in viewers without XFA support.
+fn remove_xfa(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    let Ok(catalog) = doc.catalog_mut() else {
+        return;
+    };
+    catalog.remove(b"NeedsRendering");
+    let form = match catalog.get_mut(b"AcroForm") {
+        Ok(Object::Dictionary(form)) => form,
+        Ok(Object::Reference(id)) => {
+            let id = *id;
+            match doc.get_dictionary_mut(id) {
+                Ok(form) => form,
+                Err(_) => return,
+            }
+        }
+        _ => return,
+    };
+    if form.remove(b"XFA").is_some() {
+        removed.push(SanitizedItem::new(SanitizedKind::Xfa, "XFA form"));
+    }
+}
+
+/// Replace the file identifier with a random one, so the output can't be matched to the
+/// file it came from
+fn reset_file_id(doc: &mut Document, removed: &mut Vec<SanitizedItem>) {
+    if !doc.trailer.has(b"ID") {
+        return;
+    }
+    let id = random_file_id();
+    doc.trailer.set(
+        "ID",
+        vec![
+            Object::String(id.clone(), StringFormat::Hexadecimal),
+            Object::String(id, StringFormat::Hexadecimal),
+        ],
+    );
+    removed.push(SanitizedItem::new(SanitizedKind::FileId, "File identifier"));
+}
+
+/// 16 random bytes from the standard library's randomly seeded hasher
+fn random_file_id() -> Vec<u8> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let mut id = Vec::with_capacity(16);
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        id.extend_from_slice(&hasher.finish().to_be_bytes());
+    }
+    id
+}
+
+/// Remove a name tree from the catalog's `/Names` dictionary and return it
+fn take_catalog_name_tree(doc: &mut Document, tree: &[u8]) -> Option<Object> {
+    let names = doc.catalog().ok()?.get(b"Names").ok()?.clone();
+    let names = match names {
+        Object::Reference(id) => doc.get_dictionary_mut(id).ok()?,
+        Object::Dictionary(_) => doc
+            .catalog_mut()
+            .ok()?
+            .get_mut(b"Names")
+            .and_then(Object::as_dict_mut)
+            .ok()?,
+        _ => return None,
+    };
+    names.remove(tree)
+}
+
+/// Every key and value in a name tree
+fn collect_name_tree(
+    doc: &Document,
+    node: &Object,
+    depth: u32,
+    entries: &mut Vec<(Vec<u8>, Object)>,
+) {
+    if depth > MAX_NAME_TREE_DEPTH {
+        return;
+    }
+    let Ok(node) = doc.dereference(node).and_then(|(_, node)| node.as_dict()) else {
+        return;
+    };
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        for pair in names.chunks(2) {
+            if let [name, value] = pair {
+                if let Ok(name) = name.as_str() {
+                    entries.push((name.to_vec(), value.clone()));
+                }
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids {
+            collect_name_tree(doc, kid, depth + 1, entries);
+        }
+    }
+}
+
+/// The file name a file specification gives, preferring the Unicode `/UF`
+fn file_spec_name(doc: &Document, file_spec: &Object) -> Option<String> {
+    match doc.dereference(file_spec).ok()?.1 {
+        name @ Object::String(..) => decode_text_string(name).ok(),
+        Object::Dictionary(spec) => spec
+            .get(b"UF")
+            .or_else(|_| spec.get(b"F"))
+            .and_then(decode_text_string)
+            .ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::{dictionary, Stream};
+
+    /// A one-page document; returns it with the ids of the catalog and the page
+    fn document() -> (Document, ObjectId, ObjectId) {
+        let (doc, page_ids) = testing::document(1, |_, _| Dictionary::new());
+        let catalog_id = testing::catalog_id(&doc);
+        (doc, catalog_id, page_ids[0])
+    }
+
+    fn action(kind: &str) -> Dictionary {
+        dictionary! { "S" => kind }
+    }
+
+    fn kinds(removed: &[SanitizedItem], kind: SanitizedKind) -> Vec<&str> {
+        removed
+            .iter()
+            .filter(|item| item.kind == kind)
+            .map(|item| item.description.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn removes_metadata_and_resets_the_file_id() {
+        let (mut doc, catalog_id, _) = document();
+        let info_id = doc.add_object(dictionary! {
+            "Author" => Object::string_literal("Jane Doe"),
+            "Producer" => Object::string_literal("Writer"),
+        });
+        doc.trailer.set("Info", info_id);
+        let xmp_id = doc.add_object(Stream::new(dictionary! {}, b"<x:xmpmeta/>".to_vec()));
+        let old_id = Object::String(vec![1; 16], StringFormat::Hexadecimal);
+        doc.trailer.set("ID", vec![old_id.clone(), old_id.clone()]);
+        doc.get_dictionary_mut(catalog_id)
+            .unwrap()
+            .set("Metadata", xmp_id);
+
+        let removed = sanitize_document(&mut doc).unwrap();
+        assert_eq!(
+            kinds(&removed, SanitizedKind::Info),
+            ["Author: Jane Doe", "Producer: Writer"]
+        );
+        assert_eq!(
+            kinds(&removed, SanitizedKind::Xmp),
+            ["Document XMP metadata"]
+        );
+        assert_eq!(kinds(&removed, SanitizedKind::FileId), ["File identifier"]);
+        assert!(!doc.trailer.has(b"Info"));
+        assert!(!doc.catalog().unwrap().has(b"Metadata"));
+        assert!(doc.get_object(xmp_id).is_err());
+        let id = doc.trailer.get(b"ID").and_then(Object::as_array).unwrap();
+        assert_ne!(id[0], old_id);
+    }
+
+    #[test]
+    fn removes_embedded_and_attached_files() {
+        let (mut doc, catalog_id, page_id) = document();
+        let file_spec = |name: &str| {
+            dictionary! { "Type" => "Filespec", "UF" => Object::string_literal(name) }
+        };
+        let embedded = file_spec("data.xlsx");
+        let attached = file_spec("notes.txt");
+        let associated = file_spec("source.xml");
+        let catalog = doc.get_dictionary_mut(catalog_id).unwrap();
+        catalog.set(
+            "Names",
+            dictionary! {
+                "EmbeddedFiles" => dictionary! {
+                    "Names" => vec![Object::string_literal("data"), embedded.into()],
+                },
+            },
+        );
+        catalog.set("AF", vec![associated.into()]);
+        catalog.set("Collection", dictionary! { "View" => "T" });
+        doc.get_dictionary_mut(page_id).unwrap().set(
+            "Annots",
+            vec![
+                dictionary! { "Subtype" => "FileAttachment", "FS" => attached }.into(),
+                dictionary! { "Subtype" => "Text" }.into(),
+            ],
+        );
+
+        let removed = sanitize_document(&mut doc).unwrap();
+        assert_eq!(
+            kinds(&removed, SanitizedKind::EmbeddedFile),
+            [
+                "data.xlsx",
+                "Portfolio settings",
+                "notes.txt (attached to page 1)",
+                "source.xml"
+            ]
+        );
+        assert!(!doc.catalog().unwrap().has(b"Collection"));
+        let page = doc.get_dictionary(page_id).unwrap();
+        assert_eq!(
+            page.get(b"Annots")
+                .and_then(Object::as_array)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn removes_javascript() {
+        let (mut doc, catalog_id, page_id) = document();
+        let script =
+            dictionary! { "S" => "JavaScript", "JS" => Object::string_literal("app.alert(1)") };
+        let script_id = doc.add_object(script.clone());
+        let catalog = doc.get_dictionary_mut(catalog_id).unwrap();
+        catalog.set(
+            "Names",
+            dictionary! {
+                "JavaScript" => dictionary! {
+                    "Names" => vec![Object::string_literal("init"), script_id.into()],
+                },
+            },
+        );
+        catalog.set("OpenAction", script_id);
+        doc.get_dictionary_mut(page_id)
+            .unwrap()
+            .set("AA", dictionary! { "O" => script });
+
+        let removed = sanitize_document(&mut doc).unwrap();
+        let mut scripts = kinds(&removed, SanitizedKind::JavaScript);
+        scripts.sort();
+        assert_eq!(
+            scripts,
+            [
+                "Document script \"init\"",
+                "JavaScript action in /O",
+                "JavaScript action in /OpenAction"
+            ]
+        );
+        assert!(!doc.catalog().unwrap().has(b"OpenAction"));
+        assert!(!doc.get_dictionary(page_id).unwrap().has(b"AA"));
+        assert!(doc.get_object(script_id).is_err());
+    }
+
+    #[test]
+    fn removes_external_actions() {
+        let (mut doc, catalog_id, page_id) = document();
+        let launch_id = doc.add_object(action("Launch"));
+        let catalog = doc.get_dictionary_mut(catalog_id).unwrap();
+        catalog.set("OpenAction", launch_id);
+        catalog.set(
+            "AA",
+            dictionary! { "WC" => action("SubmitForm"), "WS" => action("ImportData") },
+        );
+        let mut go_to = dictionary! {
+            "S" => "GoTo",
+            "D" => vec![page_id.into(), "Fit".into()],
+        };
+        go_to.set("Next", vec![action("GoToR").into(), action("URI").into()]);
+        doc.get_dictionary_mut(page_id).unwrap().set(
+            "Annots",
+            vec![
+                dictionary! { "Subtype" => "Link", "A" => action("GoToE") }.into(),
+                dictionary! { "Subtype" => "Link", "A" => go_to }.into(),
+            ],
+        );
+
+        let removed = sanitize_document(&mut doc).unwrap();
+        let mut actions = kinds(&removed, SanitizedKind::Action);
+        actions.sort();
+        assert_eq!(
+            actions,
+            [
+                "GoToE action in /A",
+                "GoToR action in /Next",
+                "ImportData action in /WS",
+                "Launch action in /OpenAction",
+                "SubmitForm action in /WC"
+            ]
+        );
+        let catalog = doc.catalog().unwrap();
+        assert!(!catalog.has(b"OpenAction") && !catalog.has(b"AA"));
+
+        // In-document and web links stay
+        let page = doc.get_dictionary(page_id).unwrap();
+        let annots = page.get(b"Annots").and_then(Object::as_array).unwrap();
+        assert!(!annots[0].as_dict().unwrap().has(b"A"));
+        let go_to = annots[1]
+            .as_dict()
+            .unwrap()
+            .get(b"A")
+            .and_then(Object::as_dict);
+        let next = go_to
+            .unwrap()
+            .get(b"Next")
+            .and_then(Object::as_array)
+            .unwrap();
+        assert_eq!(next, &vec![Object::Dictionary(action("URI"))]);
+    }
+
+    #[test]
+    fn removes_xfa_forms() {
+        let (mut doc, catalog_id, _) = document();
+        let xfa_id = doc.add_object(Stream::new(dictionary! {}, b"<xdp:xdp/>".to_vec()));
+        let form_id = doc.add_object(dictionary! {
+            "Fields" => Vec::<Object>::new(),
+            "XFA" => xfa_id,
+        });
+        let catalog = doc.get_dictionary_mut(catalog_id).unwrap();
+        catalog.set("AcroForm", form_id);
+        catalog.set("NeedsRendering", true);
+
+        let removed = sanitize_document(&mut doc).unwrap();
+        assert_eq!(kinds(&removed, SanitizedKind::Xfa), ["XFA form"]);
+        assert!(!doc.get_dictionary(form_id).unwrap().has(b"XFA"));
+        assert!(!doc.catalog().unwrap().has(b"NeedsRendering"));
+        assert!(doc.get_object(xfa_id).is_err());
+    }
+
+    #[test]
+    fn refuses_encrypted_documents() {
+        let (mut doc, _, _) = document();
+        let encrypt_id = doc.add_object(dictionary! { "Filter" => "Standard" });
+        doc.trailer.set("Encrypt", encrypt_id);
+        assert!(sanitize_document(&mut doc).is_err());
+    }
+}
@@ -0,0 +1,587 @@
+use crate::assembly::{
+    assemble, named_destinations, outline_children, outline_root, save_assembled, target_page,
+    PageSelection, PdfSource,
+};
+use lopdf::{decode_text_string, Dictionary, Document, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Bookmark titles are cut to this many characters in file names
+const MAX_TITLE_CHARS: usize = 80;
+
+/// How to divide a document
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "camelCase")]
+pub enum SplitMode {
+    /// One file per top-level bookmark
+    Bookmarks,
+    /// Files of this many pages
+    EveryPages(u32),
+    /// Files of as many pages as fit in this many bytes
+    MaxBytes(u64),
+}
+
+/// A file written by a split
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SplitFile {
+    pub path: PathBuf,
+    /// The bookmark the file starts at, when splitting by bookmarks
+    pub title: Option<String>,
+    pub first_page: u32,
+    pub last_page: u32,
+    pub size: u64,
+}
+
+/// A run of pages that becomes one file
+struct Section {
+    title: Option<String>,
+    pages: RangeInclusive<u32>,
+}
+
+/// Split a PDF into files in `output_dir`. Bookmark splits name files after the bookmark
+/// titles; other splits name them `base_name` plus the pages they hold. Existing files are
+/// never overwritten. Each file is written as soon as it's built.
+pub fn split_pdf_blocking(
+    source: &PdfSource,
+    mode: SplitMode,
+    output_dir: &Path,
+    base_name: Option<&str>,
+) -> Result<Vec<SplitFile>, String> {
+    let doc = source.load()?;
+    let page_count = doc.get_pages().len() as u32;
+    if page_count == 0 {
+        return Err("PDF has no pages".to_string());
+    }
+    let base_name = match (base_name, source) {
+        (Some(name), _) => file_name_part(name),
+        (None, PdfSource::Path { path }) => path
+            .file_stem()
+            .map(|stem| file_name_part(&stem.to_string_lossy()))
+            .unwrap_or_else(|| "document".to_string()),
+        (None, PdfSource::Content { .. }) => "document".to_string(),
+    };
+    let parts = PartBuilder::new(&doc);
+    let mut writer = PartWriter {
+        output_dir,
+        base_name,
+        index_width: 1,
+        page_width: page_count.to_string().len(),
+        files: Vec::new(),
+    };
+
+    match mode {
+        SplitMode::Bookmarks => {
+            let sections = bookmark_sections(&doc, page_count)?;
+            writer.index_width = sections.len().to_string().len();
+            for section in sections {
+                let content = parts.build(section.pages.clone())?;
+                writer.write(section, &content)?;
+            }
+        }
+        SplitMode::EveryPages(0) => return Err("Pages per file must be at least 1".to_string()),
+        SplitMode::EveryPages(pages) => {
+            for first in (1..=page_count).step_by(pages as usize) {
+                let pages = first..=first.saturating_add(pages - 1).min(page_count);
+                let content = parts.build(pages.clone())?;
+                writer.write(Section { title: None, pages }, &content)?;
+            }
+        }
+        SplitMode::MaxBytes(0) => return Err("Maximum file size must be above 0".to_string()),
+        SplitMode::MaxBytes(max_bytes) => {
+            split_by_size(&parts, page_count, max_bytes, |section, content| {
+                writer.write(section, &content)
+            })?
+        }
+    }
+    Ok(writer.files)
+}
+
+/// Names and writes the files of a split, keeping a list of them
+struct PartWriter<'a> {
+    output_dir: &'a Path,
+    base_name: String,
+    /// Digits of the part numbers that start bookmark file names
+    index_width: usize,
+    /// Digits of the page numbers in other file names
+    page_width: usize,
+    files: Vec<SplitFile>,
+}
+
+impl PartWriter<'_> {
+    fn write(&mut self, section: Section, content: &[u8]) -> Result<(), String> {
+        let (first_page, last_page) = (*section.pages.start(), *section.pages.end());
+        let (index_width, page_width) = (self.index_width, self.page_width);
+        let name = match &section.title {
+            Some(title) => format!(
+                "{:0index_width$} {}",
+                self.files.len() + 1,
+                file_name_part(title)
+            ),
+            None if first_page == last_page => {
+                format!("{} {:0page_width$}", self.base_name, first_page)
+            }
+            None => format!(
+                "{} {:0page_width$}-{:0page_width$}",
+                self.base_name, first_page, last_page
+            ),
+        };
+        let path = write_new_file(self.output_dir, &name, content)?;
+        println!(
+            "Wrote pages {}-{} to {}",
+            first_page,
+            last_page,
+            path.display()
+        );
+        self.files.push(SplitFile {
+            path,
+            title: section.title,
+            first_page,
+            last_page,
+            size: content.len() as u64,
+        });
+        Ok(())
+    }
+}
+
+/// One section per top-level bookmark, from its page up to the next bookmark's. Pages
+/// before the first bookmark join its section.
+fn bookmark_sections(doc: &Document, page_count: u32) -> Result<Vec<Section>, String> {
+    let no_bookmarks = || "PDF has no bookmarks to split by".to_string();
+    let root = outline_root(doc).ok_or_else(no_bookmarks)?;
+    let names = named_destinations(doc);
+    let page_numbers: HashMap<ObjectId, u32> = doc
+        .get_pages()
+        .into_iter()
+        .map(|(number, id)| (id, number))
+        .collect();
+
+    let mut starts: Vec<(u32, String)> = outline_children(doc, root)
+        .into_iter()
+        .filter_map(|item| {
+            let item = doc.get_dictionary(item).ok()?;
+            let page = page_numbers.get(&target_page(doc, item, &names)?)?;
+            let title = item
+                .get(b"Title")
+                .and_then(|title| doc.dereference(title))
+                .ok()
+                .and_then(|(_, title)| decode_text_string(title).ok())
+                .unwrap_or_default();
+            Some((*page, title))
+        })
+        .collect();
+    // Bookmarks out of page order are put in order; of several on one page, the first wins
+    starts.sort_by_key(|(page, _)| *page);
+    starts.dedup_by_key(|(page, _)| *page);
+    if starts.is_empty() {
+        return Err(no_bookmarks());
+    }
+
+    let ends: Vec<u32> = starts
+        .iter()
+        .skip(1)
+        .map(|(page, _)| page - 1)
+        .chain([page_count])
+        .collect();
+    Ok(starts
+        .into_iter()
+        .zip(ends)
+        .enumerate()
+        .map(|(index, ((first, title), last))| Section {
+            title: Some(title),
+            pages: if index == 0 { 1 } else { first }..=last,
+        })
+        .collect())
+}
+
+/// Greedily fill each file with pages while an estimate of their size fits, then shrink
+/// the part until the saved file does. A page too big on its own gets a file to itself.
+/// Each part is handed to `on_part` once it's settled.
+fn split_by_size(
+    parts: &PartBuilder,
+    page_count: u32,
+    max_bytes: u64,
+    mut on_part: impl FnMut(Section, Vec<u8>) -> Result<(), String>,
+) -> Result<(), String> {
+    let page_objects = &parts.page_objects;
+    let mut first = 1;
+    while first <= page_count {
+        let mut seen = HashSet::new();
+        let mut estimate = 0;
+        let mut last = first - 1;
+        while last < page_count {
+            let added: u64 = page_objects[last as usize]
+                .iter()
+                .filter(|(id, _)| !seen.contains(id))
+                .map(|(_, size)| size)
+                .sum();
+            if last >= first && estimate + added > max_bytes {
+                break;
+            }
+            estimate += added;
+            seen.extend(page_objects[last as usize].iter().map(|(id, _)| *id));
+            last += 1;
+        }
+
+        let content = loop {
+            let content = parts.build(first..=last)?;
+            let size = content.len() as u64;
+            if size <= max_bytes || last == first {
+                if size > max_bytes {
+                    println!("Page {} alone is {} bytes, over the limit", first, size);
+                }
+                break content;
+            }
+            let pages = (last - first + 1) as u64;
+            let fitting = (pages * max_bytes / size).clamp(1, pages - 1);
+            last = first + fitting as u32 - 1;
+        };
+        on_part(
+            Section {
+                title: None,
+                pages: first..=last,
+            },
+            content,
+        )?;
+        first = last + 1;
+    }
+    Ok(())
+}
+
+/// For each page, the objects it uses and roughly how many bytes each takes in a file.
+/// Other pages, and the page tree, are not followed.
+fn page_objects(doc: &Document) -> Vec<Vec<(ObjectId, u64)>> {
+    let pages: HashSet<ObjectId> = doc.get_pages().into_values().collect();
+    doc.get_pages()
+        .into_values()
+        .map(|page_id| {
+            let mut objects = Vec::new();
+            let mut seen = HashSet::from([page_id]);
+            let mut pending = vec![page_id];
+            while let Some(id) = pending.pop() {
+                let Ok(object) = doc.get_object(id) else {
+                    continue;
+                };
+                objects.push((id, object_size(object) + 32));
+                let mut refs = Vec::new();
+                collect_references(object, &mut refs);
+                for id in refs {
+                    if !pages.contains(&id) && seen.insert(id) {
+                        pending.push(id);
+                    }
+                }
+            }
+            objects
+        })
+        .collect()
+}
+
+fn collect_references(object: &Object, refs: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => refs.push(*id),
+        Object::Array(items) => items.iter().for_each(|item| collect_references(item, refs)),
+        Object::Dictionary(dict) => dict
+            .iter()
+            .filter(|(key, _)| key.as_slice() != b"Parent")
+            .for_each(|(_, value)| collect_references(value, refs)),
+        Object::Stream(stream) => stream
+            .dict
+            .iter()
+            .for_each(|(_, value)| collect_references(value, refs)),
+        _ => {}
+    }
+}
+
+/// Approximate serialized size of an object
+fn object_size(object: &Object) -> u64 {
+    match object {
+        Object::Null | Object::Boolean(_) => 5,
+        Object::Integer(_) | Object::Real(_) => 8,
+        Object::Name(name) => name.len() as u64 + 2,
+        Object::String(text, _) => text.len() as u64 + 2,
+        Object::Reference(_) => 8,
+        Object::Array(items) => items.iter().map(object_size).sum::<u64>() + 2,
+        Object::Dictionary(dict) => dictionary_size(dict),
+        Object::Stream(stream) => stream.content.len() as u64 + 20 + dictionary_size(&stream.dict),
+    }
+}
+
+fn dictionary_size(dict: &Dictionary) -> u64 {
+    dict.iter()
+        .map(|(key, value)| key.len() as u64 + 2 + object_size(value))
+        .sum::<u64>()
+        + 4
+}
+
+/// Builds the parts of a split, each from a copy of only the objects it needs rather than
+/// of the whole document
+struct PartBuilder<'a> {
+    doc: &'a Document,
+    /// Objects reachable without going through a page: the catalog, page tree, outline
+    /// and so on. Page dictionaries are included, but not what they use.
+    shared: Vec<ObjectId>,
+    /// For each page, the objects it uses and roughly how many bytes each takes
+    page_objects: Vec<Vec<(ObjectId, u64)>>,
+}
+
+impl<'a> PartBuilder<'a> {
+    fn new(doc: &'a Document) -> Self {
+        let pages: HashSet<ObjectId> = doc.get_pages().into_values().collect();
+        let mut shared = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = Vec::new();
+        for (_, value) in doc.trailer.iter() {
+            collect_references(value, &mut pending);
+        }
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            shared.push(id);
+            if pages.contains(&id) {
+                continue;
+            }
+            if let Ok(object) = doc.get_object(id) {
+                collect_references(object, &mut pending);
+            }
+        }
+        PartBuilder {
+            doc,
+            shared,
+            page_objects: page_objects(doc),
+        }
+    }
+
+    /// The document cut down to the shared objects and those of `pages`. Other pages keep
+    /// their dictionaries, so page numbers stay the same.
+    fn document(&self, pages: &RangeInclusive<u32>) -> Document {
+        let mut part = Document::with_version(self.doc.version.clone());
+        part.trailer = self.doc.trailer.clone();
+        part.max_id = self.doc.max_id;
+        let page_objects = pages
+            .clone()
+            .flat_map(|page| &self.page_objects[page as usize - 1])
+            .map(|(id, _)| *id);
+        for id in self.shared.iter().copied().chain(page_objects) {
+            if let Some(object) = self.doc.objects.get(&id) {
+                part.objects.entry(id).or_insert_with(|| object.clone());
+            }
+        }
+        part
+    }
+
+    /// Save the given pages as a PDF of their own
+    fn build(&self, pages: RangeInclusive<u32>) -> Result<Vec<u8>, String> {
+        let part = self.document(&pages);
+        let selection: Vec<PageSelection> = pages
+            .map(|page| PageSelection { source: 0, page })
+            .collect();
+        let part = assemble(vec![part], &selection)?;
+        Ok(save_assembled(part, None)?.content.unwrap_or_default())
+    }
+}
+
+/// Make text safe to use in a file name on every platform
+fn file_name_part(text: &str) -> String {
+    let cleaned: String = text
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => ' ',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    let cleaned: String = cleaned
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MAX_TITLE_CHARS)
+        .collect();
+    let cleaned = cleaned.trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        "Untitled".to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Write `content` to a new file `dir/name.pdf`, or `dir/name (2).pdf` and so on if that
+/// already exists
+fn write_new_file(dir: &Path, name: &str, content: &[u8]) -> Result<PathBuf, String> {
+    let mut copy = 1;
+    loop {
+        let path = match copy {
+            1 => dir.join(format!("{}.pdf", name)),
+            copy => dir.join(format!("{} ({}).pdf", name, copy)),
+        };
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                copy += 1;
+                continue;
+            }
+            Err(e) => return Err(format!("Failed to create {}: {}", path.display(), e)),
+        };
+        if let Err(e) = file.write_all(content) {
+            let _ = std::fs::remove_file(&path);
+            return Err(format!("Failed to write {}: {}", path.display(), e));
+        }
+        return Ok(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::dictionary;
+
+    /// A document with a page per entry of `content_sizes`, each drawing that many bytes of
+    /// content, and a bookmark per `(title, page index)`
+    fn document(content_sizes: &[usize], bookmarks: &[(&str, usize)]) -> Document {
+        let (mut doc, page_ids) = testing::document(content_sizes.len(), |doc, index| {
+            dictionary! { "Contents" => testing::content(doc, &vec![b' '; content_sizes[index]]) }
+        });
+        testing::add_bookmarks(&mut doc, &page_ids, bookmarks);
+        doc
+    }
+
+    fn source(mut doc: Document) -> PdfSource {
+        PdfSource::Content {
+            content: testing::save(&mut doc),
+        }
+    }
+
+    /// An empty directory of its own for a test
+    fn output_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("leed-split-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(files: &[SplitFile]) -> Vec<String> {
+        files
+            .iter()
+            .map(|file| {
+                file.path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    fn split_sizes(doc: &Document, max_bytes: u64) -> Vec<(RangeInclusive<u32>, usize)> {
+        let mut parts = Vec::new();
+        let page_count = doc.get_pages().len() as u32;
+        split_by_size(
+            &PartBuilder::new(doc),
+            page_count,
+            max_bytes,
+            |section, content| {
+                parts.push((section.pages, content.len()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        parts
+    }
+
+    #[test]
+    fn splits_by_size_within_the_limit() {
+        let doc = document(&[2000; 7], &[]);
+        let parts = split_sizes(&doc, 5000);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|(_, size)| *size <= 5000));
+        let pages: Vec<u32> = parts.into_iter().flat_map(|(pages, _)| pages).collect();
+        assert_eq!(pages, (1..=7).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn gives_a_page_over_the_limit_a_file_of_its_own() {
+        let doc = document(&[500, 8000, 500, 500], &[]);
+        let parts = split_sizes(&doc, 4000);
+        let pages: Vec<RangeInclusive<u32>> = parts.into_iter().map(|(pages, _)| pages).collect();
+        assert_eq!(pages, [1..=1, 2..=2, 3..=4]);
+    }
+
+    #[test]
+    fn parts_copy_only_the_objects_their_pages_use() {
+        let (mut doc, page_ids) = testing::document(3, |doc, index| {
+            let content = format!("({})", index + 1);
+            dictionary! { "Contents" => testing::content(doc, content.as_bytes()) }
+        });
+        testing::add_bookmarks(&mut doc, &page_ids, &[("One", 0), ("Two", 1), ("Three", 2)]);
+        let contents: Vec<ObjectId> = page_ids
+            .iter()
+            .map(|&id| {
+                let page = doc.get_dictionary(id).unwrap();
+                page.get(b"Contents").unwrap().as_reference().unwrap()
+            })
+            .collect();
+
+        let parts = PartBuilder::new(&doc);
+        let part = parts.document(&(2..=2));
+        assert!(part.objects.contains_key(&contents[1]));
+        assert!(!part.objects.contains_key(&contents[0]));
+        assert!(!part.objects.contains_key(&contents[2]));
+        assert_eq!(part.get_pages().len(), 3);
+
+        let part = Document::load_mem(&parts.build(2..=2).unwrap()).unwrap();
+        let page_id = part.page_iter().next().unwrap();
+        assert_eq!(part.get_page_content(page_id).unwrap(), b"(2)");
+        let root = outline_root(&part).unwrap();
+        assert_eq!(outline_children(&part, root).len(), 1);
+    }
+
+    #[test]
+    fn splits_every_few_pages_without_overwriting() {
+        let dir = output_dir("every-pages");
+        std::fs::write(dir.join("doc 1-2.pdf"), b"existing").unwrap();
+        let source = source(document(&[10; 5], &[]));
+        let files =
+            split_pdf_blocking(&source, SplitMode::EveryPages(2), &dir, Some("doc")).unwrap();
+
+        assert_eq!(
+            file_names(&files),
+            ["doc 1-2 (2).pdf", "doc 3-4.pdf", "doc 5.pdf"]
+        );
+        assert_eq!(std::fs::read(dir.join("doc 1-2.pdf")).unwrap(), b"existing");
+        for file in &files {
+            let part = Document::load(&file.path).unwrap();
+            let pages = (file.last_page - file.first_page + 1) as usize;
+            assert_eq!(part.get_pages().len(), pages);
+            assert_eq!(std::fs::metadata(&file.path).unwrap().len(), file.size);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn splits_by_bookmarks() {
+        let dir = output_dir("bookmarks");
+        let source = source(document(&[10; 5], &[("Part/Two", 3), ("Intro", 1)]));
+        let files = split_pdf_blocking(&source, SplitMode::Bookmarks, &dir, None).unwrap();
+
+        assert_eq!(file_names(&files), ["1 Intro.pdf", "2 Part Two.pdf"]);
+        let pages: Vec<(u32, u32)> = files
+            .iter()
+            .map(|file| (file.first_page, file.last_page))
+            .collect();
+        assert_eq!(pages, [(1, 3), (4, 5)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cleans_file_names() {
+        assert_eq!(file_name_part("  a:b*c?  d. "), "a b c d");
+        assert_eq!(file_name_part("..."), "Untitled");
+        assert_eq!(file_name_part(&"x".repeat(100)).len(), MAX_TITLE_CHARS);
+    }
+}
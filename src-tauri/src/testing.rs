@@ -0,0 +1,118 @@
+use crate::assembly::{recount_outline, relink_outline_children};
+use lopdf::{dictionary, Dictionary, Document, Object, ObjectId, Stream};
+use moxcms::{
+    ColorProfile, DataColorSpace, LutDataType, LutStore, LutType, LutWarehouse, Matrix3d,
+    ProfileClass,
+};
+
+/// A document with `count` pages on a 100×100 media box. `page` is given each page's
+/// index and returns the entries to add to it, e.g. its content and resources.
+pub fn document(
+    count: usize,
+    page: impl FnMut(&mut Document, usize) -> Dictionary,
+) -> (Document, Vec<ObjectId>) {
+    let mut doc = Document::with_version("1.5");
+    let page_ids = add_page_tree(&mut doc, count, page);
+    (doc, page_ids)
+}
+
+/// Give an existing document a catalog and a page tree of `count` pages, as `document` does
+pub fn add_page_tree(
+    doc: &mut Document,
+    count: usize,
+    mut page: impl FnMut(&mut Document, usize) -> Dictionary,
+) -> Vec<ObjectId> {
+    let pages_id = doc.new_object_id();
+    let page_ids: Vec<ObjectId> = (0..count)
+        .map(|index| {
+            let mut dict = dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "MediaBox" => vec![0.into(), 0.into(), 100.into(), 100.into()],
+            };
+            for (key, value) in page(doc, index) {
+                dict.set(key, value);
+            }
+            doc.add_object(dict)
+        })
+        .collect();
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => count as i64,
+            "Kids" => page_ids.iter().map(|&id| id.into()).collect::<Vec<Object>>(),
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+    doc.trailer.set("Root", catalog_id);
+    page_ids
+}
+
+/// A content stream object holding `content`
+pub fn content(doc: &mut Document, content: &[u8]) -> Object {
+    doc.add_object(Stream::new(dictionary! {}, content.to_vec()))
+        .into()
+}
+
+/// Give the document a flat outline with a bookmark per `(title, index into page_ids)`
+pub fn add_bookmarks(doc: &mut Document, page_ids: &[ObjectId], bookmarks: &[(&str, usize)]) {
+    if bookmarks.is_empty() {
+        return;
+    }
+    let root = doc.add_object(dictionary! { "Type" => "Outlines" });
+    let items: Vec<ObjectId> = bookmarks
+        .iter()
+        .map(|&(title, page)| {
+            doc.add_object(dictionary! {
+                "Title" => Object::string_literal(title),
+                "Dest" => vec![page_ids[page].into(), "Fit".into()],
+            })
+        })
+        .collect();
+    relink_outline_children(doc, root, &items);
+    recount_outline(doc, root, true, 0);
+    doc.catalog_mut().unwrap().set("Outlines", root);
+}
+
+pub fn save(doc: &mut Document) -> Vec<u8> {
+    let mut content = Vec::new();
+    doc.save_to(&mut content).unwrap();
+    content
+}
+
+/// The id of the document catalog
+pub fn catalog_id(doc: &Document) -> ObjectId {
+    doc.trailer.get(b"Root").unwrap().as_reference().unwrap()
+}
+
+/// An ICC profile for a CMYK press that prints only its black ink: K is rendered from white
+/// to black and the other inks are ignored, which no naive conversion would do
+pub fn black_ink_profile() -> Vec<u8> {
+    // Two grid points per ink, the first ink varying slowest; Lab is encoded 0-65280 for L*
+    // and with 0x8000 as neutral for a* and b*
+    let clut = (0..16u16)
+        .flat_map(|corner| {
+            let lightness = if corner & 1 == 1 { 0 } else { 0xff00 };
+            [lightness, 0x8000, 0x8000]
+        })
+        .collect();
+    let identity = |channels: usize| LutStore::Store16([0, 0xffff].repeat(channels));
+    let mut profile = ColorProfile::default();
+    profile.color_space = DataColorSpace::Cmyk;
+    profile.pcs = DataColorSpace::Lab;
+    profile.profile_class = ProfileClass::OutputDevice;
+    profile.lut_a_to_b_perceptual = Some(LutWarehouse::Lut(LutDataType {
+        num_input_channels: 4,
+        num_output_channels: 3,
+        num_clut_grid_points: 2,
+        matrix: Matrix3d::IDENTITY,
+        num_input_table_entries: 2,
+        num_output_table_entries: 2,
+        input_table: identity(4),
+        clut_table: LutStore::Store16(clut),
+        output_table: identity(3),
+        lut_type: LutType::Lut16,
+    }));
+    profile.encode().unwrap()
+}
@@ -0,0 +1,360 @@
+use crate::resources::{decode_content, page_content};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Stop collecting problems after this many, a broken document can have thousands
+const MAX_PROBLEMS: usize = 50;
+
+/// Resource categories whose entries must resolve
+const RESOURCE_CATEGORIES: [&[u8]; 7] = [
+    b"Font",
+    b"XObject",
+    b"ExtGState",
+    b"ColorSpace",
+    b"Pattern",
+    b"Shading",
+    b"Properties",
+];
+
+/// Page count and structural problems of a document, taken before compressing it so the
+/// output is only blamed for problems it introduced
+pub struct DocumentCheck {
+    pub pages: usize,
+    pub problems: Vec<String>,
+    /// Number of content operations on each page, None where the content doesn't parse
+    pub operations: Vec<Option<usize>>,
+}
+
+/// Outcome of re-reading a compressed PDF
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationReport {
+    /// The output parsed, has as many pages as the original and no new problems
+    pub valid: bool,
+    pub original_pages: usize,
+    pub output_pages: usize,
+    /// Problems found in the output that the original didn't have
+    pub problems: Vec<String>,
+    /// Why the original was returned instead of the output, if it was
+    pub rollback_reason: Option<RollbackReason>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RollbackReason {
+    /// The output failed verification
+    Invalid,
+    /// The output was larger than the original
+    Larger,
+}
+
+impl VerificationReport {
+    /// Decide whether the original should be returned instead of an output of
+    /// `output_size` bytes, recording the reason
+    pub fn should_roll_back(&mut self, output_size: usize, original_size: usize) -> bool {
+        self.rollback_reason = if !self.valid {
+            Some(RollbackReason::Invalid)
+        } else if output_size > original_size {
+            Some(RollbackReason::Larger)
+        } else {
+            None
+        };
+        self.rollback_reason.is_some()
+    }
+}
+
+/// Walk the page tree and every page's contents and resources
+pub fn check_document(doc: &Document) -> DocumentCheck {
+    let mut problems = Vec::new();
+    check_page_tree(doc, &mut problems);
+
+    let pages = doc.get_pages();
+    let mut operations = Vec::with_capacity(pages.len());
+    for (&number, &page_id) in &pages {
+        if problems.len() >= MAX_PROBLEMS {
+            break;
+        }
+        operations.push(check_page(doc, number, page_id, &mut problems));
+    }
+
+    DocumentCheck {
+        pages: pages.len(),
+        problems,
+        operations,
+    }
+}
+
+/// Compare the re-parsed output (or the error parsing it) against the original's check
+pub fn verify_output(
+    original: &DocumentCheck,
+    output: lopdf::Result<Document>,
+) -> VerificationReport {
+    let mut report = VerificationReport {
+        original_pages: original.pages,
+        ..Default::default()
+    };
+
+    let output = match output {
+        Ok(doc) => doc,
+        Err(e) => {
+            report
+                .problems
+                .push(format!("Output could not be parsed: {}", e));
+            return report;
+        }
+    };
+
+    let check = check_document(&output);
+    report.output_pages = check.pages;
+    if check.pages != original.pages {
+        report.problems.push(format!(
+            "Output has {} pages, the original has {}",
+            check.pages, original.pages
+        ));
+    }
+    // Compression rewrites operators but never adds or drops them, so a page with fewer
+    // operations has lost content
+    for (index, (&before, &after)) in original
+        .operations
+        .iter()
+        .zip(&check.operations)
+        .enumerate()
+    {
+        if let (Some(before), Some(after)) = (before, after) {
+            if before != after {
+                report.problems.push(format!(
+                    "Page {}: output has {} content operations, the original has {}",
+                    index + 1,
+                    after,
+                    before
+                ));
+            }
+        }
+    }
+    let known: HashSet<&String> = original.problems.iter().collect();
+    report.problems.extend(
+        check
+            .problems
+            .into_iter()
+            .filter(|problem| !known.contains(problem)),
+    );
+
+    report.valid = report.problems.is_empty();
+    report
+}
+
+/// Check the page tree from the catalog down: nodes resolve, there are no loops, and each
+/// `/Count` matches the pages below it
+fn check_page_tree(doc: &Document, problems: &mut Vec<String>) {
+    let root = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"Pages"))
+        .and_then(Object::as_reference);
+    match root {
+        Ok(root) => {
+            check_page_tree_node(doc, root, &mut HashSet::new(), problems);
+        }
+        Err(_) => problems.push("Catalog has no /Pages reference".to_string()),
+    }
+}
+
+/// Returns the number of pages under `id`
+fn check_page_tree_node(
+    doc: &Document,
+    id: ObjectId,
+    visited: &mut HashSet<ObjectId>,
+    problems: &mut Vec<String>,
+) -> usize {
+    if !visited.insert(id) {
+        problems.push(format!("Page tree refers to node {} {} twice", id.0, id.1));
+        return 0;
+    }
+    let Ok(node) = doc.get_dictionary(id) else {
+        problems.push(format!("Page tree node {} {} is missing", id.0, id.1));
+        return 0;
+    };
+
+    // Some writers leave out /Type, so a node with /Kids is taken to be a /Pages node
+    let is_pages_node = match node.get(b"Type").and_then(Object::as_name) {
+        Ok(b"Pages") => true,
+        Ok(b"Page") => false,
+        _ => node.has(b"Kids"),
+    };
+    if !is_pages_node {
+        return 1;
+    }
+
+    let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) else {
+        problems.push(format!("Page tree node {} {} has no /Kids", id.0, id.1));
+        return 0;
+    };
+    let mut count = 0;
+    for kid in kids {
+        match kid.as_reference() {
+            Ok(kid) => count += check_page_tree_node(doc, kid, visited, problems),
+            Err(_) => problems.push(format!(
+                "Page tree node {} {} has a kid that isn't a reference",
+                id.0, id.1
+            )),
+        }
+    }
+
+    if let Ok(declared) = node.get(b"Count").and_then(Object::as_i64) {
+        if declared != count as i64 {
+            problems.push(format!(
+                "Page tree node {} {} declares /Count {} but has {} pages",
+                id.0, id.1, declared, count
+            ));
+        }
+    }
+    count
+}
+
+/// Check a page's content streams parse and its resources resolve. Returns the number of
+/// content operations, if the content parses.
+fn check_page(
+    doc: &Document,
+    number: u32,
+    page_id: ObjectId,
+    problems: &mut Vec<String>,
+) -> Option<usize> {
+    for content_id in doc.get_page_contents(page_id) {
+        if doc
+            .get_object(content_id)
+            .and_then(Object::as_stream)
+            .is_err()
+        {
+            problems.push(format!("Page {}: content stream is missing", number));
+        }
+    }
+    let operations = match page_content(doc, page_id) {
+        Some(content) => {
+            let operations = decode_content(&content).map(|content| content.operations.len());
+            if operations.is_none() {
+                problems.push(format!("Page {}: content stream can't be parsed", number));
+            }
+            operations
+        }
+        None => {
+            problems.push(format!("Page {}: content stream can't be read", number));
+            None
+        }
+    };
+
+    let Ok((direct, inherited)) = doc.get_page_resources(page_id) else {
+        problems.push(format!("Page {}: resources can't be read", number));
+        return operations;
+    };
+    if let Some(resources) = direct {
+        check_resources(doc, number, resources, problems);
+    }
+    for id in inherited {
+        match doc.get_dictionary(id) {
+            Ok(resources) => check_resources(doc, number, resources, problems),
+            Err(_) => problems.push(format!("Page {}: resource dictionary is missing", number)),
+        }
+    }
+    operations
+}
+
+fn check_resources(
+    doc: &Document,
+    number: u32,
+    resources: &Dictionary,
+    problems: &mut Vec<String>,
+) {
+    for category in RESOURCE_CATEGORIES {
+        let Ok(entries) = resources.get(category) else {
+            continue;
+        };
+        let Ok(entries) = doc.dereference(entries).and_then(|(_, obj)| obj.as_dict()) else {
+            problems.push(format!(
+                "Page {}: /{} resources are missing",
+                number,
+                String::from_utf8_lossy(category)
+            ));
+            continue;
+        };
+        for (name, value) in entries.iter() {
+            if let Object::Reference(id) = value {
+                if doc.get_object(*id).is_err() {
+                    problems.push(format!(
+                        "Page {}: /{} /{} is missing",
+                        number,
+                        String::from_utf8_lossy(category),
+                        String::from_utf8_lossy(name)
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use lopdf::dictionary;
+
+    /// A document with one page per content stream
+    fn document(pages: &[&[u8]]) -> Document {
+        testing::document(pages.len(), |doc, index| {
+            dictionary! { "Contents" => testing::content(doc, pages[index]) }
+        })
+        .0
+    }
+
+    fn verify(original: &[&[u8]], output: &[&[u8]]) -> VerificationReport {
+        verify_output(&check_document(&document(original)), Ok(document(output)))
+    }
+
+    #[test]
+    fn accepts_rewritten_operators() {
+        let report = verify(&[b"1 0 0 rg 0 0 5 5 re f"], &[b"0.3 g 0 0 5 5 re f"]);
+        assert!(report.valid, "{:?}", report.problems);
+        assert_eq!(report.output_pages, 1);
+    }
+
+    #[test]
+    fn rejects_dropped_content() {
+        let report = verify(&[b"q 0 0 5 5 re f Q"], &[b"q Q"]);
+        assert!(!report.valid);
+        assert_eq!(
+            report.problems,
+            ["Page 1: output has 2 content operations, the original has 4"]
+        );
+    }
+
+    #[test]
+    fn rejects_content_that_only_partly_parses() {
+        let report = verify(&[b"q Q"], &[b"q ] Q"]);
+        assert!(!report.valid);
+        assert_eq!(report.problems, ["Page 1: content stream can't be parsed"]);
+    }
+
+    #[test]
+    fn ignores_problems_the_original_had() {
+        let report = verify(&[b"q ] Q", b"q Q"], &[b"q ] Q", b"q Q"]);
+        assert!(report.valid, "{:?}", report.problems);
+    }
+
+    #[test]
+    fn rejects_missing_pages() {
+        let report = verify(&[b"q Q", b"q Q"], &[b"q Q"]);
+        assert!(!report.valid);
+        assert_eq!(report.problems[0], "Output has 1 pages, the original has 2");
+    }
+
+    #[test]
+    fn rolls_back_invalid_or_larger_output() {
+        let mut report = verify(&[b"q Q"], &[b"q Q"]);
+        assert!(!report.should_roll_back(90, 100));
+        assert!(report.should_roll_back(110, 100));
+        assert_eq!(report.rollback_reason, Some(RollbackReason::Larger));
+
+        report.valid = false;
+        assert!(report.should_roll_back(90, 100));
+        assert_eq!(report.rollback_reason, Some(RollbackReason::Invalid));
+    }
+}
@@ -0,0 +1,31 @@
+// Mirrors the serialized structs in src-tauri/src/assembly.rs
+
+/** A PDF for the page assembly commands: a file on disk or its bytes */
+export type PdfSource = { path: string } | { content: number[] };
+
+/** A page of a merged PDF: index into the sources and page number there, from 1 */
+export interface PageSelection {
+	source: number;
+	page: number;
+}
+
+export interface AssembledPdf {
+	/** The new PDF, or null when it was written to the output path */
+	content: number[] | null;
+	pageCount: number;
+}
+
+/** How split_pdf divides a document */
+export type SplitMode =
+	| { kind: 'bookmarks' }
+	| { kind: 'everyPages'; value: number }
+	| { kind: 'maxBytes'; value: number };
+
+export interface SplitFile {
+	path: string;
+	/** The bookmark the file starts at, when splitting by bookmarks */
+	title: string | null;
+	firstPage: number;
+	lastPage: number;
+	size: number;
+}
@@ -0,0 +1,161 @@
+// Mirrors the serialized structs in src-tauri/src/compression.rs
+
+export interface CompressionOptions {
+	quality?: number;
+	/** Downsample images drawn above this resolution (e.g. 72, 150, 300) */
+	targetDpi?: number | null;
+	/** Leave images untouched and only apply lossless structural optimisations */
+	lossless?: boolean;
+	/** Convert images, text and vector art to gray */
+	grayscale?: boolean;
+	/** Remove the document info dictionary and XMP metadata */
+	stripMetadata?: boolean;
+	/** Write progressive JPEGs */
+	jpegProgressive?: boolean;
+	/** Chroma subsampling for colour JPEGs (default 4:2:2) */
+	chromaSubsampling?: ChromaSubsampling;
+	/** Write a linearized ("Fast Web View") PDF whose first page shows before it has fully downloaded */
+	linearize?: boolean;
+}
+
+export type ChromaSubsampling = '4:4:4' | '4:2:2' | '4:2:0';
+
+export type ImageCompressionResult = 'recompressed' | 'skippedThreshold' | 'failed';
+
+export type SkipReason =
+	| { kind: 'hasTransparency' }
+	| { kind: 'tooSmall' }
+	| { kind: 'unsupportedBitsPerComponent' }
+	| { kind: 'unsupportedColorSpace'; name: string }
+	| { kind: 'unsupportedFilter'; name: string }
+	| { kind: 'hasPredictor' }
+	| { kind: 'hasDecodeArray' };
+
+/** How CMYK samples were handled: converted through an ICC profile, or kept as CMYK */
+export type CmykHandling = 'embeddedProfile' | 'outputIntentProfile' | 'keptCmyk';
+
+/** Photos are written as JPEG, graphics and masks losslessly with Flate, 1-bit images with CCITT G4 when smaller */
+export type ImageEncoder = 'jpeg' | 'flate' | 'ccitt';
+
+export interface ImageReport {
+	objectId: number;
+	generation: number;
+	width: number | null;
+	height: number | null;
+	colorSpace: string;
+	filter: string;
+	isSoftMask: boolean;
+	effectiveDpi: number | null;
+	newWidth: number | null;
+	newHeight: number | null;
+	cmykHandling: CmykHandling | null;
+	encoder: ImageEncoder | null;
+	result: ImageCompressionResult | null;
+	skipReason: SkipReason | null;
+	originalBytes: number;
+	compressedBytes: number;
+	bytesSaved: number;
+}
+
+export interface DedupReport {
+	images: number;
+	fonts: number;
+	bytesSaved: number;
+}
+
+export interface StructureReport {
+	streamsRedeflated: number;
+	redeflateBytesSaved: number;
+	duplicateStreams: number;
+	duplicateBytesSaved: number;
+	thumbnailsRemoved: number;
+	pieceInfoRemoved: number;
+	namedDestsRemoved: number;
+}
+
+/** Why the original was returned instead of the compressed output */
+export type RollbackReason = 'invalid' | 'larger';
+
+export interface VerificationReport {
+	/** The output parsed, has as many pages as the original and no new problems */
+	valid: boolean;
+	originalPages: number;
+	outputPages: number;
+	/** Problems found in the output that the original didn't have */
+	problems: string[];
+	rollbackReason: RollbackReason | null;
+}
+
+export interface CompressionReport {
+	quality: number;
+	targetDpi: number | null;
+	lossless: boolean;
+	grayscale: boolean;
+	/** The run was cancelled and the original PDF returned */
+	cancelled: boolean;
+	originalSize: number;
+	compressedSize: number;
+	totalImages: number;
+	candidates: number;
+	recompressed: number;
+	skippedThreshold: number;
+	failed: number;
+	downsampled: number;
+	imageBytesSaved: number;
+	skipCounts: Record<string, number>;
+	filterTypes: Record<string, number>;
+	colorspaceTypes: Record<string, number>;
+	images: ImageReport[];
+	dedup: DedupReport;
+	structure: StructureReport | null;
+	/** Colour operators in content streams rewritten to gray */
+	grayOperators: number;
+	/** Info dictionary and XMP metadata entries removed */
+	metadataRemoved: number;
+	/** Page and form resource entries no content stream used */
+	unusedResourcesRemoved: number;
+	/** Checks on the re-read output; null when the run was cancelled */
+	verification: VerificationReport | null;
+}
+
+/** A named bundle of compression settings; user presets are saved in the app data directory */
+export interface CompressionPreset {
+	name: string;
+	description: string;
+	options: CompressionOptions;
+	/** Built-in presets can't be overwritten or deleted */
+	builtIn: boolean;
+}
+
+export interface CompressedPdf {
+	content: number[];
+	report: CompressionReport;
+}
+
+export interface SizedPdf {
+	content: number[];
+	report: CompressionReport;
+	/** Settings the search settled on */
+	settings: CompressionOptions;
+	maxBytes: number;
+	attempts: number;
+}
+
+export type CompressionPhase =
+	| 'loading'
+	| 'analyzing'
+	| 'images'
+	| 'optimizing'
+	| 'saving'
+	| 'verifying';
+
+/** Payload of the "compression-progress" event */
+export interface CompressionProgress {
+	/** The run ID the compression was started with */
+	runId: string;
+	phase: CompressionPhase;
+	imagesDone: number;
+	imagesTotal: number;
+	/** Which compression run this is; target-size compression makes several */
+	attempt: number;
+}
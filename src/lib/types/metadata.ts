@@ -0,0 +1,16 @@
+// Mirrors the serialized struct in src-tauri/src/metadata.rs
+
+export interface PdfMetadata {
+	title: string | null;
+	author: string | null;
+	subject: string | null;
+	keywords: string | null;
+	/** The application the document was created in */
+	creator: string | null;
+	/** The application that converted it to PDF */
+	producer: string | null;
+	/** ISO 8601, e.g. "2024-05-01T13:45:00+02:00"; without an offset when the PDF gives none */
+	creationDate: string | null;
+	/** Set to the current time on save when null */
+	modDate: string | null;
+}
@@ -0,0 +1,44 @@
+// Mirrors the serialized structs in src-tauri/src/outline.rs
+
+/** How a bookmark shows its page; coordinates left null keep their current value */
+export type Zoom =
+	| { kind: 'xyz'; left: number | null; top: number | null; zoom: number | null }
+	| { kind: 'fit' }
+	| { kind: 'fitH'; top: number | null }
+	| { kind: 'fitV'; left: number | null }
+	| { kind: 'fitR'; left: number; bottom: number; right: number; top: number }
+	| { kind: 'fitB' }
+	| { kind: 'fitBH'; top: number | null }
+	| { kind: 'fitBV'; left: number | null };
+
+export interface OutlineItem {
+	title: string;
+	/** Page the bookmark goes to, from 1; null when it goes nowhere in this document */
+	page: number | null;
+	/** null keeps the reader's current zoom */
+	zoom: Zoom | null;
+	/** Whether the children are shown expanded */
+	open: boolean;
+	children: OutlineItem[];
+	/** The named destination the bookmark goes through, kept while it still leads to page and zoom */
+	destinationName?: string | null;
+	/** What the bookmark does when it doesn't go to a page of this document; pass it back as is */
+	action?: PdfValue | null;
+	/** Title colour as RGB components from 0 to 1 */
+	color?: [number, number, number] | null;
+	bold?: boolean;
+	italic?: boolean;
+}
+
+/** A PDF object read from the document, with page references as page numbers */
+export type PdfValue =
+	| { type: 'null' }
+	| { type: 'boolean'; value: boolean }
+	| { type: 'integer'; value: number }
+	| { type: 'real'; value: number }
+	| { type: 'name'; value: string }
+	| { type: 'string'; value: number[] }
+	| { type: 'array'; value: PdfValue[] }
+	| { type: 'dictionary'; value: [string, PdfValue][] }
+	| { type: 'stream'; value: { dict: [string, PdfValue][]; data: number[] } }
+	| { type: 'page'; value: number };
@@ -0,0 +1,21 @@
+// Mirrors the serialized structs in src-tauri/src/sanitize.rs
+
+export type SanitizedKind =
+	| 'info'
+	| 'xmp'
+	| 'fileId'
+	| 'embeddedFile'
+	| 'javaScript'
+	| 'action'
+	| 'xfa';
+
+export interface SanitizedItem {
+	kind: SanitizedKind;
+	/** What it was, e.g. "Author: Jane Doe" or an attachment's file name */
+	description: string;
+}
+
+export interface SanitizedPdf {
+	content: number[];
+	removed: SanitizedItem[];
+}
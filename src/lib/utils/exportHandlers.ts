@@ -1,7 +1,19 @@
 import { invoke } from '@tauri-apps/api/core';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 import { PDFDocument } from 'pdf-lib';
 import { PDFExporter } from './pdfExport';
 import { isTauri } from './tauriUtils';
+import type {
+	CompressedPdf,
+	CompressionOptions,
+	CompressionPreset,
+	CompressionProgress,
+	CompressionReport,
+	SizedPdf
+} from '$lib/types/compression';
+import type { PdfMetadata } from '$lib/types/metadata';
+import type { OutlineItem } from '$lib/types/outline';
+import type { SanitizedItem, SanitizedPdf } from '$lib/types/sanitize';
 
 /**
  * Extract a filename from a URL, with .pdf extension ensured.
@@ -137,16 +149,23 @@ export async function buildAnnotatedPdfExporter(
  * - Falls back to original bytes if all methods fail
  *
  * @param pdfBytes - The PDF file bytes to compress
- * @param quality  - Image quality 1-100 (lower = smaller file, more lossy). Default 75.
+ * @param options - Quality (default 75), target DPI, lossless and grayscale modes, metadata stripping, JPEG encoding; all but quality are Tauri only
+ * @param runId - Identifies the run to cancelCompression and progress listeners (Tauri only)
+ * @returns The compressed bytes, plus the Rust-side report when available (null otherwise)
  */
-export async function compressPdfBytes(pdfBytes: Uint8Array, quality = 75): Promise<Uint8Array> {
+export async function compressPdfBytes(
+	pdfBytes: Uint8Array,
+	options: CompressionOptions = {},
+	runId: string = newCompressionRunId()
+): Promise<{ bytes: Uint8Array; report: CompressionReport | null }> {
 	if (isTauri) {
 		try {
 			const compressed = (await invoke('compress_pdf', {
 				content: Array.from(pdfBytes),
-				quality
-			})) as number[];
-			return new Uint8Array(compressed);
+				options,
+				runId
+			})) as CompressedPdf;
+			return { bytes: new Uint8Array(compressed.content), report: compressed.report };
 		} catch (error) {
 			console.warn('Tauri PDF compression failed, falling back to basic compression:', error);
 		}
@@ -155,11 +174,225 @@ export async function compressPdfBytes(pdfBytes: Uint8Array, quality = 75): Prom
 	// Fallback: use pdf-lib for basic compression (useObjectStreams)
 	try {
 		const doc = await PDFDocument.load(pdfBytes, { ignoreEncryption: true });
-		return await doc.save({ useObjectStreams: true });
+		return { bytes: await doc.save({ useObjectStreams: true }), report: null };
 	} catch (error) {
 		console.warn('pdf-lib compression fallback failed, returning original:', error);
-		return pdfBytes;
+		return { bytes: pdfBytes, report: null };
+	}
+}
+
+/**
+ * Compress a PDF until it fits in a byte budget (Tauri only).
+ * The Rust side searches quality and resolution and keeps as much quality as fits.
+ *
+ * @param pdfBytes - The PDF file bytes to compress
+ * @param maxBytes - Largest acceptable output size in bytes
+ * @param runId - Identifies the run to cancelCompression and progress listeners
+ * @returns The compressed bytes, the report, and the settings that were chosen
+ * @throws If not running in Tauri, or the budget can't be reached even at the lowest settings
+ */
+export async function compressPdfToSize(
+	pdfBytes: Uint8Array,
+	maxBytes: number,
+	runId: string = newCompressionRunId()
+): Promise<{ bytes: Uint8Array; report: CompressionReport; settings: CompressionOptions }> {
+	if (!isTauri) {
+		throw new Error('Target-size compression is only available in the desktop app');
+	}
+	const sized = (await invoke('compress_pdf_to_size', {
+		content: Array.from(pdfBytes),
+		maxBytes,
+		runId
+	})) as SizedPdf;
+	return { bytes: new Uint8Array(sized.content), report: sized.report, settings: sized.settings };
+}
+
+/**
+ * Compress a PDF file on disk into another file (Tauri only).
+ * Nothing is sent over IPC but the paths and the report, so this suits very large files.
+ * Both paths must be absolute and inside the user's directories; they may be the same file.
+ *
+ * @param inputPath - PDF to compress
+ * @param outputPath - Where to write the result; replaced only once it's complete
+ * @param options - Same options as compressPdfBytes
+ * @param runId - Identifies the run to cancelCompression and progress listeners
+ * @returns The compression report; `report.cancelled` means the original was copied as is
+ */
+export async function compressPdfFile(
+	inputPath: string,
+	outputPath: string,
+	options: CompressionOptions = {},
+	runId: string = newCompressionRunId()
+): Promise<CompressionReport> {
+	if (!isTauri) {
+		throw new Error('File compression is only available in the desktop app');
 	}
+	return (await invoke('compress_pdf_file', {
+		inputPath,
+		outputPath,
+		options,
+		runId
+	})) as CompressionReport;
+}
+
+/**
+ * List the built-in compression presets followed by the user's own (Tauri only).
+ *
+ * @returns The presets, or an empty list outside Tauri
+ */
+export async function listCompressionPresets(): Promise<CompressionPreset[]> {
+	if (!isTauri) return [];
+	return (await invoke('list_compression_presets')) as CompressionPreset[];
+}
+
+/**
+ * Save a user preset, replacing any with the same name (Tauri only).
+ *
+ * @returns The updated preset list
+ * @throws If the name is empty or belongs to a built-in preset
+ */
+export async function saveCompressionPreset(
+	preset: CompressionPreset
+): Promise<CompressionPreset[]> {
+	return (await invoke('save_compression_preset', { preset })) as CompressionPreset[];
+}
+
+/**
+ * Delete a user preset by name (Tauri only).
+ *
+ * @returns The updated preset list
+ */
+export async function deleteCompressionPreset(name: string): Promise<CompressionPreset[]> {
+	return (await invoke('delete_compression_preset', { name })) as CompressionPreset[];
+}
+
+/**
+ * Remove the document info, XMP metadata, embedded files and JavaScript from a PDF and
+ * reset its file identifier (Tauri only).
+ *
+ * @param pdfBytes - The PDF file bytes to sanitize
+ * @returns The sanitized bytes and a list of everything that was removed
+ * @throws If not running in Tauri, or the PDF can't be read (e.g. it's encrypted)
+ */
+export async function sanitizePdfBytes(
+	pdfBytes: Uint8Array
+): Promise<{ bytes: Uint8Array; removed: SanitizedItem[] }> {
+	if (!isTauri) {
+		throw new Error('Sanitizing is only available in the desktop app');
+	}
+	const sanitized = (await invoke('sanitize_pdf', {
+		content: Array.from(pdfBytes)
+	})) as SanitizedPdf;
+	return { bytes: new Uint8Array(sanitized.content), removed: sanitized.removed };
+}
+
+/**
+ * Read a PDF's title, author, dates and other metadata (Tauri only).
+ *
+ * @param pdfBytes - The PDF file bytes
+ * @returns Values from the Info dictionary, or the XMP metadata where it has none
+ */
+export async function getPdfMetadata(pdfBytes: Uint8Array): Promise<PdfMetadata> {
+	if (!isTauri) {
+		throw new Error('Reading document properties is only available in the desktop app');
+	}
+	return (await invoke('get_pdf_metadata', {
+		source: { content: Array.from(pdfBytes) }
+	})) as PdfMetadata;
+}
+
+/**
+ * Replace a PDF's metadata in both the Info dictionary and the XMP metadata (Tauri only).
+ *
+ * @param pdfBytes - The PDF file bytes
+ * @param metadata - The new values; null fields are removed, and a null modDate becomes now
+ * @returns The updated PDF bytes
+ * @throws If a date isn't valid ISO 8601 or PDF date syntax
+ */
+export async function setPdfMetadata(
+	pdfBytes: Uint8Array,
+	metadata: PdfMetadata
+): Promise<Uint8Array> {
+	if (!isTauri) {
+		throw new Error('Editing document properties is only available in the desktop app');
+	}
+	const content = (await invoke('set_pdf_metadata', {
+		content: Array.from(pdfBytes),
+		metadata
+	})) as number[];
+	return new Uint8Array(content);
+}
+
+/**
+ * Read a PDF's bookmarks, with named destinations resolved to pages (Tauri only).
+ *
+ * @param pdfBytes - The PDF file bytes
+ * @returns The outline tree; empty when the PDF has none
+ */
+export async function getPdfOutline(pdfBytes: Uint8Array): Promise<OutlineItem[]> {
+	if (!isTauri) {
+		throw new Error('Reading bookmarks is only available in the desktop app');
+	}
+	return (await invoke('get_pdf_outline', {
+		source: { content: Array.from(pdfBytes) }
+	})) as OutlineItem[];
+}
+
+/**
+ * Replace a PDF's bookmarks (Tauri only). To change them on export instead, pass
+ * `outline` to PDFExporter.exportFile.
+ *
+ * @param pdfBytes - The PDF file bytes
+ * @param outline - The new tree; an empty array removes the outline
+ * @returns The updated PDF bytes
+ */
+export async function setPdfOutline(
+	pdfBytes: Uint8Array,
+	outline: OutlineItem[]
+): Promise<Uint8Array> {
+	if (!isTauri) {
+		throw new Error('Editing bookmarks is only available in the desktop app');
+	}
+	const content = (await invoke('set_pdf_outline', {
+		content: Array.from(pdfBytes),
+		outline
+	})) as number[];
+	return new Uint8Array(content);
+}
+
+/**
+ * Make an ID for a compression run, to pass to the compress call and cancelCompression.
+ */
+export function newCompressionRunId(): string {
+	return crypto.randomUUID();
+}
+
+/**
+ * Subscribe to progress events from the Rust compressor (Tauri only).
+ *
+ * @param callback - Called with the phase and images done/total as compression runs
+ * @param runId - Only report this run; all runs when omitted
+ * @returns A function that stops listening
+ */
+export async function onCompressionProgress(
+	callback: (progress: CompressionProgress) => void,
+	runId?: string
+): Promise<UnlistenFn> {
+	if (!isTauri) return () => {};
+	return listen<CompressionProgress>('compression-progress', (event) => {
+		if (runId === undefined || event.payload.runId === runId) callback(event.payload);
+	});
+}
+
+/**
+ * Ask a running compression to stop (Tauri only). Other runs carry on.
+ * Its pending compress call then resolves with the original bytes and `report.cancelled` set.
+ *
+ * @param runId - The ID the compression was started with
+ */
+export async function cancelCompression(runId: string): Promise<void> {
+	if (!isTauri) return;
+	await invoke('cancel_compression', { runId });
 }
 
 /**
@@ -10,9 +10,20 @@ import type {
 	StampAnnotation,
 	ImageAnnotation
 } from '../stores/drawingStore';
+import type { OutlineItem } from '$lib/types/outline';
 import { getStampById } from '../stores/drawingStore';
 import { transformPoint } from './rotationUtils';
 
+// Processing applied by the Tauri backend before the exported file is written
+export interface TauriExportOptions {
+	/** Write the PDF for Fast Web View */
+	linearize?: boolean;
+	/** Strip metadata, attachments and JavaScript */
+	sanitize?: boolean;
+	/** Replace the document outline (bookmarks); an empty array removes it */
+	outline?: OutlineItem[];
+}
+
 export interface PageAnnotations {
 	drawingPaths: DrawingPath[];
 	textAnnotations: TextAnnotation[];
@@ -915,13 +926,15 @@ export class PDFExporter {
 	}
 
 	// Enhanced export method that uses Tauri backend when available
+	// `options` (outline, sanitize, linearize) only apply in the desktop app
 	static async exportFile(
 		data: Uint8Array | Blob,
 		defaultFilename: string,
-		mimeType: string
+		mimeType: string,
+		options: TauriExportOptions = {}
 	): Promise<boolean> {
 		if (isTauri) {
-			return await PDFExporter.exportWithTauri(data, defaultFilename, mimeType);
+			return await PDFExporter.exportWithTauri(data, defaultFilename, mimeType, options);
 		} else {
 			PDFExporter.downloadFile(data, defaultFilename, mimeType);
 			return true;
@@ -932,7 +945,8 @@ export class PDFExporter {
 	static async exportWithTauri(
 		data: Uint8Array | Blob,
 		defaultFilename: string,
-		mimeType: string
+		mimeType: string,
+		options: TauriExportOptions = {}
 	): Promise<boolean> {
 		try {
 			console.log('Using Tauri backend for file export');
@@ -974,7 +988,10 @@ export class PDFExporter {
 				content: Array.from(bytes),
 				defaultFilename,
 				filterName,
-				extension
+				extension,
+				linearize: options.linearize ?? false,
+				sanitize: options.sanitize ?? false,
+				outline: options.outline ?? null
 			});
 
 			if (!filePath) {
@@ -1,5 +1,14 @@
+import { invoke } from '@tauri-apps/api/core';
 import { PDFDocument } from 'pdf-lib';
 import * as pdfjsLib from 'pdfjs-dist';
+import { isTauri } from './tauriUtils';
+import type {
+	AssembledPdf,
+	PageSelection,
+	PdfSource,
+	SplitFile,
+	SplitMode
+} from '$lib/types/assembly';
 
 // Initialize PDF.js worker
 if (typeof window !== 'undefined') {
@@ -120,6 +129,54 @@ export async function mergePDFs(
 	return await mergePDFsWithFiles(pages, filesMap);
 }
 
+/**
+ * Run one of the native page assembly commands (Tauri only). They keep outlines,
+ * links and form fields, and handle files too large for pdf-lib in the webview.
+ *
+ * @param command - merge_pdfs, extract_pdf_pages, reorder_pdf_pages, delete_pdf_pages or rotate_pdf_pages
+ * @param args - The command's arguments; without outputPath the new PDF's bytes are returned
+ */
+export async function runNativeAssembly(
+	command: string,
+	args: Record<string, unknown>
+): Promise<AssembledPdf> {
+	if (!isTauri) {
+		throw new Error('Native page assembly is only available in the desktop app');
+	}
+	return (await invoke(command, args)) as AssembledPdf;
+}
+
+/**
+ * Split a PDF into files in a directory (Tauri only): one per top-level bookmark, named
+ * after it, or every N pages, or as many pages as fit under a maximum size.
+ *
+ * @param source - The PDF, by path or bytes
+ * @param mode - How to divide it
+ * @param outputDir - Absolute path of the directory to write to; existing files are kept
+ * @param baseName - Start of file names for page and size splits (default: the source file name)
+ * @returns The files written, in page order
+ */
+export async function splitPdf(
+	source: PdfSource,
+	mode: SplitMode,
+	outputDir: string,
+	baseName?: string
+): Promise<SplitFile[]> {
+	if (!isTauri) {
+		throw new Error('Splitting is only available in the desktop app');
+	}
+	return (await invoke('split_pdf', { source, mode, outputDir, baseName })) as SplitFile[];
+}
+
+async function fileSource(file: File): Promise<PdfSource> {
+	return { content: Array.from(new Uint8Array(await file.arrayBuffer())) };
+}
+
+async function assembledBytes(command: string, args: Record<string, unknown>): Promise<Uint8Array> {
+	const result = await runNativeAssembly(command, args);
+	return new Uint8Array(result.content ?? []);
+}
+
 /**
  * Merge PDFs with file map for efficient loading
  */
@@ -127,6 +184,28 @@ export async function mergePDFsWithFiles(
 	pages: PDFPageInfo[],
 	filesMap: Map<string, File>
 ): Promise<Uint8Array> {
+	if (isTauri) {
+		try {
+			const fileIds = [...new Set(pages.map((page) => page.sourceFileId))];
+			const sources = await Promise.all(
+				fileIds.map((id) => {
+					const file = filesMap.get(id);
+					if (!file) {
+						throw new Error(`File not found for ID: ${id}`);
+					}
+					return fileSource(file);
+				})
+			);
+			const selection: PageSelection[] = pages.map((page) => ({
+				source: fileIds.indexOf(page.sourceFileId),
+				page: page.pageNumber
+			}));
+			return await assembledBytes('merge_pdfs', { sources, pages: selection });
+		} catch (error) {
+			console.warn('Native merge failed, falling back to pdf-lib:', error);
+		}
+	}
+
 	const mergedPdf = await PDFDocument.create();
 	const loadedPdfs = new Map<string, PDFDocument>();
 	
@@ -155,12 +234,28 @@ export async function mergePDFsWithFiles(
 }
 
 /**
- * Reorder pages within a single PDF
+ * Reorder pages within a single PDF.
+ * `newOrder` lists 1-indexed page numbers; pages left out are dropped and pages listed
+ * more than once are copied.
  */
 export async function reorderPDFPages(
 	file: File,
 	newOrder: number[]
 ): Promise<Uint8Array> {
+	if (isTauri) {
+		try {
+			// reorder_pdf_pages only takes every page exactly once, so the order is sent as a
+			// one-file merge, which takes any selection
+			const selection: PageSelection[] = newOrder.map((page) => ({ source: 0, page }));
+			return await assembledBytes('merge_pdfs', {
+				sources: [await fileSource(file)],
+				pages: selection
+			});
+		} catch (error) {
+			console.warn('Native reorder failed, falling back to pdf-lib:', error);
+		}
+	}
+
 	const arrayBuffer = await file.arrayBuffer();
 	const sourcePdf = await PDFDocument.load(arrayBuffer);
 	const reorderedPdf = await PDFDocument.create();
@@ -181,6 +276,17 @@ export async function deletePDFPages(
 	file: File,
 	pageNumbersToDelete: number[]
 ): Promise<Uint8Array> {
+	if (isTauri) {
+		try {
+			return await assembledBytes('delete_pdf_pages', {
+				source: await fileSource(file),
+				pages: pageNumbersToDelete
+			});
+		} catch (error) {
+			console.warn('Native delete failed, falling back to pdf-lib:', error);
+		}
+	}
+
 	const arrayBuffer = await file.arrayBuffer();
 	const sourcePdf = await PDFDocument.load(arrayBuffer);
 	const newPdf = await PDFDocument.create();